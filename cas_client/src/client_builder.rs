@@ -0,0 +1,329 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use cas_object::CompressionScheme;
+use chunk_cache::CacheConfig;
+use reqwest_retry::DefaultRetryableStrategy;
+use tokio::sync::Semaphore;
+use utils::auth::AuthConfig;
+use xet_threadpool::ThreadPool;
+
+use crate::error::{CasClientError, Result};
+use crate::{ChunkHashSource, Client, HttpClientConfig, LocalClient, RemoteClient, RetryConfig};
+
+/// Where the client built by a `ClientBuilder` stores xorbs and shards.
+enum Backend {
+    Server(String),
+    FileSystem(PathBuf),
+    #[cfg(any(test, feature = "memory_client"))]
+    Memory,
+}
+
+/// Builds a fully configured `Client` from a single set of settings, for a CAS server endpoint or
+/// for local storage.
+///
+/// The storage is required, as is the shard cache directory for a server endpoint.  The other
+/// settings default to those of `RemoteClient::new`; settings that only apply to a server are
+/// ignored for local storage.
+#[derive(Default)]
+pub struct ClientBuilder {
+    backend: Option<Backend>,
+    fallback_endpoints: Vec<String>,
+    compression: Option<CompressionScheme>,
+    auth: Option<AuthConfig>,
+    cache_config: Option<CacheConfig>,
+    shard_cache_directory: Option<PathBuf>,
+    http_config: HttpClientConfig,
+    retry_config: Option<RetryConfig<DefaultRetryableStrategy>>,
+    max_concurrent_requests: Option<usize>,
+    max_open_output_handles: Option<usize>,
+    max_concurrent_reconstruction_queries: Option<usize>,
+    max_reconstruction_terms: Option<usize>,
+    max_response_bytes: Option<u64>,
+    chunk_hash_source: Option<ChunkHashSource>,
+    decompression_buffers: Option<usize>,
+    durable_writes: bool,
+    validate_version: bool,
+    coalesce_hint: bool,
+    dry_run: bool,
+}
+
+impl ClientBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores xorbs and shards on the CAS server at `endpoint`.
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.backend = Some(Backend::Server(endpoint.to_owned()));
+        self
+    }
+
+    /// Stores xorbs and shards in the local directory `path`.
+    pub fn with_local_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.backend = Some(Backend::FileSystem(path.into()));
+        self
+    }
+
+    /// Stores xorbs and shards in memory, for tests.
+    #[cfg(any(test, feature = "memory_client"))]
+    pub fn with_memory_storage(mut self) -> Self {
+        self.backend = Some(Backend::Memory);
+        self
+    }
+
+    /// Sets the mirror endpoints downloads fall back to; see `RemoteClient::with_fallback_endpoints`.
+    pub fn with_fallback_endpoints(mut self, fallback_endpoints: Vec<String>) -> Self {
+        self.fallback_endpoints = fallback_endpoints;
+        self
+    }
+
+    /// Sets the compression scheme of uploaded xorbs; None picks one per xorb.
+    pub fn with_compression(mut self, compression: Option<CompressionScheme>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn with_auth(mut self, auth: Option<AuthConfig>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Sets the chunk cache; without one, downloaded chunks aren't cached.
+    pub fn with_cache_config(mut self, cache_config: Option<CacheConfig>) -> Self {
+        self.cache_config = cache_config;
+        self
+    }
+
+    /// Sets the directory shards fetched for global dedup are written to.
+    pub fn with_shard_cache_directory(mut self, shard_cache_directory: impl Into<PathBuf>) -> Self {
+        self.shard_cache_directory = Some(shard_cache_directory.into());
+        self
+    }
+
+    pub fn with_http_config(mut self, http_config: HttpClientConfig) -> Self {
+        self.http_config = http_config;
+        self
+    }
+
+    /// Sets how failed requests are retried.  Requests that must not be retried on 429 still aren't.
+    pub fn with_retry_config(mut self, retry_config: RetryConfig<DefaultRetryableStrategy>) -> Self {
+        self.retry_config = Some(retry_config);
+        self
+    }
+
+    /// Limits the requests in flight at once to `max_concurrent_requests`, rather than sharing the
+    /// limit of the threadpool with its other clients.
+    pub fn with_max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.max_concurrent_requests = Some(max_concurrent_requests);
+        self
+    }
+
+    /// See `RemoteClient::with_max_open_output_handles`.
+    pub fn with_max_open_output_handles(mut self, max_open_output_handles: usize) -> Self {
+        self.max_open_output_handles = Some(max_open_output_handles);
+        self
+    }
+
+    /// See `RemoteClient::with_max_concurrent_reconstruction_queries`.
+    pub fn with_max_concurrent_reconstruction_queries(mut self, max_concurrent_reconstruction_queries: usize) -> Self {
+        self.max_concurrent_reconstruction_queries = Some(max_concurrent_reconstruction_queries);
+        self
+    }
+
+    /// See `RemoteClient::with_max_reconstruction_terms`.
+    pub fn with_max_reconstruction_terms(mut self, max_reconstruction_terms: usize) -> Self {
+        self.max_reconstruction_terms = Some(max_reconstruction_terms);
+        self
+    }
+
+    /// See `RemoteClient::with_max_response_bytes`.
+    pub fn with_max_response_bytes(mut self, max_response_bytes: u64) -> Self {
+        self.max_response_bytes = Some(max_response_bytes);
+        self
+    }
+
+    /// See `RemoteClient::with_chunk_verification`.
+    pub fn with_chunk_verification(mut self, chunk_hash_source: Option<ChunkHashSource>) -> Self {
+        self.chunk_hash_source = chunk_hash_source;
+        self
+    }
+
+    /// See `RemoteClient::with_decompression_buffer_pool`.
+    pub fn with_decompression_buffer_pool(mut self, max_buffers: usize) -> Self {
+        self.decompression_buffers = Some(max_buffers);
+        self
+    }
+
+    /// See `RemoteClient::with_durable_writes`.
+    pub fn with_durable_writes(mut self, durable_writes: bool) -> Self {
+        self.durable_writes = durable_writes;
+        self
+    }
+
+    /// See `RemoteClient::with_version_validation`.
+    pub fn with_version_validation(mut self, validate_version: bool) -> Self {
+        self.validate_version = validate_version;
+        self
+    }
+
+    /// See `RemoteClient::with_coalesce_hint`.
+    pub fn with_coalesce_hint(mut self, coalesce_hint: bool) -> Self {
+        self.coalesce_hint = coalesce_hint;
+        self
+    }
+
+    /// Skips uploads, as in `RemoteClient::new`.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Builds the client, failing if a required setting is missing or a setting is invalid.
+    pub fn build(self, threadpool: Arc<ThreadPool>) -> Result<Arc<dyn Client + Send + Sync>> {
+        let missing = |what: &str| CasClientError::ConfigurationError(format!("client builder: missing {what}"));
+
+        match self.backend.ok_or_else(|| missing("endpoint or local path"))? {
+            Backend::Server(endpoint) => {
+                let shard_cache_directory =
+                    self.shard_cache_directory.ok_or_else(|| missing("shard cache directory"))?;
+                // Fail on invalid extra headers here, as the remote client expects its HTTP clients
+                // to build.
+                self.http_config.extra_header_map()?;
+                let network_request_permits = match self.max_concurrent_requests {
+                    Some(0) => {
+                        return Err(CasClientError::ConfigurationError(
+                            "client builder: max concurrent requests must be at least 1".to_owned(),
+                        ))
+                    },
+                    Some(max_concurrent_requests) => Arc::new(Semaphore::new(max_concurrent_requests)),
+                    None => threadpool.network_request_permits(),
+                };
+
+                let mut client = RemoteClient::new_with_configs(
+                    threadpool,
+                    &endpoint,
+                    self.compression,
+                    &self.auth,
+                    &self.cache_config,
+                    shard_cache_directory,
+                    self.dry_run,
+                    &self.http_config,
+                    self.retry_config.unwrap_or_default(),
+                    network_request_permits,
+                )?
+                .with_fallback_endpoints(self.fallback_endpoints)?
+                .with_version_validation(self.validate_version)
+                .with_coalesce_hint(self.coalesce_hint)
+                .with_durable_writes(self.durable_writes);
+                if let Some(max_open_output_handles) = self.max_open_output_handles {
+                    client = client.with_max_open_output_handles(max_open_output_handles);
+                }
+                if let Some(max_concurrent_reconstruction_queries) = self.max_concurrent_reconstruction_queries {
+                    client = client.with_max_concurrent_reconstruction_queries(max_concurrent_reconstruction_queries);
+                }
+                if let Some(max_reconstruction_terms) = self.max_reconstruction_terms {
+                    client = client.with_max_reconstruction_terms(max_reconstruction_terms);
+                }
+                if let Some(max_response_bytes) = self.max_response_bytes {
+                    client = client.with_max_response_bytes(max_response_bytes);
+                }
+                if let Some(chunk_hash_source) = self.chunk_hash_source {
+                    client = client.with_chunk_verification(chunk_hash_source);
+                }
+                if let Some(max_buffers) = self.decompression_buffers {
+                    client = client.with_decompression_buffer_pool(max_buffers);
+                }
+                Ok(Arc::new(client))
+            },
+            Backend::FileSystem(path) => Ok(Arc::new(LocalClient::new(path, None)?)),
+            #[cfg(any(test, feature = "memory_client"))]
+            Backend::Memory => Ok(Arc::new(crate::MemoryLocalClient::new())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cas_object::test_utils::*;
+    use mdb_shard::file_structs::{FileDataSequenceEntry, FileDataSequenceHeader, MDBFileInfo};
+    use mdb_shard::shard_format::test_routines::convert_to_file;
+    use mdb_shard::shard_in_memory::MDBInMemoryShard;
+    use merklehash::compute_data_hash;
+
+    use super::*;
+    use crate::interface::buffer::BufferProvider;
+    use crate::OutputProvider;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_build_and_round_trip() {
+        let threadpool = ThreadPool::from_current_runtime();
+        let client = ClientBuilder::new()
+            .with_memory_storage()
+            .with_compression(Some(CompressionScheme::LZ4))
+            .build(threadpool.clone())
+            .unwrap();
+
+        let (c, _, raw_data, chunk_boundaries) = build_cas_object(8, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        client
+            .put("default", &c.info.cashash, raw_data.clone(), chunk_boundaries)
+            .await
+            .unwrap();
+
+        let file_hash = compute_data_hash(&raw_data);
+        let mut shard = MDBInMemoryShard::default();
+        shard
+            .add_file_reconstruction_info(MDBFileInfo {
+                metadata: FileDataSequenceHeader::new(file_hash, 1, false, false),
+                segments: vec![FileDataSequenceEntry::new(c.info.cashash, raw_data.len(), 0, 8)],
+                verification: vec![],
+                metadata_ext: None,
+            })
+            .unwrap();
+        let shard_data = convert_to_file(&shard).unwrap();
+        client
+            .upload_shard("default", &compute_data_hash(&shard_data), true, &shard_data, &[0; 32])
+            .await
+            .unwrap();
+
+        let provider = BufferProvider::default();
+        let buf = provider.buf.clone();
+        client
+            .get_file(&file_hash, None, &OutputProvider::Buffer(provider), None)
+            .await
+            .unwrap();
+        assert_eq!(buf.value(), raw_data);
+
+        // A server client needs a valid endpoint and a shard cache directory.
+        let tmp_dir = tempfile::TempDir::new().unwrap();
+        assert!(matches!(ClientBuilder::new().build(threadpool.clone()), Err(CasClientError::ConfigurationError(_))));
+        assert!(matches!(
+            ClientBuilder::new()
+                .with_endpoint("http://localhost:8080")
+                .build(threadpool.clone()),
+            Err(CasClientError::ConfigurationError(_))
+        ));
+        assert!(matches!(
+            ClientBuilder::new()
+                .with_endpoint("localhost:8080")
+                .with_shard_cache_directory(tmp_dir.path())
+                .build(threadpool.clone()),
+            Err(CasClientError::ConfigurationError(_))
+        ));
+        assert!(matches!(
+            ClientBuilder::new()
+                .with_endpoint("http://localhost:8080")
+                .with_shard_cache_directory(tmp_dir.path())
+                .with_max_concurrent_requests(0)
+                .build(threadpool.clone()),
+            Err(CasClientError::ConfigurationError(_))
+        ));
+        assert!(ClientBuilder::new()
+            .with_endpoint("http://localhost:8080")
+            .with_shard_cache_directory(tmp_dir.path())
+            .with_retry_config(RetryConfig::default().with_num_retries(1))
+            .with_max_concurrent_requests(4)
+            .build(threadpool)
+            .is_ok());
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+/// Source of time for retry backoff, so that tests can drive retries without waiting.
+#[async_trait]
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    async fn sleep(&self, duration: Duration);
+}
+
+/// The real clock, sleeping on the tokio timer.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+#[async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// A clock that only moves when advanced.  Sleeping returns immediately, recording the requested
+/// duration and advancing the clock by it.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct MockClock {
+    state: std::sync::Mutex<MockClockState>,
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct MockClockState {
+    now: Instant,
+    sleeps: Vec<Duration>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: std::sync::Mutex::new(MockClockState {
+                now: Instant::now(),
+                sleeps: Vec::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn advance(&self, duration: Duration) {
+        self.state.lock().unwrap().now += duration;
+    }
+
+    /// The durations of all sleeps requested so far, in order.
+    pub(crate) fn sleeps(&self) -> Vec<Duration> {
+        self.state.lock().unwrap().sleeps.clone()
+    }
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.state.lock().unwrap().now
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.state.lock().unwrap().sleeps.push(duration);
+        self.advance(duration);
+    }
+}
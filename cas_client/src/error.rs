@@ -1,3 +1,5 @@
+use cas_types::Key;
+use http::StatusCode;
 use merklehash::MerkleHash;
 use thiserror::Error;
 
@@ -40,6 +42,9 @@ pub enum CasClientError {
     #[error("Parse Error: {0}")]
     ParseError(#[from] url::ParseError),
 
+    #[error(transparent)]
+    RequestFailed(Box<RequestFailure>),
+
     #[error("ReqwestMiddleware Error: {0}")]
     ReqwestMiddlewareError(#[from] reqwest_middleware::Error),
 
@@ -51,6 +56,53 @@ pub enum CasClientError {
 
     #[error("CAS object not found for hash: {0}")]
     XORBNotFound(MerkleHash),
+
+    #[error("Shard {0} is not in the shard cache")]
+    ShardNotCached(MerkleHash),
+
+    #[error("CAS object is invalid or does not match hash: {0}")]
+    InvalidXORB(MerkleHash),
+
+    #[error("Chunk {index} of xorb {hash} does not match the hash recorded for it")]
+    ChunkHashMismatch { hash: MerkleHash, index: u32 },
+
+    #[error("File {0} changed on the server during the download")]
+    VersionChanged(MerkleHash),
+
+    #[error("Response too large: {0}")]
+    ResponseTooLarge(String),
+
+    #[error("Reconstruction offset overflow: {0}")]
+    ReconstructionOverflow(String),
+
+    #[error("Lock poisoned")]
+    LockPoison,
+
+    #[error("Canceled")]
+    Canceled,
+
+    #[error("Upload aborted, the server is out of storage or the storage quota is exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("JSON Error: {0}")]
+    JsonError(#[from] serde_json::Error),
+}
+
+/// A failed request to CAS or to the blob store, with the request and the object it concerned.
+#[derive(Error, Debug)]
+#[error(
+    "{api} request for {key} to {url} failed{}: {source}",
+    .status.map(|status| format!(" with status {status}")).unwrap_or_default()
+)]
+pub struct RequestFailure {
+    pub api: String,
+    /// The request URL, without any query string.
+    pub url: String,
+    pub key: Key,
+    /// The status of the response, if one was received.
+    pub status: Option<StatusCode>,
+    #[source]
+    pub source: reqwest_middleware::Error,
 }
 
 // Define our own result type here (this seems to be the standard).
@@ -65,6 +117,12 @@ impl PartialEq for CasClientError {
     }
 }
 
+impl<T> From<std::sync::PoisonError<T>> for CasClientError {
+    fn from(_value: std::sync::PoisonError<T>) -> Self {
+        CasClientError::LockPoison
+    }
+}
+
 impl From<utils::errors::SingleflightError<CasClientError>> for CasClientError {
     fn from(value: utils::singleflight::SingleflightError<CasClientError>) -> Self {
         match value {
@@ -1,27 +1,237 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use anyhow::anyhow;
-use cas_types::REQUEST_ID_HEADER;
+use cas_types::{Key, CLIENT_REQUEST_ID_HEADER, REQUEST_ID_HEADER};
 use error_printer::{ErrorPrinter, OptionPrinter};
+use futures::StreamExt;
 use http::StatusCode;
-use reqwest::header::{HeaderValue, AUTHORIZATION};
-use reqwest::{Request, Response};
+use hyper_util::client::legacy::connect::HttpInfo;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, DATE, RETRY_AFTER};
+use reqwest::{Request, Response, ResponseBuilderExt, Url};
 use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Middleware, Next};
-use reqwest_retry::policies::ExponentialBackoff;
 use reqwest_retry::{
-    default_on_request_failure, default_on_request_success, DefaultRetryableStrategy, RetryTransientMiddleware,
-    Retryable, RetryableStrategy,
+    default_on_request_failure, default_on_request_success, DefaultRetryableStrategy, Retryable, RetryableStrategy,
 };
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
 use tracing::{debug, warn};
 use utils::auth::{AuthConfig, TokenProvider};
+use utils::metrics::MetricsSink;
 
+use crate::clock::{Clock, TokioClock};
+use crate::error::RequestFailure;
 use crate::{error, CasClientError};
 
 const NUM_RETRIES: u32 = 5;
 const BASE_RETRY_DELAY_MS: u64 = 3000; // 3s
 const BASE_RETRY_MAX_DURATION_MS: u64 = 6 * 60 * 1000; // 6m
+const MAX_RETRY_AFTER_MS: u64 = 60 * 1000; // 1m
+
+/// The number of request attempts sent to a host, labeled by "host".
+pub const HTTP_REQUESTS: &str = "cas_client_http_requests";
+/// The number of connections opened to a host, labeled by "host"; the other requests to the host
+/// reused a connection.
+pub const HTTP_CONNECTIONS_OPENED: &str = "cas_client_http_connections_opened";
+/// The number of requests to a host in flight, each holding a connection, from sending the request
+/// until its response body is read or dropped; labeled by "host".
+pub const HTTP_REQUESTS_IN_FLIGHT: &str = "cas_client_http_requests_in_flight";
+
+utils::configurable_constants! {
+// Env (HF_XET_IP_VERSION_PREFERENCE) to choose the IP version of connections to hosts that resolve
+// to both IPv4 and IPv6 addresses: "happy_eyeballs" (the default), "ipv4" or "ipv6".  Preferring
+// one version helps on dual-stack networks where the other one blackholes.
+    ref IP_VERSION_PREFERENCE: IpVersionPreference = IpVersionPreference::HappyEyeballs;
+
+// The timeout for resolving a host name, in milliseconds; 0 leaves resolution without a timeout.
+    ref DNS_RESOLUTION_TIMEOUT_MS: u64 = 0;
+
+// How long before its expiration, by the server's clock, the CAS token is refreshed, in seconds.
+    ref TOKEN_EXPIRY_MARGIN_SECS: u64 = 30;
+}
+
+/// The IP version used to connect to a host that resolves to both IPv4 and IPv6 addresses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpVersionPreference {
+    /// Connect to the addresses in the order the system resolver returns them, racing the two IP
+    /// versions as in RFC 8305.
+    #[default]
+    HappyEyeballs,
+    /// Connect only to the IPv4 addresses of a host, unless it has none.
+    PreferIpv4,
+    /// Connect only to the IPv6 addresses of a host, unless it has none.
+    PreferIpv6,
+}
+
+impl FromStr for IpVersionPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "happy_eyeballs" => Ok(Self::HappyEyeballs),
+            "ipv4" => Ok(Self::PreferIpv4),
+            "ipv6" => Ok(Self::PreferIpv6),
+            _ => Err(format!("invalid IP version preference {s:?}; expected happy_eyeballs, ipv4 or ipv6")),
+        }
+    }
+}
+
+impl IpVersionPreference {
+    /// Keeps the addresses of the preferred IP version, if there are any.
+    fn select(self, addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let preferred = match self {
+            Self::HappyEyeballs => return addrs,
+            Self::PreferIpv4 => SocketAddr::is_ipv4,
+            Self::PreferIpv6 => SocketAddr::is_ipv6,
+        };
+        if addrs.iter().any(preferred) {
+            addrs.into_iter().filter(preferred).collect()
+        } else {
+            addrs
+        }
+    }
+}
+
+/// How the HTTP clients resolve host names and pick the addresses to connect to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ConnectionConfig {
+    pub ip_version: IpVersionPreference,
+    pub dns_resolution_timeout: Option<Duration>,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            ip_version: *IP_VERSION_PREFERENCE,
+            dns_resolution_timeout: (*DNS_RESOLUTION_TIMEOUT_MS > 0)
+                .then(|| Duration::from_millis(*DNS_RESOLUTION_TIMEOUT_MS)),
+        }
+    }
+}
+
+/// Generates the id sent with every attempt of a logical request.
+pub type RequestIdGenerator = Arc<dyn Fn() -> String + Send + Sync>;
+
+/// What the HTTP clients send to identify themselves and their requests.
+#[derive(Clone)]
+pub struct HttpClientConfig {
+    /// The User-Agent header sent with every request.
+    pub user_agent: String,
+
+    /// Generates the id sent in the `X-Xet-Client-Request-Id` header, once per logical request so
+    /// that all its retries share the id; no header is sent if None.
+    pub request_id_generator: Option<RequestIdGenerator>,
+
+    /// Receives the request and connection metrics of each host, `HTTP_REQUESTS`,
+    /// `HTTP_CONNECTIONS_OPENED` and `HTTP_REQUESTS_IN_FLIGHT`; none are reported if None.
+    pub metrics_sink: Option<Arc<dyn MetricsSink>>,
+
+    /// Headers sent with every request, e.g. those a gateway in front of the server requires.  The
+    /// headers the client sets itself, such as Authorization, take precedence over these.
+    pub extra_headers: HashMap<String, String>,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            user_agent: format!("xet-core/{}", env!("CARGO_PKG_VERSION")),
+            request_id_generator: Some(Arc::new(|| uuid::Uuid::new_v4().to_string())),
+            metrics_sink: None,
+            extra_headers: HashMap::new(),
+        }
+    }
+}
+
+impl HttpClientConfig {
+    /// Sets the User-Agent header sent with every request.
+    pub fn with_user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Sets the generator of the id sent with every attempt of a logical request.
+    pub fn with_request_id_generator(mut self, generator: Option<RequestIdGenerator>) -> Self {
+        self.request_id_generator = generator;
+        self
+    }
+
+    /// Sets the sink the request and connection metrics of each host are reported to.
+    pub fn with_metrics_sink(mut self, metrics_sink: Option<Arc<dyn MetricsSink>>) -> Self {
+        self.metrics_sink = metrics_sink;
+        self
+    }
+
+    /// Sets the headers sent with every request, failing if any name or value isn't a valid header.
+    pub fn with_extra_headers(mut self, extra_headers: HashMap<String, String>) -> Result<Self, CasClientError> {
+        self.extra_headers = extra_headers;
+        self.extra_header_map()?;
+        Ok(self)
+    }
+
+    /// Parses the extra headers, failing on the first invalid name or value.
+    pub(crate) fn extra_header_map(&self) -> Result<HeaderMap, CasClientError> {
+        let mut headers = HeaderMap::with_capacity(self.extra_headers.len());
+        for (name, value) in &self.extra_headers {
+            let header_name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| CasClientError::ConfigurationError(format!("invalid header name {name:?}: {e}")))?;
+            let header_value = HeaderValue::from_str(value)
+                .map_err(|e| CasClientError::ConfigurationError(format!("invalid value of header {name:?}: {e}")))?;
+            headers.insert(header_name, header_value);
+        }
+        Ok(headers)
+    }
+}
+
+/// Resolves host names with the system resolver, applying the timeout and IP version preference of
+/// a ConnectionConfig.
+struct ConfiguredResolver {
+    config: ConnectionConfig,
+}
+
+impl Resolve for ConfiguredResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let ConnectionConfig {
+            ip_version,
+            dns_resolution_timeout,
+        } = self.config;
+        let host = name.as_str().to_owned();
+
+        Box::pin(async move {
+            let lookup = tokio::net::lookup_host((host.as_str(), 0));
+            let addrs = match dns_resolution_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, lookup)
+                    .await
+                    .map_err(|_| format!("resolving {host} timed out after {timeout:?}"))??,
+                None => lookup.await?,
+            };
+            let addrs: Addrs = Box::new(ip_version.select(addrs.collect()).into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+/// Builds the reqwest client underneath the middleware, sending the user agent and extra headers of
+/// `http_config`, and installing a resolver only if the connection config differs from the system
+/// defaults.
+pub(crate) fn build_reqwest_client(
+    connection_config: ConnectionConfig,
+    http_config: &HttpClientConfig,
+) -> std::result::Result<reqwest::Client, CasClientError> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(&http_config.user_agent)
+        .default_headers(http_config.extra_header_map()?);
+    if connection_config.ip_version != IpVersionPreference::HappyEyeballs
+        || connection_config.dns_resolution_timeout.is_some()
+    {
+        builder = builder.dns_resolver(Arc::new(ConfiguredResolver {
+            config: connection_config,
+        }));
+    }
+    Ok(builder.build()?)
+}
 
 /// A strategy that doesn't retry on 429, and defaults to `DefaultRetryableStrategy` otherwise.
 pub struct No429RetryStratey;
@@ -49,7 +259,16 @@ pub struct RetryConfig<R: RetryableStrategy> {
     /// Base max duration for retry attempts, default to 6m.
     max_retry_interval_ms: u64,
 
+    /// Longest wait a `Retry-After` header of a response can ask for, default to 1m.
+    max_retry_after_ms: u64,
+
+    /// Whether to randomize each retry delay uniformly between 0 and the exponential backoff.
+    jitter: bool,
+
     strategy: R,
+
+    /// Clock timing the backoff between retries.
+    clock: Arc<dyn Clock>,
 }
 
 impl Default for RetryConfig<DefaultRetryableStrategy> {
@@ -60,7 +279,10 @@ impl Default for RetryConfig<DefaultRetryableStrategy> {
             num_retries: NUM_RETRIES,
             min_retry_interval_ms: BASE_RETRY_DELAY_MS,
             max_retry_interval_ms: BASE_RETRY_MAX_DURATION_MS,
+            max_retry_after_ms: MAX_RETRY_AFTER_MS,
+            jitter: true,
             strategy: DefaultRetryableStrategy,
+            clock: Arc::new(TokioClock),
         }
     }
 }
@@ -71,7 +293,106 @@ impl RetryConfig<No429RetryStratey> {
             num_retries: NUM_RETRIES,
             min_retry_interval_ms: BASE_RETRY_DELAY_MS,
             max_retry_interval_ms: BASE_RETRY_MAX_DURATION_MS,
+            max_retry_after_ms: MAX_RETRY_AFTER_MS,
+            jitter: true,
             strategy: No429RetryStratey,
+            clock: Arc::new(TokioClock),
+        }
+    }
+}
+
+impl<R: RetryableStrategy> RetryConfig<R> {
+    /// Sets the clock timing the backoff between retries.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets the number of retries for transient errors.
+    pub fn with_num_retries(mut self, num_retries: u32) -> Self {
+        self.num_retries = num_retries;
+        self
+    }
+
+    /// Sets the delay before the first retry, doubled for every later retry up to `max_interval`.
+    pub fn with_retry_intervals(mut self, min_interval: Duration, max_interval: Duration) -> Self {
+        self.min_retry_interval_ms = min_interval.as_millis() as u64;
+        self.max_retry_interval_ms = max_interval.as_millis() as u64;
+        self
+    }
+
+    /// Caps the wait a `Retry-After` header can ask for, so that a server can't hold off the
+    /// retries for longer than `max_retry_after`.
+    pub fn with_max_retry_after(mut self, max_retry_after: Duration) -> Self {
+        self.max_retry_after_ms = max_retry_after.as_millis() as u64;
+        self
+    }
+
+    /// The same retry settings, deciding what to retry with `strategy`.
+    pub(crate) fn with_strategy<S: RetryableStrategy>(&self, strategy: S) -> RetryConfig<S> {
+        RetryConfig {
+            num_retries: self.num_retries,
+            min_retry_interval_ms: self.min_retry_interval_ms,
+            max_retry_interval_ms: self.max_retry_interval_ms,
+            max_retry_after_ms: self.max_retry_after_ms,
+            jitter: self.jitter,
+            strategy,
+            clock: self.clock.clone(),
+        }
+    }
+
+    /// The delay before the retry following `n_past_retries` retries: the min interval doubled for
+    /// every past retry, capped at the max interval, and jittered if enabled.
+    fn retry_delay(&self, n_past_retries: u32) -> Duration {
+        let delay = Duration::from_millis(self.min_retry_interval_ms)
+            .saturating_mul(2u32.saturating_pow(n_past_retries))
+            .min(Duration::from_millis(self.max_retry_interval_ms));
+        if self.jitter {
+            delay.mul_f64(rand::random::<f64>())
+        } else {
+            delay
+        }
+    }
+
+    /// The delay before retrying the failed attempt `result`: the wait asked for by its
+    /// `Retry-After` header, capped at the max, or otherwise the backoff after `n_past_retries`.
+    fn retry_delay_for(&self, result: &reqwest_middleware::Result<Response>, n_past_retries: u32) -> Duration {
+        match result.as_ref().ok().and_then(retry_after) {
+            Some(retry_after) => retry_after.min(Duration::from_millis(self.max_retry_after_ms)),
+            None => self.retry_delay(n_past_retries),
+        }
+    }
+}
+
+/// The wait asked for by the `Retry-After` header of `response`, given either in seconds or as an
+/// HTTP date; a date in the past asks for no wait.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+#[cfg(test)]
+impl RetryConfig<DefaultRetryableStrategy> {
+    /// Doesn't retry, so that tests against failing servers complete quickly.
+    pub(crate) fn no_retry() -> Self {
+        Self {
+            num_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Retries without waiting, so that tests can observe retries without waiting out the backoff.
+    pub(crate) fn immediate_retry(num_retries: u32) -> Self {
+        Self {
+            num_retries,
+            min_retry_interval_ms: 0,
+            max_retry_interval_ms: 0,
+            max_retry_after_ms: MAX_RETRY_AFTER_MS,
+            ..Self::default()
         }
     }
 }
@@ -81,44 +402,292 @@ impl RetryConfig<No429RetryStratey> {
 pub fn build_auth_http_client<R: RetryableStrategy + Send + Sync + 'static>(
     auth_config: &Option<AuthConfig>,
     retry_config: RetryConfig<R>,
+) -> std::result::Result<ClientWithMiddleware, CasClientError> {
+    build_limited_auth_http_client(auth_config, retry_config, &HttpClientConfig::default(), None)
+}
+
+/// Builds HTTP Client to talk to CAS.
+/// Includes retry middleware with exponential backoff.
+pub fn build_http_client<R: RetryableStrategy + Send + Sync + 'static>(
+    retry_config: RetryConfig<R>,
+) -> std::result::Result<ClientWithMiddleware, CasClientError> {
+    build_limited_http_client(retry_config, &HttpClientConfig::default(), None)
+}
+
+/// Like `build_auth_http_client`, but identifying the client and its requests as in `http_config`,
+/// and if `network_request_permits` is given, every request attempt holds one of the permits while
+/// in flight.
+pub(crate) fn build_limited_auth_http_client<R: RetryableStrategy + Send + Sync + 'static>(
+    auth_config: &Option<AuthConfig>,
+    retry_config: RetryConfig<R>,
+    http_config: &HttpClientConfig,
+    network_request_permits: Option<Arc<Semaphore>>,
 ) -> std::result::Result<ClientWithMiddleware, CasClientError> {
     let auth_middleware = auth_config.as_ref().map(AuthMiddleware::from).info_none("CAS auth disabled");
+    let request_id_middleware = http_config.request_id_generator.clone().map(RequestIdMiddleware::new);
     let logging_middleware = Some(LoggingMiddleware);
     let retry_middleware = get_retry_middleware(retry_config);
-    let reqwest_client = reqwest::Client::builder().build()?;
+    let concurrency_limit_middleware = network_request_permits.map(|permits| ConcurrencyLimitMiddleware { permits });
+    let metrics_middleware = http_config.metrics_sink.clone().map(ConnectionMetricsMiddleware::new);
+    let reqwest_client = build_reqwest_client(ConnectionConfig::default(), http_config)?;
     Ok(ClientBuilder::new(reqwest_client)
         .maybe_with(auth_middleware)
+        .maybe_with(request_id_middleware)
         .maybe_with(Some(retry_middleware))
         .maybe_with(logging_middleware)
+        .maybe_with(concurrency_limit_middleware)
+        .maybe_with(metrics_middleware)
         .build())
 }
 
-/// Builds HTTP Client to talk to CAS.
-/// Includes retry middleware with exponential backoff.
-pub fn build_http_client<R: RetryableStrategy + Send + Sync + 'static>(
+/// Like `build_http_client`, but identifying the client and its requests as in `http_config`, and
+/// if `network_request_permits` is given, every request attempt holds one of the permits while in
+/// flight.
+pub(crate) fn build_limited_http_client<R: RetryableStrategy + Send + Sync + 'static>(
     retry_config: RetryConfig<R>,
+    http_config: &HttpClientConfig,
+    network_request_permits: Option<Arc<Semaphore>>,
 ) -> std::result::Result<ClientWithMiddleware, CasClientError> {
+    let request_id_middleware = http_config.request_id_generator.clone().map(RequestIdMiddleware::new);
     let retry_middleware = get_retry_middleware(retry_config);
     let logging_middleware = Some(LoggingMiddleware);
-    let reqwest_client = reqwest::Client::builder().build()?;
+    let concurrency_limit_middleware = network_request_permits.map(|permits| ConcurrencyLimitMiddleware { permits });
+    let metrics_middleware = http_config.metrics_sink.clone().map(ConnectionMetricsMiddleware::new);
+    let reqwest_client = build_reqwest_client(ConnectionConfig::default(), http_config)?;
     Ok(ClientBuilder::new(reqwest_client)
+        .maybe_with(request_id_middleware)
         .maybe_with(Some(retry_middleware))
         .maybe_with(logging_middleware)
+        .maybe_with(concurrency_limit_middleware)
+        .maybe_with(metrics_middleware)
         .build())
 }
 
-/// Configurable Retry middleware with exponential backoff and configurable number of retries using reqwest-retry
-fn get_retry_middleware<R: RetryableStrategy + Send + Sync>(
+/// Tags each logical request with a generated id in the `X-Xet-Client-Request-Id` header, unless
+/// the request already has one.  Added before the retry middleware, so all the attempts of a
+/// request carry the same id.
+struct RequestIdMiddleware {
+    generator: RequestIdGenerator,
+}
+
+impl RequestIdMiddleware {
+    fn new(generator: RequestIdGenerator) -> Self {
+        Self { generator }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for RequestIdMiddleware {
+    async fn handle(
+        &self,
+        mut req: Request,
+        extensions: &mut http::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        if !req.headers().contains_key(CLIENT_REQUEST_ID_HEADER) {
+            let request_id = (self.generator)();
+            let header_value = HeaderValue::from_str(&request_id).map_err(|e| {
+                reqwest_middleware::Error::Middleware(anyhow!("invalid request id {request_id:?}: {e}"))
+            })?;
+            req.headers_mut().insert(CLIENT_REQUEST_ID_HEADER, header_value);
+        }
+        next.run(req, extensions).await
+    }
+}
+
+/// Bounds the number of requests in flight across all the clients sharing `permits`.  Added after
+/// the retry middleware, so each attempt takes a permit and none is held while backing off.  The
+/// permit is held from sending the request until the response body is read or dropped.
+struct ConcurrencyLimitMiddleware {
+    permits: Arc<Semaphore>,
+}
+
+#[async_trait::async_trait]
+impl Middleware for ConcurrencyLimitMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut http::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|e| reqwest_middleware::Error::Middleware(anyhow!("network request permits closed: {e}")))?;
+        let response = next.run(req, extensions).await?;
+        Ok(hold_until_body_read(response, permit))
+    }
+}
+
+/// Reports the requests, connections opened and requests in flight of each host to a metrics sink.
+/// Added last, so that it sees each attempt as it's sent, once it holds its network request permit.
+///
+/// A response comes on a new connection if the connection's local and remote addresses weren't seen
+/// before for its host.
+struct ConnectionMetricsMiddleware {
+    sink: Arc<dyn MetricsSink>,
+    hosts: Arc<std::sync::Mutex<HashMap<String, HostConnections>>>,
+}
+
+#[derive(Default)]
+struct HostConnections {
+    in_flight: usize,
+    connections: HashSet<(SocketAddr, SocketAddr)>,
+}
+
+impl ConnectionMetricsMiddleware {
+    fn new(sink: Arc<dyn MetricsSink>) -> Self {
+        Self {
+            sink,
+            hosts: Default::default(),
+        }
+    }
+}
+
+/// Counts a request to a host as in flight until dropped.
+struct InFlightRequest {
+    sink: Arc<dyn MetricsSink>,
+    hosts: Arc<std::sync::Mutex<HashMap<String, HostConnections>>>,
+    host: String,
+}
+
+impl InFlightRequest {
+    fn start(middleware: &ConnectionMetricsMiddleware, host: String) -> Self {
+        let request = Self {
+            sink: middleware.sink.clone(),
+            hosts: middleware.hosts.clone(),
+            host,
+        };
+        request.update_in_flight(|in_flight| in_flight + 1);
+        request
+    }
+
+    fn update_in_flight(&self, update: impl FnOnce(usize) -> usize) {
+        let mut hosts = self.hosts.lock().unwrap();
+        let connections = hosts.entry(self.host.clone()).or_default();
+        connections.in_flight = update(connections.in_flight);
+        self.sink
+            .labeled_gauge(HTTP_REQUESTS_IN_FLIGHT, &[("host", &self.host)], connections.in_flight as f64);
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        self.update_in_flight(|in_flight| in_flight.saturating_sub(1));
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for ConnectionMetricsMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut http::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let host = req.url().host_str().unwrap_or_default().to_owned();
+        self.sink.labeled_counter(HTTP_REQUESTS, &[("host", &host)], 1);
+
+        let in_flight = InFlightRequest::start(self, host.clone());
+        let response = next.run(req, extensions).await?;
+
+        if let Some(info) = response.extensions().get::<HttpInfo>() {
+            let is_new = self
+                .hosts
+                .lock()
+                .unwrap()
+                .entry(host.clone())
+                .or_default()
+                .connections
+                .insert((info.local_addr(), info.remote_addr()));
+            if is_new {
+                self.sink.labeled_counter(HTTP_CONNECTIONS_OPENED, &[("host", &host)], 1);
+            }
+        }
+        Ok(hold_until_body_read(response, in_flight))
+    }
+}
+
+/// Returns the response with a body that holds `guard`, e.g. a network request permit, until it's
+/// read to the end or dropped.
+fn hold_until_body_read<G: Send + Sync + 'static>(response: Response, guard: G) -> Response {
+    let mut builder = http::Response::builder()
+        .status(response.status())
+        .version(response.version())
+        .url(response.url().clone());
+    if let Some(headers) = builder.headers_mut() {
+        *headers = response.headers().clone();
+    }
+    if let Some(extensions) = builder.extensions_mut() {
+        *extensions = response.extensions().clone();
+    }
+
+    let body = response.bytes_stream().map(move |chunk| {
+        let _ = &guard;
+        chunk
+    });
+    // The parts come from a valid response, so building can't fail.
+    Response::from(builder.body(reqwest::Body::wrap_stream(body)).unwrap())
+}
+
+/// Configurable Retry middleware with exponential backoff and configurable number of retries
+fn get_retry_middleware<R: RetryableStrategy + Send + Sync>(config: RetryConfig<R>) -> RetryMiddleware<R> {
+    RetryMiddleware { config }
+}
+
+/// Retries requests that fail with an error the strategy deems transient, waiting out an
+/// exponential backoff on the configured clock between attempts.
+pub struct RetryMiddleware<R: RetryableStrategy> {
     config: RetryConfig<R>,
-) -> RetryTransientMiddleware<ExponentialBackoff, R> {
-    let retry_policy = ExponentialBackoff::builder()
-        .retry_bounds(
-            Duration::from_millis(config.min_retry_interval_ms),
-            Duration::from_millis(config.max_retry_interval_ms),
-        )
-        .build_with_max_retries(config.num_retries);
+}
+
+#[async_trait::async_trait]
+impl<R: RetryableStrategy + Send + Sync + 'static> Middleware for RetryMiddleware<R> {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut http::Extensions,
+        next: Next<'_>,
+    ) -> reqwest_middleware::Result<Response> {
+        let start = self.config.clock.now();
+        let mut n_past_retries = 0;
+        loop {
+            let duplicate_request = req.try_clone().ok_or_else(|| {
+                reqwest_middleware::Error::Middleware(anyhow!(
+                    "Request object is not clonable. Are you passing a streaming body?"
+                ))
+            })?;
+
+            let result = next.clone().run(duplicate_request, extensions).await;
+
+            if is_insufficient_storage(&result) || self.config.strategy.handle(&result) != Some(Retryable::Transient) {
+                return result;
+            }
+            if n_past_retries >= self.config.num_retries {
+                debug!(
+                    "Giving up after {n_past_retries} retries over {:?}",
+                    self.config.clock.now().saturating_duration_since(start)
+                );
+                return result;
+            }
+
+            let delay = self.config.retry_delay_for(&result, n_past_retries);
+            // Release the failed attempt, e.g. its network request permit, before backing off.
+            drop(result);
+
+            warn!("Retry attempt #{n_past_retries}. Sleeping {delay:?} before the next attempt");
+            self.config.clock.sleep(delay).await;
+            n_past_retries += 1;
+        }
+    }
+}
 
-    RetryTransientMiddleware::new_with_policy_and_strategy(retry_policy, config.strategy)
+/// Whether the server rejected the request for lack of storage or quota, which retrying doesn't fix;
+/// such requests are never retried, whatever the strategy.
+fn is_insufficient_storage(result: &reqwest_middleware::Result<Response>) -> bool {
+    matches!(result, Ok(response) if response.status() == StatusCode::INSUFFICIENT_STORAGE)
 }
 
 /// Helper trait to allow the reqwest_middleware client to optionally add a middleware.
@@ -154,7 +723,9 @@ impl Middleware for LoggingMiddleware {
                 let status_code = res.status().as_u16();
                 let request_id = request_id_from_response(res);
                 debug!(request_id, status_code, "Received CAS response");
-                if Some(Retryable::Transient) == default_on_request_success(res) {
+                if Some(Retryable::Transient) == default_on_request_success(res)
+                    && res.status() != StatusCode::INSUFFICIENT_STORAGE
+                {
                     warn!(request_id, "Status Code: {status_code:?}. Retrying...");
                 }
             })
@@ -195,7 +766,9 @@ impl AuthMiddleware {
 impl From<&AuthConfig> for AuthMiddleware {
     fn from(cfg: &AuthConfig) -> Self {
         Self {
-            token_provider: Arc::new(Mutex::new(TokenProvider::new(cfg))),
+            token_provider: Arc::new(Mutex::new(
+                TokenProvider::new(cfg).with_expiry_margin(Duration::from_secs(*TOKEN_EXPIRY_MARGIN_SECS)),
+            )),
         }
     }
 }
@@ -212,7 +785,18 @@ impl Middleware for AuthMiddleware {
 
         let headers = req.headers_mut();
         headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", token)).unwrap());
-        next.run(req, extensions).await
+        let res = next.run(req, extensions).await?;
+
+        // Token expirations are set by the server's clock; track how far the local clock is off.
+        if let Some(server_time) = res
+            .headers()
+            .get(DATE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| httpdate::parse_http_date(date).ok())
+        {
+            self.token_provider.lock().await.record_server_time(server_time);
+        }
+        Ok(res)
     }
 }
 
@@ -220,6 +804,10 @@ impl Middleware for AuthMiddleware {
 /// transforming the implementation into some new error type.
 pub trait ResponseErrorLogger<T> {
     fn process_error(self, api: &str) -> T;
+
+    /// Like `process_error` for a request to `url` concerning the object `key`, keeping both in
+    /// the returned error.
+    fn process_error_for(self, api: &str, url: &Url, key: &Key) -> T;
 }
 
 /// Add ResponseErrorLogger to Result<Response> for our requests.
@@ -234,6 +822,31 @@ impl ResponseErrorLogger<error::Result<Response>> for reqwest_middleware::Result
         let error_message = format!("{api} api failed: request id: {request_id}");
         Ok(res.error_for_status().log_error(error_message)?)
     }
+
+    fn process_error_for(self, api: &str, url: &Url, key: &Key) -> error::Result<Response> {
+        // The query string of a presigned URL holds credentials, so leave it out of the error.
+        let mut url = url.clone();
+        url.set_query(None);
+        let request_failed = |status, source| {
+            CasClientError::RequestFailed(Box::new(RequestFailure {
+                api: api.to_owned(),
+                url: url.to_string(),
+                key: key.clone(),
+                status,
+                source,
+            }))
+        };
+
+        let res = self
+            .log_error(format!("error invoking {api} api for {key}"))
+            .map_err(|e| request_failed(None, e))?;
+        let status = res.status();
+        let request_id = request_id_from_response(&res);
+        let error_message = format!("{api} api for {key} failed: request id: {request_id}");
+        res.error_for_status()
+            .log_error(error_message)
+            .map_err(|e| request_failed(Some(status), e.into()))
+    }
 }
 
 pub fn request_id_from_response(res: &Response) -> &str {
@@ -247,13 +860,12 @@ pub fn request_id_from_response(res: &Response) -> &str {
 
 #[cfg(test)]
 mod tests {
-    use std::time::SystemTime;
-
     use httpmock::prelude::*;
     use reqwest::StatusCode;
     use tracing_test::traced_test;
 
     use super::*;
+    use crate::clock::MockClock;
 
     #[tokio::test]
     #[traced_test]
@@ -270,7 +882,10 @@ mod tests {
                 num_retries: 1,
                 min_retry_interval_ms: 0,
                 max_retry_interval_ms: 3000,
+                max_retry_after_ms: MAX_RETRY_AFTER_MS,
+                jitter: true,
                 strategy: DefaultRetryableStrategy,
+                clock: Arc::new(TokioClock),
             };
             let client = build_auth_http_client(&None, retry_config).unwrap();
 
@@ -295,7 +910,10 @@ mod tests {
                 num_retries: 1,
                 min_retry_interval_ms: 0,
                 max_retry_interval_ms: 3000,
+                max_retry_after_ms: MAX_RETRY_AFTER_MS,
+                jitter: true,
                 strategy: No429RetryStratey,
+                clock: Arc::new(TokioClock),
             };
             let client = build_auth_http_client(&None, retry_config).unwrap();
 
@@ -324,7 +942,10 @@ mod tests {
                 num_retries: 2,
                 min_retry_interval_ms: 0,
                 max_retry_interval_ms: 3000,
+                max_retry_after_ms: MAX_RETRY_AFTER_MS,
+                jitter: true,
                 strategy: DefaultRetryableStrategy,
+                clock: Arc::new(TokioClock),
             };
             let client = build_auth_http_client(&None, retry_config).unwrap();
 
@@ -349,7 +970,10 @@ mod tests {
                 num_retries: 2,
                 min_retry_interval_ms: 0,
                 max_retry_interval_ms: 3000,
+                max_retry_after_ms: MAX_RETRY_AFTER_MS,
+                jitter: true,
                 strategy: No429RetryStratey,
+                clock: Arc::new(TokioClock),
             };
             let client = build_auth_http_client(&None, retry_config).unwrap();
 
@@ -363,6 +987,88 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_retry_backoff_with_mock_clock() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/data");
+            then.status(StatusCode::INTERNAL_SERVER_ERROR.as_u16());
+        });
+
+        let clock = Arc::new(MockClock::new());
+        let start = clock.now();
+        let retry_config = RetryConfig {
+            num_retries: 4,
+            min_retry_interval_ms: 1000,
+            max_retry_interval_ms: 5000,
+            max_retry_after_ms: MAX_RETRY_AFTER_MS,
+            jitter: false,
+            strategy: DefaultRetryableStrategy,
+            clock: clock.clone(),
+        };
+        let client = build_auth_http_client(&None, retry_config).unwrap();
+
+        let response = client.get(server.url("/data")).send().await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(5, mock.hits());
+        // The delay doubles from the min interval with every retry, capped at the max interval.
+        assert_eq!(clock.sleeps(), [1, 2, 4, 5].map(Duration::from_secs));
+        assert_eq!(clock.now() - start, Duration::from_secs(12));
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_header() {
+        let clock = Arc::new(MockClock::new());
+        let retry_config = || RetryConfig {
+            num_retries: 2,
+            min_retry_interval_ms: 100,
+            max_retry_interval_ms: 100,
+            max_retry_after_ms: MAX_RETRY_AFTER_MS,
+            jitter: false,
+            strategy: DefaultRetryableStrategy,
+            clock: clock.clone(),
+        };
+
+        // The wait asked for by the server replaces the backoff.
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/data");
+            then.status(StatusCode::SERVICE_UNAVAILABLE.as_u16()).header("Retry-After", "2");
+        });
+        let client = build_auth_http_client(&None, retry_config()).unwrap();
+        let start = clock.now();
+        let response = client.get(server.url("/data")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(3, mock.hits());
+        assert_eq!(clock.now() - start, Duration::from_secs(4));
+
+        // Waits longer than the max are capped, and waits given as dates are honored too.
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(3600));
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/data");
+            then.status(StatusCode::TOO_MANY_REQUESTS.as_u16()).header("Retry-After", date);
+        });
+        let client =
+            build_auth_http_client(&None, retry_config().with_max_retry_after(Duration::from_secs(10))).unwrap();
+        let start = clock.now();
+        client.get(server.url("/data")).send().await.unwrap();
+        assert_eq!(clock.now() - start, Duration::from_secs(20));
+
+        // Without the header, or with an invalid one, the backoff applies.
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/data");
+            then.status(StatusCode::SERVICE_UNAVAILABLE.as_u16())
+                .header("Retry-After", "soon");
+        });
+        let client = build_auth_http_client(&None, retry_config()).unwrap();
+        let start = clock.now();
+        client.get(server.url("/data")).send().await.unwrap();
+        assert_eq!(clock.now() - start, Duration::from_millis(200));
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_retry_policy_delay() {
@@ -379,7 +1085,10 @@ mod tests {
                 num_retries: 2,
                 min_retry_interval_ms: 1000,
                 max_retry_interval_ms: 6000,
+                max_retry_after_ms: MAX_RETRY_AFTER_MS,
+                jitter: true,
                 strategy: DefaultRetryableStrategy,
+                clock: Arc::new(TokioClock),
             };
             let client = build_auth_http_client(&None, retry_config).unwrap();
 
@@ -406,7 +1115,10 @@ mod tests {
                 num_retries: 2,
                 min_retry_interval_ms: 1000,
                 max_retry_interval_ms: 6000,
+                max_retry_after_ms: MAX_RETRY_AFTER_MS,
+                jitter: true,
                 strategy: No429RetryStratey,
+                clock: Arc::new(TokioClock),
             };
             let client = build_auth_http_client(&None, retry_config).unwrap();
 
@@ -435,7 +1147,10 @@ mod tests {
             num_retries: 10,
             min_retry_interval_ms: 1000,
             max_retry_interval_ms: 6000,
+            max_retry_after_ms: MAX_RETRY_AFTER_MS,
+            jitter: true,
             strategy: No429RetryStratey,
+            clock: Arc::new(TokioClock),
         };
         let client = build_auth_http_client(&None, retry_config).unwrap();
 
@@ -446,4 +1161,229 @@ mod tests {
         assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
         assert_eq!(1, mock.hits());
     }
+
+    #[test]
+    fn test_ip_version_selection() {
+        let v4: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let v6: SocketAddr = "[::1]:0".parse().unwrap();
+
+        assert_eq!(IpVersionPreference::HappyEyeballs.select(vec![v6, v4]), vec![v6, v4]);
+        assert_eq!(IpVersionPreference::PreferIpv4.select(vec![v6, v4]), vec![v4]);
+        assert_eq!(IpVersionPreference::PreferIpv6.select(vec![v6, v4]), vec![v6]);
+
+        // Without an address of the preferred version, the others are kept.
+        assert_eq!(IpVersionPreference::PreferIpv4.select(vec![v6]), vec![v6]);
+        assert_eq!(IpVersionPreference::PreferIpv6.select(vec![v4]), vec![v4]);
+
+        assert_eq!("IPv4".parse(), Ok(IpVersionPreference::PreferIpv4));
+        assert_eq!("happy_eyeballs".parse(), Ok(IpVersionPreference::HappyEyeballs));
+        assert!("ipv5".parse::<IpVersionPreference>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connection_config() {
+        // The mock server listens on 127.0.0.1 only.
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/data");
+            then.status(200).body("ok");
+        });
+        let url = format!("http://localhost:{}/data", server.port());
+
+        for ip_version in [
+            IpVersionPreference::HappyEyeballs,
+            IpVersionPreference::PreferIpv4,
+            IpVersionPreference::PreferIpv6,
+        ] {
+            for dns_resolution_timeout in [None, Some(Duration::from_secs(10))] {
+                build_reqwest_client(
+                    ConnectionConfig {
+                        ip_version,
+                        dns_resolution_timeout,
+                    },
+                    &HttpClientConfig::default(),
+                )
+                .unwrap();
+            }
+        }
+
+        let client = build_reqwest_client(
+            ConnectionConfig {
+                ip_version: IpVersionPreference::PreferIpv4,
+                dns_resolution_timeout: Some(Duration::from_secs(10)),
+            },
+            &HttpClientConfig::default(),
+        )
+        .unwrap();
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "ok");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_user_agent_and_request_id_on_retries() {
+        let server = MockServer::start();
+        let first = server.mock(|when, then| {
+            when.method(GET)
+                .path("/data")
+                .header("user-agent", "xet-test/1.0")
+                .header(CLIENT_REQUEST_ID_HEADER, "request-0");
+            then.status(500);
+        });
+        let second = server.mock(|when, then| {
+            when.method(GET)
+                .path("/data")
+                .header("user-agent", "xet-test/1.0")
+                .header(CLIENT_REQUEST_ID_HEADER, "request-1");
+            then.status(200);
+        });
+
+        let generated = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = generated.clone();
+        let http_config = HttpClientConfig::default()
+            .with_user_agent("xet-test/1.0")
+            .with_request_id_generator(Some(Arc::new(move || {
+                format!("request-{}", counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed))
+            })));
+        let client =
+            build_limited_auth_http_client(&None, RetryConfig::immediate_retry(2), &http_config, None).unwrap();
+
+        // Every attempt of a retried request carries the same id.
+        let response = client.get(server.url("/data")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        first.assert_hits(3);
+        assert_eq!(generated.load(std::sync::atomic::Ordering::Relaxed), 1);
+
+        // The next request gets a new id.
+        let response = client.get(server.url("/data")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        second.assert_hits(1);
+        first.assert_hits(3);
+    }
+
+    #[tokio::test]
+    async fn test_default_request_id() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET)
+                .path("/data")
+                .header("user-agent", format!("xet-core/{}", env!("CARGO_PKG_VERSION")))
+                .header_exists(CLIENT_REQUEST_ID_HEADER);
+            then.status(200);
+        });
+
+        let client = build_http_client(RetryConfig::no_retry()).unwrap();
+        let response = client.get(server.url("/data")).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_extra_headers() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET)
+                .path("/data")
+                .header("x-tenant-id", "tenant-1")
+                .header("authorization", "Bearer token");
+            then.status(200);
+        });
+
+        let extra_headers = HashMap::from([
+            ("X-Tenant-Id".to_owned(), "tenant-1".to_owned()),
+            ("Authorization".to_owned(), "Bearer overridden".to_owned()),
+        ]);
+        let http_config = HttpClientConfig::default().with_extra_headers(extra_headers).unwrap();
+        let client = build_limited_http_client(RetryConfig::no_retry(), &http_config, None).unwrap();
+
+        // The headers set on the request take precedence.
+        let response = client
+            .get(server.url("/data"))
+            .header(AUTHORIZATION, "Bearer token")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        mock.assert();
+
+        for (name, value) in [("bad name", "value"), ("x-tenant-id", "bad\nvalue")] {
+            let extra_headers = HashMap::from([(name.to_owned(), value.to_owned())]);
+            let err = HttpClientConfig::default().with_extra_headers(extra_headers).err().unwrap();
+            assert!(matches!(err, CasClientError::ConfigurationError(_)), "{err:?}");
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordingSink {
+        counters: std::sync::Mutex<HashMap<(String, String), u64>>,
+        gauges: std::sync::Mutex<HashMap<(String, String), f64>>,
+    }
+
+    impl RecordingSink {
+        fn counter_value(&self, name: &str, host: &str) -> u64 {
+            let counters = self.counters.lock().unwrap();
+            counters.get(&(name.to_owned(), host.to_owned())).copied().unwrap_or_default()
+        }
+    }
+
+    impl MetricsSink for RecordingSink {
+        fn counter(&self, _name: &str, _value: u64) {}
+
+        fn gauge(&self, _name: &str, _value: f64) {}
+
+        fn histogram(&self, _name: &str, _value: f64) {}
+
+        fn labeled_counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+            assert_eq!(labels.len(), 1);
+            *self
+                .counters
+                .lock()
+                .unwrap()
+                .entry((name.to_owned(), labels[0].1.to_owned()))
+                .or_default() += value;
+        }
+
+        fn labeled_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+            assert_eq!(labels.len(), 1);
+            self.gauges
+                .lock()
+                .unwrap()
+                .insert((name.to_owned(), labels[0].1.to_owned()), value);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connection_metrics_per_host() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/data");
+            then.status(200).body("data");
+        });
+
+        let sink = Arc::new(RecordingSink::default());
+        let http_config = HttpClientConfig::default().with_metrics_sink(Some(sink.clone()));
+        let client = build_limited_http_client(RetryConfig::no_retry(), &http_config, None).unwrap();
+
+        // The same server under two host names.
+        let urls = [
+            ("127.0.0.1", server.url("/data")),
+            ("localhost", format!("http://localhost:{}/data", server.port())),
+        ];
+        for (host, url) in &urls {
+            for i in 0..3 {
+                let response = client.get(url).send().await.unwrap();
+                assert_eq!(sink.gauges.lock().unwrap()[&(HTTP_REQUESTS_IN_FLIGHT.to_owned(), host.to_string())], 1.);
+                assert_eq!(response.text().await.unwrap(), "data");
+                assert_eq!(sink.counter_value(HTTP_REQUESTS, host), i + 1);
+            }
+        }
+
+        for (host, _) in &urls {
+            assert_eq!(sink.counter_value(HTTP_REQUESTS, host), 3);
+            // The requests are sent one at a time, so they share one connection.
+            assert_eq!(sink.counter_value(HTTP_CONNECTIONS_OPENED, host), 1);
+            assert_eq!(sink.gauges.lock().unwrap()[&(HTTP_REQUESTS_IN_FLIGHT.to_owned(), host.to_string())], 0.);
+        }
+    }
 }
@@ -1,17 +1,21 @@
-use std::collections::HashMap;
-use std::fs::OpenOptions;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
 use std::io::{Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
 
 use async_trait::async_trait;
 use cas_types::{FileRange, QueryReconstructionResponse};
 use mdb_shard::shard_file_reconstructor::FileReconstructor;
 use merklehash::MerkleHash;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
 use utils::progress::ProgressUpdater;
 
 use crate::error::Result;
-use crate::CasClientError;
+use crate::{CasClientError, TransferStats};
 
 /// A Client to the CAS (Content Addressed Storage) service to allow storage and
 /// management of XORBs (Xet Object Remote Block). A XORB represents a collection
@@ -25,10 +29,11 @@ pub trait UploadClient {
     /// Chunk Boundaries must be complete; i.e. the last entry in chunk boundary
     /// must be the length of data. For instance, if data="helloworld" with 2 chunks
     /// ["hello" "world"], chunk_boundaries should be [5, 10].
-    /// Empty data and empty chunk boundaries are not accepted.
+    /// Empty data and empty chunk boundaries are not accepted.  The boundaries are checked on the
+    /// client side too, before anything is stored; see `validate_chunk_boundaries`.
     ///
-    /// Note that put may background in some implementations and a flush()
-    /// will be needed.
+    /// Note that put may background in some implementations and a `flush`
+    /// will be needed before the XORB is durable.
     async fn put(
         &self,
         prefix: &str,
@@ -37,8 +42,61 @@ pub trait UploadClient {
         chunk_and_boundaries: Vec<(MerkleHash, u32)>,
     ) -> Result<usize>;
 
+    /// Like `put`, but also returns whether the XORB was newly stored rather than already present
+    /// in the CAS, as (was_inserted, bytes transmitted).  By default, a put that writes no bytes is
+    /// taken to have found the XORB already present.
+    async fn put_with_status(
+        &self,
+        prefix: &str,
+        hash: &MerkleHash,
+        data: Vec<u8>,
+        chunk_and_boundaries: Vec<(MerkleHash, u32)>,
+    ) -> Result<(bool, usize)> {
+        let nbytes_trans = self.put(prefix, hash, data, chunk_and_boundaries).await?;
+        Ok((nbytes_trans != 0, nbytes_trans))
+    }
+
+    /// Like `put`, but gives up as soon as `cancel` is triggered, returning
+    /// `CasClientError::Canceled`.  The put is dropped along with its in-flight request; since the
+    /// CAS verifies a XORB against its hash before storing it, a canceled put stores nothing.
+    async fn put_cancelable(
+        &self,
+        prefix: &str,
+        hash: &MerkleHash,
+        data: Vec<u8>,
+        chunk_and_boundaries: Vec<(MerkleHash, u32)>,
+        cancel: &CancellationToken,
+    ) -> Result<usize> {
+        tokio::select! {
+            biased;
+            _ = cancel.cancelled() => Err(CasClientError::Canceled),
+            result = self.put(prefix, hash, data, chunk_and_boundaries) => result,
+        }
+    }
+
     /// Check if a XORB already exists.
     async fn exists(&self, prefix: &str, hash: &MerkleHash) -> Result<bool>;
+
+    /// Waits until every XORB put so far is durable in the CAS, returning the first error of a
+    /// backgrounded put.  Implementations whose puts complete before returning have nothing to do.
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Fails with `CasClientError::InvalidArguments` unless `chunk_and_boundaries` splits `data` into
+/// non-empty chunks: the data isn't empty, the boundaries strictly increase and the last one is the
+/// length of the data.
+pub(crate) fn validate_chunk_boundaries(data: &[u8], chunk_and_boundaries: &[(MerkleHash, u32)]) -> Result<()> {
+    let increasing = chunk_and_boundaries
+        .iter()
+        .try_fold(0, |start, &(_, end)| (end > start).then_some(end))
+        .is_some();
+    let complete = chunk_and_boundaries.last().is_some_and(|&(_, end)| end as usize == data.len());
+    if data.is_empty() || !increasing || !complete {
+        return Err(CasClientError::InvalidArguments);
+    }
+    Ok(())
 }
 
 /// A Client to the CAS (Content Addressed Storage) service to allow reconstructing a
@@ -62,6 +120,66 @@ pub trait ReconstructionClient {
         progress_updater: Option<Arc<dyn ProgressUpdater>>,
     ) -> Result<u64>;
 
+    /// Like `get_file`, but also returns the transfer stats of the download.
+    ///
+    /// The default implementation only records the total bytes and wall clock time;
+    /// implementations that fetch terms individually fill in the per-term stats.
+    async fn get_file_with_stats(
+        &self,
+        hash: &MerkleHash,
+        byte_range: Option<FileRange>,
+        output_provider: &OutputProvider,
+        progress_updater: Option<Arc<dyn ProgressUpdater>>,
+    ) -> Result<TransferStats> {
+        let start = Instant::now();
+        let total_bytes = self.get_file(hash, byte_range, output_provider, progress_updater).await?;
+        Ok(TransferStats {
+            terms: vec![],
+            total_bytes,
+            wall_clock: start.elapsed(),
+            max_buffered_terms: 0,
+        })
+    }
+
+    /// Like `get_file`, but gives up as soon as `cancel` is triggered, returning
+    /// `CasClientError::Canceled`.
+    ///
+    /// A file output is written to a temporary file in the same directory, renamed over the
+    /// destination once the download completes; a canceled or failed download only removes the
+    /// temporary file, so an existing file at the destination is left as it was.  Whatever was
+    /// written to other outputs is discarded (see `OutputProvider::discard`).
+    async fn get_file_cancelable(
+        &self,
+        hash: &MerkleHash,
+        byte_range: Option<FileRange>,
+        output_provider: &OutputProvider,
+        progress_updater: Option<Arc<dyn ProgressUpdater>>,
+        cancel: &CancellationToken,
+    ) -> Result<u64> {
+        let staged = output_provider.staged();
+        let result = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => Err(CasClientError::Canceled),
+            result = self.get_file(hash, byte_range, &staged.output, progress_updater) => result,
+        };
+        match result {
+            Ok(n_bytes) => {
+                staged.commit()?;
+                Ok(n_bytes)
+            },
+            Err(e) => {
+                // Writers the dropped download left running, e.g. in spawned tasks, are shut out of
+                // the output before it's discarded, so they can't write it again afterwards.
+                staged.discard()?;
+                Err(e)
+            },
+        }
+    }
+
+    /// The length of the file `hash` as reconstructed, i.e. the summed unpacked length of the terms
+    /// it's reconstructed from, without fetching any of its data.
+    async fn get_file_size(&self, hash: &MerkleHash) -> Result<u64>;
+
     async fn batch_get_file(&self, files: HashMap<MerkleHash, &OutputProvider>) -> Result<u64> {
         let mut n_bytes = 0;
         // Provide the basic naive implementation as a default.
@@ -76,6 +194,9 @@ pub trait ReconstructionClient {
 #[derive(Debug, Clone)]
 pub enum OutputProvider {
     File(FileProvider),
+    CasCache(CasCacheWriteProvider),
+    Tee(TeeOutputProvider),
+    Stream(StreamOutputProvider),
     #[cfg(test)]
     Buffer(buffer::BufferProvider),
 }
@@ -85,29 +206,614 @@ impl OutputProvider {
     pub(crate) fn get_writer_at(&self, start: u64) -> Result<Box<dyn Write + Send>> {
         match self {
             OutputProvider::File(fp) => fp.get_writer_at(start),
+            OutputProvider::CasCache(cp) => cp.get_writer_at(start),
+            OutputProvider::Tee(tp) => tp.get_writer_at(start),
+            OutputProvider::Stream(sp) => Ok(sp.get_writer_at(start)),
             #[cfg(test)]
             OutputProvider::Buffer(bp) => bp.get_writer_at(start),
         }
     }
+
+    /// Creates the output as an empty file, replacing any existing contents, e.g. for the
+    /// reconstruction of an empty file, which has no terms to write.
+    pub fn write_empty(&self) -> Result<()> {
+        match self {
+            OutputProvider::File(fp) => fp.write_empty(),
+            OutputProvider::CasCache(cp) => Ok(cp.create_partial()?.set_len(0)?),
+            OutputProvider::Tee(tp) => tp.inner.write_empty(),
+            OutputProvider::Stream(_) => Ok(()),
+            #[cfg(test)]
+            OutputProvider::Buffer(bp) => bp.get_writer_at(0).map(|_| ()),
+        }
+    }
+
+    /// Flushes what was written to the output to disk, so that it survives a crash: a file output
+    /// is fsync'ed along with its directory, so that a newly created file's entry is durable too.
+    /// Other outputs aren't files, and have nothing to sync.
+    pub fn sync_to_disk(&self) -> Result<()> {
+        match self {
+            OutputProvider::File(fp) => fp.sync_to_disk(),
+            OutputProvider::CasCache(_) => Ok(()),
+            OutputProvider::Tee(tp) => tp.inner.sync_to_disk(),
+            OutputProvider::Stream(_) => Ok(()),
+            #[cfg(test)]
+            OutputProvider::Buffer(bp) => {
+                bp.syncs.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(())
+            },
+        }
+    }
+
+    /// An output writing to the same place as this one, except that a file output, directly or
+    /// under a tee, is written to a temporary file beside it until the staged output is committed.
+    pub(crate) fn staged(&self) -> StagedOutput {
+        match self {
+            OutputProvider::File(fp) => {
+                let file_name = fp.filename.file_name().unwrap_or_default().to_string_lossy();
+                let temp = fp
+                    .filename
+                    .with_file_name(format!(".{file_name}.{}.partial", uuid::Uuid::new_v4()));
+                let gate = Arc::new(RwLock::new(false));
+                StagedOutput {
+                    output: OutputProvider::File(FileProvider {
+                        filename: temp.clone(),
+                        sparse: fp.sparse,
+                        discarded: Some(gate.clone()),
+                    }),
+                    staged_file: Some(StagedFile {
+                        temp,
+                        dest: fp.filename.clone(),
+                        discarded: gate,
+                    }),
+                }
+            },
+            OutputProvider::Tee(tp) => {
+                let inner = tp.inner.staged();
+                StagedOutput {
+                    output: OutputProvider::Tee(TeeOutputProvider {
+                        inner: Box::new(inner.output),
+                        state: tp.state.clone(),
+                    }),
+                    staged_file: inner.staged_file,
+                }
+            },
+            _ => StagedOutput {
+                output: self.clone(),
+                staged_file: None,
+            },
+        }
+    }
+
+    /// Removes whatever was written to the output, e.g. after a canceled download.  A file output
+    /// is deleted.
+    pub fn discard(&self) -> Result<()> {
+        match self {
+            OutputProvider::File(fp) => match std::fs::remove_file(&fp.filename) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            },
+            OutputProvider::CasCache(cp) => {
+                cp.discard();
+                Ok(())
+            },
+            OutputProvider::Tee(tp) => {
+                *tp.state.lock()? = TeeHashState::default();
+                tp.inner.discard()
+            },
+            OutputProvider::Stream(sp) => {
+                sp.state.lock()?.pending.clear();
+                Ok(())
+            },
+            #[cfg(test)]
+            OutputProvider::Buffer(bp) => {
+                bp.buf.clear();
+                Ok(())
+            },
+        }
+    }
+}
+
+/// An output whose file is written to a temporary file until the download completes; see
+/// `OutputProvider::staged`.
+pub(crate) struct StagedOutput {
+    pub(crate) output: OutputProvider,
+    staged_file: Option<StagedFile>,
+}
+
+struct StagedFile {
+    temp: PathBuf,
+    dest: PathBuf,
+    /// Set once the temporary file is discarded; writers hold the read lock while they open or
+    /// write the file, so none does either once it's set.
+    discarded: Arc<RwLock<bool>>,
+}
+
+impl StagedOutput {
+    /// Moves the written file into place at the destination.
+    pub(crate) fn commit(self) -> Result<()> {
+        let Some(file) = self.staged_file else {
+            return Ok(());
+        };
+        match std::fs::rename(&file.temp, &file.dest) {
+            // Nothing was written, as for an empty reconstruction, so there's nothing to move.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    /// Removes what was written: the temporary file of a file output, once no writer can write it
+    /// anymore, or the contents of other outputs.
+    pub(crate) fn discard(self) -> Result<()> {
+        let Some(file) = self.staged_file else {
+            return self.output.discard();
+        };
+        if let OutputProvider::Tee(tp) = &self.output {
+            *tp.state.lock()? = TeeHashState::default();
+        }
+        // Waits for the writes in progress to finish.
+        *file.discarded.write()? = true;
+        match std::fs::remove_file(&file.temp) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
 }
 
 /// Provides new Writers to a file located at a particular location
 #[derive(Debug, Clone)]
 pub struct FileProvider {
     filename: PathBuf,
+    sparse: bool,
+    /// For the temporary file of a staged output, whether it was discarded, after which it's no
+    /// longer opened or written.
+    discarded: Option<Arc<RwLock<bool>>>,
 }
 
 impl FileProvider {
     pub fn new(filename: PathBuf) -> Self {
-        Self { filename }
+        Self {
+            filename,
+            sparse: false,
+            discarded: None,
+        }
+    }
+
+    /// Writes the file as a sparse file: blocks of zeros are left as holes rather than written, on
+    /// platforms and filesystems that support punching holes.  The file reads back the same.
+    pub fn with_sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
     }
 
     fn get_writer_at(&self, start: u64) -> Result<Box<dyn Write + Send>> {
+        let Some(discarded) = &self.discarded else {
+            return self.open_writer_at(start);
+        };
+        // The file is opened under the lock, so it isn't created again once discarded.
+        let inner = {
+            let _guard = lock_undiscarded(discarded).map_err(|_| CasClientError::Canceled)?;
+            self.open_writer_at(start)?
+        };
+        Ok(Box::new(StagedFileWriter {
+            inner,
+            discarded: discarded.clone(),
+        }))
+    }
+
+    fn open_writer_at(&self, start: u64) -> Result<Box<dyn Write + Send>> {
         let mut file = OpenOptions::new()
             .write(true)
             .truncate(false)
             .create(true)
             .open(&self.filename)?;
+        if self.sparse {
+            return Ok(Box::new(SparseFileWriter { file, offset: start }));
+        }
+        file.seek(SeekFrom::Start(start))?;
+        Ok(Box::new(file))
+    }
+
+    fn write_empty(&self) -> Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(&self.filename)?;
+        Ok(())
+    }
+
+    fn sync_to_disk(&self) -> Result<()> {
+        OpenOptions::new().write(true).open(&self.filename)?.sync_all()?;
+        // Directories can only be opened, and so synced, this way on unix.
+        #[cfg(unix)]
+        if let Some(dir) = self.filename.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the temporary file of a staged output, failing once it's discarded.
+struct StagedFileWriter {
+    inner: Box<dyn Write + Send>,
+    discarded: Arc<RwLock<bool>>,
+}
+
+/// Holds off the discarding of a staged output while writing it, failing if it was discarded.
+fn lock_undiscarded(discarded: &RwLock<bool>) -> std::io::Result<std::sync::RwLockReadGuard<'_, bool>> {
+    let guard = discarded
+        .read()
+        .map_err(|_| std::io::Error::other("staged output lock poisoned"))?;
+    if *guard {
+        return Err(std::io::Error::other("the download's output was discarded"));
+    }
+    Ok(guard)
+}
+
+impl Write for StagedFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let _guard = lock_undiscarded(&self.discarded)?;
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        let _guard = lock_undiscarded(&self.discarded)?;
+        self.inner.flush()
+    }
+}
+
+/// The size and alignment of the blocks of zeros a `SparseFileWriter` leaves as holes.
+const SPARSE_BLOCK_SIZE: u64 = 4096;
+
+/// Writes to a file from `offset` on, punching holes for the aligned blocks of zeros instead of
+/// writing them; holes also clear whatever the file held there before.
+struct SparseFileWriter {
+    file: File,
+    offset: u64,
+}
+
+impl SparseFileWriter {
+    fn write_run(&mut self, data: &[u8], is_hole: bool, ends_write: bool) -> std::io::Result<()> {
+        let len = data.len() as u64;
+        if is_hole && file_utils::punch_hole(&self.file, self.offset, len)? {
+            // Holes don't extend the file, so write the last zero of a write to give the file its size.
+            if ends_write {
+                self.file.seek(SeekFrom::Start(self.offset + len - 1))?;
+                self.file.write_all(&[0])?;
+            }
+        } else {
+            self.file.seek(SeekFrom::Start(self.offset))?;
+            self.file.write_all(data)?;
+        }
+        self.offset += len;
+        Ok(())
+    }
+}
+
+impl Write for SparseFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Split the buffer on the block boundaries of the file, and write the runs of data and of
+        // whole zero blocks.
+        let mut run_start = 0;
+        let mut run_is_hole = false;
+        let mut pos = 0;
+        while pos < buf.len() {
+            let block_offset = self.offset + (pos - run_start) as u64;
+            let block_len = (SPARSE_BLOCK_SIZE - block_offset % SPARSE_BLOCK_SIZE).min((buf.len() - pos) as u64);
+            let block = &buf[pos..pos + block_len as usize];
+            let is_hole = block_len == SPARSE_BLOCK_SIZE && block.iter().all(|&b| b == 0);
+            if is_hole != run_is_hole && pos > run_start {
+                self.write_run(&buf[run_start..pos], run_is_hole, false)?;
+                run_start = pos;
+            }
+            run_is_hole = is_hole;
+            pos += block.len();
+        }
+        if run_start < buf.len() {
+            self.write_run(&buf[run_start..], run_is_hole, true)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Forwards the output of a reconstruction to another output while computing the SHA-256 of the
+/// bytes written, so that a download can be verified without reading the file back.
+///
+/// Writers may be opened anywhere in the file and written concurrently; bytes written ahead of the
+/// part of the file hashed so far are buffered until the bytes before them are written, so at most
+/// the terms of a download in flight at once are held in memory.
+#[derive(Debug, Clone)]
+pub struct TeeOutputProvider {
+    inner: Box<OutputProvider>,
+    state: Arc<Mutex<TeeHashState>>,
+}
+
+impl TeeOutputProvider {
+    pub fn new(inner: OutputProvider) -> Self {
+        Self {
+            inner: Box::new(inner),
+            state: Default::default(),
+        }
+    }
+
+    /// The SHA-256 of the bytes written from the start of the file, or None if some bytes after a
+    /// gap in the written ranges couldn't be hashed yet.  Call this once the reconstruction is
+    /// complete.
+    pub fn sha256(&self) -> Result<Option<MerkleHash>> {
+        let state = self.state.lock()?;
+        if !state.pending.is_empty() {
+            return Ok(None);
+        }
+        let sha256 = state.hasher.clone().finalize();
+        Ok(Some(MerkleHash::from_hex(&format!("{sha256:x}")).expect("Converting sha256 to merklehash.")))
+    }
+
+    /// The number of bytes hashed.
+    pub fn hashed_bytes(&self) -> Result<u64> {
+        Ok(self.state.lock()?.hashed_bytes)
+    }
+
+    fn get_writer_at(&self, start: u64) -> Result<Box<dyn Write + Send>> {
+        Ok(Box::new(TeeWriter {
+            inner: self.inner.get_writer_at(start)?,
+            offset: start,
+            state: self.state.clone(),
+        }))
+    }
+}
+
+#[derive(Debug, Default)]
+struct TeeHashState {
+    hasher: Sha256,
+    hashed_bytes: u64,
+    /// Bytes written past the hashed prefix of the file, by offset.
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl TeeHashState {
+    fn record(&mut self, offset: u64, data: &[u8]) {
+        if offset > self.hashed_bytes {
+            let pending = self.pending.entry(offset).or_default();
+            if pending.len() < data.len() {
+                *pending = data.to_vec();
+            }
+            return;
+        }
+
+        self.hash_from(offset, data);
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > self.hashed_bytes {
+                break;
+            }
+            let (offset, data) = entry.remove_entry();
+            self.hash_from(offset, &data);
+        }
+    }
+
+    /// Hashes the part of `data`, written at `offset` at or before the end of the hashed prefix, past
+    /// that prefix; bytes written again, e.g. by a retried write, are only hashed once.
+    fn hash_from(&mut self, offset: u64, data: &[u8]) {
+        let skip = (self.hashed_bytes - offset) as usize;
+        if skip < data.len() {
+            self.hasher.update(&data[skip..]);
+            self.hashed_bytes += (data.len() - skip) as u64;
+        }
+    }
+}
+
+struct TeeWriter {
+    inner: Box<dyn Write + Send>,
+    offset: u64,
+    state: Arc<Mutex<TeeHashState>>,
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.state
+            .lock()
+            .map_err(|_| std::io::Error::other("tee hash state lock poisoned"))?
+            .record(self.offset, &buf[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Forwards the bytes of a reconstruction to a channel in file order, so that they can be read as
+/// they're downloaded rather than once the whole file is written.
+///
+/// As for a `TeeOutputProvider`, bytes written ahead of those sent so far are held until the bytes
+/// before them are written.  The channel is unbounded: a reader slower than the download makes the
+/// bytes not read yet pile up in memory.  It's closed once every clone of the provider is dropped,
+/// i.e. once the reconstruction writing to it is done.
+#[derive(Debug, Clone)]
+pub struct StreamOutputProvider {
+    state: Arc<Mutex<StreamState>>,
+}
+
+impl StreamOutputProvider {
+    /// Returns the provider and the receiving end of its channel.
+    pub fn new() -> (Self, UnboundedReceiver<Vec<u8>>) {
+        let (sender, receiver) = unbounded_channel();
+        let state = StreamState {
+            sender,
+            sent_bytes: 0,
+            pending: BTreeMap::new(),
+        };
+        (
+            Self {
+                state: Arc::new(Mutex::new(state)),
+            },
+            receiver,
+        )
+    }
+
+    fn get_writer_at(&self, start: u64) -> Box<dyn Write + Send> {
+        Box::new(StreamWriter {
+            offset: start,
+            state: self.state.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct StreamState {
+    sender: UnboundedSender<Vec<u8>>,
+    sent_bytes: u64,
+    /// Bytes written past the sent prefix of the file, by offset.
+    pending: BTreeMap<u64, Vec<u8>>,
+}
+
+impl StreamState {
+    fn record(&mut self, offset: u64, data: &[u8]) {
+        if offset > self.sent_bytes {
+            let pending = self.pending.entry(offset).or_default();
+            if pending.len() < data.len() {
+                *pending = data.to_vec();
+            }
+            return;
+        }
+
+        self.send_from(offset, data);
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > self.sent_bytes {
+                break;
+            }
+            let (offset, data) = entry.remove_entry();
+            self.send_from(offset, &data);
+        }
+    }
+
+    /// Sends the part of `data`, written at `offset` at or before the end of the sent prefix, past
+    /// that prefix.  Once the receiver is dropped, the bytes are discarded.
+    fn send_from(&mut self, offset: u64, data: &[u8]) {
+        let skip = (self.sent_bytes - offset) as usize;
+        if skip < data.len() {
+            let _ = self.sender.send(data[skip..].to_vec());
+            self.sent_bytes += (data.len() - skip) as u64;
+        }
+    }
+}
+
+struct StreamWriter {
+    offset: u64,
+    state: Arc<Mutex<StreamState>>,
+}
+
+impl Write for StreamWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.state
+            .lock()
+            .map_err(|_| std::io::Error::other("stream state lock poisoned"))?
+            .record(self.offset, buf);
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes a whole file into a content addressed cache directory, at
+/// `<cache_dir>/<first 2 hex digits of the hash>/<remaining hex digits>`, and links it to the
+/// requested destination, so that repeated downloads of the same file share one copy on disk.
+///
+/// Data is written to a partial file next to the cache entry; `commit` moves it into place once the
+/// download is complete.  The destination is a hard link to the cache entry, or a copy of it if
+/// the link fails, e.g. across filesystems.  Since hard links share their contents, destinations
+/// must be treated as read-only.
+#[derive(Debug, Clone)]
+pub struct CasCacheWriteProvider {
+    file_hash: MerkleHash,
+    cache_path: PathBuf,
+    partial_path: PathBuf,
+    destination: PathBuf,
+}
+
+impl CasCacheWriteProvider {
+    pub fn new(cache_dir: impl AsRef<Path>, file_hash: &MerkleHash, destination: PathBuf) -> Self {
+        let hex = file_hash.hex();
+        let cache_path = cache_dir.as_ref().join(&hex[..2]).join(&hex[2..]);
+        let partial_path = cache_path.with_file_name(format!(".{}.{:016x}.partial", &hex[2..], rand::random::<u64>()));
+        Self {
+            file_hash: *file_hash,
+            cache_path,
+            partial_path,
+            destination,
+        }
+    }
+
+    pub fn file_hash(&self) -> &MerkleHash {
+        &self.file_hash
+    }
+
+    /// The location of the file in the cache directory.
+    pub fn cache_path(&self) -> &Path {
+        &self.cache_path
+    }
+
+    pub fn destination(&self) -> &Path {
+        &self.destination
+    }
+
+    /// Whether the file is already in the cache, so that it only needs to be linked.
+    pub fn is_cached(&self) -> bool {
+        self.cache_path.is_file()
+    }
+
+    /// Moves the completely written file into the cache and links it to the destination.
+    pub fn commit(&self) -> Result<()> {
+        if !self.partial_path.exists() {
+            // Nothing was written for an empty file.
+            self.create_partial()?;
+        }
+        std::fs::rename(&self.partial_path, &self.cache_path)?;
+        self.link_to_destination()
+    }
+
+    /// Removes the partially written file after a failed download.
+    pub fn discard(&self) {
+        let _ = std::fs::remove_file(&self.partial_path);
+    }
+
+    /// Links the cached file to the destination, replacing any existing file there, and falls back
+    /// to copying it if it can't be hard linked.
+    pub fn link_to_destination(&self) -> Result<()> {
+        if let Some(parent) = self.destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        match std::fs::remove_file(&self.destination) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {},
+        }
+        if std::fs::hard_link(&self.cache_path, &self.destination).is_err() {
+            std::fs::copy(&self.cache_path, &self.destination)?;
+        }
+        Ok(())
+    }
+
+    fn create_partial(&self) -> Result<std::fs::File> {
+        if let Some(parent) = self.partial_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(OpenOptions::new()
+            .write(true)
+            .truncate(false)
+            .create(true)
+            .open(&self.partial_path)?)
+    }
+
+    fn get_writer_at(&self, start: u64) -> Result<Box<dyn Write + Send>> {
+        let mut file = self.create_partial()?;
         file.seek(SeekFrom::Start(start))?;
         Ok(Box::new(file))
     }
@@ -165,6 +871,7 @@ pub trait Client: UploadClient + ReconstructionClient + ShardClientInterface {}
 #[cfg(test)]
 pub mod buffer {
     use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Mutex;
 
     use super::*;
@@ -172,13 +879,81 @@ pub mod buffer {
     #[derive(Debug, Default, Clone)]
     pub struct BufferProvider {
         pub buf: ThreadSafeBuffer,
+        /// If set, opening more writers at once than this fails, as when running out of
+        /// file descriptors, and writers are slow to flush so that they stay open long enough
+        /// to overlap.
+        max_open_writers: Option<usize>,
+        open_writers: Arc<AtomicUsize>,
+        /// If set, every write blocks for this long, as with a slow disk.
+        write_delay: Option<std::time::Duration>,
+        /// The number of times the output was synced to disk.
+        pub(crate) syncs: Arc<AtomicUsize>,
     }
 
     impl BufferProvider {
+        pub fn with_max_open_writers(max_open_writers: usize) -> Self {
+            Self {
+                max_open_writers: Some(max_open_writers),
+                ..Self::default()
+            }
+        }
+
+        pub fn with_write_delay(write_delay: std::time::Duration) -> Self {
+            Self {
+                write_delay: Some(write_delay),
+                ..Self::default()
+            }
+        }
+
+        pub fn num_syncs(&self) -> usize {
+            self.syncs.load(Ordering::SeqCst)
+        }
+
         pub fn get_writer_at(&self, start: u64) -> Result<Box<dyn Write + Send>> {
             let mut buffer = self.buf.clone();
             buffer.idx = start;
-            Ok(Box::new(buffer))
+
+            let num_open = self.open_writers.fetch_add(1, Ordering::SeqCst) + 1;
+            let writer = OpenWriter {
+                buffer,
+                open_writers: self.open_writers.clone(),
+                slow_flush: self.max_open_writers.is_some(),
+                write_delay: self.write_delay,
+            };
+            if self.max_open_writers.is_some_and(|max| num_open > max) {
+                return Err(std::io::Error::other("Too many open files").into());
+            }
+            Ok(Box::new(writer))
+        }
+    }
+
+    /// A writer counted as open until it is dropped.
+    struct OpenWriter {
+        buffer: ThreadSafeBuffer,
+        open_writers: Arc<AtomicUsize>,
+        slow_flush: bool,
+        write_delay: Option<std::time::Duration>,
+    }
+
+    impl Write for OpenWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if let Some(write_delay) = self.write_delay {
+                std::thread::sleep(write_delay);
+            }
+            self.buffer.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            if self.slow_flush {
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            }
+            self.buffer.flush()
+        }
+    }
+
+    impl Drop for OpenWriter {
+        fn drop(&mut self) {
+            self.open_writers.fetch_sub(1, Ordering::SeqCst);
         }
     }
 
@@ -195,6 +970,10 @@ pub mod buffer {
         pub fn value(&self) -> Vec<u8> {
             self.inner.lock().unwrap().get_ref().clone()
         }
+
+        pub fn clear(&self) {
+            self.inner.lock().unwrap().get_mut().clear();
+        }
     }
 
     impl Write for ThreadSafeBuffer {
@@ -211,3 +990,33 @@ pub mod buffer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staged_file_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output");
+        std::fs::write(&path, b"existing").unwrap();
+        let output = OutputProvider::File(FileProvider::new(path.clone()));
+
+        // A discarded output leaves the destination as it was, and a writer opened before can't
+        // write to it anymore.
+        let staged = output.staged();
+        let mut writer = staged.output.get_writer_at(0).unwrap();
+        writer.write_all(b"partial").unwrap();
+        staged.discard().unwrap();
+        assert!(writer.write_all(b"more").is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"existing");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let staged = output.staged();
+        staged.output.get_writer_at(0).unwrap().write_all(b"complete").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"existing");
+        staged.commit().unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"complete");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
@@ -1,17 +1,36 @@
 #![allow(dead_code)]
 
 pub use chunk_cache::{CacheConfig, CHUNK_CACHE_SIZE_BYTES};
-pub use http_client::{build_auth_http_client, build_http_client, RetryConfig};
-use interface::RegistrationClient;
-pub use interface::{Client, FileProvider, OutputProvider, ReconstructionClient, UploadClient};
+pub use client_builder::ClientBuilder;
+pub use clock::{Clock, TokioClock};
+pub use http_client::{
+    build_auth_http_client, build_http_client, HttpClientConfig, IpVersionPreference, RequestIdGenerator, RetryConfig,
+    HTTP_CONNECTIONS_OPENED, HTTP_REQUESTS, HTTP_REQUESTS_IN_FLIGHT,
+};
+pub use interface::{
+    CasCacheWriteProvider, Client, FileProvider, OutputProvider, ReconstructionClient, RegistrationClient,
+    ShardDedupProber, StreamOutputProvider, TeeOutputProvider, UploadClient,
+};
 pub use local_client::LocalClient;
-pub use remote_client::RemoteClient;
+#[cfg(any(test, feature = "memory_client"))]
+pub use memory_client::MemoryLocalClient;
+pub use reconstruction_plan::{PlannedTerm, ReconstructionPlan};
+pub use remote_client::{
+    normalize_endpoint, ChunkHashSource, RemoteClient, MAX_RECONSTRUCTION_TERMS, MAX_RESPONSE_BYTES,
+};
+pub use transfer_stats::{TermTransferStats, TransferStats};
 
-pub use crate::error::CasClientError;
+pub use crate::error::{CasClientError, RequestFailure};
 pub use crate::interface::ShardClientInterface;
 
+mod client_builder;
+mod clock;
 mod error;
 mod http_client;
 mod interface;
 mod local_client;
+#[cfg(any(test, feature = "memory_client"))]
+mod memory_client;
+mod reconstruction_plan;
 pub mod remote_client;
+mod transfer_stats;
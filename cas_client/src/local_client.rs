@@ -1,5 +1,6 @@
 use std::fs::{metadata, File};
 use std::io::{BufReader, BufWriter, Cursor, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -8,7 +9,7 @@ use async_trait::async_trait;
 use cas_object::CasObject;
 use cas_types::{FileRange, Key};
 use heed::types::*;
-use mdb_shard::file_structs::MDBFileInfo;
+use mdb_shard::file_structs::{FileDataSequenceEntry, MDBFileInfo};
 use mdb_shard::shard_file_reconstructor::FileReconstructor;
 use mdb_shard::utils::shard_file_name;
 use mdb_shard::{MDBShardFile, MDBShardInfo, ShardFileManager};
@@ -20,7 +21,7 @@ use tracing::{debug, error, info, warn};
 use utils::progress::ProgressUpdater;
 
 use crate::error::{CasClientError, Result};
-use crate::interface::{OutputProvider, ShardDedupProber, UploadClient};
+use crate::interface::{validate_chunk_boundaries, OutputProvider, ShardDedupProber, UploadClient};
 use crate::{Client, ReconstructionClient, RegistrationClient, ShardClientInterface};
 
 pub struct LocalClient {
@@ -205,6 +206,29 @@ impl LocalClient {
         Ok(ret)
     }
 
+    /// Get the uncompressed bytes `byte_range` of a CAS object's chunk range [a, b), only
+    /// decompressing the chunks overlapping them.
+    fn get_object_bytes_in_range(
+        &self,
+        hash: &MerkleHash,
+        chunk_range: (u32, u32),
+        byte_range: Range<u32>,
+    ) -> Result<Vec<u8>> {
+        if chunk_range.0 >= chunk_range.1 {
+            return Ok(vec![]);
+        }
+
+        let file_path = self.get_path_for_entry(hash);
+        let file = File::open(&file_path).map_err(|_| {
+            error!("Unable to find file in local CAS {:?}", file_path);
+            CasClientError::XORBNotFound(*hash)
+        })?;
+
+        let mut reader = BufReader::new(file);
+        let cas = CasObject::deserialize(&mut reader)?;
+        Ok(cas.get_bytes_in_chunk_range(&mut reader, chunk_range.0, chunk_range.1, byte_range.start, byte_range.end)?)
+    }
+
     fn get_length(&self, hash: &MerkleHash) -> Result<u32> {
         let file_path = self.get_path_for_entry(hash);
         match File::open(file_path) {
@@ -229,15 +253,7 @@ impl UploadClient for LocalClient {
         data: Vec<u8>,
         chunk_and_boundaries: Vec<(MerkleHash, u32)>,
     ) -> Result<usize> {
-        // no empty writes
-        if chunk_and_boundaries.is_empty() || data.is_empty() {
-            return Err(CasClientError::InvalidArguments);
-        }
-
-        // last boundary must be end of data
-        if chunk_and_boundaries.last().unwrap().1 as usize != data.len() {
-            return Err(CasClientError::InvalidArguments);
-        }
+        validate_chunk_boundaries(&data, &chunk_and_boundaries)?;
 
         // moved hash validation into [CasObject::serialize], so removed from here.
 
@@ -400,7 +416,7 @@ impl ReconstructionClient for LocalClient {
         hash: &MerkleHash,
         byte_range: Option<FileRange>,
         output_provider: &OutputProvider,
-        _progress_updater: Option<Arc<dyn ProgressUpdater>>,
+        progress_updater: Option<Arc<dyn ProgressUpdater>>,
     ) -> Result<u64> {
         let Some((file_info, _)) = self
             .shard_manager
@@ -412,29 +428,56 @@ impl ReconstructionClient for LocalClient {
         };
         let mut writer = output_provider.get_writer_at(0)?;
 
-        // This is just used for testing, so inefficient is fine.
+        // This is just used for testing, so buffering the whole range is fine.
         let mut file_vec = Vec::new();
-        for entry in &file_info.segments {
-            let mut entry_bytes = self
-                .get_object_range(&entry.cas_hash, vec![(entry.chunk_index_start, entry.chunk_index_end)])?
-                .pop()
-                .unwrap();
+        for (entry, segment_range) in segments_in_range(&file_info, byte_range) {
+            let mut entry_bytes = self.get_object_bytes_in_range(
+                &entry.cas_hash,
+                (entry.chunk_index_start, entry.chunk_index_end),
+                segment_range,
+            )?;
             file_vec.append(&mut entry_bytes);
         }
 
-        let start = byte_range.as_ref().map(|range| range.start as usize).unwrap_or(0);
-        let end = byte_range
-            .as_ref()
-            .map(|range| range.end as usize)
-            .unwrap_or(file_vec.len())
-            .min(file_vec.len());
+        writer.write_all(&file_vec)?;
+        progress_updater.inspect(|updater| updater.update(file_vec.len() as u64));
 
-        writer.write_all(&file_vec[start..end])?;
+        Ok(file_vec.len() as u64)
+    }
 
-        Ok((end - start) as u64)
+    async fn get_file_size(&self, hash: &MerkleHash) -> Result<u64> {
+        let Some((file_info, _)) = self
+            .shard_manager
+            .get_file_reconstruction_info(hash)
+            .await
+            .map_err(|e| anyhow!("{e}"))?
+        else {
+            return Err(CasClientError::FileNotFound(*hash));
+        };
+        Ok(file_info.file_size() as u64)
     }
 }
 
+/// Returns the segments of `file_info` overlapping `byte_range` (the whole file if None), each with
+/// the part of its uncompressed bytes within the range.
+pub(crate) fn segments_in_range(
+    file_info: &MDBFileInfo,
+    byte_range: Option<FileRange>,
+) -> impl Iterator<Item = (&FileDataSequenceEntry, Range<u32>)> {
+    let FileRange { start, end } = byte_range.unwrap_or(FileRange {
+        start: 0,
+        end: u64::MAX,
+    });
+    let mut segment_start = 0u64;
+    file_info.segments.iter().filter_map(move |entry| {
+        let segment_end = segment_start + entry.unpacked_segment_bytes as u64;
+        let offset = segment_start;
+        segment_start = segment_end;
+        (segment_end > start && offset < end)
+            .then(|| (entry, (start.saturating_sub(offset) as u32)..((end.min(segment_end) - offset) as u32)))
+    })
+}
+
 impl Client for LocalClient {}
 
 fn map_heed_db_error(e: heed::Error) -> CasClientError {
@@ -638,6 +681,40 @@ mod tests {
             .unwrap();
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_put_rejects_malformed_boundaries() {
+        let data = b"helloworld".to_vec();
+        let hash = compute_data_hash(&data);
+        let h = MerkleHash::default();
+        let clients: Vec<Box<dyn Client + Send + Sync>> = vec![
+            Box::new(LocalClient::temporary().unwrap()),
+            Box::new(crate::MemoryLocalClient::new()),
+        ];
+
+        for client in clients {
+            for (data, chunk_and_boundaries) in [
+                // empty data, with or without boundaries
+                (vec![], vec![]),
+                (vec![], vec![(h, 0)]),
+                // no boundaries
+                (data.clone(), vec![]),
+                // boundaries that go back, or repeat for an empty chunk
+                (data.clone(), vec![(h, 7), (h, 5), (h, 10)]),
+                (data.clone(), vec![(h, 5), (h, 5), (h, 10)]),
+                // a last boundary short of or past the end of the data
+                (data.clone(), vec![(h, 5), (h, 8)]),
+                (data.clone(), vec![(h, 5), (h, 12)]),
+            ] {
+                assert_eq!(
+                    client.put("default", &hash, data, chunk_and_boundaries).await.unwrap_err(),
+                    CasClientError::InvalidArguments
+                );
+            }
+            // Nothing was stored.
+            assert!(!client.exists("default", &hash).await.unwrap());
+        }
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn test_global_dedup() {
         let tmp_dir = TempDir::new().unwrap();
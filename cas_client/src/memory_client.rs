@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use cas_object::CasObject;
+use cas_types::{FileRange, Key};
+use mdb_shard::file_structs::MDBFileInfo;
+use mdb_shard::shard_file_reconstructor::FileReconstructor;
+use mdb_shard::utils::shard_file_name;
+use mdb_shard::MDBShardInfo;
+use merkledb::aggregate_hashes::with_salt;
+use merklehash::MerkleHash;
+use tracing::info;
+use utils::progress::ProgressUpdater;
+
+use crate::error::{CasClientError, Result};
+use crate::interface::{validate_chunk_boundaries, OutputProvider, ShardDedupProber, UploadClient};
+use crate::local_client::segments_in_range;
+use crate::{Client, ReconstructionClient, RegistrationClient, ShardClientInterface};
+
+/// Serialized xorbs, keyed by (prefix, hash).
+type XorbStore = Arc<RwLock<HashMap<(String, MerkleHash), Vec<u8>>>>;
+
+/// A drop-in replacement for `LocalClient` that keeps all xorbs and shards in memory,
+/// for tests that don't need to persist anything to disk.
+///
+/// Clones share the same underlying storage.
+#[derive(Clone, Default)]
+pub struct MemoryLocalClient {
+    xorbs: XorbStore,
+    /// Uploaded shards, keyed by shard hash.
+    shards: Arc<RwLock<HashMap<MerkleHash, Vec<u8>>>>,
+    /// File reconstruction info from the uploaded shards, with the hash of the defining shard.
+    file_info: Arc<RwLock<HashMap<MerkleHash, (MDBFileInfo, MerkleHash)>>>,
+    /// Salted chunk hash -> shard hash, for global dedup queries.
+    global_dedup: Arc<RwLock<HashMap<MerkleHash, MerkleHash>>>,
+
+    shard_cache_dir: Option<PathBuf>,
+}
+
+impl MemoryLocalClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like `new`, but global dedup queries write the matching shards into `shard_cache_dir`.
+    pub fn with_global_dedup(shard_cache_dir: PathBuf) -> Self {
+        Self {
+            shard_cache_dir: Some(shard_cache_dir),
+            ..Self::default()
+        }
+    }
+
+    /// Returns all entries in the client
+    pub fn get_all_entries(&self) -> Result<Vec<Key>> {
+        Ok(self
+            .xorbs
+            .read()?
+            .keys()
+            .map(|(prefix, hash)| Key {
+                prefix: prefix.clone(),
+                hash: *hash,
+            })
+            .collect())
+    }
+
+    /// Deletes an entry
+    pub fn delete(&self, prefix: &str, hash: &MerkleHash) -> Result<()> {
+        self.xorbs.write()?.remove(&(prefix.to_owned(), *hash));
+        Ok(())
+    }
+
+    pub fn get(&self, prefix: &str, hash: &MerkleHash) -> Result<Vec<u8>> {
+        let xorbs = self.xorbs.read()?;
+        let xorb = xorbs
+            .get(&(prefix.to_owned(), *hash))
+            .ok_or(CasClientError::XORBNotFound(*hash))?;
+
+        let mut reader = Cursor::new(xorb);
+        let cas = CasObject::deserialize(&mut reader)?;
+        Ok(cas.get_all_bytes(&mut reader)?)
+    }
+
+    /// Get the uncompressed bytes `byte_range` of the chunk index range [start, end) of a xorb under
+    /// any prefix, as file reconstruction info doesn't record the prefix.
+    fn get_object_range(&self, hash: &MerkleHash, start: u32, end: u32, byte_range: Range<u32>) -> Result<Vec<u8>> {
+        if start >= end {
+            return Ok(vec![]);
+        }
+
+        let xorbs = self.xorbs.read()?;
+        let xorb = xorbs
+            .iter()
+            .find_map(|((_, h), xorb)| (h == hash).then_some(xorb))
+            .ok_or(CasClientError::XORBNotFound(*hash))?;
+
+        let mut reader = Cursor::new(xorb);
+        let cas = CasObject::deserialize(&mut reader)?;
+        Ok(cas.get_bytes_in_chunk_range(&mut reader, start, end, byte_range.start, byte_range.end)?)
+    }
+}
+
+#[async_trait]
+impl UploadClient for MemoryLocalClient {
+    async fn put(
+        &self,
+        prefix: &str,
+        hash: &MerkleHash,
+        data: Vec<u8>,
+        chunk_and_boundaries: Vec<(MerkleHash, u32)>,
+    ) -> Result<usize> {
+        validate_chunk_boundaries(&data, &chunk_and_boundaries)?;
+
+        if self.exists(prefix, hash).await? {
+            info!("object {hash:?} already exists in memory CAS; returning.");
+            return Ok(0);
+        }
+
+        let mut writer = Cursor::new(Vec::new());
+        let (_, bytes_written) = CasObject::serialize(
+            &mut writer,
+            hash,
+            &data,
+            &chunk_and_boundaries,
+            Some(cas_object::CompressionScheme::None),
+        )?;
+
+        self.xorbs.write()?.insert((prefix.to_owned(), *hash), writer.into_inner());
+
+        Ok(bytes_written)
+    }
+
+    async fn exists(&self, prefix: &str, hash: &MerkleHash) -> Result<bool> {
+        Ok(self.xorbs.read()?.contains_key(&(prefix.to_owned(), *hash)))
+    }
+}
+
+#[async_trait]
+impl RegistrationClient for MemoryLocalClient {
+    async fn upload_shard(
+        &self,
+        _prefix: &str,
+        shard_hash: &MerkleHash,
+        _force_sync: bool,
+        shard_data: &[u8],
+        salt: &[u8; 32],
+    ) -> Result<bool> {
+        let mut reader = Cursor::new(shard_data);
+        let shard = MDBShardInfo::load_from_reader(&mut reader)?;
+        let file_infos = shard.read_all_file_info_sections(&mut reader)?;
+        let chunk_hashes = MDBShardInfo::filter_cas_chunks_for_global_dedup(&mut reader)?;
+
+        {
+            let mut file_info = self.file_info.write()?;
+            for fi in file_infos {
+                file_info.insert(fi.metadata.file_hash, (fi, *shard_hash));
+            }
+        }
+
+        {
+            let mut global_dedup = self.global_dedup.write()?;
+            for chunk in chunk_hashes {
+                if let Ok(salted_chunk_hash) = with_salt(&chunk, salt) {
+                    global_dedup.insert(salted_chunk_hash, *shard_hash);
+                }
+            }
+        }
+
+        self.shards.write()?.insert(*shard_hash, shard_data.to_vec());
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl FileReconstructor<CasClientError> for MemoryLocalClient {
+    async fn get_file_reconstruction_info(
+        &self,
+        file_hash: &MerkleHash,
+    ) -> Result<Option<(MDBFileInfo, Option<MerkleHash>)>> {
+        Ok(self
+            .file_info
+            .read()?
+            .get(file_hash)
+            .map(|(fi, shard_hash)| (fi.clone(), Some(*shard_hash))))
+    }
+}
+
+#[async_trait]
+impl ShardDedupProber for MemoryLocalClient {
+    async fn query_for_global_dedup_shard(
+        &self,
+        _prefix: &str,
+        chunk_hash: &MerkleHash,
+        salt: &[u8; 32],
+    ) -> Result<Option<PathBuf>> {
+        let Some(shard_cache_dir) = self.shard_cache_dir.as_ref() else {
+            return Err(CasClientError::Other("Shard cache directory not set for get_dedup_shards.".to_owned()));
+        };
+
+        let Ok(salted_chunk_hash) = with_salt(chunk_hash, salt) else {
+            return Ok(None);
+        };
+        let Some(shard_hash) = self.global_dedup.read()?.get(&salted_chunk_hash).copied() else {
+            return Ok(None);
+        };
+
+        // The caller expects the shard on disk.
+        let dest = shard_cache_dir.join(shard_file_name(&shard_hash));
+        let shards = self.shards.read()?;
+        let shard_data = shards.get(&shard_hash).ok_or_else(|| {
+            CasClientError::Other(format!("Shard {shard_hash:?} in global dedup table but not stored"))
+        })?;
+        std::fs::write(&dest, shard_data)?;
+        Ok(Some(dest))
+    }
+}
+
+impl ShardClientInterface for MemoryLocalClient {}
+
+#[async_trait]
+impl ReconstructionClient for MemoryLocalClient {
+    async fn get_file(
+        &self,
+        hash: &MerkleHash,
+        byte_range: Option<FileRange>,
+        output_provider: &OutputProvider,
+        progress_updater: Option<Arc<dyn ProgressUpdater>>,
+    ) -> Result<u64> {
+        let Some((file_info, _)) = self.get_file_reconstruction_info(hash).await? else {
+            return Err(CasClientError::FileNotFound(*hash));
+        };
+        let mut writer = output_provider.get_writer_at(0)?;
+
+        let mut file_vec = Vec::new();
+        for (entry, segment_range) in segments_in_range(&file_info, byte_range) {
+            let mut entry_bytes =
+                self.get_object_range(&entry.cas_hash, entry.chunk_index_start, entry.chunk_index_end, segment_range)?;
+            file_vec.append(&mut entry_bytes);
+        }
+
+        writer.write_all(&file_vec)?;
+        progress_updater.inspect(|updater| updater.update(file_vec.len() as u64));
+
+        Ok(file_vec.len() as u64)
+    }
+
+    async fn get_file_size(&self, hash: &MerkleHash) -> Result<u64> {
+        let Some((file_info, _)) = self.get_file_reconstruction_info(hash).await? else {
+            return Err(CasClientError::FileNotFound(*hash));
+        };
+        Ok(file_info.file_size() as u64)
+    }
+}
+
+impl Client for MemoryLocalClient {}
+
+#[cfg(test)]
+mod tests {
+    use cas_object::test_utils::*;
+    use cas_object::CompressionScheme::LZ4;
+    use mdb_shard::utils::parse_shard_filename;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_put_get_multiple_xorbs() {
+        let client = MemoryLocalClient::new();
+
+        let mut xorbs = Vec::new();
+        for num_chunks in [1, 3, 16, 44] {
+            let (c, _, data, chunk_boundaries) = build_cas_object(num_chunks, ChunkSize::Random(512, 15633), LZ4);
+            let written = client
+                .put("default", &c.info.cashash, data.clone(), chunk_boundaries)
+                .await
+                .unwrap();
+            assert!(written > 0);
+            xorbs.push((c.info.cashash, data));
+        }
+
+        // Uploading the same xorb again is a no-op.
+        let (hash, data) = &xorbs[0];
+        let boundaries = vec![(*hash, data.len() as u32)];
+        assert_eq!(client.put("default", hash, data.clone(), boundaries).await.unwrap(), 0);
+
+        for (hash, data) in &xorbs {
+            assert!(client.exists("default", hash).await.unwrap());
+            assert!(!client.exists("other", hash).await.unwrap());
+            assert_eq!(&client.get("default", hash).unwrap(), data);
+        }
+
+        let mut entries = client.get_all_entries().unwrap();
+        entries.sort_by_key(|k| k.hash);
+        let mut expected: Vec<_> = xorbs
+            .iter()
+            .map(|(hash, _)| Key {
+                prefix: "default".into(),
+                hash: *hash,
+            })
+            .collect();
+        expected.sort_by_key(|k| k.hash);
+        assert_eq!(entries, expected);
+
+        client.delete("default", hash).unwrap();
+        assert_eq!(client.get("default", hash).unwrap_err(), CasClientError::XORBNotFound(*hash));
+
+        // Clones share storage.
+        assert_eq!(client.clone().get_all_entries().unwrap().len(), xorbs.len() - 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_global_dedup() {
+        let tmp_dir = TempDir::new().unwrap();
+        let shard_dir_1 = tmp_dir.path().join("shard_1");
+        std::fs::create_dir_all(&shard_dir_1).unwrap();
+        let shard_dir_2 = tmp_dir.path().join("shard_2");
+        std::fs::create_dir_all(&shard_dir_2).unwrap();
+
+        let shard_in = mdb_shard::shard_format::test_routines::gen_random_shard_with_cas_references(
+            0, &[16; 8], &[2; 20], true, true,
+        )
+        .unwrap();
+        let new_shard_path = shard_in.write_to_directory(&shard_dir_1).unwrap();
+        let shard_hash = parse_shard_filename(&new_shard_path).unwrap();
+
+        let client = MemoryLocalClient::with_global_dedup(shard_dir_2.clone());
+        client
+            .upload_shard("default", &shard_hash, true, &std::fs::read(&new_shard_path).unwrap(), &[1; 32])
+            .await
+            .unwrap();
+
+        for fi in shard_in.file_content.values() {
+            let (file_info, shard) = client
+                .get_file_reconstruction_info(&fi.metadata.file_hash)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&file_info, fi);
+            assert_eq!(shard, Some(shard_hash));
+        }
+
+        let dedup_hashes =
+            MDBShardInfo::filter_cas_chunks_for_global_dedup(&mut std::fs::File::open(&new_shard_path).unwrap())
+                .unwrap();
+        assert_ne!(dedup_hashes.len(), 0);
+
+        let new_shard = client
+            .query_for_global_dedup_shard("default", &dedup_hashes[0], &[1; 32])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(new_shard, shard_dir_2.join(shard_file_name(&shard_hash)));
+        assert_eq!(std::fs::read(new_shard).unwrap(), std::fs::read(new_shard_path).unwrap());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_get_file_sparse() {
+        use std::os::unix::fs::MetadataExt;
+
+        use mdb_shard::file_structs::{FileDataSequenceEntry, FileDataSequenceHeader};
+        use mdb_shard::shard_format::test_routines::convert_to_file;
+        use mdb_shard::shard_in_memory::MDBInMemoryShard;
+        use merklehash::compute_data_hash;
+
+        use crate::FileProvider;
+
+        const MB: usize = 1 << 20;
+        const CHUNK_SIZE: usize = 64 << 10;
+
+        // Mostly zeros, with some data between the zeros and a zero region at the end.
+        let mut data = vec![0u8; 4 * MB];
+        data[MB + 100..2 * MB].copy_from_slice(&gen_random_bytes((MB - 100) as u32));
+        let chunk_boundaries: Vec<_> = data
+            .chunks(CHUNK_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| (compute_data_hash(chunk), (i * CHUNK_SIZE + chunk.len()) as u32))
+            .collect();
+        let client = MemoryLocalClient::new();
+        let xorb_hash = compute_data_hash(&data);
+        client
+            .put("default", &xorb_hash, data.clone(), chunk_boundaries.clone())
+            .await
+            .unwrap();
+
+        let file_hash = compute_data_hash(xorb_hash.as_bytes());
+        let mut shard = MDBInMemoryShard::default();
+        shard
+            .add_file_reconstruction_info(MDBFileInfo {
+                metadata: FileDataSequenceHeader::new(file_hash, 1, false, false),
+                segments: vec![FileDataSequenceEntry::new(
+                    xorb_hash,
+                    data.len(),
+                    0,
+                    chunk_boundaries.len(),
+                )],
+                verification: vec![],
+                metadata_ext: None,
+            })
+            .unwrap();
+        let shard_data = convert_to_file(&shard).unwrap();
+        client
+            .upload_shard("default", &compute_data_hash(&shard_data), true, &shard_data, &[0; 32])
+            .await
+            .unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let dense_path = dir.path().join("dense");
+        let output = OutputProvider::File(FileProvider::new(dense_path.clone()));
+        client.get_file(&file_hash, None, &output, None).await.unwrap();
+
+        // The sparse file overwrites an existing file, whose contents must not show through the holes.
+        let sparse_path = dir.path().join("sparse");
+        std::fs::write(&sparse_path, gen_random_bytes(4 * MB as u32)).unwrap();
+        let output = OutputProvider::File(FileProvider::new(sparse_path.clone()).with_sparse(true));
+        client.get_file(&file_hash, None, &output, None).await.unwrap();
+
+        assert_eq!(std::fs::read(&dense_path).unwrap(), data);
+        assert_eq!(std::fs::read(&sparse_path).unwrap(), data);
+        let allocated = |path: &PathBuf| std::fs::metadata(path).unwrap().blocks() * 512;
+        assert!(allocated(&sparse_path) < allocated(&dense_path) / 2);
+        assert!(allocated(&sparse_path) < 2 * MB as u64);
+    }
+}
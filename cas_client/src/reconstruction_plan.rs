@@ -0,0 +1,289 @@
+use std::fmt;
+use std::ops::Range;
+
+use cas_types::{CASReconstructionTerm, ChunkRange, FileRange, HttpRange, QueryReconstructionResponse};
+
+use crate::error::{CasClientError, Result};
+
+/// Number of hex characters of the xorb hash shown in a plan.
+const SHORT_HASH_LEN: usize = 16;
+
+/// A single term of a reconstruction plan: a range of chunks of a xorb, where to fetch it from
+/// and where its bytes land in the output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedTerm {
+    /// Leading hex characters of the xorb hash.
+    pub xorb_hash: String,
+    pub unpacked_length: u32,
+    pub chunk_range: ChunkRange,
+    pub url: String,
+    pub url_range: HttpRange,
+    /// The range of file bytes written from this term.
+    pub output_range: FileRange,
+}
+
+/// The ordered terms a download of a file executes, as returned by
+/// `RemoteClient::explain_reconstruction`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReconstructionPlan {
+    /// Number of bytes skipped at the start of the first term.
+    pub offset_into_first_range: u64,
+    pub terms: Vec<PlannedTerm>,
+}
+
+impl ReconstructionPlan {
+    /// Builds the plan for the reconstruction response of `byte_range` of a file (or the whole file
+    /// if None), trimming the terms to the requested bytes the same way the download does.
+    pub fn new(response: &QueryReconstructionResponse, byte_range: Option<FileRange>) -> Result<Self> {
+        let total_len = reconstruction_length(&response.terms, byte_range.as_ref())?;
+        let output_ranges = term_output_ranges(&response.terms, response.offset_into_first_range, total_len)?;
+        let range_start = byte_range.map(|range| range.start).unwrap_or(0);
+
+        let mut terms = Vec::with_capacity(response.terms.len());
+        for (term, output) in response.terms.iter().zip(output_ranges) {
+            let fetch_info = response
+                .fetch_info
+                .get(&term.hash)
+                .and_then(|infos| {
+                    infos
+                        .iter()
+                        .find(|info| info.range.start <= term.range.start && info.range.end >= term.range.end)
+                })
+                .ok_or(CasClientError::InvalidArguments)?;
+
+            let len = output.term_range.len() as u64;
+            let output_start = checked_offset(range_start, output.output_offset)?;
+            terms.push(PlannedTerm {
+                xorb_hash: term.hash.0.hex()[..SHORT_HASH_LEN].to_owned(),
+                unpacked_length: term.unpacked_length,
+                chunk_range: term.range.clone(),
+                url: fetch_info.url.clone(),
+                url_range: fetch_info.url_range.clone(),
+                output_range: FileRange {
+                    start: output_start,
+                    end: checked_offset(output_start, len)?,
+                },
+            });
+        }
+
+        Ok(Self {
+            offset_into_first_range: response.offset_into_first_range,
+            terms,
+        })
+    }
+
+    /// Total number of bytes written by the plan.
+    pub fn total_length(&self) -> u64 {
+        self.terms.iter().map(|t| t.output_range.end - t.output_range.start).sum()
+    }
+}
+
+/// The part of a term's data written to the output, and where it is written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TermOutputRange {
+    /// Byte range within the unpacked data of the term.
+    pub term_range: Range<usize>,
+    /// Offset of the data in the output, relative to the start of the reconstructed range.
+    pub output_offset: u64,
+}
+
+/// The number of bytes reconstructed from `terms`: the length of `byte_range` if given, otherwise
+/// the summed length of all the terms.
+pub(crate) fn reconstruction_length(terms: &[CASReconstructionTerm], byte_range: Option<&FileRange>) -> Result<u64> {
+    match byte_range {
+        Some(range) => range.end.checked_sub(range.start).ok_or(CasClientError::InvalidRange),
+        None => terms
+            .iter()
+            .try_fold(0u64, |acc, term| checked_offset(acc, term.unpacked_length as u64)),
+    }
+}
+
+/// Computes which bytes of each term are written where, for a reconstruction of `total_len` bytes
+/// starting `offset_into_first_range` bytes into the first term.  All offsets are accumulated
+/// as u64, and an inconsistent or overflowing plan is an error rather than wrapping.
+pub(crate) fn term_output_ranges(
+    terms: &[CASReconstructionTerm],
+    offset_into_first_range: u64,
+    total_len: u64,
+) -> Result<Vec<TermOutputRange>> {
+    let mut output_offset = 0u64;
+    let mut remaining = total_len;
+
+    let mut ranges = Vec::with_capacity(terms.len());
+    for (idx, term) in terms.iter().enumerate() {
+        let start = if idx == 0 { offset_into_first_range } else { 0 };
+        let end = checked_offset(start, remaining)?.min(term.unpacked_length as u64);
+        let len = end.checked_sub(start).ok_or_else(|| {
+            CasClientError::ReconstructionOverflow(format!(
+                "offset into first range {start} exceeds the first term length {}",
+                term.unpacked_length
+            ))
+        })?;
+
+        // Both ends are at most the u32 term length.
+        ranges.push(TermOutputRange {
+            term_range: start as usize..end as usize,
+            output_offset,
+        });
+        output_offset = checked_offset(output_offset, len)?;
+        remaining -= len;
+    }
+
+    Ok(ranges)
+}
+
+/// Derives the reconstruction of `byte_range` from the reconstruction of the whole file, keeping the
+/// terms that overlap the range and the fetch info of their xorbs.  Returns None if the range isn't
+/// a non-empty range within the file, so that the query is left to the server.
+pub(crate) fn slice_reconstruction(
+    full: &QueryReconstructionResponse,
+    byte_range: &FileRange,
+) -> Option<QueryReconstructionResponse> {
+    if full.offset_into_first_range != 0 || byte_range.start >= byte_range.end {
+        return None;
+    }
+
+    let mut terms = Vec::new();
+    let mut offset_into_first_range = 0;
+    let mut term_start = 0u64;
+    for term in &full.terms {
+        let term_end = term_start.checked_add(term.unpacked_length as u64)?;
+        if term_end > byte_range.start {
+            if terms.is_empty() {
+                offset_into_first_range = byte_range.start - term_start;
+            }
+            terms.push(term.clone());
+        }
+        term_start = term_end;
+        if term_end >= byte_range.end {
+            break;
+        }
+    }
+    // term_start is now the end of the last term kept
+    if terms.is_empty() || term_start < byte_range.end {
+        return None;
+    }
+
+    let fetch_info = full
+        .fetch_info
+        .iter()
+        .filter(|(hash, _)| terms.iter().any(|term| &term.hash == *hash))
+        .map(|(hash, infos)| (*hash, infos.clone()))
+        .collect();
+
+    Some(QueryReconstructionResponse {
+        offset_into_first_range,
+        terms,
+        fetch_info,
+    })
+}
+
+pub(crate) fn checked_offset(offset: u64, len: u64) -> Result<u64> {
+    offset
+        .checked_add(len)
+        .ok_or_else(|| CasClientError::ReconstructionOverflow(format!("offset {offset} + length {len} overflows u64")))
+}
+
+impl fmt::Display for ReconstructionPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} terms, {} bytes, offset into first range {}",
+            self.terms.len(),
+            self.total_length(),
+            self.offset_into_first_range
+        )?;
+        for (idx, term) in self.terms.iter().enumerate() {
+            writeln!(
+                f,
+                "{idx}: xorb {} chunks {} ({} bytes) -> output {}, fetch {} bytes {}",
+                term.xorb_hash, term.chunk_range, term.unpacked_length, term.output_range, term.url_range, term.url
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cas_types::HexMerkleHash;
+    use merklehash::MerkleHash;
+
+    use super::*;
+
+    fn term(unpacked_length: u32) -> CASReconstructionTerm {
+        CASReconstructionTerm {
+            hash: HexMerkleHash::default(),
+            unpacked_length,
+            range: ChunkRange { start: 0, end: 1 },
+        }
+    }
+
+    #[test]
+    fn test_term_output_ranges_past_u32_max() {
+        // Three terms of almost 4 GiB each, about 12 GiB in total.
+        let terms = vec![term(u32::MAX), term(u32::MAX), term(u32::MAX - 10)];
+        let total_len = reconstruction_length(&terms, None).unwrap();
+        assert_eq!(total_len, 3 * u32::MAX as u64 - 10);
+
+        let ranges = term_output_ranges(&terms, 0, total_len).unwrap();
+        let offsets: Vec<u64> = ranges.iter().map(|r| r.output_offset).collect();
+        assert_eq!(offsets, vec![0, u32::MAX as u64, 2 * u32::MAX as u64]);
+        assert_eq!(ranges[2].term_range, 0..(u32::MAX - 10) as usize);
+
+        // Skipping into the first term and stopping partway through the last.
+        let ranges = term_output_ranges(&terms, 100, total_len - 1000).unwrap();
+        assert_eq!(ranges[0].term_range, 100..u32::MAX as usize);
+        assert_eq!(ranges[1].output_offset, u32::MAX as u64 - 100);
+        assert_eq!(ranges[2].output_offset, 2 * u32::MAX as u64 - 100);
+        let last = &ranges[2];
+        assert_eq!(last.output_offset + last.term_range.len() as u64, total_len - 1000);
+    }
+
+    #[test]
+    fn test_term_output_ranges_errors() {
+        let terms = vec![term(100), term(100)];
+
+        // The offset into the first term can't be past its end.
+        assert!(matches!(term_output_ranges(&terms, 101, 50), Err(CasClientError::ReconstructionOverflow(_))));
+        assert!(matches!(term_output_ranges(&terms, 1, u64::MAX), Err(CasClientError::ReconstructionOverflow(_))));
+        assert_eq!(
+            reconstruction_length(&terms, Some(&FileRange { start: 10, end: 5 })).unwrap_err(),
+            CasClientError::InvalidRange
+        );
+    }
+
+    #[test]
+    fn test_slice_reconstruction() {
+        let full = QueryReconstructionResponse {
+            offset_into_first_range: 0,
+            terms: (1..=3u64)
+                .map(|i| CASReconstructionTerm {
+                    hash: HexMerkleHash(MerkleHash::from([i, 0, 0, 0])),
+                    ..term(100)
+                })
+                .collect(),
+            fetch_info: (1..=3u64)
+                .map(|i| (HexMerkleHash(MerkleHash::from([i, 0, 0, 0])), vec![]))
+                .collect(),
+        };
+        let slice = |start, end| slice_reconstruction(&full, &FileRange { start, end });
+        let hashes = |terms: &[CASReconstructionTerm]| terms.iter().map(|term| term.hash).collect::<Vec<_>>();
+
+        let sliced = slice(150, 250).unwrap();
+        assert_eq!(sliced.offset_into_first_range, 50);
+        assert_eq!(hashes(&sliced.terms), hashes(&full.terms[1..3]));
+        assert_eq!(sliced.fetch_info.len(), 2);
+
+        let sliced = slice(0, 100).unwrap();
+        assert_eq!(sliced.offset_into_first_range, 0);
+        assert_eq!(hashes(&sliced.terms), hashes(&full.terms[..1]));
+
+        assert_eq!(hashes(&slice(0, 300).unwrap().terms), hashes(&full.terms));
+
+        // Empty ranges and ranges past the end of the file aren't sliced.
+        assert!(slice(10, 10).is_none());
+        assert!(slice(250, 301).is_none());
+        assert!(slice(300, 400).is_none());
+    }
+}
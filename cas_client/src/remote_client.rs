@@ -1,40 +1,58 @@
-use std::cmp::{max, min};
+use std::cell::Cell;
 use std::collections::{HashMap, HashSet};
-use std::io::{Cursor, Write};
+use std::fmt;
+use std::io::{BufReader, Cursor, Read, Write};
 use std::ops::Range;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
 use async_trait::async_trait;
-use cas_object::{CasObject, CompressionScheme};
+use cas_object::{CasObject, CompressionScheme, DecompressionBufferPool, XorbSchemeLearner, CAS_CHUNK_HEADER_LENGTH};
 use cas_types::{
     BatchQueryReconstructionResponse, CASReconstructionFetchInfo, CASReconstructionTerm, FileRange, HexMerkleHash,
     HttpRange, Key, QueryReconstructionResponse, UploadShardResponse, UploadShardResponseType, UploadXorbResponse,
+    IDEMPOTENCY_KEY_HEADER,
 };
-use chunk_cache::{CacheConfig, ChunkCache};
+use chunk_cache::{CacheConfig, ChunkCache, DegradingCache};
 use error_printer::ErrorPrinter;
 use file_utils::SafeFileCreator;
 use futures::stream::FuturesUnordered;
 use futures::{StreamExt, TryStreamExt};
-use http::header::RANGE;
+use http::header::{ETAG, IF_NONE_MATCH, RANGE};
+use mdb_shard::error::MDBShardError;
 use mdb_shard::file_structs::{FileDataSequenceEntry, FileDataSequenceHeader, MDBFileInfo};
 use mdb_shard::shard_file_reconstructor::FileReconstructor;
+use mdb_shard::shard_range_reader::{read_file_info_from_ranges, ShardRangeReader};
 use mdb_shard::utils::shard_file_name;
-use merklehash::{HashedWrite, MerkleHash};
-use reqwest::{StatusCode, Url};
+use mdb_shard::{MDBShardInfo, ShardSummary};
+use merklehash::{compute_data_hash, HashedWrite, MerkleHash};
+use reqwest::{ResponseBuilderExt, StatusCode, Url};
 use reqwest_middleware::ClientWithMiddleware;
-use tokio::sync::Semaphore;
-use tracing::{debug, error, info, trace};
+use reqwest_retry::DefaultRetryableStrategy;
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+use tracing::{debug, error, info, trace, warn};
 use utils::auth::AuthConfig;
 use utils::progress::ProgressUpdater;
 use utils::singleflight::Group;
 use xet_threadpool::ThreadPool;
 
 use crate::error::{CasClientError, Result};
-use crate::http_client::{ResponseErrorLogger, RetryConfig};
+use crate::http_client::{
+    request_id_from_response, HttpClientConfig, No429RetryStratey, ResponseErrorLogger, RetryConfig,
+};
 use crate::interface::{ShardDedupProber, *};
-use crate::{http_client, Client, RegistrationClient, ShardClientInterface};
+use crate::reconstruction_plan::{
+    checked_offset, reconstruction_length, slice_reconstruction, term_output_ranges, TermOutputRange,
+};
+use crate::{
+    http_client, Client, ReconstructionPlan, RegistrationClient, ShardClientInterface, TermTransferStats, TransferStats,
+};
 
 const FORCE_SYNC_METHOD: reqwest::Method = reqwest::Method::PUT;
 const NON_FORCE_SYNC_METHOD: reqwest::Method = reqwest::Method::POST;
@@ -45,19 +63,105 @@ pub const PREFIX_DEFAULT: &str = "default";
 utils::configurable_constants! {
    ref NUM_CONCURRENT_RANGE_GETS: usize = 16;
 
+// The number of reconstruction queries in flight at once when querying the reconstructions of many
+// files up front.  The responses are small, so this is well above the number of concurrent range gets.
+    ref NUM_CONCURRENT_RECONSTRUCTION_QUERIES: usize = 64;
+
 // Env (HF_XET_RECONSTRUCT_WRITE_SEQUENTIALLY) to switch to writing terms sequentially to disk.
 // Benchmarks have shown that on SSD machines, writing in parallel seems to far outperform
 // sequential term writes.
 // However, this is not likely the case for writing to HDD and may in fact be worse,
 // so for those machines, setting this env may help download perf.
     ref RECONSTRUCT_WRITE_SEQUENTIALLY: bool = false;
+
+// The maximum number of reconstruction responses kept for revalidation with their ETag; the cache
+// is cleared once this is reached.
+    ref RECONSTRUCTION_CACHE_MAX_ENTRIES: usize = 1024;
+
+// For how long after a reconstruction is fetched it's reused: a full file reconstruction answers
+// ranged queries of the same file without a request, and any reconstruction with an ETag is
+// revalidated instead of fetched again.  Its fetch urls are presigned and eventually expire.
+    ref RECONSTRUCTION_CACHE_SLICE_MAX_AGE_SECS: u64 = 300;
+
+// The maximum number of output file handles open at once while writing terms in parallel, across
+// all downloads of a client. Kept well below the default file descriptor limit on macOS (256).
+    ref MAX_OPEN_OUTPUT_HANDLES: usize = 32;
+
+// The number of times the download of a xorb range is resumed from the last byte received when
+// its response is cut off, before the download fails.
+    ref MAX_RANGE_DOWNLOAD_RESUMES: usize = 3;
+
+// The maximum number of terms accepted in a reconstruction response; parsing a response with more
+// fails as soon as the limit is passed, before the terms are all allocated.
+    ref MAX_RECONSTRUCTION_TERMS: usize = 10_000_000;
+
+// The maximum number of fetched terms waiting to be written when writing terms sequentially; once
+// this many are waiting, fetching pauses until the writer catches up.
+    ref RECONSTRUCT_WRITE_BUFFER_TERMS: usize = 16;
+
+// The maximum size of the body of a response from CAS, e.g. a reconstruction, dedup shard or xorb.
+// Reading a larger body fails as soon as the limit is passed, so a misbehaving server can't exhaust
+// the memory of the client.
+    ref MAX_RESPONSE_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+// How long `RemoteClient::warmup` waits for the endpoint to answer, in milliseconds, before giving up.
+    ref WARMUP_TIMEOUT_MS: u64 = 10_000;
+}
+
+type RangeDownloadSingleFlight = Arc<Group<DownloadedRange, CasClientError>>;
+
+/// The deserialized data and chunk byte indices of a downloaded range, with the time spent receiving
+/// and decompressing it.
+type DownloadedRange = (Vec<u8>, Vec<u32>, Duration, Duration);
+
+/// Reconstruction responses keyed by (endpoint, file hash, byte range): full file responses, and
+/// ranged responses that came with an ETag.
+type ReconstructionCache = Mutex<HashMap<(String, MerkleHash, Option<FileRange>), CachedReconstruction>>;
+
+#[derive(Clone)]
+struct CachedReconstruction {
+    /// The ETag to revalidate the response with, if the server sent one.
+    etag: Option<String>,
+    response: QueryReconstructionResponse,
+    fetched_at: Instant,
+}
+
+/// The ETag of a reconstruction response, with the byte range of the query it answered.
+struct ReconstructionVersion {
+    etag: String,
+    bytes_range: Option<FileRange>,
+}
+
+/// Validates a CAS endpoint and returns it in the form requests are built from: an absolute http or
+/// https url without trailing slashes, e.g. `https://host/path/` becomes
+/// `https://host/path`.
+pub fn normalize_endpoint(endpoint: &str) -> Result<String> {
+    let invalid = |reason: &str| CasClientError::ConfigurationError(format!("invalid endpoint {endpoint:?}: {reason}"));
+
+    let trimmed = endpoint.trim().trim_end_matches('/');
+    if !trimmed.contains("://") {
+        return Err(invalid("missing scheme, expected e.g. https://host"));
+    }
+    let url = Url::parse(trimmed).map_err(|e| invalid(&e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid("scheme must be http or https"));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(invalid("must not have a query or fragment"));
+    }
+
+    Ok(url.as_str().trim_end_matches('/').to_string())
 }
 
-type RangeDownloadSingleFlight = Arc<Group<(Vec<u8>, Vec<u32>), CasClientError>>;
+/// The source of the recorded chunk hashes that downloaded xorb chunks are verified against.
+pub type ChunkHashSource = Arc<dyn FileReconstructor<MDBShardError> + Send + Sync>;
 
 pub struct RemoteClient {
     endpoint: String,
+    fallback_endpoints: Vec<String>,
     compression: Option<CompressionScheme>,
+    // Shared by the xorbs uploaded without a compression scheme.
+    scheme_learner: Arc<XorbSchemeLearner>,
     dry_run: bool,
     http_client: Arc<ClientWithMiddleware>,
     authenticated_http_client: Arc<ClientWithMiddleware>,
@@ -65,10 +169,23 @@ pub struct RemoteClient {
     chunk_cache: Option<Arc<dyn ChunkCache>>,
     threadpool: Arc<ThreadPool>,
     range_download_single_flight: RangeDownloadSingleFlight,
+    reconstruction_cache: ReconstructionCache,
+    output_handles: Arc<Semaphore>,
+    max_concurrent_reconstruction_queries: usize,
+    max_reconstruction_terms: usize,
+    max_response_bytes: u64,
     shard_cache_directory: PathBuf,
+    chunk_hash_source: Option<ChunkHashSource>,
+    decompression_buffers: Option<Arc<DecompressionBufferPool>>,
+    durable_writes: bool,
+    write_buffer_terms: usize,
+    validate_version: bool,
+    coalesce_hint: bool,
 }
 
 impl RemoteClient {
+    /// Creates a client of the CAS server at `endpoint`, failing with `ConfigurationError` if the
+    /// endpoint isn't valid; see `normalize_endpoint`.
     pub fn new(
         threadpool: Arc<ThreadPool>,
         endpoint: &str,
@@ -77,7 +194,64 @@ impl RemoteClient {
         cache_config: &Option<CacheConfig>,
         shard_cache_directory: PathBuf,
         dry_run: bool,
-    ) -> Self {
+    ) -> Result<Self> {
+        Self::new_with_http_config(
+            threadpool,
+            endpoint,
+            compression,
+            auth,
+            cache_config,
+            shard_cache_directory,
+            dry_run,
+            &HttpClientConfig::default(),
+        )
+    }
+
+    /// Like `new`, but with the user agent and request id generator of `http_config`, which apply
+    /// to all the requests of the client, including shard uploads and queries.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_with_http_config(
+        threadpool: Arc<ThreadPool>,
+        endpoint: &str,
+        compression: Option<CompressionScheme>,
+        auth: &Option<AuthConfig>,
+        cache_config: &Option<CacheConfig>,
+        shard_cache_directory: PathBuf,
+        dry_run: bool,
+        http_config: &HttpClientConfig,
+    ) -> Result<Self> {
+        Self::new_with_configs(
+            threadpool.clone(),
+            endpoint,
+            compression,
+            auth,
+            cache_config,
+            shard_cache_directory,
+            dry_run,
+            http_config,
+            RetryConfig::default(),
+            threadpool.network_request_permits(),
+        )
+    }
+
+    /// Like `new_with_http_config`, retrying requests as in `retry_config` (except that requests
+    /// that must not be retried on 429 still aren't), with every request attempt holding one of
+    /// `network_request_permits` while in flight.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new_with_configs(
+        threadpool: Arc<ThreadPool>,
+        endpoint: &str,
+        compression: Option<CompressionScheme>,
+        auth: &Option<AuthConfig>,
+        cache_config: &Option<CacheConfig>,
+        shard_cache_directory: PathBuf,
+        dry_run: bool,
+        http_config: &HttpClientConfig,
+        retry_config: RetryConfig<DefaultRetryableStrategy>,
+        network_request_permits: Arc<Semaphore>,
+    ) -> Result<Self> {
+        let endpoint = normalize_endpoint(endpoint)?;
+
         // use disk cache if cache_config provided.
         let chunk_cache = if let Some(cache_config) = cache_config {
             if cache_config.cache_size == 0 {
@@ -88,31 +262,269 @@ impl RemoteClient {
                     "Using disk cache directory: {:?}, size: {}.",
                     cache_config.cache_directory, cache_config.cache_size
                 );
+                // A cache that can't be written to is turned off rather than failing downloads.
                 chunk_cache::get_cache(cache_config)
                     .log_error("failed to initialize cache, not using cache")
                     .ok()
+                    .map(|cache| Arc::new(DegradingCache::new(cache)) as Arc<dyn ChunkCache>)
             }
         } else {
             None
         };
         let range_download_single_flight = Arc::new(Group::new());
 
-        Self {
-            endpoint: endpoint.to_string(),
+        // Requests to CAS and to the blob store share the network request permits, by default those
+        // of the threadpool, so uploads and downloads together stay within its limit.
+        let permits = network_request_permits;
+
+        Ok(Self {
+            endpoint,
+            fallback_endpoints: Vec::new(),
             compression,
+            scheme_learner: Default::default(),
             dry_run,
             authenticated_http_client: Arc::new(
-                http_client::build_auth_http_client(auth, RetryConfig::default()).unwrap(),
+                http_client::build_limited_auth_http_client(
+                    auth,
+                    retry_config.with_strategy(DefaultRetryableStrategy),
+                    http_config,
+                    Some(permits.clone()),
+                )
+                .unwrap(),
             ),
             conservative_authenticated_http_client: Arc::new(
-                http_client::build_auth_http_client(auth, RetryConfig::no429retry()).unwrap(),
+                http_client::build_limited_auth_http_client(
+                    auth,
+                    retry_config.with_strategy(No429RetryStratey),
+                    http_config,
+                    Some(permits.clone()),
+                )
+                .unwrap(),
+            ),
+            http_client: Arc::new(
+                http_client::build_limited_http_client(retry_config, http_config, Some(permits)).unwrap(),
             ),
-            http_client: Arc::new(http_client::build_http_client(RetryConfig::default()).unwrap()),
             chunk_cache,
             threadpool,
             range_download_single_flight,
+            reconstruction_cache: Default::default(),
+            output_handles: Arc::new(Semaphore::new(*MAX_OPEN_OUTPUT_HANDLES)),
+            max_concurrent_reconstruction_queries: *NUM_CONCURRENT_RECONSTRUCTION_QUERIES,
+            max_reconstruction_terms: *MAX_RECONSTRUCTION_TERMS,
+            max_response_bytes: *MAX_RESPONSE_BYTES,
             shard_cache_directory,
+            chunk_hash_source: None,
+            decompression_buffers: None,
+            durable_writes: false,
+            write_buffer_terms: *RECONSTRUCT_WRITE_BUFFER_TERMS,
+            validate_version: false,
+            coalesce_hint: false,
+        })
+    }
+
+    /// Sets the mirror endpoints to fall back to, in order, when reconstructing a file
+    /// from the primary endpoint fails.  Only downloads use the fallback endpoints.  Fails with
+    /// `ConfigurationError` if any of them isn't valid; see `normalize_endpoint`.
+    pub fn with_fallback_endpoints(mut self, fallback_endpoints: Vec<String>) -> Result<Self> {
+        self.fallback_endpoints = fallback_endpoints
+            .iter()
+            .map(|endpoint| normalize_endpoint(endpoint))
+            .collect::<Result<_>>()?;
+        Ok(self)
+    }
+
+    /// Verifies every chunk fetched from the blob store against the chunk hashes `chunk_hash_source`
+    /// has recorded for its xorb, failing the download with `CasClientError::ChunkHashMismatch` on a
+    /// mismatch.  Chunks of xorbs with no recorded hashes, and chunks read from the chunk cache, are
+    /// not verified.
+    pub fn with_chunk_verification(mut self, chunk_hash_source: ChunkHashSource) -> Self {
+        self.chunk_hash_source = Some(chunk_hash_source);
+        self
+    }
+
+    /// Decompresses the fetched xorb ranges with scratch buffers from a pool of up to `max_buffers`
+    /// buffers shared by all downloads of the client, rather than allocating them for every chunk;
+    /// 0 turns the pool off.  Each range fetched at once uses up to 2 buffers of up to a chunk each.
+    pub fn with_decompression_buffer_pool(mut self, max_buffers: usize) -> Self {
+        self.decompression_buffers = (max_buffers > 0).then(|| Arc::new(DecompressionBufferPool::new(max_buffers)));
+        self
+    }
+
+    /// Syncs each downloaded file to disk, along with its directory, before the download returns,
+    /// so that a file reported as downloaded survives a crash or power loss right after.
+    ///
+    /// Without it, the written data may sit in the OS page cache for a while.  Syncing waits for
+    /// the whole file to reach the disk, which can take as long as writing it did on slow disks,
+    /// and on some filesystems flushes other pending writes too; it's off by default.
+    pub fn with_durable_writes(mut self, durable_writes: bool) -> Self {
+        self.durable_writes = durable_writes;
+        self
+    }
+
+    /// Once a download completes, checks with the server that the file still has the version (ETag)
+    /// its reconstruction was queried at; if it changed, the output is discarded and the download
+    /// fails with `CasClientError::VersionChanged`, so that the caller can retry it.  Files whose
+    /// reconstruction came without an ETag are not checked.
+    pub fn with_version_validation(mut self, validate_version: bool) -> Self {
+        self.validate_version = validate_version;
+        self
+    }
+
+    /// Asks the server, with the `coalesce=true` query parameter of reconstruction queries, to merge
+    /// adjacent terms into fewer terms.  Servers that don't support the hint ignore it.  Adjacent
+    /// fetch ranges of a xorb are coalesced client-side either way.
+    pub fn with_coalesce_hint(mut self, coalesce_hint: bool) -> Self {
+        self.coalesce_hint = coalesce_hint;
+        self
+    }
+
+    /// Connects to the endpoint ahead of the first transfer, so that the first user-visible request
+    /// doesn't pay for the DNS resolution and the TCP and TLS handshakes: sends a HEAD request to the
+    /// endpoint, leaving the connection in the pool of the client's CAS requests.  Whatever the
+    /// response, the connection is established; failures and timeouts are logged and ignored.
+    pub async fn warmup(&self) {
+        let start = Instant::now();
+        let timeout = Duration::from_millis(*WARMUP_TIMEOUT_MS);
+        match tokio::time::timeout(timeout, self.authenticated_http_client.head(&self.endpoint).send()).await {
+            Ok(Ok(response)) => {
+                debug!("Warmed up connection to {} in {:?} ({})", self.endpoint, start.elapsed(), response.status())
+            },
+            Ok(Err(e)) => warn!("Failed to warm up connection to {}: {e}", self.endpoint),
+            Err(_) => warn!("Warming up connection to {} timed out after {timeout:?}", self.endpoint),
+        }
+    }
+
+    /// Returns the plan that downloading `byte_range` of the file (or the whole file if None) would
+    /// execute: the ordered terms with the xorb, chunk range and fetch url of each, and the range of
+    /// the output each writes.  Only queries the reconstruction; no xorb data is fetched.
+    pub async fn explain_reconstruction(
+        &self,
+        file_id: &MerkleHash,
+        byte_range: Option<FileRange>,
+    ) -> Result<ReconstructionPlan> {
+        let response = self.get_reconstruction(file_id, byte_range.clone()).await?;
+        ReconstructionPlan::new(&response, byte_range)
+    }
+
+    /// Reconstructs the bytes of the file covered by the given ranges in one pass, fetching each
+    /// term once, and writes the bytes of each range to its own output, starting at offset 0 of
+    /// that output.  Ranges may overlap; every output receives all the bytes of its range.
+    ///
+    /// Returns the total number of bytes written to all the outputs.
+    pub async fn get_file_multiplexed(
+        &self,
+        hash: &MerkleHash,
+        outputs: Vec<(FileRange, OutputProvider)>,
+    ) -> Result<u64> {
+        if outputs.iter().any(|(range, _)| range.end < range.start) {
+            return Err(CasClientError::InvalidRange);
+        }
+        let covering_range = FileRange {
+            start: outputs.iter().map(|(range, _)| range.start).min().unwrap_or(0),
+            end: outputs.iter().map(|(range, _)| range.end).max().unwrap_or(0),
+        };
+        if covering_range.start >= covering_range.end {
+            return Ok(0);
+        }
+
+        let manifest = self.get_reconstruction(hash, Some(covering_range.clone())).await?;
+        let total_len = reconstruction_length(&manifest.terms, Some(&covering_range))?;
+        let output_ranges = term_output_ranges(&manifest.terms, manifest.offset_into_first_range, total_len)?;
+        let fetch_info = Arc::new(manifest.fetch_info);
+
+        let futs_iter = manifest.terms.into_iter().map(|term| {
+            get_one_term(
+                self.http_client.clone(),
+                self.chunk_cache.clone(),
+                term,
+                fetch_info.clone(),
+                self.range_download_single_flight.clone(),
+                self.chunk_hash_source.clone(),
+                self.decompression_buffers.clone(),
+            )
+        });
+        let mut futs_buffered_enumerated = futures::stream::iter(futs_iter)
+            .buffered(*NUM_CONCURRENT_RANGE_GETS)
+            .enumerate();
+
+        let mut total_written = 0;
+        while let Some((term_idx, term_data_result)) = futs_buffered_enumerated.next().await {
+            let (term_data, _) = term_data_result.log_error(format!("error fetching 1 term at index {term_idx}"))?;
+            let TermOutputRange {
+                term_range,
+                output_offset,
+            } = &output_ranges[term_idx];
+            let term_start = checked_offset(covering_range.start, *output_offset)?;
+            let term_end = checked_offset(term_start, term_range.len() as u64)?;
+            let term_bytes = &term_data[term_range.clone()];
+
+            // Write the part of the term inside each output range, at its offset in that range.
+            for (range, output) in &outputs {
+                let start = range.start.max(term_start);
+                let end = range.end.min(term_end);
+                if start >= end {
+                    continue;
+                }
+                let mut writer = output.get_writer_at(start - range.start)?;
+                writer.write_all(&term_bytes[(start - term_start) as usize..(end - term_start) as usize])?;
+                writer.flush()?;
+                total_written += end - start;
+            }
+        }
+
+        if self.durable_writes {
+            for (_, output) in &outputs {
+                self.sync_to_disk(output).await?;
+            }
         }
+
+        Ok(total_written)
+    }
+
+    /// Syncs `output` to disk on a blocking thread, as an fsync can stall for a long time.
+    async fn sync_to_disk(&self, output: &OutputProvider) -> Result<()> {
+        let output = output.clone();
+        self.threadpool
+            .spawn_blocking(move || output.sync_to_disk())
+            .await
+            .map_err(|e| CasClientError::Other(format!("Error joining output sync task {e:?}")))?
+    }
+
+    /// Sets the maximum number of output file handles open at once while writing downloaded
+    /// terms in parallel, shared by all downloads of this client.
+    pub fn with_max_open_output_handles(mut self, max_open_output_handles: usize) -> Self {
+        self.output_handles = Arc::new(Semaphore::new(max_open_output_handles));
+        self
+    }
+
+    /// Sets the maximum number of reconstruction queries in flight at once when the reconstructions of
+    /// many files are queried up front, as by `prefetch_files`, independently of the number of xorb
+    /// ranges fetched at once; must be at least 1.  Every query still holds a network request permit
+    /// while in flight.
+    pub fn with_max_concurrent_reconstruction_queries(mut self, max_concurrent_reconstruction_queries: usize) -> Self {
+        self.max_concurrent_reconstruction_queries = max_concurrent_reconstruction_queries.max(1);
+        self
+    }
+
+    /// Sets the maximum number of fetched terms waiting to be written when writing terms
+    /// sequentially; must be at least 1.
+    pub fn with_write_buffer_terms(mut self, write_buffer_terms: usize) -> Self {
+        self.write_buffer_terms = write_buffer_terms.max(1);
+        self
+    }
+
+    /// Sets the maximum number of terms accepted in a reconstruction response; larger responses
+    /// fail with `CasClientError::ResponseTooLarge`.
+    pub fn with_max_reconstruction_terms(mut self, max_reconstruction_terms: usize) -> Self {
+        self.max_reconstruction_terms = max_reconstruction_terms;
+        self
+    }
+
+    /// Sets the maximum size of the body of a response from CAS; reading a larger body fails with
+    /// `CasClientError::ResponseTooLarge`.
+    pub fn with_max_response_bytes(mut self, max_response_bytes: u64) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
     }
 }
 
@@ -125,6 +537,19 @@ impl UploadClient for RemoteClient {
         data: Vec<u8>,
         chunk_and_boundaries: Vec<(MerkleHash, u32)>,
     ) -> Result<usize> {
+        let (_, nbytes_trans) = self.put_with_status(prefix, hash, data, chunk_and_boundaries).await?;
+        Ok(nbytes_trans)
+    }
+
+    async fn put_with_status(
+        &self,
+        prefix: &str,
+        hash: &MerkleHash,
+        data: Vec<u8>,
+        chunk_and_boundaries: Vec<(MerkleHash, u32)>,
+    ) -> Result<(bool, usize)> {
+        validate_chunk_boundaries(&data, &chunk_and_boundaries)?;
+
         let key = Key {
             prefix: prefix.to_string(),
             hash: *hash,
@@ -138,7 +563,7 @@ impl UploadClient for RemoteClient {
             debug!("{key:?} inserted into CAS.");
         }
 
-        Ok(nbytes_trans)
+        Ok((was_uploaded, nbytes_trans))
     }
 
     async fn exists(&self, prefix: &str, hash: &MerkleHash) -> Result<bool> {
@@ -166,34 +591,86 @@ impl ReconstructionClient for RemoteClient {
         output_provider: &OutputProvider,
         progress_updater: Option<Arc<dyn ProgressUpdater>>,
     ) -> Result<u64> {
-        // get manifest of xorbs to download, api call to CAS
-        let manifest = self.get_reconstruction(hash, byte_range.clone()).await?;
-        let terms = manifest.terms;
-        let fetch_info = Arc::new(manifest.fetch_info);
+        let stats = self
+            .get_file_with_stats(hash, byte_range, output_provider, progress_updater)
+            .await?;
+        Ok(stats.total_bytes)
+    }
 
-        // If the user has set the `HF_XET_RECONSTRUCT_WRITE_SEQUENTIALLY=true` env variable, then we
-        // should write the file to the output sequentially instead of in parallel.
-        if *RECONSTRUCT_WRITE_SEQUENTIALLY {
-            self.reconstruct_file_to_writer(
-                terms,
-                fetch_info,
-                manifest.offset_into_first_range,
-                byte_range,
-                output_provider,
-                progress_updater,
-            )
-            .await
-        } else {
-            self.reconstruct_file_to_writer_parallel(
-                terms,
-                fetch_info,
-                manifest.offset_into_first_range,
-                byte_range,
-                output_provider,
-                progress_updater,
-            )
-            .await
+    async fn get_file_with_stats(
+        &self,
+        hash: &MerkleHash,
+        byte_range: Option<FileRange>,
+        output_provider: &OutputProvider,
+        progress_updater: Option<Arc<dyn ProgressUpdater>>,
+    ) -> Result<TransferStats> {
+        let start = Instant::now();
+
+        // Try the primary endpoint first, then each fallback endpoint in order.  As every term is
+        // written at its absolute offset in the output, a later attempt simply overwrites any data
+        // written by a failed one; likewise, its progress is only reported past the bytes already
+        // reported by the failed one.
+        let endpoints: Vec<&str> = std::iter::once(self.endpoint.as_str())
+            .chain(self.fallback_endpoints.iter().map(String::as_str))
+            .collect();
+        let mut expected_len = None;
+        let reported_bytes = Arc::new(AtomicU64::new(0));
+
+        for (idx, endpoint) in endpoints.iter().enumerate() {
+            let result = self
+                .get_file_from_endpoint(
+                    endpoint,
+                    hash,
+                    byte_range.clone(),
+                    output_provider,
+                    progress_updater.clone().map(|inner| {
+                        Arc::new(AttemptProgressUpdater {
+                            inner,
+                            attempt_bytes: AtomicU64::new(0),
+                            reported_bytes: reported_bytes.clone(),
+                        }) as Arc<dyn ProgressUpdater>
+                    }),
+                    &mut expected_len,
+                )
+                .await;
+
+            match result {
+                Ok(mut stats) => {
+                    if self.durable_writes {
+                        self.sync_to_disk(output_provider).await?;
+                    }
+                    stats.wall_clock = start.elapsed();
+                    info!(
+                        file_hash = %hash,
+                        total_bytes = stats.total_bytes,
+                        num_terms = stats.num_terms(),
+                        wall_clock_ms = stats.wall_clock.as_millis() as u64,
+                        network_ms = stats.total_network_duration().as_millis() as u64,
+                        decompress_ms = stats.total_decompression_duration().as_millis() as u64,
+                        "download complete"
+                    );
+                    return Ok(stats);
+                },
+                // The file changed rather than the endpoint failing; the caller retries the download.
+                Err(e @ CasClientError::VersionChanged(_)) => return Err(e),
+                Err(e) if idx + 1 < endpoints.len() => {
+                    warn!(
+                        "Reconstructing {hash} from {endpoint} failed with {e:?}; retrying from {}",
+                        endpoints[idx + 1]
+                    );
+                },
+                Err(e) => return Err(e),
+            }
         }
+
+        unreachable!("the primary endpoint is always attempted")
+    }
+
+    /// The reconstruction queried for the size is cached, so a download of the file right after
+    /// doesn't query it again.
+    async fn get_file_size(&self, hash: &MerkleHash) -> Result<u64> {
+        let response = self.get_reconstruction(hash, None).await?;
+        reconstruction_length(&response.terms, None)
     }
 
     async fn batch_get_file(&self, files: HashMap<MerkleHash, &OutputProvider>) -> Result<u64> {
@@ -215,9 +692,14 @@ impl ReconstructionClient for RemoteClient {
             ret_size += if *RECONSTRUCT_WRITE_SEQUENTIALLY {
                 self.reconstruct_file_to_writer(terms, fetch_info.clone(), 0, None, w, None)
                     .await?
+                    .total_bytes
             } else {
                 self.reconstruct_file_to_writer_parallel(terms, fetch_info.clone(), 0, None, w, None)
                     .await?
+                    .total_bytes
+            };
+            if self.durable_writes {
+                self.sync_to_disk(w).await?;
             }
         }
 
@@ -232,98 +714,467 @@ impl Reconstructable for RemoteClient {
         file_id: &MerkleHash,
         bytes_range: Option<FileRange>,
     ) -> Result<QueryReconstructionResponse> {
-        let url = Url::parse(&format!("{}/reconstruction/{}", self.endpoint, file_id.hex()))?;
-
-        let mut request = self.authenticated_http_client.get(url);
-        if let Some(range) = bytes_range {
-            // convert exclusive-end to inclusive-end range
-            request = request.header(RANGE, format!("{}-{}", range.start, range.end - 1))
-        }
-        let response = request.send().await.process_error("get_reconstruction")?;
-
-        let len = response.content_length();
-        debug!("file_id: {file_id} query_reconstruction len {len:?}");
-
-        let query_reconstruction_response: QueryReconstructionResponse = response
-            .json()
-            .await
-            .log_error("error json parsing QueryReconstructionResponse")?;
-        Ok(query_reconstruction_response)
+        let (response, _) = self
+            .get_reconstruction_from_endpoint(&self.endpoint, file_id, bytes_range)
+            .await?;
+        Ok(response)
     }
 }
 
 impl Client for RemoteClient {}
 
 impl RemoteClient {
-    async fn batch_get_reconstruction(
+    /// Queries the reconstruction of the file from the given endpoint.
+    ///
+    /// Responses that come with an ETag are cached; a later query for the same file and range
+    /// within `RECONSTRUCTION_CACHE_SLICE_MAX_AGE_SECS` sends the ETag in `If-None-Match`, and a
+    /// 304 response reuses the cached reconstruction instead of downloading it again.  Responses
+    /// without an ETag, or past that age, are fetched in full.
+    ///
+    /// Full file responses are also cached without an ETag, and a ranged query within
+    /// `RECONSTRUCTION_CACHE_SLICE_MAX_AGE_SECS` of one is answered by slicing its terms, without
+    /// a request.
+    ///
+    /// Returns the version of the response along with it, if it came with an ETag.
+    async fn get_reconstruction_from_endpoint(
         &self,
-        file_ids: impl Iterator<Item = &MerkleHash>,
-    ) -> Result<BatchQueryReconstructionResponse> {
-        let mut url_str = format!("{}/reconstructions?", self.endpoint);
-        let mut is_first = true;
-        for hash in file_ids {
-            if is_first {
-                is_first = false;
-            } else {
-                url_str.push('&');
-            }
-            url_str.push_str("file_id=");
-            url_str.push_str(hash.hex().as_str());
+        endpoint: &str,
+        file_id: &MerkleHash,
+        bytes_range: Option<FileRange>,
+    ) -> Result<(QueryReconstructionResponse, Option<ReconstructionVersion>)> {
+        let mut url = Url::parse(&format!("{endpoint}/reconstruction/{}", file_id.hex()))?;
+        if self.coalesce_hint {
+            url.query_pairs_mut().append_pair("coalesce", "true");
         }
-        let url: Url = url_str.parse()?;
+        let key = Key {
+            prefix: PREFIX_DEFAULT.to_string(),
+            hash: *file_id,
+        };
 
-        let response = self
-            .authenticated_http_client
-            .get(url)
-            .send()
-            .await
-            .process_error("batch_get_reconstruction")?;
+        if let Some(range) = &bytes_range {
+            let full = self
+                .reconstruction_cache
+                .lock()?
+                .get(&(endpoint.to_owned(), *file_id, None))
+                .filter(|full| {
+                    full.fetched_at.elapsed() < Duration::from_secs(*RECONSTRUCTION_CACHE_SLICE_MAX_AGE_SECS)
+                })
+                .and_then(|full| {
+                    let version = full.etag.clone().map(|etag| ReconstructionVersion {
+                        etag,
+                        bytes_range: None,
+                    });
+                    Some((slice_reconstruction(&full.response, range)?, version))
+                });
+            if let Some(response) = full {
+                debug!("file_id: {file_id} query_reconstruction for range {range} served from the full reconstruction");
+                return Ok(response);
+            }
+        }
 
-        let query_reconstruction_response: BatchQueryReconstructionResponse = response
-            .json()
-            .await
-            .log_error("error json parsing BatchQueryReconstructionResponse")?;
-        Ok(query_reconstruction_response)
-    }
+        let cache_key = (endpoint.to_owned(), *file_id, bytes_range.clone());
+        // Past its max age, the cached reconstruction is fetched again rather than revalidated, as a
+        // 304 would keep its fetch urls, which may have expired.
+        let cached = self
+            .reconstruction_cache
+            .lock()?
+            .get(&cache_key)
+            .filter(|cached| {
+                cached.fetched_at.elapsed() < Duration::from_secs(*RECONSTRUCTION_CACHE_SLICE_MAX_AGE_SECS)
+            })
+            .cloned();
 
-    pub async fn upload(
-        &self,
-        key: &Key,
-        contents: Vec<u8>,
-        chunk_and_boundaries: Vec<(MerkleHash, u32)>,
-    ) -> Result<(bool, usize)> {
-        let url = Url::parse(&format!("{}/xorb/{key}", self.endpoint))?;
+        let mut request = self.authenticated_http_client.get(url.clone());
+        if let Some(range) = &bytes_range {
+            // convert exclusive-end to inclusive-end range
+            request = request.header(RANGE, format!("{}-{}", range.start, range.end - 1))
+        }
+        if let Some(etag) = cached.as_ref().and_then(|cached| cached.etag.as_ref()) {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request.send().await.process_error_for("get_reconstruction", &url, &key)?;
 
-        let mut writer = Cursor::new(Vec::new());
+        if response.status() == StatusCode::NOT_MODIFIED {
+            let Some(cached) = cached.filter(|cached| cached.etag.is_some()) else {
+                return Err(CasClientError::Other(
+                    "get_reconstruction returned 304 Not Modified to an unconditional request".to_owned(),
+                ));
+            };
+            debug!("file_id: {file_id} query_reconstruction not modified, using cached response");
+            let version = cached.etag.map(|etag| ReconstructionVersion { etag, bytes_range });
+            return Ok((cached.response, version));
+        }
 
-        let (_, nbytes_trans) =
-            CasObject::serialize(&mut writer, &key.hash, &contents, &chunk_and_boundaries, self.compression)?;
-        // free memory before the "slow" network transfer below
-        drop(contents);
+        let len = response.content_length();
+        debug!("file_id: {file_id} query_reconstruction len {len:?}");
+        check_content_length(&response, self.max_response_bytes, "get_reconstruction")?;
 
-        debug!("Upload: POST to {url:?} for {key:?}");
-        writer.set_position(0);
-        let data = writer.into_inner();
+        let etag = response.headers().get(ETAG).and_then(|v| v.to_str().ok()).map(str::to_owned);
 
-        if !self.dry_run {
-            let response = self
-                .authenticated_http_client
-                .post(url)
-                .body(data)
-                .send()
-                .await
-                .process_error("upload_xorb")?;
-            let response_parsed: UploadXorbResponse = response.json().await?;
+        let mut query_reconstruction_response = self.parse_reconstruction_body(response, "get_reconstruction").await?;
+        coalesce_fetch_info(&mut query_reconstruction_response.fetch_info);
 
-            Ok((response_parsed.was_inserted, nbytes_trans))
+        let mut reconstruction_cache = self.reconstruction_cache.lock()?;
+        if etag.is_some() || bytes_range.is_none() {
+            if reconstruction_cache.len() >= *RECONSTRUCTION_CACHE_MAX_ENTRIES {
+                reconstruction_cache.clear();
+            }
+            let cached = CachedReconstruction {
+                etag: etag.clone(),
+                response: query_reconstruction_response.clone(),
+                fetched_at: Instant::now(),
+            };
+            reconstruction_cache.insert(cache_key, cached);
         } else {
-            Ok((true, nbytes_trans))
+            reconstruction_cache.remove(&cache_key);
         }
+
+        let version = etag.map(|etag| ReconstructionVersion { etag, bytes_range });
+        Ok((query_reconstruction_response, version))
     }
 
-    /// use the reconstruction response from CAS to re-create the described file for any calls
-    /// to download files from S3/blob store using urls from the fetch information section of
-    /// the response it will use the provided http client.
+    /// Parses the reconstruction response from its body as it streams in; see
+    /// `parse_reconstruction_response`.  Fails with `ResponseTooLarge` if the body is larger than
+    /// `max_response_bytes`.
+    async fn parse_reconstruction_body(
+        &self,
+        response: reqwest::Response,
+        api: &str,
+    ) -> Result<QueryReconstructionResponse> {
+        // Parse the response as it streams in on a blocking thread, rather than buffering the
+        // whole body first; for large files, the body is many times the size of the parsed terms.
+        // The stream fails once the body passes the size limit, which is flagged to tell the
+        // failure apart from a malformed body.
+        let max_bytes = self.max_response_bytes;
+        let body_too_large = Arc::new(AtomicBool::new(false));
+        let too_large_flag = body_too_large.clone();
+        let mut received = 0u64;
+        let body_stream = response.bytes_stream().map(move |bytes| {
+            let bytes = bytes.map_err(std::io::Error::other)?;
+            received += bytes.len() as u64;
+            if received > max_bytes {
+                too_large_flag.store(true, Ordering::Relaxed);
+                return Err(std::io::Error::other("response body too large"));
+            }
+            Ok(bytes)
+        });
+        let reader = SyncIoBridge::new(StreamReader::new(body_stream));
+        let max_terms = self.max_reconstruction_terms;
+        self.threadpool
+            .spawn_blocking(move || parse_reconstruction_response(reader, max_terms))
+            .await
+            .map_err(|e| CasClientError::Other(format!("Error joining reconstruction parsing task {e:?}")))?
+            .map_err(|e| {
+                if body_too_large.load(Ordering::Relaxed) {
+                    response_too_large(api, max_bytes)
+                } else {
+                    e
+                }
+            })
+            .log_error("error json parsing QueryReconstructionResponse")
+    }
+
+    /// Checks that the reconstruction of the file from `endpoint` still has the given version, failing
+    /// with `CasClientError::VersionChanged` if it doesn't.  Only the response headers are read.
+    async fn validate_version(
+        &self,
+        endpoint: &str,
+        file_id: &MerkleHash,
+        version: &ReconstructionVersion,
+    ) -> Result<()> {
+        let ReconstructionVersion { etag, bytes_range } = version;
+        let url = Url::parse(&format!("{endpoint}/reconstruction/{}", file_id.hex()))?;
+        let key = Key {
+            prefix: PREFIX_DEFAULT.to_string(),
+            hash: *file_id,
+        };
+
+        let mut request = self.authenticated_http_client.get(url.clone()).header(IF_NONE_MATCH, etag);
+        if let Some(range) = bytes_range {
+            request = request.header(RANGE, format!("{}-{}", range.start, range.end - 1))
+        }
+        let response = request
+            .send()
+            .await
+            .process_error_for("validate_reconstruction_version", &url, &key)?;
+
+        // A server that ignores If-None-Match still answers with the current ETag.
+        let current = response.headers().get(ETAG).and_then(|v| v.to_str().ok());
+        if response.status() == StatusCode::NOT_MODIFIED || current == Some(etag.as_str()) {
+            return Ok(());
+        }
+
+        debug!("file_id: {file_id} changed from version {etag} to {current:?} during the download");
+        self.reconstruction_cache
+            .lock()?
+            .retain(|(cached_endpoint, cached_file_id, _), _| cached_endpoint != endpoint || cached_file_id != file_id);
+        Err(CasClientError::VersionChanged(*file_id))
+    }
+
+    /// Reconstructs the file using the reconstruction and fetch urls from the given endpoint.
+    ///
+    /// `expected_len` carries the length of the full file as described by a previously queried
+    /// endpoint, so that a mirror describing a file of a different length is rejected rather than
+    /// mixing data from the two into the output.
+    async fn get_file_from_endpoint(
+        &self,
+        endpoint: &str,
+        hash: &MerkleHash,
+        byte_range: Option<FileRange>,
+        output_provider: &OutputProvider,
+        progress_updater: Option<Arc<dyn ProgressUpdater>>,
+        expected_len: &mut Option<u64>,
+    ) -> Result<TransferStats> {
+        // get manifest of xorbs to download, api call to CAS
+        let (manifest, version) = self
+            .get_reconstruction_from_endpoint(endpoint, hash, byte_range.clone())
+            .await?;
+        let terms = manifest.terms;
+        let fetch_info = Arc::new(manifest.fetch_info);
+
+        if byte_range.is_none() {
+            let file_len = reconstruction_length(&terms, None)?;
+            match expected_len {
+                Some(len) if *len != file_len => {
+                    return Err(CasClientError::Other(format!(
+                        "reconstruction of {hash} from {endpoint} has length {file_len}, expected {len}"
+                    )));
+                },
+                _ => *expected_len = Some(file_len),
+            }
+        }
+
+        // If the user has set the `HF_XET_RECONSTRUCT_WRITE_SEQUENTIALLY=true` env variable, then we
+        // should write the file to the output sequentially instead of in parallel.
+        let stats = if *RECONSTRUCT_WRITE_SEQUENTIALLY {
+            self.reconstruct_file_to_writer(
+                terms,
+                fetch_info,
+                manifest.offset_into_first_range,
+                byte_range,
+                output_provider,
+                progress_updater,
+            )
+            .await?
+        } else {
+            self.reconstruct_file_to_writer_parallel(
+                terms,
+                fetch_info,
+                manifest.offset_into_first_range,
+                byte_range,
+                output_provider,
+                progress_updater,
+            )
+            .await?
+        };
+
+        if let Some(version) = version.filter(|_| self.validate_version) {
+            if let Err(e) = self.validate_version(endpoint, hash, &version).await {
+                if matches!(e, CasClientError::VersionChanged(_)) {
+                    output_provider.discard()?;
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(stats)
+    }
+
+    async fn batch_get_reconstruction(
+        &self,
+        file_ids: impl Iterator<Item = &MerkleHash>,
+    ) -> Result<BatchQueryReconstructionResponse> {
+        let mut url_str = format!("{}/reconstructions?", self.endpoint);
+        let mut is_first = true;
+        for hash in file_ids {
+            if is_first {
+                is_first = false;
+            } else {
+                url_str.push('&');
+            }
+            url_str.push_str("file_id=");
+            url_str.push_str(hash.hex().as_str());
+        }
+        let url: Url = url_str.parse()?;
+
+        let response = self
+            .authenticated_http_client
+            .get(url)
+            .send()
+            .await
+            .process_error("batch_get_reconstruction")?;
+
+        let body = read_body_limited(response, self.max_response_bytes, "batch_get_reconstruction").await?;
+        let query_reconstruction_response: BatchQueryReconstructionResponse =
+            serde_json::from_slice(&body).log_error("error json parsing BatchQueryReconstructionResponse")?;
+        Ok(query_reconstruction_response)
+    }
+
+    pub async fn upload(
+        &self,
+        key: &Key,
+        contents: Vec<u8>,
+        chunk_and_boundaries: Vec<(MerkleHash, u32)>,
+    ) -> Result<(bool, usize)> {
+        let url = Url::parse(&format!("{}/xorb/{key}", self.endpoint))?;
+
+        // Compression is CPU-bound, so serialize on a blocking thread rather than an async worker.
+        // The contents are dropped at the end of the closure, freeing memory before the "slow"
+        // network transfer below.
+        let hash = key.hash;
+        // Without a configured scheme, the xorbs uploaded by this client share the scheme their first
+        // xorbs agree on rather than each being trial compressed.
+        let compression = self.compression;
+        let scheme_learner = self.scheme_learner.clone();
+        let (data, nbytes_trans) = self
+            .threadpool
+            .spawn_blocking(move || -> Result<(Vec<u8>, usize)> {
+                let mut writer = Cursor::new(Vec::new());
+                let (_, nbytes_trans) = match compression {
+                    Some(_) => CasObject::serialize(&mut writer, &hash, &contents, &chunk_and_boundaries, compression)?,
+                    None => CasObject::serialize_with_learner(
+                        &mut writer,
+                        &hash,
+                        &contents,
+                        &chunk_and_boundaries,
+                        &scheme_learner,
+                    )?,
+                };
+                Ok((writer.into_inner(), nbytes_trans))
+            })
+            .await
+            .map_err(|e| CasClientError::Other(format!("Error joining xorb serialization task {e:?}")))??;
+
+        debug!("Upload: POST to {url:?} for {key:?}");
+
+        if !self.dry_run {
+            let response = self
+                .authenticated_http_client
+                .post(url.clone())
+                .header(IDEMPOTENCY_KEY_HEADER, idempotency_key("xorb", key))
+                .body(data)
+                .send()
+                .await;
+            let response = check_quota_exceeded(response, self.max_response_bytes, "upload_xorb")
+                .await?
+                .process_error_for("upload_xorb", &url, key)?;
+            let body = read_body_limited(response, self.max_response_bytes, "upload_xorb").await?;
+            let response_parsed: UploadXorbResponse = serde_json::from_slice(&body)?;
+
+            Ok((response_parsed.was_inserted, nbytes_trans))
+        } else {
+            Ok((true, nbytes_trans))
+        }
+    }
+
+    /// Fetches the xorb `prefix`/`hash` and returns its decompressed contents, e.g. a small blob
+    /// stored with `UploadClient::put`.  The xorb is validated against `hash` before its contents
+    /// are returned.
+    pub async fn get_xorb(&self, prefix: &str, hash: &MerkleHash) -> Result<Vec<u8>> {
+        let key = Key {
+            prefix: prefix.to_string(),
+            hash: *hash,
+        };
+        let url = Url::parse(&format!("{}/xorb/{key}", self.endpoint))?;
+
+        debug!("Get xorb: GET to {url:?} for {key:?}");
+
+        let response = self.authenticated_http_client.get(url.clone()).send().await;
+        if matches!(&response, Ok(response) if response.status() == StatusCode::NOT_FOUND) {
+            return Err(CasClientError::XORBNotFound(*hash));
+        }
+        let response = response.process_error_for("get_xorb", &url, &key)?;
+        let data = read_body_limited(response, self.max_response_bytes, "get_xorb").await?;
+
+        // Validating recomputes all the chunk hashes, so do it on a blocking thread.
+        let hash = *hash;
+        self.threadpool
+            .spawn_blocking(move || -> Result<Vec<u8>> {
+                let mut reader = Cursor::new(data);
+                let Some(cas) = CasObject::validate_cas_object(&mut reader, &hash)? else {
+                    return Err(CasClientError::InvalidXORB(hash));
+                };
+                Ok(cas.get_all_bytes(&mut reader)?)
+            })
+            .await
+            .map_err(|e| CasClientError::Other(format!("Error joining xorb validation task {e:?}")))?
+    }
+
+    /// Downloads the xorb ranges needed to reconstruct the given files into the chunk cache, without
+    /// writing the files anywhere, so that later downloads of them are served from the cache.  Terms
+    /// that are already cached are skipped, and a range shared by several terms or files is
+    /// downloaded once.
+    ///
+    /// The reconstructions of all the files are queried first, up to
+    /// `with_max_concurrent_reconstruction_queries` at once, then the ranges are downloaded, up to
+    /// `NUM_CONCURRENT_RANGE_GETS` at once.
+    ///
+    /// Returns the number of bytes of decompressed data added to the cache.  Fails if the client
+    /// has no chunk cache.
+    pub async fn prefetch_files(&self, file_hashes: &[MerkleHash]) -> Result<u64> {
+        let Some(chunk_cache) = self.chunk_cache.clone() else {
+            return Err(CasClientError::ConfigurationError("prefetching files requires a chunk cache".to_owned()));
+        };
+
+        let manifests = query_reconstructions(self, file_hashes, self.max_concurrent_reconstruction_queries).await?;
+
+        // The fetch ranges of the terms that aren't cached, by the key downloads are shared by.
+        let mut to_fetch = HashMap::new();
+        for manifest in &manifests {
+            for term in &manifest.terms {
+                let key = Key {
+                    prefix: PREFIX_DEFAULT.to_string(),
+                    hash: term.hash.into(),
+                };
+                if let Ok(Some(_)) = chunk_cache.get(&key, &term.range).log_error("cache error") {
+                    continue;
+                }
+                let fetch_term = fetch_term_for(&manifest.fetch_info, term)?;
+                to_fetch
+                    .entry(format!("{} {}", fetch_term.url, fetch_term.url_range))
+                    .or_insert_with(|| (term.hash, fetch_term.clone()));
+            }
+        }
+
+        let fetches = to_fetch.into_iter().map(|(download_key, (hash, fetch_term))| {
+            let http_client = self.http_client.clone();
+            let chunk_cache = chunk_cache.clone();
+            async move {
+                let (download_result, _) = self
+                    .range_download_single_flight
+                    .work(
+                        &download_key,
+                        download_range(http_client, fetch_term.clone(), hash, self.decompression_buffers.clone()),
+                    )
+                    .await;
+                let (data, chunk_byte_indices, _, _) = download_result?;
+                if let Some(chunk_hash_source) = &self.chunk_hash_source {
+                    verify_chunk_hashes(
+                        chunk_hash_source,
+                        &hash.into(),
+                        fetch_term.range.start,
+                        &data,
+                        &chunk_byte_indices,
+                    )
+                    .await?;
+                }
+                let key = Key {
+                    prefix: PREFIX_DEFAULT.to_string(),
+                    hash: hash.into(),
+                };
+                chunk_cache.put(&key, &fetch_term.range, &chunk_byte_indices, &data)?;
+                Ok::<_, CasClientError>(data.len() as u64)
+            }
+        });
+        futures::stream::iter(fetches)
+            .buffer_unordered(*NUM_CONCURRENT_RANGE_GETS)
+            .try_fold(0, |total, bytes| async move { Ok(total + bytes) })
+            .await
+    }
+
+    /// use the reconstruction response from CAS to re-create the described file for any calls
+    /// to download files from S3/blob store using urls from the fetch information section of
+    /// the response it will use the provided http client.
     ///
     /// To reconstruct, this function will iterate through each CASReconstructionTerm in the `terms` section
     /// of the QueryReconstructionResponse, fetching the data for that term. Each term is a range of chunks
@@ -332,6 +1183,12 @@ impl RemoteClient {
     ///
     /// To fetch the data for each term, this function will consult the fetch_info section of the reconstruction
     /// response. See `get_one_term`.
+    ///
+    /// Terms are fetched by a producer task and handed to the writer in order through a channel of
+    /// `write_buffer_terms` terms; when the writer falls behind, the producer waits for room in the
+    /// channel instead of fetching further ahead, which caps the fetched data held in memory.
+    ///
+    /// Returns the transfer stats of the reconstruction, with per-term stats in term order.
     #[allow(clippy::too_many_arguments)]
     pub async fn reconstruct_file_to_writer(
         &self,
@@ -341,46 +1198,71 @@ impl RemoteClient {
         byte_range: Option<FileRange>,
         writer: &OutputProvider,
         progress_updater: Option<Arc<dyn ProgressUpdater>>,
-    ) -> Result<u64> {
-        let total_len = if let Some(range) = byte_range {
-            range.end - range.start
-        } else {
-            terms.iter().fold(0, |acc, x| acc + x.unpacked_length as u64)
-        };
+    ) -> Result<TransferStats> {
+        let start_time = Instant::now();
+        let total_len = reconstruction_length(&terms, byte_range.as_ref())?;
+        let output_ranges = term_output_ranges(&terms, offset_into_first_range, total_len)?;
         let mut writer = writer.get_writer_at(0)?;
 
-        let futs_iter = terms.into_iter().map(|term| {
+        let http_client = self.http_client.clone();
+        let chunk_cache = self.chunk_cache.clone();
+        let range_download_single_flight = self.range_download_single_flight.clone();
+        let chunk_hash_source = self.chunk_hash_source.clone();
+        let decompression_buffers = self.decompression_buffers.clone();
+        let futs_iter = terms.into_iter().map(move |term| {
             get_one_term(
-                self.http_client.clone(),
-                self.chunk_cache.clone(),
+                http_client.clone(),
+                chunk_cache.clone(),
                 term,
                 fetch_info.clone(),
-                self.range_download_single_flight.clone(),
+                range_download_single_flight.clone(),
+                chunk_hash_source.clone(),
+                decompression_buffers.clone(),
             )
         });
-        let mut futs_buffered_enumerated = futures::stream::iter(futs_iter)
-            .buffered(*NUM_CONCURRENT_RANGE_GETS)
-            .enumerate();
 
-        let mut remaining_len = total_len;
-        while let Some((term_idx, term_data_result)) = futs_buffered_enumerated.next().await {
-            let term_data = term_data_result.log_error(format!("error fetching 1 term at index {term_idx}"))?;
-            let start = if term_idx == 0 {
-                // use offset_into_first_range for the first term if needed
-                max(0, offset_into_first_range as usize)
-            } else {
-                0
+        // The producer stops at the first failed term, or once the writer stops receiving.
+        let (term_sender, mut term_receiver) = mpsc::channel(self.write_buffer_terms);
+        let producer = self.threadpool.spawn(async move {
+            let mut futs_buffered = futures::stream::iter(futs_iter).buffered(*NUM_CONCURRENT_RANGE_GETS);
+            while let Some(term_data_result) = futs_buffered.next().await {
+                let failed = term_data_result.is_err();
+                if term_sender.send(term_data_result).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        let _abort_producer = AbortOnDrop(vec![producer.abort_handle()]);
+
+        let mut term_stats = Vec::new();
+        let mut max_buffered_terms = 0;
+        loop {
+            max_buffered_terms = max_buffered_terms.max(term_receiver.len());
+            let Some(term_data_result) = term_receiver.recv().await else {
+                break;
             };
-            let end: usize = min(remaining_len + start as u64, term_data.len() as u64) as usize;
-            writer.write_all(&term_data[start..end])?;
-            let len_written = (end - start) as u64;
-            remaining_len -= len_written;
+            let term_idx = term_stats.len();
+            let (term_data, mut stats) =
+                term_data_result.log_error(format!("error fetching 1 term at index {term_idx}"))?;
+            let term_range = output_ranges[term_idx].term_range.clone();
+            let len_written = term_range.len() as u64;
+            writer.write_all(&term_data[term_range])?;
             progress_updater.as_ref().inspect(|updater| updater.update(len_written));
+            stats.bytes = len_written;
+            term_stats.push(stats);
         }
+        producer
+            .await
+            .map_err(|e| CasClientError::Other(format!("Error joining term fetch task {e:?}")))?;
 
         writer.flush()?;
 
-        Ok(total_len)
+        Ok(TransferStats {
+            terms: term_stats,
+            total_bytes: total_len,
+            wall_clock: start_time.elapsed(),
+            max_buffered_terms,
+        })
     }
 
     /// Uses the reconstruction response and optionally requested FileRange to re-create a file,
@@ -388,6 +1270,8 @@ impl RemoteClient {
     ///
     /// Unlike `reconstruct_file_to_writer`, this function will spawn a task for writing each
     /// term to the output, with each task writing to its part of the file.
+    ///
+    /// Returns the transfer stats of the reconstruction, with per-term stats in term order.
     pub async fn reconstruct_file_to_writer_parallel(
         &self,
         terms: Vec<CASReconstructionTerm>,
@@ -396,57 +1280,192 @@ impl RemoteClient {
         byte_range: Option<FileRange>,
         output_provider: &OutputProvider,
         progress_updater: Option<Arc<dyn ProgressUpdater>>,
-    ) -> Result<u64> {
-        let total_len = if let Some(range) = byte_range {
-            range.end - range.start
-        } else {
-            terms.iter().fold(0, |acc, x| acc + x.unpacked_length as u64)
-        };
+    ) -> Result<TransferStats> {
+        let start_time = Instant::now();
+        let total_len = reconstruction_length(&terms, byte_range.as_ref())?;
+        let output_ranges = term_output_ranges(&terms, offset_into_first_range, total_len)?;
         let task_info = TermWriteTask {
             http_client: self.http_client.clone(),
             chunk_cache: self.chunk_cache.clone(),
             range_download_single_flight: self.range_download_single_flight.clone(),
             fetch_info,
             semaphore: Arc::new(Semaphore::new(*NUM_CONCURRENT_RANGE_GETS)),
+            output_handles: self.output_handles.clone(),
             output: output_provider.clone(),
+            chunk_hash_source: self.chunk_hash_source.clone(),
+            decompression_buffers: self.decompression_buffers.clone(),
         };
-        // Build term tasks, computing the offsets needed for the downloaded term and
-        // offset for the output.
-        let mut bytes_written = 0;
-        let mut remaining = total_len;
-        let term_tasks = terms.into_iter().enumerate().map(|(idx, term)| {
-            let start = if idx == 0 { offset_into_first_range as usize } else { 0 };
-            let end = min(start as u64 + remaining, term.unpacked_length as u64) as usize;
-            let file_offset = bytes_written;
-            let len = (end - start) as u64;
-
-            bytes_written += len;
-            remaining -= len;
-
+        // Build term tasks with the part of the downloaded term to write and its offset in the output.
+        let term_tasks = terms.into_iter().zip(output_ranges).enumerate().map(|(idx, (term, output))| {
             let task = task_info.clone();
-            task.write_term(term, start..end, file_offset)
+            let fut = task.write_term(term, output.term_range, output.output_offset);
+            async move { fut.await.map(|stats| (idx, stats)) }
         });
 
-        // Spawn the tasks
+        // Spawn the tasks, aborting them if the reconstruction is dropped, e.g. when it's canceled.
         let mut handles = FuturesUnordered::new();
         term_tasks.for_each(|task| {
             let handle = self.threadpool.spawn(task);
             handles.push(handle);
         });
+        let _abort_tasks = AbortOnDrop(handles.iter().map(JoinHandle::abort_handle).collect());
 
         // Join the tasks as they come in.
         let mut total_written = 0;
+        let mut term_stats = vec![TermTransferStats::default(); handles.len()];
         while let Some(result) = handles.next().await {
             match result {
-                Ok(Ok(len_written)) => {
-                    progress_updater.as_ref().inspect(|updater| updater.update(len_written));
-                    total_written += len_written;
+                Ok(Ok((idx, stats))) => {
+                    progress_updater.as_ref().inspect(|updater| updater.update(stats.bytes));
+                    total_written += stats.bytes;
+                    term_stats[idx] = stats;
                 },
                 Ok(Err(e)) => Err(e)?,
                 Err(e) => Err(CasClientError::Other(format!("Error joining download task {e:?}")))?,
             }
         }
-        Ok(total_written)
+        Ok(TransferStats {
+            terms: term_stats,
+            total_bytes: total_written,
+            wall_clock: start_time.elapsed(),
+            max_buffered_terms: 0,
+        })
+    }
+}
+
+/// Parses a reconstruction response incrementally from the reader, holding only a small read buffer
+/// of the serialized response in memory at a time.  Fails with `ResponseTooLarge` as soon as more
+/// than `max_terms` terms have been read.
+fn parse_reconstruction_response(reader: impl Read, max_terms: usize) -> Result<QueryReconstructionResponse> {
+    let too_large = Cell::new(false);
+    let seed = ReconstructionResponseSeed {
+        max_terms,
+        too_large: &too_large,
+    };
+
+    let mut deserializer = serde_json::Deserializer::from_reader(BufReader::new(reader));
+    let parsed = seed.deserialize(&mut deserializer).and_then(|response| {
+        deserializer.end()?;
+        Ok(response)
+    });
+
+    match parsed {
+        Err(_) if too_large.get() => {
+            Err(CasClientError::ResponseTooLarge(format!("reconstruction response has more than {max_terms} terms")))
+        },
+        parsed => Ok(parsed?),
+    }
+}
+
+/// Deserializes a QueryReconstructionResponse, failing once it has more than `max_terms` terms
+/// instead of collecting them all first.  Sets `too_large` when that happens, to tell the failure
+/// apart from malformed json.
+#[derive(Clone, Copy)]
+struct ReconstructionResponseSeed<'a> {
+    max_terms: usize,
+    too_large: &'a Cell<bool>,
+}
+
+impl<'de> DeserializeSeed<'de> for ReconstructionResponseSeed<'_> {
+    type Value = QueryReconstructionResponse;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> std::result::Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ReconstructionResponseSeed<'_> {
+    type Value = QueryReconstructionResponse;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a reconstruction response")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<Self::Value, A::Error> {
+        let mut offset_into_first_range = None;
+        let mut terms = None;
+        let mut fetch_info = None;
+
+        while let Some(field) = map.next_key::<String>()? {
+            match field.as_str() {
+                "offset_into_first_range" => offset_into_first_range = Some(map.next_value()?),
+                "terms" => terms = Some(map.next_value_seed(ReconstructionTermsSeed(self))?),
+                "fetch_info" => fetch_info = Some(map.next_value()?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                },
+            }
+        }
+
+        Ok(QueryReconstructionResponse {
+            offset_into_first_range: offset_into_first_range
+                .ok_or_else(|| de::Error::missing_field("offset_into_first_range"))?,
+            terms: terms.ok_or_else(|| de::Error::missing_field("terms"))?,
+            fetch_info: fetch_info.ok_or_else(|| de::Error::missing_field("fetch_info"))?,
+        })
+    }
+}
+
+struct ReconstructionTermsSeed<'a>(ReconstructionResponseSeed<'a>);
+
+impl<'de> DeserializeSeed<'de> for ReconstructionTermsSeed<'_> {
+    type Value = Vec<CASReconstructionTerm>;
+
+    fn deserialize<D: de::Deserializer<'de>>(self, deserializer: D) -> std::result::Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ReconstructionTermsSeed<'_> {
+    type Value = Vec<CASReconstructionTerm>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of reconstruction terms")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<Self::Value, A::Error> {
+        let max_terms = self.0.max_terms;
+        let mut terms = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(max_terms));
+
+        while let Some(term) = seq.next_element()? {
+            if terms.len() == max_terms {
+                self.0.too_large.set(true);
+                return Err(de::Error::custom(format!("more than {max_terms} reconstruction terms")));
+            }
+            terms.push(term);
+        }
+
+        Ok(terms)
+    }
+}
+
+/// Forwards the progress of one attempt at a download, counting only the bytes past the most
+/// reported by any attempt, so that the progress of a download retried from another endpoint
+/// doesn't add up to more than its size.
+#[derive(Debug)]
+struct AttemptProgressUpdater {
+    inner: Arc<dyn ProgressUpdater>,
+    attempt_bytes: AtomicU64,
+    reported_bytes: Arc<AtomicU64>,
+}
+
+impl ProgressUpdater for AttemptProgressUpdater {
+    fn update(&self, increment: u64) {
+        let attempt_bytes = self.attempt_bytes.fetch_add(increment, Ordering::Relaxed) + increment;
+        let reported_bytes = self.reported_bytes.fetch_max(attempt_bytes, Ordering::Relaxed);
+        if attempt_bytes > reported_bytes {
+            self.inner.update(attempt_bytes - reported_bytes);
+        }
+    }
+}
+
+/// Aborts the given tasks when dropped, so that the tasks spawned by a reconstruction stop when the
+/// reconstruction is dropped before they complete.
+struct AbortOnDrop(Vec<AbortHandle>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.iter().for_each(AbortHandle::abort);
     }
 }
 
@@ -460,12 +1479,21 @@ struct TermWriteTask {
     range_download_single_flight: RangeDownloadSingleFlight,
     fetch_info: Arc<HashMap<HexMerkleHash, Vec<CASReconstructionFetchInfo>>>,
     semaphore: Arc<Semaphore>,
+    /// Limits the number of output writers open at once.
+    output_handles: Arc<Semaphore>,
     output: OutputProvider,
+    chunk_hash_source: Option<ChunkHashSource>,
+    decompression_buffers: Option<Arc<DecompressionBufferPool>>,
 }
 
 impl TermWriteTask {
     /// Download the term and write it to the underlying storage.
-    async fn write_term(self, term: CASReconstructionTerm, term_range: Range<usize>, file_offset: u64) -> Result<u64> {
+    async fn write_term(
+        self,
+        term: CASReconstructionTerm,
+        term_range: Range<usize>,
+        file_offset: u64,
+    ) -> Result<TermTransferStats> {
         // acquire permit from the semaphore limiting the download parallelism.
         let _permit = self
             .semaphore
@@ -475,10 +1503,17 @@ impl TermWriteTask {
             .map_err(|_| CasClientError::Other("couldn't acquire semaphore".to_string()))?;
 
         // download the term
-        let term_data =
-            get_one_term(self.http_client, self.chunk_cache, term, self.fetch_info, self.range_download_single_flight)
-                .await
-                .log_error("error fetching 1 term")?;
+        let (term_data, mut stats) = get_one_term(
+            self.http_client,
+            self.chunk_cache,
+            term,
+            self.fetch_info,
+            self.range_download_single_flight,
+            self.chunk_hash_source,
+            self.decompression_buffers,
+        )
+        .await
+        .log_error("error fetching 1 term")?;
 
         if term_range.end > term_data.len() {
             error!(
@@ -490,11 +1525,19 @@ impl TermWriteTask {
         }
         let len = (term_range.end - term_range.start) as u64;
 
-        // write the term
-        let mut writer = self.output.get_writer_at(file_offset)?;
-        writer.write_all(&term_data[term_range])?;
-        writer.flush()?;
-        Ok(len)
+        // write the term, holding an output handle permit for as long as the writer is open.
+        {
+            let _handle_permit = self
+                .output_handles
+                .acquire()
+                .await
+                .map_err(|_| CasClientError::Other("couldn't acquire output handle semaphore".to_string()))?;
+            let mut writer = self.output.get_writer_at(file_offset)?;
+            writer.write_all(&term_data[term_range])?;
+            writer.flush()?;
+        }
+        stats.bytes = len;
+        Ok(stats)
     }
 }
 
@@ -509,14 +1552,23 @@ impl TermWriteTask {
 ///
 /// If the fetch_info section (provided as in the QueryReconstructionResponse) fails to contain a term
 /// that matches our requested CASReconstructionTerm, it is considered a bad output from the CAS API.
+///
+/// If a chunk hash source is given, the downloaded chunks are verified against it before they are
+/// cached or used (see `verify_chunk_hashes`).
+///
+/// Along with the term data, returns the timing stats for the fetch; the `bytes` field is
+/// left for the caller to fill in with the number of bytes used from the term.
 pub(crate) async fn get_one_term(
     http_client: Arc<ClientWithMiddleware>,
     chunk_cache: Option<Arc<dyn ChunkCache>>,
     term: CASReconstructionTerm,
     fetch_info: Arc<HashMap<HexMerkleHash, Vec<CASReconstructionFetchInfo>>>,
     range_download_single_flight: RangeDownloadSingleFlight,
-) -> Result<Vec<u8>> {
+    chunk_hash_source: Option<ChunkHashSource>,
+    decompression_buffers: Option<Arc<DecompressionBufferPool>>,
+) -> Result<(Vec<u8>, TermTransferStats)> {
     debug!("term: {term:?}");
+    let start_time = Instant::now();
 
     if term.range.end < term.range.start {
         return Err(CasClientError::InvalidRange);
@@ -529,29 +1581,38 @@ pub(crate) async fn get_one_term(
             hash: term.hash.into(),
         };
         if let Ok(Some(cached)) = cache.get(&key, &term.range).log_error("cache error") {
-            return Ok(cached);
+            let stats = TermTransferStats {
+                fetch_duration: start_time.elapsed(),
+                cache_hit: true,
+                ..Default::default()
+            };
+            return Ok((cached, stats));
         }
     }
 
-    // get the fetch info term for the key
-    // then find the term within the ranges that will match our requested range
-    // if either operation fails, this is a result of a bad response from the reconstruction api.
-    let hash_fetch_info = fetch_info
-        .get(&term.hash)
-        .ok_or(CasClientError::InvalidArguments)
-        .log_error("invalid response from CAS server: failed to get term hash in fetch info")?;
-    let fetch_term = hash_fetch_info
-        .iter()
-        .find(|fterm| fterm.range.start <= term.range.start && fterm.range.end >= term.range.end)
-        .ok_or(CasClientError::InvalidArguments)
-        .log_error("invalid response from CAS server: failed to match hash in fetch_info")?
-        .clone();
+    let fetch_term = fetch_term_for(&fetch_info, &term)?.clone();
 
     // fetch the range from blob store and deserialize the chunks
     // then put into the cache if used
-    let (mut data, chunk_byte_indices) = range_download_single_flight
-        .work_dump_caller_info(&fetch_term.url, download_range(http_client, fetch_term.clone(), term.hash))
-        .await?;
+    // A url may serve several ranges of the xorb, so downloads are shared by url and range.
+    let (download_result, is_owner) = range_download_single_flight
+        .work(
+            &format!("{} {}", fetch_term.url, fetch_term.url_range),
+            download_range(http_client, fetch_term.clone(), term.hash, decompression_buffers),
+        )
+        .await;
+    let (mut data, chunk_byte_indices, network_duration, decompression_duration) = download_result?;
+    // only the caller that performed the download spent time receiving and decompressing it
+    let (network_duration, decompression_duration) = if is_owner {
+        (network_duration, decompression_duration)
+    } else {
+        (Duration::ZERO, Duration::ZERO)
+    };
+
+    if let Some(chunk_hash_source) = &chunk_hash_source {
+        verify_chunk_hashes(chunk_hash_source, &term.hash.into(), fetch_term.range.start, &data, &chunk_byte_indices)
+            .await?;
+    }
 
     // now write it to cache, the whole fetched term
     if let Some(cache) = chunk_cache {
@@ -587,7 +1648,96 @@ pub(crate) async fn get_one_term(
         )));
     }
 
-    Ok(data)
+    let stats = TermTransferStats {
+        fetch_duration: start_time.elapsed(),
+        network_duration,
+        decompression_duration,
+        ..Default::default()
+    };
+    Ok((data, stats))
+}
+
+/// Queries the full reconstructions of `file_hashes`, with up to `max_concurrent` queries in flight at
+/// once, returning them in the order of the hashes.  Fails on the first query that fails.
+async fn query_reconstructions<R: Reconstructable + Sync + ?Sized>(
+    reconstructable: &R,
+    file_hashes: &[MerkleHash],
+    max_concurrent: usize,
+) -> Result<Vec<QueryReconstructionResponse>> {
+    futures::stream::iter(
+        file_hashes
+            .iter()
+            .map(|file_hash| reconstructable.get_reconstruction(file_hash, None)),
+    )
+    .buffered(max_concurrent)
+    .try_collect()
+    .await
+}
+
+/// Finds the fetch info whose range holds the chunks of `term`.  Fails if there is none, which is
+/// the result of a bad response from the reconstruction api.
+fn fetch_term_for<'a>(
+    fetch_info: &'a HashMap<HexMerkleHash, Vec<CASReconstructionFetchInfo>>,
+    term: &CASReconstructionTerm,
+) -> Result<&'a CASReconstructionFetchInfo> {
+    fetch_info
+        .get(&term.hash)
+        .ok_or(CasClientError::InvalidArguments)
+        .log_error("invalid response from CAS server: failed to get term hash in fetch info")?
+        .iter()
+        .find(|fterm| fterm.range.start <= term.range.start && fterm.range.end >= term.range.end)
+        .ok_or(CasClientError::InvalidArguments)
+        .log_error("invalid response from CAS server: failed to match hash in fetch_info")
+}
+
+/// Checks the chunks of `data`, delimited by `chunk_byte_indices` and starting at chunk
+/// `first_chunk_index` of the xorb `xorb_hash`, against the chunk hashes `chunk_hash_source` recorded
+/// for the xorb.  Nothing is checked if no hashes are recorded for the xorb.
+async fn verify_chunk_hashes(
+    chunk_hash_source: &ChunkHashSource,
+    xorb_hash: &MerkleHash,
+    first_chunk_index: u32,
+    data: &[u8],
+    chunk_byte_indices: &[u32],
+) -> Result<()> {
+    let Some(recorded) = chunk_hash_source.get_xorb_chunk_hashes(xorb_hash).await? else {
+        debug!("No chunk hashes recorded for xorb {xorb_hash}, not verifying its chunks");
+        return Ok(());
+    };
+
+    for (i, bounds) in chunk_byte_indices.windows(2).enumerate() {
+        let index = first_chunk_index + i as u32;
+        let chunk = &data[bounds[0] as usize..bounds[1] as usize];
+        if recorded.get(index as usize) != Some(&compute_data_hash(chunk)) {
+            return Err(CasClientError::ChunkHashMismatch {
+                hash: *xorb_hash,
+                index,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Merges the fetch infos of each xorb that fetch adjacent ranges from the same url, so that the
+/// consecutive terms of a xorb are downloaded with one request rather than one each.
+fn coalesce_fetch_info(fetch_info: &mut HashMap<HexMerkleHash, Vec<CASReconstructionFetchInfo>>) {
+    for xorb_fetch_info in fetch_info.values_mut() {
+        xorb_fetch_info.sort_by_key(|info| (info.range.start, info.range.end));
+        let mut coalesced: Vec<CASReconstructionFetchInfo> = Vec::with_capacity(xorb_fetch_info.len());
+        for info in xorb_fetch_info.drain(..) {
+            if let Some(last) = coalesced.last_mut().filter(|last| last.url == info.url) {
+                if let (Some(range), Some(url_range)) =
+                    (last.range.coalesce(&info.range), last.url_range.coalesce_inclusive(&info.url_range))
+                {
+                    last.range = range;
+                    last.url_range = url_range;
+                    continue;
+                }
+            }
+            coalesced.push(info);
+        }
+        *xorb_fetch_info = coalesced;
+    }
 }
 
 fn range_header(range: &HttpRange) -> String {
@@ -597,38 +1747,241 @@ fn range_header(range: &HttpRange) -> String {
 /// use the provided http_client to make requests to S3/blob store using the url and url_range
 /// parts of a CASReconstructionFetchInfo. The url_range part is used directly in a http Range header
 /// value (see fn `range_header`).
-async fn download_range(
+///
+/// If the response body is cut off, the rest of the range is requested from the last byte received,
+/// up to MAX_RANGE_DOWNLOAD_RESUMES times, instead of downloading the whole range again.
+///
+/// The chunks are deserialized as the body streams in, so that only the chunk being received is
+/// held compressed, and decompressed with scratch buffers from `decompression_buffers` if given.
+///
+/// Returns the deserialized data, the chunk byte indices, the time spent receiving the response
+/// (apart from deserializing it) and the time spent deserializing it.
+async fn download_range(
     http_client: Arc<ClientWithMiddleware>,
     fetch_term: CASReconstructionFetchInfo,
     hash: HexMerkleHash,
-) -> Result<(Vec<u8>, Vec<u32>)> {
+    decompression_buffers: Option<Arc<DecompressionBufferPool>>,
+) -> Result<DownloadedRange> {
     trace!("{hash},{},{}", fetch_term.range.start, fetch_term.range.end);
+    let start = Instant::now();
 
     let url = Url::parse(fetch_term.url.as_str())?;
-    let response = http_client
-        .get(url)
-        .header(RANGE, range_header(&fetch_term.url_range))
-        .send()
-        .await
-        .log_error("error getting from s3")?
-        .error_for_status()
-        .log_error("get from s3 error code")?;
-
-    if let Some(content_length) = response.content_length() {
-        // + 1 since range S3/HTTP range is inclusive on both ends
-        // remove this check to be agnostic to range-end-exclusive blob store requests
-        let expected_len = fetch_term.url_range.end - fetch_term.url_range.start + 1;
-        if content_length != expected_len as u64 {
-            error!("got back a smaller byte range ({content_length}) than requested ({expected_len}) from s3");
-            return Err(CasClientError::InvalidRange);
+    let key = Key {
+        prefix: PREFIX_DEFAULT.to_string(),
+        hash: hash.into(),
+    };
+    // + 1 since range S3/HTTP range is inclusive on both ends
+    let expected_len = (fetch_term.url_range.end - fetch_term.url_range.start + 1) as usize;
+
+    let mut deserializer = StreamingChunkDeserializer::new(decompression_buffers.as_deref());
+    let mut resumes = 0;
+    loop {
+        let received_len = deserializer.received_len;
+        let remaining_range = HttpRange {
+            start: fetch_term.url_range.start + received_len as u32,
+            end: fetch_term.url_range.end,
+        };
+        let response = http_client
+            .get(url.clone())
+            .header(RANGE, range_header(&remaining_range))
+            .send()
+            .await
+            .process_error_for("get_xorb_range", &url, &key)?;
+
+        if let Some(content_length) = response.content_length() {
+            // remove this check to be agnostic to range-end-exclusive blob store requests
+            let remaining_len = expected_len - received_len;
+            if content_length != remaining_len as u64 {
+                error!("got back a smaller byte range ({content_length}) than requested ({remaining_len}) from s3");
+                return Err(CasClientError::InvalidRange);
+            }
+        }
+
+        let received = receive_chunks(response, &mut deserializer, expected_len).await;
+        if deserializer.received_len >= expected_len {
+            break;
+        }
+        // A malformed chunk won't parse any better the next time.
+        if let Err(e @ CasClientError::CasObjectError(_)) = received {
+            return Err(e);
         }
+        if resumes == *MAX_RANGE_DOWNLOAD_RESUMES {
+            received.log_error("error receiving body from s3")?;
+            return Err(CasClientError::Other(format!(
+                "received {} of the {expected_len} bytes of {url}",
+                deserializer.received_len
+            )));
+        }
+        resumes += 1;
+        warn!(
+            "Download of {key} cut off after {} of {expected_len} bytes ({received:?}); resuming",
+            deserializer.received_len
+        );
     }
 
-    let (data, chunk_byte_indices) = cas_object::deserialize_async::deserialize_chunks_from_stream(
-        response.bytes_stream().map_err(std::io::Error::other),
-    )
-    .await?;
-    Ok((data, chunk_byte_indices))
+    let decompression_duration = deserializer.decompression_duration;
+    let (data, chunk_byte_indices) = deserializer.finish();
+    Ok((
+        data,
+        chunk_byte_indices,
+        start.elapsed().saturating_sub(decompression_duration),
+        decompression_duration,
+    ))
+}
+
+/// Feeds the body of `response` to `deserializer` as it arrives, so that the bytes received before
+/// an error are kept.  Fails with `ResponseTooLarge` if more than `max_len` bytes are received in all.
+async fn receive_chunks(
+    response: reqwest::Response,
+    deserializer: &mut StreamingChunkDeserializer<'_>,
+    max_len: usize,
+) -> Result<()> {
+    let mut stream = response.bytes_stream();
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes?;
+        if deserializer.received_len + bytes.len() > max_len {
+            return Err(CasClientError::ResponseTooLarge(format!(
+                "xorb range response is larger than the {max_len} bytes requested"
+            )));
+        }
+        deserializer.push(&bytes)?;
+    }
+    Ok(())
+}
+
+/// Deserializes the chunks of a xorb range from its bytes as they arrive, holding back only the
+/// bytes of the chunk not fully received yet.
+struct StreamingChunkDeserializer<'a> {
+    pool: Option<&'a DecompressionBufferPool>,
+    /// The bytes received so far, and those of them not deserialized yet.
+    received_len: usize,
+    pending: Vec<u8>,
+    data: Vec<u8>,
+    chunk_byte_indices: Vec<u32>,
+    decompression_duration: Duration,
+}
+
+impl<'a> StreamingChunkDeserializer<'a> {
+    fn new(pool: Option<&'a DecompressionBufferPool>) -> Self {
+        Self {
+            pool,
+            received_len: 0,
+            pending: Vec::new(),
+            data: Vec::new(),
+            chunk_byte_indices: vec![0],
+            decompression_duration: Duration::ZERO,
+        }
+    }
+
+    /// Deserializes the chunks completed by `bytes`.
+    fn push(&mut self, bytes: &[u8]) -> Result<()> {
+        self.received_len += bytes.len();
+        self.pending.extend_from_slice(bytes);
+
+        let start = Instant::now();
+        let mut consumed = 0;
+        while let Some(header_bytes) = self.pending.get(consumed..consumed + CAS_CHUNK_HEADER_LENGTH) {
+            let header = cas_object::parse_chunk_header(header_bytes.try_into().unwrap())?;
+            let chunk_len = CAS_CHUNK_HEADER_LENGTH + header.get_compressed_length() as usize;
+            let Some(mut chunk) = self.pending.get(consumed..consumed + chunk_len) else {
+                break;
+            };
+            let (_, uncompressed_len) =
+                cas_object::deserialize_chunk_to_writer_with_pool(&mut chunk, &mut self.data, self.pool)?;
+            let chunk_end = self.chunk_byte_indices.last().copied().unwrap_or(0) + uncompressed_len;
+            self.chunk_byte_indices.push(chunk_end);
+            consumed += chunk_len;
+        }
+        self.pending.drain(..consumed);
+        self.decompression_duration += start.elapsed();
+        Ok(())
+    }
+
+    /// The data and chunk byte indices of the chunks received in full.
+    fn finish(self) -> (Vec<u8>, Vec<u32>) {
+        (self.data, self.chunk_byte_indices)
+    }
+}
+
+fn response_too_large(api: &str, max_bytes: u64) -> CasClientError {
+    CasClientError::ResponseTooLarge(format!("{api} response is larger than {max_bytes} bytes"))
+}
+
+/// Fails with `ResponseTooLarge` if the Content-Length of `response` is over `max_bytes`, before
+/// any of the body is read.
+fn check_content_length(response: &reqwest::Response, max_bytes: u64, api: &str) -> Result<()> {
+    match response.content_length() {
+        Some(len) if len > max_bytes => Err(response_too_large(api, max_bytes)),
+        _ => Ok(()),
+    }
+}
+
+/// Reads the body of `response`, failing with `ResponseTooLarge` as soon as it's known to be over
+/// `max_bytes`: from its Content-Length, or once more than that has been received.
+async fn read_body_limited(response: reqwest::Response, max_bytes: u64, api: &str) -> Result<Vec<u8>> {
+    check_content_length(&response, max_bytes, api)?;
+    let mut body = Vec::new();
+    let mut stream = response.bytes_stream();
+    while let Some(bytes) = stream.next().await {
+        let bytes = bytes?;
+        if (body.len() + bytes.len()) as u64 > max_bytes {
+            return Err(response_too_large(api, max_bytes));
+        }
+        body.extend_from_slice(&bytes);
+    }
+    Ok(body)
+}
+
+/// Fails with `QuotaExceeded` if the server rejected an upload for lack of storage or quota: with a
+/// 507, or with an error whose JSON body has an `error` or `code` mentioning the quota.  Other
+/// results are passed through, an error response being rebuilt after its body is read.
+async fn check_quota_exceeded(
+    result: reqwest_middleware::Result<reqwest::Response>,
+    max_bytes: u64,
+    api: &str,
+) -> Result<reqwest_middleware::Result<reqwest::Response>> {
+    let response = match result {
+        Ok(response) if response.status().is_client_error() || response.status().is_server_error() => response,
+        result => return Ok(result),
+    };
+
+    let status = response.status();
+    let request_id = request_id_from_response(&response).to_owned();
+    let mut builder = http::Response::builder()
+        .status(status)
+        .version(response.version())
+        .url(response.url().clone());
+    if let Some(headers) = builder.headers_mut() {
+        *headers = response.headers().clone();
+    }
+    let body = read_body_limited(response, max_bytes, api).await?;
+
+    let quota_error = serde_json::from_slice::<serde_json::Value>(&body).ok().filter(|error| {
+        ["error", "code"].iter().any(|field| {
+            error[field]
+                .as_str()
+                .is_some_and(|value| value.to_lowercase().contains("quota"))
+        })
+    });
+    if status == StatusCode::INSUFFICIENT_STORAGE || quota_error.is_some() {
+        let message = match quota_error.as_ref().and_then(|error| error["message"].as_str()) {
+            Some(message) => message.to_owned(),
+            None => String::from_utf8_lossy(&body).trim().to_owned(),
+        };
+        return Err(CasClientError::QuotaExceeded(format!(
+            "{api} failed with status {status}: {message:?}, request id: {request_id}"
+        )));
+    }
+
+    // The parts come from a valid response, so building can't fail.
+    Ok(Ok(reqwest::Response::from(builder.body(body).unwrap())))
+}
+
+/// The idempotency key of an upload of the object `key`.  It is derived from the content hash
+/// alone, so it is the same for every attempt (including the retries made by the retry
+/// middleware, which resends the request with its headers) but differs between objects.
+fn idempotency_key(kind: &str, key: &Key) -> String {
+    format!("{kind}/{key}")
 }
 
 #[async_trait]
@@ -659,14 +2012,18 @@ impl RegistrationClient for RemoteClient {
 
         let response = self
             .authenticated_http_client
-            .request(method, url)
+            .request(method, url.clone())
+            .header(IDEMPOTENCY_KEY_HEADER, idempotency_key("shard", &key))
             .body(shard_data.to_vec())
             .send()
-            .await
-            .process_error("upload_shard")?;
+            .await;
+        let response = check_quota_exceeded(response, self.max_response_bytes, "upload_shard")
+            .await?
+            .process_error_for("upload_shard", &url, &key)?;
 
+        let body = read_body_limited(response, self.max_response_bytes, "upload_shard").await?;
         let response_parsed: UploadShardResponse =
-            response.json().await.log_error("error json decoding upload_shard response")?;
+            serde_json::from_slice(&body).log_error("error json decoding upload_shard response")?;
 
         match response_parsed.result {
             UploadShardResponseType::Exists => Ok(false),
@@ -682,14 +2039,18 @@ impl FileReconstructor<CasClientError> for RemoteClient {
         file_hash: &MerkleHash,
     ) -> Result<Option<(MDBFileInfo, Option<MerkleHash>)>> {
         let url = Url::parse(&format!("{}/reconstruction/{}", self.endpoint, file_hash.hex()))?;
+        let key = Key {
+            prefix: PREFIX_DEFAULT.to_string(),
+            hash: *file_hash,
+        };
 
-        let response = self
-            .authenticated_http_client
-            .get(url)
-            .send()
-            .await
-            .process_error("get_reconstruction_info")?;
-        let response_info: QueryReconstructionResponse = response.json().await?;
+        let response = self.authenticated_http_client.get(url.clone()).send().await.process_error_for(
+            "get_reconstruction_info",
+            &url,
+            &key,
+        )?;
+        check_content_length(&response, self.max_response_bytes, "get_reconstruction_info")?;
+        let response_info = self.parse_reconstruction_body(response, "get_reconstruction_info").await?;
 
         Ok(Some((
             MDBFileInfo {
@@ -709,6 +2070,65 @@ impl FileReconstructor<CasClientError> for RemoteClient {
     }
 }
 
+impl RemoteClient {
+    /// Returns the file info of `file_hash` in the shard `shard_hash`, or None if the shard doesn't
+    /// have it.  Only the parts of the shard needed to find the file info are fetched, with range
+    /// requests, rather than the whole shard as for global dedup.
+    pub async fn get_file_info_from_shard(
+        &self,
+        prefix: &str,
+        shard_hash: &MerkleHash,
+        file_hash: &MerkleHash,
+    ) -> Result<Option<MDBFileInfo>> {
+        let key = Key {
+            prefix: prefix.to_owned(),
+            hash: *shard_hash,
+        };
+        let url = Url::parse(&format!("{}/shard/{key}", self.endpoint))?;
+        read_file_info_from_ranges(&RemoteShardRanges { client: self, url, key }, file_hash).await
+    }
+}
+
+/// Reads ranges of a shard stored in CAS with HTTP range requests.
+struct RemoteShardRanges<'a> {
+    client: &'a RemoteClient,
+    url: Url,
+    key: Key,
+}
+
+impl RemoteShardRanges<'_> {
+    async fn get(&self, range: String, expected_len: u64) -> Result<Vec<u8>> {
+        let response = self
+            .client
+            .authenticated_http_client
+            .get(self.url.clone())
+            .header(RANGE, range.clone())
+            .send()
+            .await
+            .process_error_for("get_shard_range", &self.url, &self.key)?;
+        let data = read_body_limited(response, self.client.max_response_bytes, "get_shard_range").await?;
+        if data.len() as u64 != expected_len {
+            return Err(CasClientError::Other(format!(
+                "expected {expected_len} bytes for range {range} of shard {}, got {}",
+                self.key,
+                data.len()
+            )));
+        }
+        Ok(data)
+    }
+}
+
+#[async_trait]
+impl ShardRangeReader<CasClientError> for RemoteShardRanges<'_> {
+    async fn read_range(&self, start: u64, end: u64) -> Result<Vec<u8>> {
+        self.get(format!("bytes={start}-{}", end - 1), end - start).await
+    }
+
+    async fn read_suffix(&self, len: u64) -> Result<Vec<u8>> {
+        self.get(format!("bytes=-{len}"), len).await
+    }
+}
+
 #[async_trait]
 impl ShardDedupProber for RemoteClient {
     async fn query_for_global_dedup_shard(
@@ -743,12 +2163,18 @@ impl ShardDedupProber for RemoteClient {
         if !response.status().is_success() {
             return Ok(None);
         }
+        check_content_length(&response, self.max_response_bytes, "query_dedup_shard")?;
 
         let writer = SafeFileCreator::new_unnamed()?;
         // Compute the actual hash to use as the shard file name
         let mut hashed_writer = HashedWrite::new(writer);
 
+        let mut received = 0;
         while let Some(chunk) = response.chunk().await? {
+            received += chunk.len() as u64;
+            if received > self.max_response_bytes {
+                return Err(response_too_large("query_dedup_shard", self.max_response_bytes));
+            }
             hashed_writer.write_all(&chunk)?;
         }
         hashed_writer.flush()?;
@@ -765,11 +2191,33 @@ impl ShardDedupProber for RemoteClient {
 
 impl ShardClientInterface for RemoteClient {}
 
+impl RemoteClient {
+    /// Summarizes the files and xorbs of the shard `shard_hash` from its copy in the shard cache,
+    /// e.g. to see what a shard fetched for global dedup holds.  Fails with
+    /// `CasClientError::ShardNotCached` if the shard isn't in the cache.
+    pub fn describe_shard(&self, shard_hash: &MerkleHash) -> Result<ShardSummary> {
+        let path = self.shard_cache_directory.join(shard_file_name(shard_hash));
+        let mut reader = match std::fs::File::open(&path) {
+            Ok(file) => BufReader::new(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(CasClientError::ShardNotCached(*shard_hash));
+            },
+            Err(e) => return Err(e.into()),
+        };
+        let shard = MDBShardInfo::load_from_reader(&mut reader)?;
+        Ok(shard.summary(&mut reader)?)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
     use cas_object::test_utils::{build_cas_object, ChunkSize};
     use cas_types::ChunkRange;
     use chunk_cache::MockChunkCache;
+    use httpmock::prelude::*;
+    use tokio_util::sync::CancellationToken;
     use tracing_test::traced_test;
 
     use super::*;
@@ -784,6 +2232,48 @@ mod tests {
     const TEST_NUM_CHUNKS: usize = 20;
     const TEST_UNPACKED_LEN: u32 = (TEST_CHUNK_SIZE * TEST_NUM_CHUNKS) as u32;
 
+    #[test]
+    fn test_normalize_endpoint() {
+        assert_eq!(normalize_endpoint("http://host/").unwrap(), "http://host");
+        assert_eq!(normalize_endpoint("http://host").unwrap(), "http://host");
+        assert_eq!(normalize_endpoint("https://host/path/").unwrap(), "https://host/path");
+        assert_eq!(normalize_endpoint("https://host/path").unwrap(), "https://host/path");
+        assert_eq!(normalize_endpoint(" https://host:8080// ").unwrap(), "https://host:8080");
+
+        for endpoint in [
+            "host",
+            "host:8080",
+            "",
+            "ftp://host",
+            "https://",
+            "https://host/?a=b",
+            "https://host/#x",
+        ] {
+            let err = normalize_endpoint(endpoint).unwrap_err();
+            assert!(matches!(err, CasClientError::ConfigurationError(_)), "{endpoint:?}: {err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_normalizes_endpoints() {
+        let new_client = |endpoint: &str| {
+            RemoteClient::new(ThreadPool::from_current_runtime(), endpoint, None, &None, &None, "".into(), false)
+        };
+
+        let client = new_client(" https://host// ")
+            .unwrap()
+            .with_fallback_endpoints(vec!["http://mirror/path/".to_owned()])
+            .unwrap();
+        assert_eq!(client.endpoint, "https://host");
+        assert_eq!(client.fallback_endpoints, ["http://mirror/path"]);
+
+        assert!(matches!(new_client("host"), Err(CasClientError::ConfigurationError(_))));
+        let result = new_client("https://host")
+            .unwrap()
+            .with_fallback_endpoints(vec!["http://mirror".to_owned(), "mirror:8080".to_owned()]);
+        assert!(matches!(result, Err(CasClientError::ConfigurationError(_))));
+    }
+
     #[ignore = "requires a running CAS server"]
     #[traced_test]
     #[test]
@@ -801,7 +2291,8 @@ mod tests {
             &None,
             "".into(),
             false,
-        );
+        )
+        .unwrap();
         // Act
         let result = threadpool
             .external_run_async_task(async move { client.put(prefix, &c.info.cashash, data, chunk_boundaries).await })
@@ -911,11 +2402,24 @@ mod tests {
                 conservative_authenticated_http_client: http_client.clone(),
                 http_client,
                 endpoint: "".to_string(),
+                fallback_endpoints: vec![],
                 compression: Some(CompressionScheme::LZ4),
+                scheme_learner: Default::default(),
                 dry_run: false,
                 threadpool: threadpool.clone(),
                 range_download_single_flight: Arc::new(Group::new()),
+                reconstruction_cache: Default::default(),
+                output_handles: Arc::new(Semaphore::new(*MAX_OPEN_OUTPUT_HANDLES)),
+                max_concurrent_reconstruction_queries: *NUM_CONCURRENT_RECONSTRUCTION_QUERIES,
+                max_reconstruction_terms: *MAX_RECONSTRUCTION_TERMS,
+                max_response_bytes: *MAX_RESPONSE_BYTES,
                 shard_cache_directory: "".into(),
+                chunk_hash_source: None,
+                decompression_buffers: None,
+                durable_writes: false,
+                write_buffer_terms: *RECONSTRUCT_WRITE_BUFFER_TERMS,
+                validate_version: false,
+                coalesce_hint: false,
             };
 
             let provider = BufferProvider::default();
@@ -937,7 +2441,7 @@ mod tests {
                 .unwrap();
             assert_eq!(test1.expect_error, resp.is_err());
             if !test1.expect_error {
-                assert_eq!(test1.expected_len, resp.unwrap());
+                assert_eq!(test1.expected_len, resp.unwrap().total_bytes);
                 assert_eq!(vec![1; test1.expected_len as usize], buf.value());
             }
 
@@ -958,11 +2462,24 @@ mod tests {
                 authenticated_http_client,
                 http_client,
                 endpoint: "".to_string(),
+                fallback_endpoints: vec![],
                 compression: Some(CompressionScheme::LZ4),
+                scheme_learner: Default::default(),
                 dry_run: false,
                 threadpool: threadpool.clone(),
                 range_download_single_flight: Arc::new(Group::new()),
+                reconstruction_cache: Default::default(),
+                output_handles: Arc::new(Semaphore::new(*MAX_OPEN_OUTPUT_HANDLES)),
+                max_concurrent_reconstruction_queries: *NUM_CONCURRENT_RECONSTRUCTION_QUERIES,
+                max_reconstruction_terms: *MAX_RECONSTRUCTION_TERMS,
+                max_response_bytes: *MAX_RESPONSE_BYTES,
                 shard_cache_directory: "".into(),
+                chunk_hash_source: None,
+                decompression_buffers: None,
+                durable_writes: false,
+                write_buffer_terms: *RECONSTRUCT_WRITE_BUFFER_TERMS,
+                validate_version: false,
+                coalesce_hint: false,
                 conservative_authenticated_http_client,
             };
             let provider = BufferProvider::default();
@@ -985,9 +2502,2039 @@ mod tests {
 
             assert_eq!(test.expect_error, resp.is_err());
             if !test.expect_error {
-                assert_eq!(test.expected_len, resp.unwrap());
+                assert_eq!(test.expected_len, resp.unwrap().total_bytes);
                 assert_eq!(vec![1; test.expected_len as usize], buf.value());
             }
         }
     }
+
+    /// Mocks the reconstruction of `file_hash` on the server as the whole xorb, split into 2 terms that
+    /// are each fetched from a different url on the same server.  The fetch urls respond with
+    /// `fetch_status`, serving the xorb data if it is 200.
+    fn mock_file_reconstruction(
+        server: &MockServer,
+        file_hash: &MerkleHash,
+        xorb: &CasObject,
+        xorb_bytes: &[u8],
+        fetch_status: u16,
+        num_terms: u32,
+    ) {
+        mock_file_reconstruction_with_delay(
+            server,
+            file_hash,
+            xorb,
+            xorb_bytes,
+            fetch_status,
+            num_terms,
+            Duration::ZERO,
+        );
+    }
+
+    /// Like `mock_file_reconstruction`, with the fetch urls responding after `fetch_delay`.  Returns
+    /// the mock of the reconstruction query.
+    fn mock_file_reconstruction_with_delay<'a>(
+        server: &'a MockServer,
+        file_hash: &MerkleHash,
+        xorb: &CasObject,
+        xorb_bytes: &[u8],
+        fetch_status: u16,
+        num_terms: u32,
+        fetch_delay: Duration,
+    ) -> httpmock::Mock<'a> {
+        let reconstruction = mock_xorb_fetches(server, xorb, xorb_bytes, fetch_status, num_terms, fetch_delay);
+        server.mock(|when, then| {
+            when.method(GET).path(format!("/reconstruction/{}", file_hash.hex()));
+            then.status(200).json_body_obj(&reconstruction);
+        })
+    }
+
+    /// Mocks the fetch urls of the terms of `mock_file_reconstruction_with_delay`, returning the
+    /// reconstruction that fetches from them.
+    fn mock_xorb_fetches(
+        server: &MockServer,
+        xorb: &CasObject,
+        xorb_bytes: &[u8],
+        fetch_status: u16,
+        num_terms: u32,
+        fetch_delay: Duration,
+    ) -> QueryReconstructionResponse {
+        let offsets = &xorb.info.chunk_boundary_offsets;
+        let unpacked_offsets = &xorb.info.unpacked_chunk_offsets;
+        let num_chunks = xorb.info.num_chunks;
+
+        let mut terms = vec![];
+        let mut xorb_fetch_info = vec![];
+        let term_chunk_ranges = (0..num_terms).map(|i| (i * num_chunks / num_terms, (i + 1) * num_chunks / num_terms));
+        for (i, (chunk_start, chunk_end)) in term_chunk_ranges.enumerate() {
+            let byte_start = if chunk_start == 0 {
+                0
+            } else {
+                offsets[chunk_start as usize - 1]
+            };
+            let byte_end = offsets[chunk_end as usize - 1];
+            let unpacked_start = if chunk_start == 0 {
+                0
+            } else {
+                unpacked_offsets[chunk_start as usize - 1]
+            };
+            let unpacked_end = unpacked_offsets[chunk_end as usize - 1];
+            let path = format!("/xorb_data/{i}");
+
+            let body = xorb_bytes[byte_start as usize..byte_end as usize].to_vec();
+            server.mock(|when, then| {
+                when.method(GET).path(path.clone());
+                then.status(fetch_status).body(body).delay(fetch_delay);
+            });
+
+            terms.push(CASReconstructionTerm {
+                hash: xorb.info.cashash.into(),
+                unpacked_length: unpacked_end - unpacked_start,
+                range: ChunkRange {
+                    start: chunk_start,
+                    end: chunk_end,
+                },
+            });
+            xorb_fetch_info.push(CASReconstructionFetchInfo {
+                range: ChunkRange {
+                    start: chunk_start,
+                    end: chunk_end,
+                },
+                url: server.url(&path),
+                url_range: HttpRange {
+                    start: byte_start,
+                    end: byte_end - 1,
+                },
+            });
+        }
+        QueryReconstructionResponse {
+            offset_into_first_range: 0,
+            terms,
+            fetch_info: HashMap::from([(xorb.info.cashash.into(), xorb_fetch_info)]),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_get_file_with_stats() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(4, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction(&server, &file_hash, &c, &xorb_bytes, 200, 2);
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+        let provider = BufferProvider::default();
+        let buf = provider.buf.clone();
+        let stats = client
+            .get_file_with_stats(&file_hash, None, &OutputProvider::Buffer(provider), None)
+            .await
+            .unwrap();
+
+        assert_eq!(buf.value(), raw_data);
+        assert_eq!(stats.total_bytes, raw_data.len() as u64);
+        assert_eq!(stats.num_terms(), 2);
+        assert_eq!(stats.terms.iter().map(|t| t.bytes).sum::<u64>(), stats.total_bytes);
+        for term in &stats.terms {
+            assert!(!term.cache_hit);
+            assert!(term.fetch_duration > Duration::ZERO);
+            assert!(term.decompression_duration > Duration::ZERO);
+            assert!(term.decompression_duration <= term.fetch_duration);
+            assert!(term.fetch_duration <= stats.wall_clock);
+        }
+        assert!(stats.fetch_duration_percentile(95.).unwrap() <= stats.wall_clock);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_describe_shard() {
+        use mdb_shard::shard_format::test_routines::{convert_to_file, gen_random_shard};
+
+        let mem_shard = gen_random_shard(0, &[4, 8, 16], &[1, 3, 8, 20], true, true).unwrap();
+        let data = convert_to_file(&mem_shard).unwrap();
+        let shard_hash = compute_data_hash(&data);
+        let cache_dir = tempfile::tempdir().unwrap();
+        std::fs::write(cache_dir.path().join(shard_file_name(&shard_hash)), &data).unwrap();
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            CAS_ENDPOINT,
+            None,
+            &None,
+            &None,
+            cache_dir.path().into(),
+            false,
+        )
+        .unwrap();
+        let summary = client.describe_shard(&shard_hash).unwrap();
+
+        let mut files = summary.files.clone();
+        files.sort();
+        let expected_files: Vec<_> = mem_shard
+            .file_content
+            .iter()
+            .map(|(hash, info)| (*hash, info.file_size() as u64))
+            .collect();
+        assert_eq!(files, expected_files);
+        let mut xorbs = summary.xorbs.clone();
+        xorbs.sort();
+        let expected_xorbs: Vec<_> = mem_shard
+            .cas_content
+            .iter()
+            .map(|(hash, info)| (*hash, info.chunks.len()))
+            .collect();
+        assert_eq!(xorbs, expected_xorbs);
+        assert_eq!(summary.num_chunks, 4 + 8 + 16);
+        assert_eq!(summary.file_bytes, expected_files.iter().map(|(_, size)| size).sum::<u64>());
+        assert_eq!(
+            summary.xorb_bytes,
+            mem_shard
+                .cas_content
+                .values()
+                .map(|info| info.metadata.num_bytes_in_cas as u64)
+                .sum::<u64>()
+        );
+        assert_eq!(summary.shard_bytes, data.len() as u64);
+
+        let missing = MerkleHash::default();
+        assert!(
+            matches!(client.describe_shard(&missing), Err(CasClientError::ShardNotCached(hash)) if hash == missing)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_warmup_reuses_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A keep-alive server recording the connections it accepts and the requests on them.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let server_requests = requests.clone();
+        tokio::spawn(async move {
+            for connection in 0.. {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let requests = server_requests.clone();
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut read_buf = [0u8; 4096];
+                    loop {
+                        let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+                            let n = stream.read(&mut read_buf).await.unwrap();
+                            if n == 0 {
+                                return;
+                            }
+                            buf.extend_from_slice(&read_buf[..n]);
+                            continue;
+                        };
+                        let head = String::from_utf8_lossy(&buf[..end]).to_string();
+                        buf.drain(..end + 4);
+                        let method = head.split(' ').next().unwrap().to_owned();
+                        requests.lock().unwrap().push((connection, method.clone()));
+
+                        let body = r#"{"offset_into_first_range":0,"terms":[],"fetch_info":{}}"#;
+                        let response = if method == "HEAD" {
+                            "HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n".to_owned()
+                        } else {
+                            format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{body}", body.len())
+                        };
+                        stream.write_all(response.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let client =
+            RemoteClient::new(ThreadPool::from_current_runtime(), &endpoint, None, &None, &None, "".into(), false)
+                .unwrap();
+        client.warmup().await;
+        assert_eq!(*requests.lock().unwrap(), [(0, "HEAD".to_owned())]);
+
+        // The reconstruction query reuses the connection opened by the warmup.
+        let response = client.get_reconstruction(&MerkleHash::default(), None).await.unwrap();
+        assert!(response.terms.is_empty());
+        assert_eq!(*requests.lock().unwrap(), [(0, "HEAD".to_owned()), (0, "GET".to_owned())]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_coalesce_adjacent_fetch_ranges() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(8, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+        let server = MockServer::start();
+
+        // Four terms fetched from the same url, serving any range of the xorb.
+        let mut reconstruction = mock_xorb_fetches(&server, &c, &xorb_bytes, 200, 4, Duration::ZERO);
+        let url = server.url("/xorb");
+        let mut range_mocks = vec![];
+        for info in reconstruction.fetch_info.values_mut().flatten() {
+            info.url = url.clone();
+            let body = xorb_bytes[info.url_range.start as usize..=info.url_range.end as usize].to_vec();
+            let header = range_header(&info.url_range);
+            range_mocks.push(server.mock(|when, then| {
+                when.method(GET).path("/xorb").header("Range", header);
+                then.status(200).body(body);
+            }));
+        }
+        let whole_range = HttpRange {
+            start: 0,
+            end: c.get_contents_length().unwrap() - 1,
+        };
+        // The response is delayed so that the four terms sharing it are all waiting on the one
+        // fetch; without a chunk cache, a term starting after it completed would fetch it again.
+        let whole_xorb_mock = server.mock(|when, then| {
+            when.method(GET).path("/xorb").header("Range", range_header(&whole_range));
+            then.status(200)
+                .body(&xorb_bytes[..=whole_range.end as usize])
+                .delay(Duration::from_millis(500));
+        });
+        let reconstruction_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/reconstruction/{}", file_hash.hex()))
+                .query_param("coalesce", "true");
+            then.status(200).json_body_obj(&reconstruction);
+        });
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap()
+        .with_coalesce_hint(true);
+        let provider = BufferProvider::default();
+        let buf = provider.buf.clone();
+        client
+            .get_file(&file_hash, None, &OutputProvider::Buffer(provider), None)
+            .await
+            .unwrap();
+        assert_eq!(buf.value(), raw_data);
+
+        // The four adjacent ranges are fetched with a single request.
+        reconstruction_mock.assert_hits(1);
+        whole_xorb_mock.assert_hits(1);
+        for range_mock in range_mocks {
+            range_mock.assert_hits(0);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_tee_output_hashes_reconstruction() {
+        use sha2::{Digest, Sha256};
+
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(32, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction_with_delay(&server, &file_hash, &c, &xorb_bytes, 200, 8, Duration::ZERO);
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("output");
+        let tee = TeeOutputProvider::new(OutputProvider::File(FileProvider::new(output_path.clone())));
+        let n_bytes = client
+            .get_file(&file_hash, None, &OutputProvider::Tee(tee.clone()), None)
+            .await
+            .unwrap();
+        assert_eq!(n_bytes, raw_data.len() as u64);
+
+        let expected = format!("{:x}", Sha256::digest(std::fs::read(&output_path).unwrap()));
+        assert_eq!(tee.sha256().unwrap().unwrap().hex(), expected);
+        assert_eq!(tee.hashed_bytes().unwrap(), raw_data.len() as u64);
+
+        // Writes out of order, or repeated, are hashed in file order once.
+        let buffer = BufferProvider::default();
+        let tee = TeeOutputProvider::new(OutputProvider::Buffer(buffer.clone()));
+        let output = OutputProvider::Tee(tee.clone());
+        for (start, end) in [(5000, 9000), (2000, 5000), (0, 1000), (1000, 3000)] {
+            output
+                .get_writer_at(start)
+                .unwrap()
+                .write_all(&raw_data[start as usize..end])
+                .unwrap();
+            assert_eq!(tee.sha256().unwrap().is_some(), start == 1000);
+        }
+        assert_eq!(tee.sha256().unwrap().unwrap().hex(), format!("{:x}", Sha256::digest(&raw_data[..9000])));
+        assert_eq!(buffer.buf.value(), &raw_data[..9000]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_stream_output() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(32, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction_with_delay(&server, &file_hash, &c, &xorb_bytes, 200, 8, Duration::ZERO);
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+        let (stream, mut receiver) = StreamOutputProvider::new();
+        let n_bytes = client
+            .get_file(&file_hash, None, &OutputProvider::Stream(stream), None)
+            .await
+            .unwrap();
+        assert_eq!(n_bytes, raw_data.len() as u64);
+        let mut streamed = Vec::new();
+        while let Some(bytes) = receiver.recv().await {
+            streamed.extend_from_slice(&bytes);
+        }
+        assert_eq!(streamed, raw_data);
+
+        // Writes out of order, or repeated, are sent in file order once.
+        let (stream, mut receiver) = StreamOutputProvider::new();
+        let output = OutputProvider::Stream(stream);
+        for (start, end) in [(5000, 9000), (2000, 5000), (0, 1000), (1000, 3000)] {
+            output
+                .get_writer_at(start)
+                .unwrap()
+                .write_all(&raw_data[start as usize..end])
+                .unwrap();
+        }
+        drop(output);
+        let mut streamed = Vec::new();
+        while let Some(bytes) = receiver.recv().await {
+            streamed.extend_from_slice(&bytes);
+        }
+        assert_eq!(streamed, &raw_data[..9000]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_ranged_reconstruction_from_cached_full_reconstruction() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(8, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        let reconstruction_mock =
+            mock_file_reconstruction_with_delay(&server, &file_hash, &c, &xorb_bytes, 200, 4, Duration::ZERO);
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+        let provider = BufferProvider::default();
+        let buf = provider.buf.clone();
+        client
+            .get_file(&file_hash, None, &OutputProvider::Buffer(provider), None)
+            .await
+            .unwrap();
+        assert_eq!(buf.value(), raw_data);
+        reconstruction_mock.assert_hits(1);
+
+        // Ranges within one term, across terms and up to the end of the file are all sliced from
+        // the cached full reconstruction.
+        for (start, end) in [(100, 200), (3000, 9000), (5000, raw_data.len() as u64)] {
+            let response = client
+                .get_reconstruction(&file_hash, Some(FileRange { start, end }))
+                .await
+                .unwrap();
+            let plan = ReconstructionPlan::new(&response, Some(FileRange { start, end })).unwrap();
+            assert_eq!(plan.total_length(), end - start);
+
+            let provider = BufferProvider::default();
+            let buf = provider.buf.clone();
+            client
+                .get_file(&file_hash, Some(FileRange { start, end }), &OutputProvider::Buffer(provider), None)
+                .await
+                .unwrap();
+            assert_eq!(buf.value(), raw_data[start as usize..end as usize]);
+        }
+        reconstruction_mock.assert_hits(1);
+
+        // A range past the end of the file is left to the server.
+        let _ = client
+            .get_reconstruction(
+                &file_hash,
+                Some(FileRange {
+                    start: 0,
+                    end: raw_data.len() as u64 + 1,
+                }),
+            )
+            .await;
+        reconstruction_mock.assert_hits(2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_network_and_decompression_time() {
+        const FETCH_DELAY: Duration = Duration::from_millis(200);
+
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(4, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        // A slow server with little data to decompress.
+        let server = MockServer::start();
+        mock_file_reconstruction_with_delay(&server, &file_hash, &c, &xorb_bytes, 200, 2, FETCH_DELAY);
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+        let provider = BufferProvider::default();
+        let buf = provider.buf.clone();
+        let stats = client
+            .get_file_with_stats(&file_hash, None, &OutputProvider::Buffer(provider), None)
+            .await
+            .unwrap();
+        assert_eq!(buf.value(), raw_data);
+
+        for term in &stats.terms {
+            assert!(term.network_duration >= FETCH_DELAY);
+            assert!(term.decompression_duration > Duration::ZERO);
+            assert!(term.network_duration + term.decompression_duration <= term.fetch_duration);
+        }
+        assert!(stats.total_network_duration() > 10 * stats.total_decompression_duration());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_get_file_cancelable() {
+        const FETCH_DELAY: Duration = Duration::from_millis(500);
+
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(4, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction_with_delay(&server, &file_hash, &c, &xorb_bytes, 200, 2, FETCH_DELAY);
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let output_path = dir.path().join("output");
+        std::fs::write(&output_path, b"existing contents").unwrap();
+        let output = OutputProvider::File(FileProvider::new(output_path.clone()));
+
+        // Cancel while the terms are being fetched.
+        let cancel = CancellationToken::new();
+        let canceler = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(FETCH_DELAY / 5).await;
+            canceler.cancel();
+        });
+        let start = Instant::now();
+        let result = client.get_file_cancelable(&file_hash, None, &output, None, &cancel).await;
+        assert!(matches!(result, Err(CasClientError::Canceled)), "{result:?}");
+        assert!(start.elapsed() < FETCH_DELAY);
+
+        // The existing file is untouched, and the term tasks of the canceled download don't write
+        // its temporary file again once their fetches complete.
+        tokio::time::sleep(2 * FETCH_DELAY).await;
+        assert_eq!(std::fs::read(&output_path).unwrap(), b"existing contents");
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let result = client
+            .get_file_cancelable(&file_hash, None, &output, None, &CancellationToken::new())
+            .await;
+        assert_eq!(result.unwrap(), raw_data.len() as u64);
+        assert_eq!(std::fs::read(&output_path).unwrap(), raw_data);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_sequential_write_backpressure() {
+        const WRITE_BUFFER_TERMS: usize = 2;
+
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(16, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction(&server, &file_hash, &c, &xorb_bytes, 200, 16);
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap()
+        .with_write_buffer_terms(WRITE_BUFFER_TERMS);
+        let manifest = client.get_reconstruction(&file_hash, None).await.unwrap();
+
+        // Terms are fetched much faster than the writer writes them.
+        let provider = BufferProvider::with_write_delay(Duration::from_millis(20));
+        let buf = provider.buf.clone();
+        let stats = client
+            .reconstruct_file_to_writer(
+                manifest.terms,
+                Arc::new(manifest.fetch_info),
+                manifest.offset_into_first_range,
+                None,
+                &OutputProvider::Buffer(provider),
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(buf.value(), raw_data);
+        assert_eq!(stats.num_terms(), 16);
+        assert_eq!(stats.max_buffered_terms, WRITE_BUFFER_TERMS);
+    }
+
+    /// Knows the chunk hashes of a single xorb.
+    struct XorbChunkHashes(MerkleHash, Vec<MerkleHash>);
+
+    #[async_trait]
+    impl FileReconstructor<MDBShardError> for XorbChunkHashes {
+        async fn get_file_reconstruction_info(
+            &self,
+            _file_hash: &MerkleHash,
+        ) -> std::result::Result<Option<(MDBFileInfo, Option<MerkleHash>)>, MDBShardError> {
+            Ok(None)
+        }
+
+        async fn get_xorb_chunk_hashes(
+            &self,
+            xorb_hash: &MerkleHash,
+        ) -> std::result::Result<Option<Vec<MerkleHash>>, MDBShardError> {
+            Ok((*xorb_hash == self.0).then(|| self.1.clone()))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_chunk_verification() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(4, ChunkSize::Fixed(2048), CompressionScheme::None);
+        let file_hash = MerkleHash::default();
+        let chunk_hashes: ChunkHashSource = Arc::new(XorbChunkHashes(c.info.cashash, c.info.chunk_hashes.clone()));
+
+        let get_file = |xorb_bytes: Vec<u8>, chunk_hash_source: Option<ChunkHashSource>| {
+            let c = &c;
+            async move {
+                let server = MockServer::start();
+                mock_file_reconstruction(&server, &file_hash, c, &xorb_bytes, 200, 2);
+                let mut client = RemoteClient::new(
+                    ThreadPool::from_current_runtime(),
+                    &server.base_url(),
+                    None,
+                    &None,
+                    &None,
+                    "".into(),
+                    false,
+                )
+                .unwrap();
+                if let Some(chunk_hash_source) = chunk_hash_source {
+                    client = client.with_chunk_verification(chunk_hash_source);
+                }
+                let provider = BufferProvider::default();
+                let buf = provider.buf.clone();
+                client
+                    .get_file(&file_hash, None, &OutputProvider::Buffer(provider), None)
+                    .await
+                    .map(|_| buf.value())
+            }
+        };
+
+        // Flip the last byte of the uncompressed data of the third chunk.
+        let mut corrupted = xorb_bytes.clone();
+        corrupted[c.info.chunk_boundary_offsets[2] as usize - 1] ^= 1;
+
+        assert_eq!(get_file(xorb_bytes.clone(), Some(chunk_hashes.clone())).await.unwrap(), raw_data);
+        let err = get_file(corrupted.clone(), Some(chunk_hashes)).await.unwrap_err();
+        assert!(
+            matches!(err, CasClientError::ChunkHashMismatch { hash, index: 2 } if hash == c.info.cashash),
+            "{err:?}"
+        );
+
+        // Without verification, or without recorded hashes for the xorb, the corruption goes unnoticed.
+        assert_ne!(get_file(corrupted.clone(), None).await.unwrap(), raw_data);
+        let unrelated: ChunkHashSource = Arc::new(XorbChunkHashes(MerkleHash::default(), vec![]));
+        assert_ne!(get_file(corrupted, Some(unrelated)).await.unwrap(), raw_data);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_get_xorb_round_trip() {
+        let (c, _, raw_data, chunk_boundaries) = build_cas_object(3, ChunkSize::Fixed(1024), CompressionScheme::None);
+        let hash = c.info.cashash;
+        let key = Key {
+            prefix: PREFIX_DEFAULT.into(),
+            hash,
+        };
+        let mut xorb = Cursor::new(Vec::new());
+        CasObject::serialize(&mut xorb, &hash, &raw_data, &chunk_boundaries, None).unwrap();
+        let xorb = xorb.into_inner();
+
+        // The server serves the xorb as serialized by the upload, under its hash as well as under
+        // a wrong one.
+        let wrong_key = Key {
+            prefix: PREFIX_DEFAULT.into(),
+            hash: MerkleHash::default(),
+        };
+        let server = MockServer::start();
+        let put_mock = server.mock(|when, then| {
+            when.method(POST).path(format!("/xorb/{key}"));
+            then.status(200).json_body(serde_json::json!({ "was_inserted": true }));
+        });
+        for key in [&key, &wrong_key] {
+            server.mock(|when, then| {
+                when.method(GET).path(format!("/xorb/{key}"));
+                then.status(200).body(xorb.clone());
+            });
+        }
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+        client
+            .put(PREFIX_DEFAULT, &hash, raw_data.clone(), chunk_boundaries)
+            .await
+            .unwrap();
+        put_mock.assert();
+
+        assert_eq!(client.get_xorb(PREFIX_DEFAULT, &hash).await.unwrap(), raw_data);
+        assert_eq!(
+            client.get_xorb(PREFIX_DEFAULT, &wrong_key.hash).await.unwrap_err(),
+            CasClientError::InvalidXORB(wrong_key.hash)
+        );
+    }
+
+    /// Starts an HTTP/1.1 server on a local port that answers POST requests with `post_body` and all
+    /// others with `get_body`, each after a short delay.  Returns the server url, the number of
+    /// requests served, and the most requests it was handling at once.
+    async fn start_counting_server(
+        get_body: Vec<u8>,
+        post_body: Vec<u8>,
+    ) -> (String, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let served = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let counters = (served.clone(), max_in_flight.clone(), Arc::new(AtomicUsize::new(0)));
+        tokio::spawn(async move {
+            let bodies = Arc::new((get_body, post_body));
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let bodies = bodies.clone();
+                let (served, max_in_flight, in_flight) = counters.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    loop {
+                        let mut request_line = String::new();
+                        if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+                            return;
+                        }
+                        let mut content_length = 0;
+                        loop {
+                            let mut header = String::new();
+                            stream.read_line(&mut header).await.unwrap();
+                            if header == "\r\n" {
+                                break;
+                            }
+                            if let Some(len) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+                                content_length = len.trim().parse().unwrap();
+                            }
+                        }
+                        stream.read_exact(&mut vec![0; content_length]).await.unwrap();
+
+                        let now_in_flight = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_in_flight.fetch_max(now_in_flight, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                        served.fetch_add(1, Ordering::SeqCst);
+
+                        let body = if request_line.starts_with("POST") {
+                            &bodies.1
+                        } else {
+                            &bodies.0
+                        };
+                        let header = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n", body.len());
+                        stream.write_all(header.as_bytes()).await.unwrap();
+                        stream.write_all(body).await.unwrap();
+                        stream.flush().await.unwrap();
+                    }
+                });
+            }
+        });
+
+        (url, served, max_in_flight)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_download_range_resumes_cut_off_body() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        let (c, _, raw_data, chunk_boundaries) = build_cas_object(3, ChunkSize::Fixed(1024), CompressionScheme::None);
+        let hash = c.info.cashash;
+        let mut xorb = Cursor::new(Vec::new());
+        CasObject::serialize(&mut xorb, &hash, &raw_data, &chunk_boundaries, None).unwrap();
+        let mut xorb = xorb.into_inner();
+        xorb.truncate(*c.info.chunk_boundary_offsets.last().unwrap() as usize);
+        let xorb = Arc::new(xorb);
+
+        // The server answers each request with the requested range, but drops the connection after
+        // half of the body on the first one.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/xorb", listener.local_addr().unwrap());
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let served = xorb.clone();
+        let requested = ranges.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let mut range = String::new();
+                loop {
+                    let mut header = String::new();
+                    stream.read_line(&mut header).await.unwrap();
+                    if header == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = header.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        range = value.trim().to_string();
+                    }
+                }
+                let (start, end) = range.split_once('-').unwrap();
+                let (start, end): (usize, usize) = (start.parse().unwrap(), end.parse().unwrap());
+                let first = {
+                    let mut requested = requested.lock().unwrap();
+                    requested.push(range.clone());
+                    requested.len() == 1
+                };
+
+                let body = &served[start..=end];
+                let header = format!("HTTP/1.1 206 Partial Content\r\ncontent-length: {}\r\n\r\n", body.len());
+                stream.write_all(header.as_bytes()).await.unwrap();
+                stream
+                    .write_all(if first { &body[..body.len() / 2] } else { body })
+                    .await
+                    .unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+
+        let fetch_term = CASReconstructionFetchInfo {
+            range: ChunkRange {
+                start: 0,
+                end: chunk_boundaries.len() as u32,
+            },
+            url,
+            url_range: HttpRange {
+                start: 0,
+                end: xorb.len() as u32 - 1,
+            },
+        };
+        let http_client = Arc::new(http_client::build_http_client(RetryConfig::default()).unwrap());
+        let (data, _, _, _) = download_range(http_client, fetch_term, hash.into(), None).await.unwrap();
+
+        assert_eq!(data, raw_data);
+        let half = xorb.len() / 2;
+        assert_eq!(*ranges.lock().unwrap(), [format!("0-{}", xorb.len() - 1), format!("{half}-{}", xorb.len() - 1)]);
+    }
+
+    #[test]
+    fn test_streaming_chunk_deserializer() {
+        let (c, _, raw_data, chunk_boundaries) = build_cas_object(4, ChunkSize::Fixed(1024), CompressionScheme::LZ4);
+        let mut xorb = Cursor::new(Vec::new());
+        CasObject::serialize(&mut xorb, &c.info.cashash, &raw_data, &chunk_boundaries, None).unwrap();
+        let mut xorb = xorb.into_inner();
+        let chunks_len = *c.info.chunk_boundary_offsets.last().unwrap() as usize;
+        xorb.truncate(chunks_len);
+        let max_chunk_len = c
+            .info
+            .chunk_boundary_offsets
+            .windows(2)
+            .map(|w| (w[1] - w[0]) as usize)
+            .max()
+            .unwrap();
+
+        // Only the chunk being received is held back, and each chunk is deserialized once complete.
+        let mut deserializer = StreamingChunkDeserializer::new(None);
+        for piece in xorb.chunks(100) {
+            deserializer.push(piece).unwrap();
+            assert!(deserializer.pending.len() < max_chunk_len);
+        }
+        assert!(deserializer.pending.is_empty());
+        assert_eq!(deserializer.received_len, chunks_len);
+        assert_eq!(deserializer.finish(), cas_object::deserialize_chunks(&mut Cursor::new(xorb)).unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_network_request_limit() {
+        const MAX_REQUESTS: usize = 2;
+
+        let (c, _, raw_data, chunk_boundaries) = build_cas_object(3, ChunkSize::Fixed(1024), CompressionScheme::None);
+        let hash = c.info.cashash;
+        let mut xorb = Cursor::new(Vec::new());
+        CasObject::serialize(&mut xorb, &hash, &raw_data, &chunk_boundaries, None).unwrap();
+
+        let (url, served, max_in_flight) =
+            start_counting_server(xorb.into_inner(), br#"{"was_inserted": true}"#.to_vec()).await;
+
+        let threadpool = Arc::new(
+            ThreadPool::from_external(tokio::runtime::Handle::current())
+                .with_max_concurrent_network_requests(MAX_REQUESTS),
+        );
+        let client = RemoteClient::new(threadpool, &url, None, &None, &None, "".into(), false).unwrap();
+
+        // Uploads and downloads at once, more of each than the limit.
+        let uploads = futures::future::join_all(
+            (0..6).map(|_| client.put(PREFIX_DEFAULT, &hash, raw_data.clone(), chunk_boundaries.clone())),
+        );
+        let downloads = futures::future::join_all((0..6).map(|_| client.get_xorb(PREFIX_DEFAULT, &hash)));
+        let (uploaded, downloaded) = futures::join!(uploads, downloads);
+
+        assert!(uploaded.into_iter().all(|r| r.is_ok()));
+        assert!(downloaded.into_iter().all(|r| r.unwrap() == raw_data));
+        assert_eq!(served.load(Ordering::SeqCst), 12);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), MAX_REQUESTS);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_get_file_info_from_shard() {
+        use mdb_shard::shard_format::test_routines::{convert_to_file, gen_random_shard};
+        use mdb_shard::shard_format::MDB_FILE_INFO_ENTRY_SIZE;
+        use mdb_shard::MDBShardInfo;
+
+        let mem_shard = gen_random_shard(0, &[4, 8], &[3], false, false).unwrap();
+        let (file_hash, file_info) = mem_shard.file_content.iter().next().unwrap();
+        let data = convert_to_file(&mem_shard).unwrap();
+        let shard = MDBShardInfo::load_from_reader(&mut Cursor::new(&data)).unwrap();
+        let shard_hash = merklehash::compute_data_hash(&data);
+
+        // Serves only the footer, the file lookup table and the file info of the one file.
+        let server = MockServer::start();
+        let footer_len = data.len() as u64 - shard.metadata.footer_offset;
+        let file_info_start = shard.metadata.file_info_offset;
+        let ranges = [
+            (format!("bytes=-{footer_len}"), shard.metadata.footer_offset..data.len() as u64),
+            (
+                format!("bytes={0}-{1}", shard.metadata.file_lookup_offset, shard.metadata.file_lookup_offset + 11),
+                shard.metadata.file_lookup_offset..shard.metadata.file_lookup_offset + 12,
+            ),
+            (
+                format!("bytes={file_info_start}-{}", file_info_start + MDB_FILE_INFO_ENTRY_SIZE as u64 - 1),
+                file_info_start..file_info_start + MDB_FILE_INFO_ENTRY_SIZE as u64,
+            ),
+            (
+                format!("bytes={file_info_start}-{}", file_info_start + file_info.num_bytes() - 1),
+                file_info_start..file_info_start + file_info.num_bytes(),
+            ),
+        ];
+        let mocks: Vec<_> = ranges
+            .iter()
+            .map(|(header, range)| {
+                server.mock(|when, then| {
+                    when.method(GET)
+                        .path(format!("/shard/{PREFIX_DEFAULT}/{shard_hash}"))
+                        .header("range", header);
+                    then.status(206).body(&data[range.start as usize..range.end as usize]);
+                })
+            })
+            .collect();
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+        let fetched = client
+            .get_file_info_from_shard(PREFIX_DEFAULT, &shard_hash, file_hash)
+            .await
+            .unwrap();
+        assert_eq!(fetched.as_ref(), Some(file_info));
+        for mock in &mocks {
+            mock.assert_hits(1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_get_file_multiplexed() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(8, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction(&server, &file_hash, &c, &xorb_bytes, 200, 4);
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+
+        // Two halves split inside a term, and a range overlapping both.
+        let len = raw_data.len() as u64;
+        let mid = len / 2 + 100;
+        let ranges = [
+            FileRange { start: 0, end: mid },
+            FileRange { start: mid, end: len },
+            FileRange {
+                start: len / 4,
+                end: 3 * len / 4,
+            },
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..ranges.len()).map(|i| dir.path().join(format!("part{i}"))).collect();
+        let outputs = ranges
+            .iter()
+            .zip(&paths)
+            .map(|(range, path)| (range.clone(), OutputProvider::File(FileProvider::new(path.clone()))))
+            .collect();
+
+        let n_bytes = client.get_file_multiplexed(&file_hash, outputs).await.unwrap();
+
+        assert_eq!(n_bytes, ranges.iter().map(|r| r.end - r.start).sum::<u64>());
+        for (range, path) in ranges.iter().zip(&paths) {
+            assert_eq!(std::fs::read(path).unwrap(), raw_data[range.start as usize..range.end as usize]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_chunk_cache_directory_per_client() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(4, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction(&server, &file_hash, &c, &xorb_bytes, 200, 2);
+
+        let cache_root = tempfile::tempdir().unwrap();
+        let new_client = |cache_dir: &str| {
+            let cache_config = CacheConfig {
+                cache_directory: cache_root.path().join(cache_dir),
+                cache_size: 1 << 20,
+            };
+            RemoteClient::new(
+                ThreadPool::from_current_runtime(),
+                &server.base_url(),
+                None,
+                &None,
+                &Some(cache_config),
+                "".into(),
+                false,
+            )
+            .unwrap()
+        };
+        let client_a = new_client("a");
+        let client_b = new_client("b");
+
+        // Downloads the file, returning the number of terms served from the chunk cache.
+        async fn cached_terms(client: &RemoteClient, file_hash: &MerkleHash, expected: &[u8]) -> usize {
+            let provider = BufferProvider::default();
+            let buf = provider.buf.clone();
+            let stats = client
+                .get_file_with_stats(file_hash, None, &OutputProvider::Buffer(provider), None)
+                .await
+                .unwrap();
+            assert_eq!(buf.value(), expected);
+            stats.terms.iter().filter(|t| t.cache_hit).count()
+        }
+
+        assert_eq!(cached_terms(&client_a, &file_hash, &raw_data).await, 0);
+        assert_eq!(cached_terms(&client_a, &file_hash, &raw_data).await, 2);
+
+        // The second client doesn't see the chunks cached by the first one.
+        assert_eq!(cached_terms(&client_b, &file_hash, &raw_data).await, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_prefetch_files() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(4, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction(&server, &file_hash, &c, &xorb_bytes, 200, 2);
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_config = CacheConfig {
+            cache_directory: cache_dir.path().to_path_buf(),
+            cache_size: 1 << 20,
+        };
+        let new_client = |cache_config: Option<CacheConfig>| {
+            RemoteClient::new(
+                ThreadPool::from_current_runtime(),
+                &server.base_url(),
+                None,
+                &None,
+                &cache_config,
+                "".into(),
+                false,
+            )
+            .unwrap()
+        };
+        let client = new_client(Some(cache_config));
+
+        assert_eq!(client.prefetch_files(&[file_hash]).await.unwrap(), raw_data.len() as u64);
+        // Everything is cached now.
+        assert_eq!(client.prefetch_files(&[file_hash]).await.unwrap(), 0);
+
+        // Reconstruct with fetch urls that can't be reached: every term comes from the cache.
+        let mut reconstruction = client.get_reconstruction(&file_hash, None).await.unwrap();
+        for fetch_term in reconstruction.fetch_info.values_mut().flatten() {
+            fetch_term.url = "http://127.0.0.1:1/unreachable".to_owned();
+        }
+        let provider = BufferProvider::default();
+        let buf = provider.buf.clone();
+        let stats = client
+            .reconstruct_file_to_writer_parallel(
+                reconstruction.terms,
+                Arc::new(reconstruction.fetch_info),
+                0,
+                None,
+                &OutputProvider::Buffer(provider),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(buf.value(), raw_data);
+        assert!(stats.terms.iter().all(|t| t.cache_hit));
+
+        assert!(matches!(
+            new_client(None).prefetch_files(&[file_hash]).await,
+            Err(CasClientError::ConfigurationError(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_download_with_unwritable_chunk_cache() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(4, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction(&server, &file_hash, &c, &xorb_bytes, 200, 2);
+
+        let cache_dir = tempfile::tempdir().unwrap();
+        let cache_directory = cache_dir.path().join("chunks");
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &Some(CacheConfig {
+                cache_directory: cache_directory.clone(),
+                cache_size: 1 << 20,
+            }),
+            "".into(),
+            false,
+        )
+        .unwrap();
+        // A file in place of the cache directory makes every cache write fail, even for root.
+        std::fs::write(&cache_directory, b"").unwrap();
+
+        for _ in 0..2 {
+            let provider = BufferProvider::default();
+            let buf = provider.buf.clone();
+            let stats = client
+                .get_file_with_stats(&file_hash, None, &OutputProvider::Buffer(provider), None)
+                .await
+                .unwrap();
+            assert_eq!(buf.value(), raw_data);
+            assert!(stats.terms.iter().all(|t| !t.cache_hit));
+        }
+        assert!(cache_directory.is_file());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_query_reconstructions_concurrency() {
+        const MAX_CONCURRENT: usize = 8;
+        const NUM_FILES: u64 = 100;
+
+        /// Answers every query after a delay, tracking how many are in flight.
+        #[derive(Default)]
+        struct InstrumentedReconstructable {
+            in_flight: AtomicUsize,
+            max_in_flight: AtomicUsize,
+        }
+
+        #[async_trait]
+        impl Reconstructable for InstrumentedReconstructable {
+            async fn get_reconstruction(
+                &self,
+                hash: &MerkleHash,
+                _byte_range: Option<FileRange>,
+            ) -> Result<QueryReconstructionResponse> {
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                // Tag the response with the file, to check the order of the results.
+                Ok(QueryReconstructionResponse {
+                    offset_into_first_range: hash[0],
+                    terms: vec![],
+                    fetch_info: HashMap::new(),
+                })
+            }
+        }
+
+        let reconstructable = InstrumentedReconstructable::default();
+        let file_hashes: Vec<MerkleHash> = (0..NUM_FILES).map(|i| MerkleHash::from([i, 0, 0, 0])).collect();
+        let manifests = query_reconstructions(&reconstructable, &file_hashes, MAX_CONCURRENT)
+            .await
+            .unwrap();
+
+        assert_eq!(reconstructable.max_in_flight.load(Ordering::SeqCst), MAX_CONCURRENT);
+        assert_eq!(
+            manifests.iter().map(|m| m.offset_into_first_range).collect::<Vec<_>>(),
+            (0..NUM_FILES).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_get_file_caps_open_output_handles() {
+        const MAX_OPEN_HANDLES: usize = 1;
+        const NUM_TERMS: u32 = 64;
+
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(NUM_TERMS, ChunkSize::Fixed(1024), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction(&server, &file_hash, &c, &xorb_bytes, 200, NUM_TERMS);
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap()
+        .with_max_open_output_handles(MAX_OPEN_HANDLES);
+
+        // The provider fails any writer opened beyond the cap, as the OS would once out of
+        // file descriptors.
+        let provider = BufferProvider::with_max_open_writers(MAX_OPEN_HANDLES);
+        let buf = provider.buf.clone();
+        let stats = client
+            .get_file_with_stats(&file_hash, None, &OutputProvider::Buffer(provider), None)
+            .await
+            .unwrap();
+
+        assert_eq!(stats.num_terms(), NUM_TERMS as usize);
+        assert_eq!(buf.value(), raw_data);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_get_file_durable_writes() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(8, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction(&server, &file_hash, &c, &xorb_bytes, 200, 4);
+
+        for durable_writes in [false, true] {
+            let client = RemoteClient::new(
+                ThreadPool::from_current_runtime(),
+                &server.base_url(),
+                None,
+                &None,
+                &None,
+                "".into(),
+                false,
+            )
+            .unwrap()
+            .with_durable_writes(durable_writes);
+
+            // The provider counts the syncs of the output.
+            let provider = BufferProvider::default();
+            let buf = provider.buf.clone();
+            let output = OutputProvider::Buffer(provider.clone());
+            client.get_file(&file_hash, None, &output, None).await.unwrap();
+            assert_eq!(buf.value(), raw_data);
+            assert_eq!(provider.num_syncs(), durable_writes as usize);
+
+            let dir = tempfile::tempdir().unwrap();
+            let output_path = dir.path().join("file");
+            let output = OutputProvider::File(FileProvider::new(output_path.clone()));
+            client.get_file(&file_hash, None, &output, None).await.unwrap();
+            assert_eq!(std::fs::read(&output_path).unwrap(), raw_data);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_explain_reconstruction() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(8, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction(&server, &file_hash, &c, &xorb_bytes, 200, 4);
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+        let plan = client.explain_reconstruction(&file_hash, None).await.unwrap();
+
+        assert_eq!(plan.terms.len(), 4);
+        assert_eq!(plan.total_length(), raw_data.len() as u64);
+        assert_eq!(plan.terms[0].output_range.start, 0);
+        for (prev, next) in plan.terms.iter().zip(plan.terms.iter().skip(1)) {
+            assert_eq!(prev.output_range.end, next.output_range.start);
+            assert_eq!(prev.chunk_range.end, next.chunk_range.start);
+        }
+        for term in &plan.terms {
+            assert!(c.info.cashash.hex().starts_with(&term.xorb_hash));
+            assert_eq!(term.output_range.end - term.output_range.start, term.unpacked_length as u64);
+        }
+        assert_eq!(plan.terms.last().unwrap().output_range.end, raw_data.len() as u64);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_get_file_fallback_endpoint() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(6, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let primary = MockServer::start();
+        mock_file_reconstruction(&primary, &file_hash, &c, &xorb_bytes, 500, 2);
+        let fallback = MockServer::start();
+        mock_file_reconstruction(&fallback, &file_hash, &c, &xorb_bytes, 200, 2);
+
+        let mut client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &primary.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap()
+        .with_fallback_endpoints(vec![fallback.base_url()])
+        .unwrap();
+        // don't wait out the retry backoff on the failing primary fetches
+        client.http_client = Arc::new(http_client::build_http_client(RetryConfig::no_retry()).unwrap());
+
+        let provider = BufferProvider::default();
+        let buf = provider.buf.clone();
+        let n_bytes = client
+            .get_file(&file_hash, None, &OutputProvider::Buffer(provider), None)
+            .await
+            .unwrap();
+
+        assert_eq!(n_bytes, raw_data.len() as u64);
+        assert_eq!(buf.value(), raw_data);
+
+        // without the fallback, the failure is returned
+        let client = RemoteClient {
+            fallback_endpoints: vec![],
+            ..client
+        };
+        let provider = BufferProvider::default();
+        assert!(client
+            .get_file(&file_hash, None, &OutputProvider::Buffer(provider), None)
+            .await
+            .is_err());
+    }
+
+    #[derive(Debug, Default)]
+    struct ByteCounter(AtomicU64);
+
+    impl ProgressUpdater for ByteCounter {
+        fn update(&self, increment: u64) {
+            self.0.fetch_add(increment, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_attempt_progress_updater() {
+        let counter = Arc::new(ByteCounter::default());
+        let reported_bytes = Arc::new(AtomicU64::new(0));
+        let attempt = || AttemptProgressUpdater {
+            inner: counter.clone(),
+            attempt_bytes: AtomicU64::new(0),
+            reported_bytes: reported_bytes.clone(),
+        };
+
+        // A failed attempt gets 300 bytes in; the next one only reports the bytes past those.
+        let failed = attempt();
+        failed.update(100);
+        failed.update(200);
+        assert_eq!(counter.0.load(Ordering::Relaxed), 300);
+
+        let retried = attempt();
+        retried.update(250);
+        assert_eq!(counter.0.load(Ordering::Relaxed), 300);
+        retried.update(250);
+        assert_eq!(counter.0.load(Ordering::Relaxed), 500);
+        retried.update(500);
+        assert_eq!(counter.0.load(Ordering::Relaxed), 1000);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_request_error_context() {
+        let (c, xorb_bytes, _, _) = build_cas_object(4, ChunkSize::Fixed(2048), CompressionScheme::LZ4);
+        let file_hash = MerkleHash::default();
+
+        let server = MockServer::start();
+        mock_file_reconstruction(&server, &file_hash, &c, &xorb_bytes, 403, 2);
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+
+        // A failed xorb range fetch names the xorb and the fetch URL.  The fetch runs under the
+        // range download single flight, which may only pass on the error message.
+        let provider = BufferProvider::default();
+        let message = client
+            .get_file(&file_hash, None, &OutputProvider::Buffer(provider), None)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(message.contains(PREFIX_DEFAULT), "{message}");
+        assert!(message.contains(&c.info.cashash.hex()), "{message}");
+        assert!(message.contains("403"), "{message}");
+        assert!(message.contains(&format!("{}/xorb_data/", server.base_url())), "{message}");
+
+        // So does a failed reconstruction query, with the file hash.
+        let missing_hash = MerkleHash::from_hex(&"ab".repeat(32)).unwrap();
+        let err = client.get_reconstruction(&missing_hash, None).await.unwrap_err();
+        assert!(matches!(err, CasClientError::RequestFailed(_)), "{err:?}");
+        let message = err.to_string();
+        assert!(message.contains(&format!("{PREFIX_DEFAULT}/{}", missing_hash.hex())), "{message}");
+        assert!(message.contains("404"), "{message}");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_idempotency_key() {
+        const NUM_RETRIES: u32 = 2;
+
+        let objects: Vec<_> = (0..2)
+            .map(|_| build_cas_object(3, ChunkSize::Fixed(1024), CompressionScheme::None))
+            .collect();
+        let keys: Vec<Key> = objects
+            .iter()
+            .map(|(c, ..)| Key {
+                prefix: PREFIX_DEFAULT.into(),
+                hash: c.info.cashash,
+            })
+            .collect();
+
+        // Every attempt fails, so each upload is retried; a mock only sees the attempts that
+        // carry its object's key.
+        let server = MockServer::start();
+        let xorb_mocks: Vec<_> = keys
+            .iter()
+            .map(|key| {
+                server.mock(|when, then| {
+                    when.method(POST)
+                        .path(format!("/xorb/{key}"))
+                        .header(IDEMPOTENCY_KEY_HEADER, format!("xorb/{key}"));
+                    then.status(500);
+                })
+            })
+            .collect();
+        let shard_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(format!("/shard/{}", keys[0]))
+                .header(IDEMPOTENCY_KEY_HEADER, format!("shard/{}", keys[0]));
+            then.status(500);
+        });
+
+        let mut client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+        client.authenticated_http_client =
+            Arc::new(http_client::build_auth_http_client(&None, RetryConfig::immediate_retry(NUM_RETRIES)).unwrap());
+
+        for ((_, _, data, chunk_boundaries), key) in objects.into_iter().zip(&keys) {
+            assert!(client.upload(key, data, chunk_boundaries).await.is_err());
+        }
+        assert!(client
+            .upload_shard(&keys[0].prefix, &keys[0].hash, false, b"shard", &[0; 32])
+            .await
+            .is_err());
+
+        for mock in xorb_mocks {
+            mock.assert_hits(1 + NUM_RETRIES as usize);
+        }
+        shard_mock.assert_hits(1 + NUM_RETRIES as usize);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_quota_exceeded() {
+        const NUM_RETRIES: u32 = 3;
+
+        let (c, _, data, chunk_boundaries) = build_cas_object(3, ChunkSize::Fixed(1024), CompressionScheme::None);
+        let key = Key {
+            prefix: PREFIX_DEFAULT.into(),
+            hash: c.info.cashash,
+        };
+        let other_key = Key {
+            prefix: PREFIX_DEFAULT.into(),
+            hash: MerkleHash::default(),
+        };
+
+        // A 507 isn't retried, nor is an error the JSON body of which names the quota; other errors
+        // are reported as before.
+        let server = MockServer::start();
+        let xorb_mock = server.mock(|when, then| {
+            when.method(POST).path(format!("/xorb/{key}"));
+            then.status(507).body("out of space");
+        });
+        let shard_mock = server.mock(|when, then| {
+            when.method(POST).path(format!("/shard/{key}"));
+            then.status(403).json_body(
+                serde_json::json!({"error": "QuotaExceeded", "message": "repository storage quota exceeded"}),
+            );
+        });
+        let other_shard_mock = server.mock(|when, then| {
+            when.method(POST).path(format!("/shard/{other_key}"));
+            then.status(403).json_body(serde_json::json!({"error": "Forbidden"}));
+        });
+
+        let mut client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+        client.authenticated_http_client =
+            Arc::new(http_client::build_auth_http_client(&None, RetryConfig::immediate_retry(NUM_RETRIES)).unwrap());
+
+        let err = client.upload(&key, data, chunk_boundaries).await.unwrap_err();
+        assert!(matches!(&err, CasClientError::QuotaExceeded(message) if message.contains("out of space")));
+        xorb_mock.assert_hits(1);
+
+        let err = client
+            .upload_shard(&key.prefix, &key.hash, false, b"shard", &[0; 32])
+            .await
+            .unwrap_err();
+        assert!(matches!(&err, CasClientError::QuotaExceeded(message) if message.contains("repository storage quota")));
+        shard_mock.assert_hits(1);
+
+        let err = client
+            .upload_shard(&other_key.prefix, &other_key.hash, false, b"shard", &[0; 32])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, CasClientError::RequestFailed(failure) if failure.status == Some(StatusCode::FORBIDDEN))
+        );
+        other_shard_mock.assert_hits(1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_extra_headers_on_all_requests() {
+        let (c, _, data, chunk_boundaries) = build_cas_object(3, ChunkSize::Fixed(1024), CompressionScheme::None);
+        let key = Key {
+            prefix: PREFIX_DEFAULT.into(),
+            hash: c.info.cashash,
+        };
+        let reconstruction = QueryReconstructionResponse {
+            offset_into_first_range: 0,
+            terms: vec![],
+            fetch_info: HashMap::new(),
+        };
+        let shard_response = UploadShardResponse {
+            result: UploadShardResponseType::SyncPerformed,
+        };
+
+        // Requests without the header aren't matched, and fail with a 404.
+        let server = MockServer::start();
+        let reconstruction_mock = server.mock(|when, then| {
+            when.method(GET)
+                .path(format!("/reconstruction/{}", key.hash.hex()))
+                .header("x-tenant-id", "tenant-1");
+            then.status(200).json_body_obj(&reconstruction);
+        });
+        let dedup_mock = server.mock(|when, then| {
+            when.method(GET).path(format!("/chunk/{key}")).header("x-tenant-id", "tenant-1");
+            then.status(404);
+        });
+        let xorb_mock = server.mock(|when, then| {
+            when.method(POST).path(format!("/xorb/{key}")).header("x-tenant-id", "tenant-1");
+            then.status(200).json_body(serde_json::json!({ "was_inserted": true }));
+        });
+        let shard_mock = server.mock(|when, then| {
+            when.method(POST)
+                .path(format!("/shard/{key}"))
+                .header("x-tenant-id", "tenant-1");
+            then.status(200).json_body_obj(&shard_response);
+        });
+
+        let shard_dir = tempfile::tempdir().unwrap();
+        let http_config = HttpClientConfig::default()
+            .with_extra_headers(HashMap::from([("x-tenant-id".to_owned(), "tenant-1".to_owned())]))
+            .unwrap();
+        let client = RemoteClient::new_with_http_config(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            shard_dir.path().to_owned(),
+            false,
+            &http_config,
+        )
+        .unwrap();
+
+        assert!(client.get_reconstruction(&key.hash, None).await.unwrap().terms.is_empty());
+        reconstruction_mock.assert();
+        assert!(client
+            .query_for_global_dedup_shard(&key.prefix, &key.hash, &[0; 32])
+            .await
+            .unwrap()
+            .is_none());
+        dedup_mock.assert();
+        client.upload(&key, data, chunk_boundaries).await.unwrap();
+        xorb_mock.assert();
+        client
+            .upload_shard(&key.prefix, &key.hash, false, b"shard", &[0; 32])
+            .await
+            .unwrap();
+        shard_mock.assert();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_shard_force_sync_method() {
+        let key = Key {
+            prefix: PREFIX_DEFAULT.into(),
+            hash: MerkleHash::default(),
+        };
+        let response = serde_json::to_string(&UploadShardResponse {
+            result: UploadShardResponseType::SyncPerformed,
+        })
+        .unwrap();
+
+        let server = MockServer::start();
+        let sync_mock = server.mock(|when, then| {
+            when.method(PUT).path(format!("/shard/{key}"));
+            then.status(200).body(&response);
+        });
+        let async_mock = server.mock(|when, then| {
+            when.method(POST).path(format!("/shard/{key}"));
+            then.status(200).body(&response);
+        });
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+
+        // A synced upload is a PUT; otherwise, it's a POST.
+        assert!(client
+            .upload_shard(&key.prefix, &key.hash, true, b"shard", &[0; 32])
+            .await
+            .unwrap());
+        sync_mock.assert_hits(1);
+        async_mock.assert_hits(0);
+
+        client
+            .upload_shard(&key.prefix, &key.hash, false, b"shard", &[0; 32])
+            .await
+            .unwrap();
+        sync_mock.assert_hits(1);
+        async_mock.assert_hits(1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_max_response_bytes() {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+        const MAX_BYTES: u64 = 1024;
+        let oversized = vec![b' '; 4 * MAX_BYTES as usize];
+        let (c, xorb_bytes, data, chunk_boundaries) =
+            build_cas_object(20, ChunkSize::Fixed(256), CompressionScheme::None);
+        let key = Key {
+            prefix: PREFIX_DEFAULT.into(),
+            hash: c.info.cashash,
+        };
+        let file_hash = MerkleHash::default();
+
+        // The reconstruction has enough terms to be over the limit; the dedup shard and the xorb upload
+        // responses are padded past it.
+        let server = MockServer::start();
+        mock_file_reconstruction(&server, &file_hash, &c, &xorb_bytes, 200, 20);
+        server.mock(|when, then| {
+            when.method(GET).path(format!("/chunk/{key}"));
+            then.status(200).body(&oversized);
+        });
+        server.mock(|when, then| {
+            when.method(POST).path(format!("/xorb/{key}"));
+            then.status(200)
+                .body(format!("{{\"was_inserted\": true}}{}", " ".repeat(oversized.len())));
+        });
+
+        let shard_dir = tempfile::tempdir().unwrap();
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            shard_dir.path().to_path_buf(),
+            false,
+        )
+        .unwrap()
+        .with_max_response_bytes(MAX_BYTES);
+
+        let provider = OutputProvider::Buffer(BufferProvider::default());
+        assert!(matches!(
+            client.get_file(&file_hash, None, &provider, None).await,
+            Err(CasClientError::ResponseTooLarge(_))
+        ));
+        assert!(matches!(
+            client.query_for_global_dedup_shard(&key.prefix, &key.hash, &[0; 32]).await,
+            Err(CasClientError::ResponseTooLarge(_))
+        ));
+        assert!(matches!(
+            client.upload(&key, data, chunk_boundaries).await,
+            Err(CasClientError::ResponseTooLarge(_))
+        ));
+
+        // Without a Content-Length, the body is cut off once it passes the limit as it streams in.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).await.unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n")
+                .await
+                .unwrap();
+            for chunk in oversized.chunks(256) {
+                stream.write_all(format!("{:x}\r\n", chunk.len()).as_bytes()).await.unwrap();
+                stream.write_all(chunk).await.unwrap();
+                stream.write_all(b"\r\n").await.unwrap();
+            }
+            stream.write_all(b"0\r\n\r\n").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let client =
+            RemoteClient::new(ThreadPool::from_current_runtime(), &endpoint, None, &None, &None, "".into(), false)
+                .unwrap()
+                .with_max_response_bytes(MAX_BYTES);
+        assert!(matches!(
+            client.get_file(&file_hash, None, &provider, None).await,
+            Err(CasClientError::ResponseTooLarge(_))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_get_reconstruction_etag_revalidation() {
+        let file_hash = MerkleHash::default();
+        let path = format!("/reconstruction/{}", file_hash.hex());
+        let reconstruction = QueryReconstructionResponse {
+            offset_into_first_range: 7,
+            terms: vec![CASReconstructionTerm {
+                hash: HexMerkleHash::default(),
+                unpacked_length: 100,
+                range: ChunkRange { start: 0, end: 2 },
+            }],
+            fetch_info: HashMap::new(),
+        };
+
+        let server = MockServer::start();
+        let full_fetch = server.mock(|when, then| {
+            when.method(GET).path(path.clone()).matches(|req| {
+                !req.headers
+                    .iter()
+                    .flatten()
+                    .any(|(name, _)| name.eq_ignore_ascii_case("if-none-match"))
+            });
+            then.status(200).header("etag", "\"v1\"").json_body_obj(&reconstruction);
+        });
+        let revalidation = server.mock(|when, then| {
+            when.method(GET).path(path.clone()).header("if-none-match", "\"v1\"");
+            then.status(304);
+        });
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+
+        for _ in 0..2 {
+            let response = client.get_reconstruction(&file_hash, None).await.unwrap();
+            assert_eq!(response.offset_into_first_range, 7);
+            assert_eq!(response.terms.len(), 1);
+            assert_eq!(response.terms[0].unpacked_length, 100);
+        }
+        full_fetch.assert_hits(1);
+        revalidation.assert_hits(1);
+
+        // Once the cached response is past its max age, it's fetched again instead of revalidated.
+        let max_age = Duration::from_secs(*RECONSTRUCTION_CACHE_SLICE_MAX_AGE_SECS + 1);
+        for cached in client.reconstruction_cache.lock().unwrap().values_mut() {
+            cached.fetched_at = Instant::now().checked_sub(max_age).unwrap();
+        }
+        let response = client.get_reconstruction(&file_hash, None).await.unwrap();
+        assert_eq!(response.offset_into_first_range, 7);
+        full_fetch.assert_hits(2);
+        revalidation.assert_hits(1);
+
+        // The refetched response replaced the expired one, so it's revalidated again.
+        client.get_reconstruction(&file_hash, None).await.unwrap();
+        full_fetch.assert_hits(2);
+        revalidation.assert_hits(2);
+
+        // Without an ETag, every full file query is a full fetch.
+        let server = MockServer::start();
+        let full_fetch = server.mock(|when, then| {
+            when.method(GET).path(path.clone()).matches(|req| {
+                !req.headers
+                    .iter()
+                    .flatten()
+                    .any(|(name, _)| name.eq_ignore_ascii_case("if-none-match"))
+            });
+            then.status(200).json_body_obj(&reconstruction);
+        });
+
+        let client = RemoteClient::new(
+            ThreadPool::from_current_runtime(),
+            &server.base_url(),
+            None,
+            &None,
+            &None,
+            "".into(),
+            false,
+        )
+        .unwrap();
+
+        for _ in 0..2 {
+            let response = client.get_reconstruction(&file_hash, None).await.unwrap();
+            assert_eq!(response.offset_into_first_range, 7);
+        }
+        full_fetch.assert_hits(2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_version_changed_during_download() {
+        let (c, xorb_bytes, raw_data, _) = build_cas_object(4, ChunkSize::Fixed(2048), CompressionScheme::None);
+        let file_hash = MerkleHash::default();
+        let path = format!("/reconstruction/{}", file_hash.hex());
+
+        // The file is at version v1 when its reconstruction is queried; the check once the download
+        // completes finds it at `current`.
+        let run = |current: &'static str| {
+            let (c, xorb_bytes, path) = (&c, &xorb_bytes, path.clone());
+            async move {
+                let server = MockServer::start();
+                let reconstruction = mock_xorb_fetches(&server, c, xorb_bytes, 200, 2, Duration::ZERO);
+                let query = server.mock(|when, then| {
+                    when.method(GET).path(path.clone()).matches(|req| {
+                        !req.headers
+                            .iter()
+                            .flatten()
+                            .any(|(name, _)| name.eq_ignore_ascii_case("if-none-match"))
+                    });
+                    then.status(200).header("etag", "\"v1\"").json_body_obj(&reconstruction);
+                });
+                let check = server.mock(|when, then| {
+                    when.method(GET).path(path).header("if-none-match", "\"v1\"");
+                    if current == "\"v1\"" {
+                        then.status(304);
+                    } else {
+                        then.status(200).header("etag", current).json_body_obj(&reconstruction);
+                    }
+                });
+
+                let client = RemoteClient::new(
+                    ThreadPool::from_current_runtime(),
+                    &server.base_url(),
+                    None,
+                    &None,
+                    &None,
+                    "".into(),
+                    false,
+                )
+                .unwrap()
+                .with_version_validation(true);
+                let provider = BufferProvider::default();
+                let buf = provider.buf.clone();
+                let result = client.get_file(&file_hash, None, &OutputProvider::Buffer(provider), None).await;
+                query.assert_hits(1);
+                check.assert_hits(1);
+                (result, buf.value())
+            }
+        };
+
+        let (result, written) = run("\"v1\"").await;
+        assert_eq!(result.unwrap(), raw_data.len() as u64);
+        assert_eq!(written, raw_data);
+
+        let (result, written) = run("\"v2\"").await;
+        assert!(matches!(result, Err(CasClientError::VersionChanged(hash)) if hash == file_hash));
+        assert!(written.is_empty());
+    }
+
+    /// Reader that records the largest read requested of it, i.e. the most of the serialized
+    /// data the consumer buffers at once.
+    struct InstrumentedReader {
+        data: Cursor<Vec<u8>>,
+        max_read_len: Arc<AtomicUsize>,
+    }
+
+    impl Read for InstrumentedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.max_read_len.fetch_max(buf.len(), Ordering::Relaxed);
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_parse_large_reconstruction_response() {
+        const NUM_TERMS: u32 = 100_000;
+
+        let reconstruction = QueryReconstructionResponse {
+            offset_into_first_range: 0,
+            terms: (0..NUM_TERMS)
+                .map(|i| CASReconstructionTerm {
+                    hash: HexMerkleHash(MerkleHash::from([i as u64, 1, 2, 3])),
+                    unpacked_length: i,
+                    range: ChunkRange { start: i, end: i + 1 },
+                })
+                .collect(),
+            fetch_info: HashMap::new(),
+        };
+        let body = serde_json::to_vec(&reconstruction).unwrap();
+
+        let max_read_len = Arc::new(AtomicUsize::new(0));
+        let reader = InstrumentedReader {
+            data: Cursor::new(body.clone()),
+            max_read_len: max_read_len.clone(),
+        };
+        let parsed = parse_reconstruction_response(reader, *MAX_RECONSTRUCTION_TERMS).unwrap();
+
+        assert_eq!(parsed.terms.len(), NUM_TERMS as usize);
+        for (i, term) in parsed.terms.iter().enumerate() {
+            assert_eq!(term.hash, reconstruction.terms[i].hash);
+            assert_eq!(term.unpacked_length, i as u32);
+            assert_eq!(term.range, reconstruction.terms[i].range);
+        }
+
+        // Buffering the full response would hold all of it at once.
+        assert!(max_read_len.load(Ordering::Relaxed) * 100 < body.len());
+    }
+
+    #[test]
+    fn test_parse_reconstruction_response_term_limit() {
+        const NUM_TERMS: u32 = 100_000;
+        const MAX_TERMS: usize = 1000;
+
+        let reconstruction = QueryReconstructionResponse {
+            offset_into_first_range: 0,
+            terms: (0..NUM_TERMS)
+                .map(|i| CASReconstructionTerm {
+                    hash: HexMerkleHash(MerkleHash::from([i as u64, 1, 2, 3])),
+                    unpacked_length: i,
+                    range: ChunkRange { start: i, end: i + 1 },
+                })
+                .collect(),
+            fetch_info: HashMap::new(),
+        };
+        let body = serde_json::to_vec(&reconstruction).unwrap();
+
+        // Within the limit, the response parses as usual.
+        let parsed = parse_reconstruction_response(&body[..], NUM_TERMS as usize).unwrap();
+        assert_eq!(parsed.terms.len(), NUM_TERMS as usize);
+
+        // Past the limit, parsing stops early, having read only a small part of the response.
+        let mut reader = InstrumentedReader {
+            data: Cursor::new(body.clone()),
+            max_read_len: Arc::new(AtomicUsize::new(0)),
+        };
+        let result = parse_reconstruction_response(&mut reader, MAX_TERMS);
+        assert!(matches!(result, Err(CasClientError::ResponseTooLarge(_))), "{result:?}");
+        assert!(reader.data.position() * 10 < body.len() as u64);
+
+        // Malformed json is still a json error.
+        let result = parse_reconstruction_response(&body[..body.len() - 1], NUM_TERMS as usize);
+        assert!(matches!(result, Err(CasClientError::JsonError(_))), "{result:?}");
+    }
 }
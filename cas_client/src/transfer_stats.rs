@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+/// Timing and size information for a single reconstruction term.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TermTransferStats {
+    /// Number of bytes from this term written to the output.
+    pub bytes: u64,
+    /// Time taken to obtain the term data, from the start of the fetch (after any
+    /// concurrency permit is acquired) until the term data is ready; includes decompression.
+    pub fetch_duration: Duration,
+    /// Time from sending the range request until its whole body was received.  Zero on a cache
+    /// hit, or when the download was shared with a concurrent fetch of the same range.
+    pub network_duration: Duration,
+    /// Time spent deserializing and decompressing the fetched chunks, including regrouping the
+    /// bytes of byte grouped chunks.  Zero on a cache hit, or when the download was shared.
+    pub decompression_duration: Duration,
+    /// Whether the term was served from the chunk cache.
+    pub cache_hit: bool,
+}
+
+/// Aggregate transfer information for a single file download.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    /// Per-term stats, in the order of the terms in the reconstruction.
+    pub terms: Vec<TermTransferStats>,
+    /// Total number of bytes written to the output.
+    pub total_bytes: u64,
+    /// Total wall clock time of the download, including the reconstruction query.
+    pub wall_clock: Duration,
+    /// The most fetched terms seen waiting to be written at once.  Only tracked when terms are
+    /// written sequentially; 0 otherwise.
+    pub max_buffered_terms: usize,
+}
+
+impl TransferStats {
+    pub fn num_terms(&self) -> usize {
+        self.terms.len()
+    }
+
+    /// Sum of the network time over all terms.  As terms are fetched concurrently, this may
+    /// exceed the wall clock time.
+    pub fn total_network_duration(&self) -> Duration {
+        self.terms.iter().map(|t| t.network_duration).sum()
+    }
+
+    /// Sum of the decompression time over all terms.  As terms are fetched
+    /// concurrently, this may exceed the wall clock time.
+    pub fn total_decompression_duration(&self) -> Duration {
+        self.terms.iter().map(|t| t.decompression_duration).sum()
+    }
+
+    /// Returns the term fetch latency at the given percentile (in [0, 100]), using
+    /// the nearest-rank method, or None if there are no terms.
+    pub fn fetch_duration_percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.terms.is_empty() {
+            return None;
+        }
+        let mut durations: Vec<Duration> = self.terms.iter().map(|t| t.fetch_duration).collect();
+        durations.sort_unstable();
+
+        let percentile = percentile.clamp(0., 100.);
+        let rank = ((percentile / 100.) * durations.len() as f64).ceil() as usize;
+        Some(durations[rank.saturating_sub(1)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch_duration_percentile() {
+        let stats = TransferStats {
+            terms: (1..=20)
+                .rev()
+                .map(|ms| TermTransferStats {
+                    fetch_duration: Duration::from_millis(ms),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+
+        assert_eq!(stats.fetch_duration_percentile(0.), Some(Duration::from_millis(1)));
+        assert_eq!(stats.fetch_duration_percentile(50.), Some(Duration::from_millis(10)));
+        assert_eq!(stats.fetch_duration_percentile(95.), Some(Duration::from_millis(19)));
+        assert_eq!(stats.fetch_duration_percentile(100.), Some(Duration::from_millis(20)));
+        assert_eq!(TransferStats::default().fetch_duration_percentile(50.), None);
+    }
+}
@@ -0,0 +1,139 @@
+//! Measures the memory a large reconstruction response takes to receive and parse, with a global
+//! allocator that tracks the peak of the bytes allocated.  This is its own test binary, so that no
+//! other test allocates while the peak is measured.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use cas_client::{ReconstructionClient, RemoteClient};
+use cas_types::{CASReconstructionTerm, ChunkRange, HexMerkleHash, QueryReconstructionResponse};
+use merklehash::MerkleHash;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use xet_threadpool::ThreadPool;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+struct PeakAllocator;
+
+impl PeakAllocator {
+    fn allocated(size: usize) {
+        let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            Self::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            // Count the new allocation before freeing the old one, as a moving realloc holds both.
+            Self::allocated(new_size);
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static GLOBAL: PeakAllocator = PeakAllocator;
+
+/// The most memory allocated at once while `fut` runs, above what was allocated before it started.
+async fn peak_allocated_during<T>(fut: impl Future<Output = T>) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let output = fut.await;
+    let peak = PEAK.load(Ordering::Relaxed);
+    drop(output);
+    peak - before
+}
+
+/// Serves `body` as the response to every request, until the test ends.
+async fn serve(body: Arc<Vec<u8>>) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).await.unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+            }
+            let head = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n", body.len());
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+    });
+    endpoint
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_reconstruction_response_peak_memory() {
+    const NUM_TERMS: u32 = 100_000;
+
+    let reconstruction = QueryReconstructionResponse {
+        offset_into_first_range: 0,
+        terms: (0..NUM_TERMS)
+            .map(|i| CASReconstructionTerm {
+                hash: HexMerkleHash(MerkleHash::from([i as u64, 1, 2, 3])),
+                unpacked_length: i,
+                range: ChunkRange { start: i, end: i + 1 },
+            })
+            .collect(),
+        fetch_info: HashMap::new(),
+    };
+    let body = Arc::new(serde_json::to_vec(&reconstruction).unwrap());
+    drop(reconstruction);
+    let endpoint = serve(body.clone()).await;
+    let file_hash = MerkleHash::default();
+    let url = format!("{endpoint}/reconstruction/{}", file_hash.hex());
+
+    // The full-buffer approach: read the whole body, then parse it, then keep a copy of the
+    // response as the client's reconstruction cache does.
+    let http_client = reqwest::Client::new();
+    let full_buffer_peak = peak_allocated_during(async {
+        let body = http_client.get(&url).send().await.unwrap().bytes().await.unwrap();
+        let response: QueryReconstructionResponse = serde_json::from_slice(&body).unwrap();
+        drop(body);
+        (response.clone(), response)
+    })
+    .await;
+
+    // The client parses the body as it streams in.
+    let client =
+        RemoteClient::new(ThreadPool::from_current_runtime(), &endpoint, None, &None, &None, "".into(), false).unwrap();
+    let mut file_size = 0;
+    let streamed_peak = peak_allocated_during(async {
+        file_size = client.get_file_size(&file_hash).await.unwrap();
+    })
+    .await;
+    assert_eq!(file_size, (0..NUM_TERMS as u64).sum::<u64>());
+
+    // Holding the whole body at once costs at least its size over parsing it as it streams in.
+    assert!(
+        streamed_peak + body.len() / 2 < full_buffer_peak,
+        "streamed peak {streamed_peak}, full buffer peak {full_buffer_peak}, body {}",
+        body.len()
+    );
+}
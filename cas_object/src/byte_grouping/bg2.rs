@@ -0,0 +1,52 @@
+/// Splits the data into 2 groups, the bytes at even and at odd positions, and concatenates them.
+/// For 2 byte elements such as f16 and bf16, each group is one byte plane.
+pub fn bg2_split(data: &[u8]) -> Vec<u8> {
+    let n = data.len();
+    let mut d = Vec::with_capacity(n);
+    d.extend(data.iter().step_by(2));
+    d.extend(data.iter().skip(1).step_by(2));
+    d
+}
+
+/// Reverses `bg2_split`.
+pub fn bg2_regroup(g: &[u8]) -> Vec<u8> {
+    let mut data = vec![0u8; g.len()];
+    bg2_regroup_into(g, &mut data);
+    data
+}
+
+/// Like `bg2_regroup`, but writes the regrouped bytes to `data`, which must be as long as `g`.
+pub fn bg2_regroup_into(g: &[u8], data: &mut [u8]) {
+    assert_eq!(g.len(), data.len());
+    let n = g.len();
+    let (g0, g1) = g.split_at(n.div_ceil(2));
+
+    for (pair, (&b0, &b1)) in data.chunks_exact_mut(2).zip(g0.iter().zip(g1)) {
+        pair[0] = b0;
+        pair[1] = b1;
+    }
+    if n % 2 == 1 {
+        data[n - 1] = g0[n / 2];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+
+    use super::*;
+
+    #[test]
+    fn test_split_regroup() {
+        let mut rng = rand::thread_rng();
+
+        for n in (0..10).chain([64 * 1024, 64 * 1024 - 53, 64 * 1024 + 135]) {
+            let data: Vec<u8> = (0..n).map(|_| rng.gen()).collect();
+            let groups = bg2_split(&data);
+            assert_eq!(groups.len(), n);
+            assert!(groups[..n.div_ceil(2)].iter().eq(data.iter().step_by(2)));
+
+            assert_eq!(bg2_regroup(&groups), data);
+        }
+    }
+}
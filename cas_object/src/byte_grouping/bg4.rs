@@ -1,4 +1,18 @@
 use std::ptr::copy_nonoverlapping;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use xet_threadpool::ThreadPool;
+
+use crate::error::{CasObjectError, Result};
+
+/// Inputs at least this long are split or regrouped in parallel by `bg4_split_parallel` and
+/// `bg4_regroup_parallel`; below it the serial versions are faster than spawning tasks.
+const BG4_PARALLEL_MIN_BYTES: usize = 4 * 1024 * 1024;
+
+/// The number of input bytes each parallel task handles.  A multiple of 4, so that every segment but
+/// the last holds whole groups of 4 bytes.
+const BG4_PARALLEL_SEGMENT_BYTES: usize = 1024 * 1024;
 
 pub fn bg4_split_separate(data: &[u8]) -> [Vec<u8>; 4] {
     let n = data.len();
@@ -119,12 +133,19 @@ pub fn bg4_regroup_separate(groups: &[Vec<u8>]) -> Vec<u8> {
 }
 
 pub fn bg4_regroup_together(g: &[u8]) -> Vec<u8> {
+    let mut data = vec![0u8; g.len()];
+    bg4_regroup_together_into(g, &mut data);
+    data
+}
+
+/// Like `bg4_regroup_together`, but writes the regrouped bytes to `data`, which must be as long as
+/// `g`.
+pub fn bg4_regroup_together_into(g: &[u8], data: &mut [u8]) {
+    assert_eq!(g.len(), data.len());
     let n = g.len();
     let split = n / 4;
     let rem = n % 4;
 
-    let mut data = vec![0u8; n];
-
     unsafe {
         let data = data.as_mut_ptr();
         let g0 = g.as_ptr();
@@ -155,8 +176,6 @@ pub fn bg4_regroup_together(g: &[u8]) -> Vec<u8> {
             _ => (),
         }
     }
-
-    data
 }
 
 pub fn bg4_regroup_together_combined_write_4(g: &[u8]) -> Vec<u8> {
@@ -259,6 +278,124 @@ pub fn bg4_regroup(g: &[u8]) -> Vec<u8> {
     bg4_regroup_together(g)
 }
 
+/// Like `bg4_regroup`, but writes the regrouped bytes to `data`, which must be as long as `g`.
+#[inline]
+pub fn bg4_regroup_into(g: &[u8], data: &mut [u8]) {
+    bg4_regroup_together_into(g, data)
+}
+
+/// The lengths of the 4 groups that `n` bytes are split into.
+fn bg4_group_lengths(n: usize) -> [usize; 4] {
+    let split = n / 4;
+    let rem = n % 4;
+    [0, 1, 2, 3].map(|k| split + usize::from(k < rem))
+}
+
+/// Like `bg4_split`, but for inputs of at least `BG4_PARALLEL_MIN_BYTES`, splits segments of the
+/// input in parallel on the blocking threads of `threadpool` and concatenates the groups of the
+/// segments.  The output is identical to that of `bg4_split`.
+pub async fn bg4_split_parallel(threadpool: &ThreadPool, data: &[u8]) -> Result<Vec<u8>> {
+    bg4_split_parallel_impl(threadpool, data, BG4_PARALLEL_MIN_BYTES, BG4_PARALLEL_SEGMENT_BYTES).await
+}
+
+async fn bg4_split_parallel_impl(
+    threadpool: &ThreadPool,
+    data: &[u8],
+    min_bytes: usize,
+    segment_bytes: usize,
+) -> Result<Vec<u8>> {
+    debug_assert!(segment_bytes > 0 && segment_bytes % 4 == 0);
+    let n = data.len();
+    if n < min_bytes || n <= segment_bytes {
+        return Ok(bg4_split(data));
+    }
+
+    let data: Arc<[u8]> = Arc::from(data);
+    let tasks: Vec<_> = (0..n)
+        .step_by(segment_bytes)
+        .map(|start| {
+            let data = data.clone();
+            threadpool.spawn_blocking(move || bg4_split(&data[start..(start + segment_bytes).min(n)]))
+        })
+        .collect();
+    let mut segments = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        segments.push(
+            task.await
+                .map_err(|e| CasObjectError::InternalError(anyhow!("bg4 split task failed: {e}")))?,
+        );
+    }
+
+    // Every segment but the last starts at a multiple of 4, so group k of the whole input is the
+    // concatenation of group k of every segment.
+    let mut split = Vec::with_capacity(n);
+    let segment_groups: Vec<_> = segments.iter().map(|s| bg4_group_lengths(s.len())).collect();
+    for k in 0..4 {
+        for (segment, lengths) in segments.iter().zip(&segment_groups) {
+            let offset: usize = lengths[..k].iter().sum();
+            split.extend_from_slice(&segment[offset..offset + lengths[k]]);
+        }
+    }
+    Ok(split)
+}
+
+/// Like `bg4_regroup`, but for inputs of at least `BG4_PARALLEL_MIN_BYTES`, regroups segments of
+/// the groups in parallel on the blocking threads of `threadpool` and concatenates the regrouped
+/// segments.  The output is identical to that of `bg4_regroup`.
+pub async fn bg4_regroup_parallel(threadpool: &ThreadPool, g: &[u8]) -> Result<Vec<u8>> {
+    bg4_regroup_parallel_impl(threadpool, g, BG4_PARALLEL_MIN_BYTES, BG4_PARALLEL_SEGMENT_BYTES).await
+}
+
+async fn bg4_regroup_parallel_impl(
+    threadpool: &ThreadPool,
+    g: &[u8],
+    min_bytes: usize,
+    segment_bytes: usize,
+) -> Result<Vec<u8>> {
+    debug_assert!(segment_bytes > 0 && segment_bytes % 4 == 0);
+    let n = g.len();
+    if n < min_bytes || n <= segment_bytes {
+        return Ok(bg4_regroup(g));
+    }
+
+    let lengths = bg4_group_lengths(n);
+    let offsets = [
+        0,
+        lengths[0],
+        lengths[0] + lengths[1],
+        lengths[0] + lengths[1] + lengths[2],
+    ];
+    let split = n / 4;
+
+    let g: Arc<[u8]> = Arc::from(g);
+    let tasks: Vec<_> = (0..split)
+        .step_by(segment_bytes / 4)
+        .map(|start| {
+            let g = g.clone();
+            threadpool.spawn_blocking(move || {
+                // Positions start..end of every group, with the last segment also taking the
+                // trailing bytes of the longer groups, form the groups of a segment of the output.
+                let end = (start + segment_bytes / 4).min(split);
+                let mut segment = Vec::with_capacity(4 * (end - start) + 3);
+                for k in 0..4 {
+                    let group_end = if end == split { lengths[k] } else { end };
+                    segment.extend_from_slice(&g[offsets[k] + start..offsets[k] + group_end]);
+                }
+                bg4_regroup(&segment)
+            })
+        })
+        .collect();
+
+    let mut data = Vec::with_capacity(n);
+    for task in tasks {
+        let segment = task
+            .await
+            .map_err(|e| CasObjectError::InternalError(anyhow!("bg4 regroup task failed: {e}")))?;
+        data.extend_from_slice(&segment);
+    }
+    Ok(data)
+}
+
 #[cfg(test)]
 mod tests {
     use rand::Rng;
@@ -296,4 +433,63 @@ mod tests {
             assert_eq!(regrouped, data);
         }
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_parallel_matches_serial() {
+        let threadpool = ThreadPool::from_current_runtime();
+        let mut rng = rand::thread_rng();
+
+        // Small segments exercise many segment counts and remainders on small inputs.
+        for n in (0..300).chain([1021, 1024, 1027, 4093, 4096, 4099]) {
+            let data: Vec<u8> = (0..n).map(|_| rng.gen()).collect();
+            let split = bg4_split(&data);
+            for segment_bytes in [4, 8, 64, 1024] {
+                let parallel_split = bg4_split_parallel_impl(&threadpool, &data, 0, segment_bytes).await.unwrap();
+                assert_eq!(parallel_split, split, "n = {n}, segment_bytes = {segment_bytes}");
+
+                let parallel_regroup = bg4_regroup_parallel_impl(&threadpool, &split, 0, segment_bytes).await.unwrap();
+                assert_eq!(parallel_regroup, data, "n = {n}, segment_bytes = {segment_bytes}");
+            }
+        }
+
+        // Around the default threshold.
+        for n in [
+            BG4_PARALLEL_MIN_BYTES - 1,
+            BG4_PARALLEL_MIN_BYTES,
+            BG4_PARALLEL_MIN_BYTES + 3,
+        ] {
+            let data: Vec<u8> = (0..n).map(|_| rng.gen()).collect();
+            let split = bg4_split_parallel(&threadpool, &data).await.unwrap();
+            assert_eq!(split, bg4_split(&data));
+            assert_eq!(bg4_regroup_parallel(&threadpool, &split).await.unwrap(), data);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    #[ignore = "run manually"]
+    async fn test_parallel_speedup() {
+        let threadpool = ThreadPool::from_current_runtime();
+        let mut rng = rand::thread_rng();
+        let n = 64 * 1024 * 1024 + 3;
+        let data: Vec<u8> = (0..n).map(|_| rng.gen()).collect();
+        const ITER: u32 = 10;
+
+        let start = std::time::Instant::now();
+        for _ in 0..ITER {
+            std::hint::black_box(bg4_regroup(&bg4_split(&data)));
+        }
+        let serial = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for _ in 0..ITER {
+            let split = bg4_split_parallel(&threadpool, &data).await.unwrap();
+            std::hint::black_box(bg4_regroup_parallel(&threadpool, &split).await.unwrap());
+        }
+        let parallel = start.elapsed();
+
+        println!("serial: {serial:?}, parallel: {parallel:?} for {ITER} split and regroup rounds of {n} bytes");
+        if std::thread::available_parallelism().map_or(1, |p| p.get()) >= 4 {
+            assert!(parallel < serial);
+        }
+    }
 }
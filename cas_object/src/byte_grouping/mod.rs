@@ -1 +1,2 @@
+pub mod bg2;
 pub mod bg4;
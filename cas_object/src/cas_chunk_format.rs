@@ -5,7 +5,7 @@ use anyhow::anyhow;
 use merkledb::constants::MAXIMUM_CHUNK_SIZE;
 
 use crate::error::CasObjectError;
-use crate::CompressionScheme;
+use crate::{CompressionScheme, DecompressionBufferPool};
 
 pub mod deserialize_async;
 
@@ -58,7 +58,7 @@ impl CASChunkHeader {
     }
 
     pub fn set_compression_scheme(&mut self, compression_scheme: CompressionScheme) {
-        self.compression_scheme = compression_scheme as u8;
+        self.compression_scheme = compression_scheme.into();
     }
 
     fn validate(&self) -> Result<(), CasObjectError> {
@@ -113,6 +113,16 @@ pub fn serialize_chunk<W: Write>(
     w: &mut W,
     compression_scheme: Option<CompressionScheme>,
 ) -> Result<usize, CasObjectError> {
+    Ok(serialize_chunk_with_scheme(chunk, w, compression_scheme)?.0)
+}
+
+/// Like `serialize_chunk`, but also returns the compression scheme recorded in the chunk header,
+/// which is `CompressionScheme::None` if compression didn't shrink the chunk.
+pub(crate) fn serialize_chunk_with_scheme<W: Write>(
+    chunk: &[u8],
+    w: &mut W,
+    compression_scheme: Option<CompressionScheme>,
+) -> Result<(usize, CompressionScheme), CasObjectError> {
     let compression_scheme = compression_scheme.unwrap_or_else(|| CompressionScheme::choose_from_data(chunk));
 
     let compressed = compression_scheme.compress_from_slice(chunk)?;
@@ -128,7 +138,7 @@ pub fn serialize_chunk<W: Write>(
     write_chunk_header(w, &header)?;
     w.write_all(&compressed)?;
 
-    Ok(size_of::<CASChunkHeader>() + compressed.len())
+    Ok((size_of::<CASChunkHeader>() + compressed.len(), compression_scheme))
 }
 
 pub fn parse_chunk_header(chunk_header_bytes: [u8; CAS_CHUNK_HEADER_LENGTH]) -> Result<CASChunkHeader, CasObjectError> {
@@ -153,13 +163,33 @@ pub fn deserialize_chunk<R: Read>(reader: &mut R) -> Result<(Vec<u8>, usize, u32
 pub fn deserialize_chunk_to_writer<R: Read, W: Write>(
     reader: &mut R,
     writer: &mut W,
+) -> Result<(usize, u32), CasObjectError> {
+    deserialize_chunk_to_writer_impl(reader, writer, None)
+}
+
+/// Like `deserialize_chunk_to_writer`, but decompresses the chunk with scratch buffers from `pool`
+/// if given; see `CompressionScheme::decompress_from_reader_with_pool`.
+pub fn deserialize_chunk_to_writer_with_pool<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    pool: Option<&DecompressionBufferPool>,
+) -> Result<(usize, u32), CasObjectError> {
+    deserialize_chunk_to_writer_impl(reader, writer, pool)
+}
+
+fn deserialize_chunk_to_writer_impl<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    pool: Option<&DecompressionBufferPool>,
 ) -> Result<(usize, u32), CasObjectError> {
     let header = deserialize_chunk_header(reader)?;
     let mut compressed_data_reader = reader.take(header.get_compressed_length().into());
 
-    let uncompressed_len = header
-        .get_compression_scheme()?
-        .decompress_from_reader(&mut compressed_data_reader, writer)?;
+    let compression_scheme = header.get_compression_scheme()?;
+    let uncompressed_len = match pool {
+        Some(pool) => compression_scheme.decompress_from_reader_with_pool(&mut compressed_data_reader, writer, pool)?,
+        None => compression_scheme.decompress_from_reader(&mut compressed_data_reader, writer)?,
+    };
 
     if uncompressed_len != header.get_uncompressed_length() as u64 {
         return Err(CasObjectError::FormatError(anyhow!(
@@ -176,9 +206,28 @@ pub fn deserialize_chunks<R: Read>(reader: &mut R) -> Result<(Vec<u8>, Vec<u32>)
     Ok((buf, chunk_byte_indices))
 }
 
+/// Like `deserialize_chunks`, but decompresses the chunks with scratch buffers from `pool`; see
+/// `CompressionScheme::decompress_from_reader_with_pool`.
+pub fn deserialize_chunks_with_pool<R: Read>(
+    reader: &mut R,
+    pool: &DecompressionBufferPool,
+) -> Result<(Vec<u8>, Vec<u32>), CasObjectError> {
+    let mut buf = Vec::new();
+    let (_, chunk_byte_indices) = deserialize_chunks_to_writer_impl(reader, &mut buf, Some(pool))?;
+    Ok((buf, chunk_byte_indices))
+}
+
 pub fn deserialize_chunks_to_writer<R: Read, W: Write>(
     reader: &mut R,
     writer: &mut W,
+) -> Result<(usize, Vec<u32>), CasObjectError> {
+    deserialize_chunks_to_writer_impl(reader, writer, None)
+}
+
+fn deserialize_chunks_to_writer_impl<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    pool: Option<&DecompressionBufferPool>,
 ) -> Result<(usize, Vec<u32>), CasObjectError> {
     let mut num_compressed_written = 0;
     let mut num_uncompressed_written = 0;
@@ -189,7 +238,7 @@ pub fn deserialize_chunks_to_writer<R: Read, W: Write>(
     chunk_byte_indices.push(num_compressed_written as u32);
 
     loop {
-        match deserialize_chunk_to_writer(reader, writer) {
+        match deserialize_chunk_to_writer_impl(reader, writer, pool) {
             Ok((delta_written, uncompressed_chunk_len)) => {
                 num_compressed_written += delta_written;
                 num_uncompressed_written += uncompressed_chunk_len;
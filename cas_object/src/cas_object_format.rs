@@ -14,9 +14,9 @@ use serde::Serialize;
 use tracing::warn;
 use utils::serialization_utils::*;
 
-use crate::cas_chunk_format::{deserialize_chunk, serialize_chunk};
+use crate::cas_chunk_format::{deserialize_chunk, serialize_chunk_with_scheme};
 use crate::error::{CasObjectError, Validate};
-use crate::CompressionScheme;
+use crate::{sniff_incompressible_format, CompressionScheme, SchemeChoice, XorbSchemeLearner};
 
 pub type CasObjectIdent = [u8; 7];
 pub(crate) const CAS_OBJECT_FORMAT_IDENT: CasObjectIdent = [b'X', b'E', b'T', b'B', b'L', b'O', b'B'];
@@ -32,6 +32,25 @@ pub(crate) const CAS_OBJECT_FORMAT_BOUNDARIES_VERSION: u8 = 1;
 const _CAS_OBJECT_INFO_DEFAULT_LENGTH_V0: u32 = 60;
 const CAS_OBJECT_INFO_DEFAULT_LENGTH: u32 = 92;
 
+/// After this many chunks in a row are stored uncompressed because compression didn't shrink them,
+/// e.g. for already compressed input, the rest of the xorb skips compressing its chunks.
+const INCOMPRESSIBLE_CHUNK_RUN: usize = 8;
+/// Once compression is skipped, every this many chunks one chunk is compressed again, so that
+/// compressible data later in the xorb turns compression back on.
+const COMPRESSION_PROBE_INTERVAL: usize = 16;
+
+utils::configurable_constants! {
+    // Whether a xorb serialized without a given compression scheme gets one scheme for all its
+    // chunks, picked by trial compressing a sample of it, rather than a scheme per chunk picked by
+    // heuristic; see CompressionScheme::choose_for_xorb.
+    ref XORB_COMPRESSION_TRIAL: bool = true;
+
+    // Whether a chunk starting with the magic bytes of an already compressed format, e.g. the first
+    // chunk of a JPEG file, starts a run of uncompressed chunks without analyzing the data; see
+    // sniff_incompressible_format.
+    ref SNIFF_INCOMPRESSIBLE_FORMATS: bool = true;
+}
+
 const AVERAGE_NUM_CHUNKS_PER_XORB: usize = IDEAL_CAS_BLOCK_SIZE / TARGET_CDC_CHUNK_SIZE;
 // Decide array preallocation size based on the declared size, to prevent an adversarial
 // giant size that leads to OOM on allocation.
@@ -958,12 +977,44 @@ impl CasObject {
 
     /// Serialize into Cas Object from uncompressed data and chunk boundaries.
     /// Assumes correctness from caller: it's the receiver's responsibility to validate a cas object.
+    ///
+    /// Without a `compression_scheme`, the scheme is chosen once for the xorb if
+    /// `XORB_COMPRESSION_TRIAL` is on, and per chunk otherwise.  Either way, chunks that don't
+    /// shrink are stored uncompressed; the scheme is recorded in each chunk's header.
+    ///
+    /// A chunk that starts a file of an already compressed format, such as JPEG or MP4, is stored
+    /// uncompressed, along with the chunks after it until a probe finds compressible data, as
+    /// after a run of chunks that didn't shrink.
     pub fn serialize<W: Write + Seek>(
         writer: &mut W,
         hash: &MerkleHash,
         data: &[u8],
         chunk_and_boundaries: &[(MerkleHash, u32)],
         compression_scheme: Option<CompressionScheme>,
+    ) -> Result<(Self, usize), CasObjectError> {
+        Self::serialize_impl(writer, hash, data, chunk_and_boundaries, compression_scheme, None)
+    }
+
+    /// Like `serialize` without a compression scheme, but with the xorb's scheme picked by
+    /// `scheme_learner` when it would be trial compressed, so that the xorbs of a session can share
+    /// the scheme their first xorbs agree on.
+    pub fn serialize_with_learner<W: Write + Seek>(
+        writer: &mut W,
+        hash: &MerkleHash,
+        data: &[u8],
+        chunk_and_boundaries: &[(MerkleHash, u32)],
+        scheme_learner: &XorbSchemeLearner,
+    ) -> Result<(Self, usize), CasObjectError> {
+        Self::serialize_impl(writer, hash, data, chunk_and_boundaries, None, Some(scheme_learner))
+    }
+
+    fn serialize_impl<W: Write + Seek>(
+        writer: &mut W,
+        hash: &MerkleHash,
+        data: &[u8],
+        chunk_and_boundaries: &[(MerkleHash, u32)],
+        compression_scheme: Option<CompressionScheme>,
+        scheme_learner: Option<&XorbSchemeLearner>,
     ) -> Result<(Self, usize), CasObjectError> {
         let mut cas = CasObject::default();
         cas.info.cashash = *hash;
@@ -975,16 +1026,51 @@ impl CasObject {
             .map(|(_, unpacked_chunk_boundary)| *unpacked_chunk_boundary)
             .collect();
 
+        // Picked on first use, so that a xorb whose chunks all skip compression isn't trial compressed.
+        let mut trial_scheme: Option<SchemeChoice> = None;
+
         let mut total_written_bytes: usize = 0;
 
+        // number of chunks in a row stored with CompressionScheme::None
+        let mut incompressible_run = 0;
+
         let mut raw_start_idx = 0;
         for boundary in chunk_and_boundaries {
             let chunk_boundary: u32 = boundary.1;
 
             let chunk_raw_bytes = &data[raw_start_idx as usize..chunk_boundary as usize];
 
+            if *SNIFF_INCOMPRESSIBLE_FORMATS && sniff_incompressible_format(chunk_raw_bytes).is_some() {
+                incompressible_run = incompressible_run.max(INCOMPRESSIBLE_CHUNK_RUN);
+            }
+
+            let skip_compression = incompressible_run >= INCOMPRESSIBLE_CHUNK_RUN
+                && (incompressible_run - INCOMPRESSIBLE_CHUNK_RUN + 1) % COMPRESSION_PROBE_INTERVAL != 0;
+            let chunk_compression_scheme = if skip_compression {
+                Some(CompressionScheme::None)
+            } else if compression_scheme.is_none() && *XORB_COMPRESSION_TRIAL {
+                if trial_scheme.is_none() {
+                    trial_scheme = Some(match scheme_learner {
+                        Some(scheme_learner) => scheme_learner.choose(data)?,
+                        None => SchemeChoice {
+                            scheme: CompressionScheme::choose_for_xorb(data)?,
+                            learned: false,
+                        },
+                    });
+                }
+                trial_scheme.map(|choice| choice.scheme)
+            } else {
+                compression_scheme
+            };
+
             // now serialize chunk directly to writer (since chunks come first!)
-            let chunk_written_bytes = serialize_chunk(chunk_raw_bytes, writer, compression_scheme)?;
+            let (chunk_written_bytes, used_scheme) =
+                serialize_chunk_with_scheme(chunk_raw_bytes, writer, chunk_compression_scheme)?;
+            if used_scheme == CompressionScheme::None {
+                incompressible_run += 1;
+            } else {
+                incompressible_run = 0;
+            }
             total_written_bytes += chunk_written_bytes;
             cas.info.chunk_boundary_offsets.push(total_written_bytes as u32);
 
@@ -992,6 +1078,10 @@ impl CasObject {
             raw_start_idx = chunk_boundary;
         }
 
+        if let (Some(scheme_learner), Some(choice)) = (scheme_learner, trial_scheme) {
+            scheme_learner.record(choice, data.len(), total_written_bytes);
+        }
+
         cas.info.fill_in_boundary_offsets();
 
         // now that footer is ready, write out to writer.
@@ -1201,6 +1291,56 @@ impl CasObject {
         self.get_range(reader, byte_start, byte_end)
     }
 
+    /// Get the uncompressed bytes `byte_start..byte_end` of the chunk range [chunk_index_start,
+    /// chunk_index_end), with byte offsets counted from the start of the chunk range.
+    ///
+    /// Chunks are compressed independently, so only the chunks overlapping the byte range are read
+    /// and decompressed.  Xorbs without unpacked chunk offsets (the v0 format) don't record where
+    /// each chunk starts once uncompressed; for them the whole chunk range is decompressed.
+    pub fn get_bytes_in_chunk_range<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        chunk_index_start: u32,
+        chunk_index_end: u32,
+        byte_start: u32,
+        byte_end: u32,
+    ) -> Result<Vec<u8>, CasObjectError> {
+        if byte_end < byte_start {
+            return Err(CasObjectError::InvalidRange);
+        }
+        self.validate_cas_object_info()?;
+        if chunk_index_end <= chunk_index_start || chunk_index_end > self.info.num_chunks {
+            return Err(CasObjectError::InvalidArguments);
+        }
+
+        let offsets = &self.info.unpacked_chunk_offsets;
+        if offsets.len() != self.info.num_chunks as usize {
+            let data = self.get_bytes_by_chunk_range(reader, chunk_index_start, chunk_index_end)?;
+            let end = (byte_end as usize).min(data.len());
+            return Ok(data[(byte_start as usize).min(end)..end].to_vec());
+        }
+
+        let unpacked_start = |chunk_index: usize| if chunk_index == 0 { 0 } else { offsets[chunk_index - 1] };
+        let range_offset = unpacked_start(chunk_index_start as usize);
+        let start = range_offset.saturating_add(byte_start);
+        let end = range_offset.saturating_add(byte_end).min(offsets[chunk_index_end as usize - 1]);
+        if start >= end {
+            return Ok(vec![]);
+        }
+
+        // The first chunk ending after start, through the first chunk ending at or after end.
+        let chunk_offsets = &offsets[chunk_index_start as usize..chunk_index_end as usize];
+        let first_chunk = chunk_index_start as usize + chunk_offsets.partition_point(|&offset| offset <= start);
+        let last_chunk = chunk_index_start as usize + chunk_offsets.partition_point(|&offset| offset < end);
+
+        let data = self.get_bytes_by_chunk_range(reader, first_chunk as u32, last_chunk as u32 + 1)?;
+        let data_offset = unpacked_start(first_chunk);
+        if data.len() < (end - data_offset) as usize {
+            return Err(CasObjectError::FormatError(anyhow!("Chunk data shorter than its unpacked chunk offsets")));
+        }
+        Ok(data[(start - data_offset) as usize..(end - data_offset) as usize].to_vec())
+    }
+
     /// Assumes chunk_data is 1+ complete chunks. Processes them sequentially and returns them as Vec<u8>.
     fn get_chunk_contents(&self, chunk_data: &[u8]) -> Result<Vec<u8>, CasObjectError> {
         // walk chunk_data, deserialize into Chunks, and then get_bytes() from each of them.
@@ -2023,6 +2163,76 @@ mod tests {
         assert!(c.uncompressed_range_length(NUM_CHUNKS + 2, NUM_CHUNKS + 1).is_err());
     }
 
+    /// Reads from the wrapped reader, counting the bytes read.
+    struct CountingReader<R> {
+        inner: R,
+        bytes_read: usize,
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.bytes_read += n;
+            Ok(n)
+        }
+    }
+
+    impl<R: Seek> Seek for CountingReader<R> {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn test_get_bytes_in_chunk_range() {
+        const NUM_CHUNKS: u32 = 64;
+        let (mut c, cas_data, raw_data, raw_chunk_boundaries) =
+            build_cas_object(NUM_CHUNKS, ChunkSize::Random(512, 2048), CompressionScheme::LZ4);
+        let mut reader = CountingReader {
+            inner: Cursor::new(cas_data),
+            bytes_read: 0,
+        };
+
+        let chunk_start = |i: u32| {
+            if i == 0 {
+                0
+            } else {
+                raw_chunk_boundaries[i as usize - 1].1 as usize
+            }
+        };
+        for (chunk_index_start, chunk_index_end, byte_start, byte_end) in [
+            (0, NUM_CHUNKS, 1000, 1100),
+            (10, 50, 300, 2000),
+            (10, 50, 0, u32::MAX),
+            (20, 21, 5, 5),
+            (63, 64, 0, 1),
+        ] {
+            let range_data = &raw_data[chunk_start(chunk_index_start)..chunk_start(chunk_index_end)];
+            let end = (byte_end as usize).min(range_data.len());
+            let expected = &range_data[(byte_start as usize).min(end)..end];
+
+            reader.bytes_read = 0;
+            let data = c
+                .get_bytes_in_chunk_range(&mut reader, chunk_index_start, chunk_index_end, byte_start, byte_end)
+                .unwrap();
+            assert_eq!(data, expected);
+            if expected.len() < 600 {
+                // At most two chunks are decompressed for a small range of a large xorb.
+                assert!(reader.bytes_read < 2 * 2048 + 100, "read {} bytes", reader.bytes_read);
+            }
+        }
+        assert_eq!(c.get_bytes_in_chunk_range(&mut reader, 0, 2, 10, 5), Err(CasObjectError::InvalidRange));
+        assert_eq!(c.get_bytes_in_chunk_range(&mut reader, 2, 2, 0, 5), Err(CasObjectError::InvalidArguments));
+
+        // Without unpacked chunk offsets, as in v0 xorbs, the whole chunk range is decompressed.
+        c.info.boundaries_version = 0;
+        c.info.unpacked_chunk_offsets.clear();
+        reader.bytes_read = 0;
+        let data = c.get_bytes_in_chunk_range(&mut reader, 0, NUM_CHUNKS, 1000, 1100).unwrap();
+        assert_eq!(data, &raw_data[1000..1100]);
+        assert_eq!(reader.bytes_read, c.get_contents_length().unwrap() as usize);
+    }
+
     #[test]
     fn test_deserialize_only_boundaries_section() {
         const CHUNK_SIZE: u32 = 100;
@@ -2065,4 +2275,223 @@ mod tests {
             assert!(original.has_chunk_hashes());
         }
     }
+
+    /// Serializes the chunks as one xorb with the given compression and returns the compression scheme
+    /// recorded in each chunk header, checking that the chunks round trip.
+    fn serialized_chunk_schemes(
+        chunks: &[Vec<u8>],
+        compression_scheme: Option<CompressionScheme>,
+    ) -> Vec<CompressionScheme> {
+        let data = chunks.concat();
+        let mut chunk_and_boundaries = Vec::new();
+        let mut offset = 0;
+        for chunk in chunks {
+            offset += chunk.len() as u32;
+            chunk_and_boundaries.push((merklehash::compute_data_hash(chunk), offset));
+        }
+
+        let mut writer = Cursor::new(Vec::new());
+        let (c, _) = CasObject::serialize(
+            &mut writer,
+            &merklehash::compute_data_hash(&data),
+            &data,
+            &chunk_and_boundaries,
+            compression_scheme,
+        )
+        .unwrap();
+        let xorb = writer.into_inner();
+
+        let mut reader = Cursor::new(&xorb);
+        assert_eq!(c.get_all_bytes(&mut reader).unwrap(), data);
+
+        let mut start = 0;
+        c.info
+            .chunk_boundary_offsets
+            .iter()
+            .map(|&end| {
+                let mut header_reader = &xorb[start..end as usize];
+                start = end as usize;
+                crate::cas_chunk_format::deserialize_chunk_header(&mut header_reader)
+                    .unwrap()
+                    .get_compression_scheme()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn gen_text_chunk(seed: usize) -> Vec<u8> {
+        (0..200)
+            .map(|i| format!("line {} of some compressible text\n", seed + i))
+            .collect::<String>()
+            .into_bytes()[..AUTO_DISABLE_CHUNK_SIZE as usize]
+            .to_vec()
+    }
+
+    const AUTO_DISABLE_CHUNK_SIZE: u32 = 4096;
+
+    #[test]
+    fn test_compression_auto_disable() {
+        // Random data doesn't compress, so every chunk is stored uncompressed.
+        let random_chunks: Vec<_> = (0..40).map(|_| gen_random_bytes(AUTO_DISABLE_CHUNK_SIZE)).collect();
+        assert!(serialized_chunk_schemes(&random_chunks, Some(CompressionScheme::LZ4))
+            .into_iter()
+            .all(|scheme| scheme == CompressionScheme::None));
+
+        // Text compresses throughout.
+        let text_chunks: Vec<_> = (0..40).map(|i| gen_text_chunk(i * 1000)).collect();
+        assert!(serialized_chunk_schemes(&text_chunks, Some(CompressionScheme::LZ4))
+            .into_iter()
+            .all(|scheme| scheme == CompressionScheme::LZ4));
+
+        // After a run of incompressible chunks compression is skipped, until a probe finds
+        // compressible data again.
+        let mixed_chunks: Vec<_> = random_chunks[..INCOMPRESSIBLE_CHUNK_RUN]
+            .iter()
+            .chain(text_chunks.iter())
+            .cloned()
+            .collect();
+        let schemes = serialized_chunk_schemes(&mixed_chunks, Some(CompressionScheme::LZ4));
+        let probe_index = INCOMPRESSIBLE_CHUNK_RUN + COMPRESSION_PROBE_INTERVAL - 1;
+        assert!(schemes[..probe_index].iter().all(|&scheme| scheme == CompressionScheme::None));
+        assert!(schemes[probe_index..].iter().all(|&scheme| scheme == CompressionScheme::LZ4));
+    }
+
+    #[test]
+    fn test_sniffed_format_skips_compression() {
+        use crate::compression_scheme::SCHEME_ANALYSIS_COUNT;
+
+        // A fake JPEG file: a JFIF header followed by data that would compress well.
+        let mut jpeg_chunks: Vec<_> = (0..4).map(|i| gen_text_chunk(i * 1000)).collect();
+        jpeg_chunks[0].splice(0..0, [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0x00]);
+
+        // The header alone picks no compression, without analyzing the data for a scheme.
+        SCHEME_ANALYSIS_COUNT.set(0);
+        assert!(serialized_chunk_schemes(&jpeg_chunks, None)
+            .into_iter()
+            .all(|scheme| scheme == CompressionScheme::None));
+        assert_eq!(SCHEME_ANALYSIS_COUNT.get(), 0);
+
+        // Without the header, the scheme is chosen by analysis as usual.
+        jpeg_chunks[0].drain(..11);
+        assert!(serialized_chunk_schemes(&jpeg_chunks, None)
+            .into_iter()
+            .all(|scheme| scheme != CompressionScheme::None));
+        assert!(SCHEME_ANALYSIS_COUNT.get() > 0);
+    }
+
+    #[test]
+    fn test_compression_scheme_per_xorb() {
+        use half::prelude::*;
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(0);
+        // Normally distributed, like model weights; Box-Muller transform.
+        let values: Vec<f64> = (0..64 * 1024)
+            .map(|_| {
+                let (u1, u2) = (rng.gen_range(f64::EPSILON..1.0), rng.gen::<f64>());
+                0.02 * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+            })
+            .collect();
+        let to_chunks = |data: Vec<u8>| data.chunks(16 * 1024).map(<[u8]>::to_vec).collect::<Vec<_>>();
+
+        // Xorbs of different dtypes, each compressed with one scheme for all its chunks.
+        let bf16_chunks = to_chunks(values.iter().flat_map(|&v| bf16::from_f64(v).to_le_bytes()).collect());
+        let f32_chunks = to_chunks(values.iter().flat_map(|&v| (v as f32).to_le_bytes()).collect());
+        let words = [
+            "the",
+            "xorb",
+            "of",
+            "chunks",
+            "is",
+            "compressed",
+            "once",
+            "with",
+            "a",
+            "scheme",
+        ];
+        let text: String = (0..32 * 1024)
+            .map(|_| words[rng.gen_range(0..words.len())])
+            .collect::<Vec<_>>()
+            .join(" ");
+        let text_chunks = to_chunks(text.into_bytes());
+        for (chunks, expected) in [
+            (&bf16_chunks, CompressionScheme::ByteGrouping2LZ4),
+            (&f32_chunks, CompressionScheme::ByteGrouping4LZ4),
+            (&text_chunks, CompressionScheme::LZ4),
+        ] {
+            assert_eq!(CompressionScheme::choose_for_xorb(&chunks.concat()).unwrap(), expected);
+
+            // Chunks the scheme doesn't shrink are still stored uncompressed.
+            let schemes = serialized_chunk_schemes(chunks, None);
+            assert!(schemes
+                .iter()
+                .all(|&scheme| scheme == expected || scheme == CompressionScheme::None));
+            assert!(schemes.iter().filter(|&&scheme| scheme == expected).count() > schemes.len() / 2);
+        }
+    }
+
+    #[test]
+    fn test_scheme_learner_skips_trials() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        use crate::compression_scheme::SCHEME_ANALYSIS_COUNT;
+
+        const NUM_XORBS: usize = 40;
+
+        let mut rng = StdRng::seed_from_u64(0);
+        // Quantized f32 model weights, normally distributed; Box-Muller transform.
+        let gen_xorb = |rng: &mut StdRng| -> Vec<u8> {
+            (0..64 * 1024)
+                .flat_map(|_| {
+                    let (u1, u2) = (rng.gen_range(f64::EPSILON..1.0), rng.gen::<f64>());
+                    let v = 0.02 * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                    (((v * 1024.).round() / 1024.) as f32).to_le_bytes()
+                })
+                .collect()
+        };
+        let serialize = |data: &[u8], scheme_learner: Option<&XorbSchemeLearner>| -> usize {
+            let chunk_and_boundaries: Vec<_> = (1..=data.len() / (16 * 1024))
+                .map(|i| {
+                    (merklehash::compute_data_hash(&data[(i - 1) * 16 * 1024..i * 16 * 1024]), (i * 16 * 1024) as u32)
+                })
+                .collect();
+            let hash = merklehash::compute_data_hash(data);
+            let mut writer = Cursor::new(Vec::new());
+            let (c, len) = match scheme_learner {
+                Some(scheme_learner) => {
+                    CasObject::serialize_with_learner(&mut writer, &hash, data, &chunk_and_boundaries, scheme_learner)
+                },
+                None => CasObject::serialize(&mut writer, &hash, data, &chunk_and_boundaries, None),
+            }
+            .unwrap();
+            assert_eq!(c.get_all_bytes(&mut Cursor::new(writer.into_inner())).unwrap(), data);
+            len
+        };
+
+        // Homogeneous xorbs are trial compressed a few times, and compress about as well as they do when
+        // each of them is trial compressed.
+        let scheme_learner = XorbSchemeLearner::new();
+        SCHEME_ANALYSIS_COUNT.set(0);
+        let mut xorbs = Vec::new();
+        let mut learned_sizes = Vec::new();
+        for _ in 0..NUM_XORBS {
+            let xorb = gen_xorb(&mut rng);
+            learned_sizes.push(serialize(&xorb, Some(&scheme_learner)));
+            xorbs.push(xorb);
+        }
+        let num_analyses = SCHEME_ANALYSIS_COUNT.get();
+        assert!(num_analyses <= NUM_XORBS / 8, "{num_analyses} analyses for {NUM_XORBS} xorbs");
+        for (xorb, learned_size) in xorbs.iter().zip(learned_sizes) {
+            let trial_size = serialize(xorb, None);
+            assert!((learned_size as f64) < trial_size as f64 * 1.02, "{learned_size} vs {trial_size}");
+        }
+
+        // A xorb that compresses much worse makes the next xorb trial compressed.
+        serialize(&gen_random_bytes(256 * 1024), Some(&scheme_learner));
+        SCHEME_ANALYSIS_COUNT.set(0);
+        serialize(&gen_xorb(&mut rng), Some(&scheme_learner));
+        assert_eq!(SCHEME_ANALYSIS_COUNT.get(), 1);
+    }
 }
@@ -6,8 +6,18 @@ use std::time::Instant;
 use anyhow::anyhow;
 use lz4_flex::frame::{FrameDecoder, FrameEncoder};
 
-use crate::byte_grouping::bg4::{bg4_regroup, bg4_split};
+use crate::byte_grouping::bg2::{bg2_regroup_into, bg2_split};
+use crate::byte_grouping::bg4::{bg4_regroup_into, bg4_split};
 use crate::error::{CasObjectError, Result};
+#[cfg(any(test, feature = "experimental_schemes"))]
+use crate::experimental_schemes;
+use crate::DecompressionBufferPool;
+
+utils::configurable_constants! {
+    // Whether data that looks to be made of 2 byte elements, e.g. f16 or bf16, is byte grouped with
+    // bg2 rather than bg4 when grouping is chosen by heuristic; bg2 keeps each byte plane in one run.
+    ref BG2_FOR_2_BYTE_ELEMENTS: bool = true;
+}
 
 pub static mut BG4_SPLIT_RUNTIME: f64 = 0.;
 pub static mut BG4_REGROUP_RUNTIME: f64 = 0.;
@@ -22,6 +32,11 @@ pub enum CompressionScheme {
     None = 0,
     LZ4 = 1,
     ByteGrouping4LZ4 = 2, // 4 byte groups
+    ByteGrouping2LZ4 = 3, // 2 byte groups
+    /// A scheme registered at runtime with `register_experimental_scheme`, by its value in
+    /// `EXPERIMENTAL_SCHEME_IDS`.
+    #[cfg(any(test, feature = "experimental_schemes"))]
+    Experimental(u8),
 }
 
 impl Display for CompressionScheme {
@@ -35,6 +50,9 @@ impl From<&CompressionScheme> for &'static str {
             CompressionScheme::None => "none",
             CompressionScheme::LZ4 => "lz4",
             CompressionScheme::ByteGrouping4LZ4 => "bg4-lz4",
+            CompressionScheme::ByteGrouping2LZ4 => "bg2-lz4",
+            #[cfg(any(test, feature = "experimental_schemes"))]
+            CompressionScheme::Experimental(id) => experimental_schemes::scheme_name(*id).unwrap_or("experimental"),
         }
     }
 }
@@ -45,6 +63,19 @@ impl From<CompressionScheme> for &'static str {
     }
 }
 
+impl From<CompressionScheme> for u8 {
+    fn from(value: CompressionScheme) -> Self {
+        match value {
+            CompressionScheme::None => 0,
+            CompressionScheme::LZ4 => 1,
+            CompressionScheme::ByteGrouping4LZ4 => 2,
+            CompressionScheme::ByteGrouping2LZ4 => 3,
+            #[cfg(any(test, feature = "experimental_schemes"))]
+            CompressionScheme::Experimental(id) => id,
+        }
+    }
+}
+
 impl TryFrom<u8> for CompressionScheme {
     type Error = CasObjectError;
 
@@ -53,54 +84,198 @@ impl TryFrom<u8> for CompressionScheme {
             0 => Ok(CompressionScheme::None),
             1 => Ok(CompressionScheme::LZ4),
             2 => Ok(CompressionScheme::ByteGrouping4LZ4),
+            3 => Ok(CompressionScheme::ByteGrouping2LZ4),
+            #[cfg(any(test, feature = "experimental_schemes"))]
+            id if experimental_schemes::is_registered(id) => Ok(CompressionScheme::Experimental(id)),
             _ => Err(CasObjectError::FormatError(anyhow!("cannot convert value {value} to CompressionScheme"))),
         }
     }
 }
 
+// Stack usage: compression and decompression don't recurse, and lz4_flex keeps its hash table in a
+// Box and its frame buffers in Vecs, so they take a small, constant amount of stack whatever the
+// input size.  They run on threadpool threads, whose stack may be as small as
+// xet_threadpool::THREADPOOL_MIN_STACK_SIZE; keep large buffers off the stack here.
 impl CompressionScheme {
+    /// Compresses data, returning the compressed bytes.  For `None` this borrows the
+    /// input without copying it.
     pub fn compress_from_slice<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>> {
         Ok(match self {
             CompressionScheme::None => data.into(),
             CompressionScheme::LZ4 => lz4_compress_from_slice(data).map(Cow::from)?,
             CompressionScheme::ByteGrouping4LZ4 => bg4_lz4_compress_from_slice(data).map(Cow::from)?,
+            CompressionScheme::ByteGrouping2LZ4 => bg2_lz4_compress_from_slice(data).map(Cow::from)?,
+            #[cfg(any(test, feature = "experimental_schemes"))]
+            CompressionScheme::Experimental(id) => experimental_schemes::compress(*id, data)?.into(),
         })
     }
 
+    /// Compresses data, appending the compressed bytes to `dest` without any intermediate
+    /// buffer for `None` and `LZ4`.  Returns the number of bytes appended.
+    ///
+    /// `dest` is only reallocated if its spare capacity is not sufficient for the output.
+    pub fn compress_into(&self, data: &[u8], dest: &mut Vec<u8>) -> Result<usize> {
+        let start_len = dest.len();
+        match self {
+            CompressionScheme::None => dest.extend_from_slice(data),
+            CompressionScheme::LZ4 => lz4_compress_into(data, dest)?,
+            CompressionScheme::ByteGrouping4LZ4 => bg4_lz4_compress_into(data, dest)?,
+            CompressionScheme::ByteGrouping2LZ4 => bg2_lz4_compress_into(data, dest)?,
+            #[cfg(any(test, feature = "experimental_schemes"))]
+            CompressionScheme::Experimental(id) => dest.extend_from_slice(&experimental_schemes::compress(*id, data)?),
+        };
+        Ok(dest.len() - start_len)
+    }
+
+    /// Decompresses data, returning the decompressed bytes.  For `None` this borrows the
+    /// input without copying it.
     pub fn decompress_from_slice<'a>(&self, data: &'a [u8]) -> Result<Cow<'a, [u8]>> {
         Ok(match self {
             CompressionScheme::None => data.into(),
             CompressionScheme::LZ4 => lz4_decompress_from_slice(data).map(Cow::from)?,
             CompressionScheme::ByteGrouping4LZ4 => bg4_lz4_decompress_from_slice(data).map(Cow::from)?,
+            CompressionScheme::ByteGrouping2LZ4 => bg2_lz4_decompress_from_slice(data).map(Cow::from)?,
+            #[cfg(any(test, feature = "experimental_schemes"))]
+            CompressionScheme::Experimental(id) => experimental_schemes::decompress(*id, data)?.into(),
         })
     }
 
     pub fn decompress_from_reader<R: Read, W: Write>(&self, reader: &mut R, writer: &mut W) -> Result<u64> {
+        self.decompress_from_reader_impl(reader, writer, None)
+    }
+
+    /// Like `decompress_from_reader`, but takes the scratch buffers of the byte grouping schemes
+    /// from `pool`, and gives them back, rather than allocating them.
+    pub fn decompress_from_reader_with_pool<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        pool: &DecompressionBufferPool,
+    ) -> Result<u64> {
+        self.decompress_from_reader_impl(reader, writer, Some(pool))
+    }
+
+    fn decompress_from_reader_impl<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        pool: Option<&DecompressionBufferPool>,
+    ) -> Result<u64> {
         Ok(match self {
             CompressionScheme::None => copy(reader, writer)?,
             CompressionScheme::LZ4 => lz4_decompress_from_reader(reader, writer)?,
-            CompressionScheme::ByteGrouping4LZ4 => bg4_lz4_decompress_from_reader(reader, writer)?,
+            CompressionScheme::ByteGrouping4LZ4 => bg4_lz4_decompress_from_reader(reader, writer, pool)?,
+            CompressionScheme::ByteGrouping2LZ4 => bg2_lz4_decompress_from_reader(reader, writer, pool)?,
+            #[cfg(any(test, feature = "experimental_schemes"))]
+            CompressionScheme::Experimental(id) => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                let decompressed = experimental_schemes::decompress(*id, &data)?;
+                writer.write_all(&decompressed)?;
+                decompressed.len() as u64
+            },
         })
     }
 
-    /// Chooses the compression scheme based on a KL-divergence heuristic.
+    /// Chooses the compression scheme based on a KL-divergence heuristic, refined by the likely
+    /// element size of the data; see `BytePlaneStats`.
+    ///
+    /// Byte grouping only helps LZ4 if it puts the bytes of a low entropy byte plane (e.g. the
+    /// sign and exponent bytes of floats) next to each other.  The 4 byte groups are exactly the
+    /// byte planes of 2 and 4 byte elements (for 2 byte elements, two groups hold each plane), but
+    /// mix two planes of 8 byte elements.  So bg4 is only chosen for 2 or 4 byte elements, and only
+    /// if one of their planes has low enough entropy for LZ4 to find repeats in it: that holds for
+    /// typical bf16 and f32 data, but usually not for f16, whose high byte has 2 mantissa bits.
+    ///
+    /// For 2 byte elements, bg2 is chosen instead of bg4 unless `BG2_FOR_2_BYTE_ELEMENTS` is off:
+    /// bg4 splits each of their byte planes across two groups, while bg2 keeps it in one.
     pub fn choose_from_data(data: &[u8]) -> Self {
+        #[cfg(test)]
+        count_scheme_analysis();
+
         let mut bg4_predictor = BG4Predictor::new();
 
         bg4_predictor.add_data(0, data);
 
-        if bg4_predictor.bg4_recommended() {
+        let planes = BytePlaneStats::from_sample(data);
+        let element_size = planes.element_size();
+        let grouping_helps = matches!(element_size, 2 | 4) && planes.min_entropy() < BG4_MAX_PLANE_ENTROPY_BITS;
+
+        if !(grouping_helps && bg4_predictor.bg4_recommended()) {
+            CompressionScheme::LZ4
+        } else if element_size == 2 && *BG2_FOR_2_BYTE_ELEMENTS {
+            CompressionScheme::ByteGrouping2LZ4
+        } else {
             CompressionScheme::ByteGrouping4LZ4
+        }
+    }
+
+    /// Chooses one compression scheme for all the chunks of a xorb by compressing a sample of the
+    /// data with each of LZ4, bg2-lz4 and bg4-lz4 and keeping the one with the smallest output.
+    ///
+    /// If the data is longer than `XORB_TRIAL_MAX_SAMPLE_BLOCKS` blocks of `XORB_TRIAL_SAMPLE_BLOCK_SIZE`
+    /// bytes, that many blocks at evenly spaced, block aligned offsets are compressed separately, the
+    /// way chunks are; otherwise all of the data is compressed as one block.  Ties go to the simpler
+    /// scheme.
+    pub fn choose_for_xorb(data: &[u8]) -> Result<Self> {
+        #[cfg(test)]
+        count_scheme_analysis();
+
+        let num_blocks = data.len().div_ceil(XORB_TRIAL_SAMPLE_BLOCK_SIZE);
+        let blocks: Vec<&[u8]> = if num_blocks <= XORB_TRIAL_MAX_SAMPLE_BLOCKS {
+            vec![data]
         } else {
-            CompressionScheme::LZ4
+            (0..XORB_TRIAL_MAX_SAMPLE_BLOCKS)
+                .map(|i| {
+                    let start = (i * num_blocks / XORB_TRIAL_MAX_SAMPLE_BLOCKS) * XORB_TRIAL_SAMPLE_BLOCK_SIZE;
+                    &data[start..(start + XORB_TRIAL_SAMPLE_BLOCK_SIZE).min(data.len())]
+                })
+                .collect()
+        };
+
+        let mut best = (CompressionScheme::LZ4, usize::MAX);
+        let mut dest = Vec::new();
+        for scheme in [
+            CompressionScheme::LZ4,
+            CompressionScheme::ByteGrouping2LZ4,
+            CompressionScheme::ByteGrouping4LZ4,
+        ] {
+            let mut compressed_len = 0;
+            for block in &blocks {
+                dest.clear();
+                compressed_len += scheme.compress_into(block, &mut dest)?;
+            }
+            if compressed_len < best.1 {
+                best = (scheme, compressed_len);
+            }
         }
+        Ok(best.0)
     }
 }
 
+#[cfg(test)]
+thread_local! {
+    /// The number of times a compression scheme was chosen by analyzing data on this thread, so that
+    /// tests can check when the analysis is skipped.
+    pub(crate) static SCHEME_ANALYSIS_COUNT: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+#[cfg(test)]
+fn count_scheme_analysis() {
+    SCHEME_ANALYSIS_COUNT.with(|count| count.set(count.get() + 1));
+}
+
 pub fn lz4_compress_from_slice(data: &[u8]) -> Result<Vec<u8>> {
-    let mut enc = FrameEncoder::new(Vec::new());
+    let mut dest = vec![];
+    lz4_compress_into(data, &mut dest)?;
+    Ok(dest)
+}
+
+fn lz4_compress_into(data: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+    let mut enc = FrameEncoder::new(dest);
     enc.write_all(data)?;
-    Ok(enc.finish()?)
+    enc.finish()?;
+    Ok(())
 }
 
 pub fn lz4_decompress_from_slice(data: &[u8]) -> Result<Vec<u8>> {
@@ -114,7 +289,46 @@ fn lz4_decompress_from_reader<R: Read, W: Write>(reader: &mut R, writer: &mut W)
     Ok(copy(&mut dec, writer)?)
 }
 
+pub fn bg2_lz4_compress_from_slice(data: &[u8]) -> Result<Vec<u8>> {
+    let mut dest = vec![];
+    bg2_lz4_compress_into(data, &mut dest)?;
+    Ok(dest)
+}
+
+fn bg2_lz4_compress_into(data: &[u8], dest: &mut Vec<u8>) -> Result<()> {
+    lz4_compress_into(&bg2_split(data), dest)
+}
+
+pub fn bg2_lz4_decompress_from_slice(data: &[u8]) -> Result<Vec<u8>> {
+    let mut dest = vec![];
+    bg2_lz4_decompress_from_reader(&mut Cursor::new(data), &mut dest, None)?;
+    Ok(dest)
+}
+
+fn bg2_lz4_decompress_from_reader<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    pool: Option<&DecompressionBufferPool>,
+) -> Result<u64> {
+    let mut g = take_buffer(pool);
+    FrameDecoder::new(reader).read_to_end(&mut g)?;
+    let mut regrouped = take_buffer(pool);
+    regrouped.resize(g.len(), 0);
+    bg2_regroup_into(&g, &mut regrouped);
+    writer.write_all(&regrouped)?;
+
+    let len = regrouped.len() as u64;
+    give_back_buffers(pool, [g, regrouped]);
+    Ok(len)
+}
+
 pub fn bg4_lz4_compress_from_slice(data: &[u8]) -> Result<Vec<u8>> {
+    let mut dest = vec![];
+    bg4_lz4_compress_into(data, &mut dest)?;
+    Ok(dest)
+}
+
+fn bg4_lz4_compress_into(data: &[u8], dest: &mut Vec<u8>) -> Result<()> {
     let s = Instant::now();
     let groups = bg4_split(data);
     unsafe {
@@ -122,40 +336,59 @@ pub fn bg4_lz4_compress_from_slice(data: &[u8]) -> Result<Vec<u8>> {
     }
 
     let s = Instant::now();
-    let mut dest = vec![];
-    let mut enc = FrameEncoder::new(&mut dest);
+    let mut enc = FrameEncoder::new(dest);
     enc.write_all(&groups)?;
     enc.finish()?;
     unsafe {
         BG4_LZ4_COMPRESS_RUNTIME += s.elapsed().as_secs_f64();
     }
 
-    Ok(dest)
+    Ok(())
 }
 
 pub fn bg4_lz4_decompress_from_slice(data: &[u8]) -> Result<Vec<u8>> {
     let mut dest = vec![];
-    bg4_lz4_decompress_from_reader(&mut Cursor::new(data), &mut dest)?;
+    bg4_lz4_decompress_from_reader(&mut Cursor::new(data), &mut dest, None)?;
     Ok(dest)
 }
 
-fn bg4_lz4_decompress_from_reader<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<u64> {
+fn bg4_lz4_decompress_from_reader<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    pool: Option<&DecompressionBufferPool>,
+) -> Result<u64> {
     let s = Instant::now();
-    let mut g = vec![];
+    let mut g = take_buffer(pool);
     FrameDecoder::new(reader).read_to_end(&mut g)?;
     unsafe {
         BG4_LZ4_DECOMPRESS_RUNTIME += s.elapsed().as_secs_f64();
     }
 
     let s = Instant::now();
-    let regrouped = bg4_regroup(&g);
+    let mut regrouped = take_buffer(pool);
+    regrouped.resize(g.len(), 0);
+    bg4_regroup_into(&g, &mut regrouped);
     unsafe {
         BG4_REGROUP_RUNTIME += s.elapsed().as_secs_f64();
     }
 
     writer.write_all(&regrouped)?;
 
-    Ok(regrouped.len() as u64)
+    let len = regrouped.len() as u64;
+    give_back_buffers(pool, [g, regrouped]);
+    Ok(len)
+}
+
+fn take_buffer(pool: Option<&DecompressionBufferPool>) -> Vec<u8> {
+    pool.map(DecompressionBufferPool::take).unwrap_or_default()
+}
+
+fn give_back_buffers<const N: usize>(pool: Option<&DecompressionBufferPool>, buffers: [Vec<u8>; N]) {
+    if let Some(pool) = pool {
+        for buffer in buffers {
+            pool.give_back(buffer);
+        }
+    }
 }
 
 pub struct BG4Predictor {
@@ -252,12 +485,112 @@ impl BG4Predictor {
     }
 }
 
+/// Size of the blocks sampled by `CompressionScheme::choose_for_xorb`; a multiple of the largest
+/// element size considered, and about the size of a chunk.
+const XORB_TRIAL_SAMPLE_BLOCK_SIZE: usize = 64 * 1024;
+
+/// Maximum number of blocks sampled by `CompressionScheme::choose_for_xorb`.
+const XORB_TRIAL_MAX_SAMPLE_BLOCKS: usize = 4;
+
+/// Size of the blocks sampled by `BytePlaneStats::from_sample`; a multiple of the largest
+/// element size considered, so every block starts at the same position within an element.
+const BYTE_PLANE_SAMPLE_BLOCK_SIZE: usize = 512;
+
+/// Maximum number of blocks sampled by `BytePlaneStats::from_sample`.
+const BYTE_PLANE_MAX_SAMPLE_BLOCKS: usize = 32;
+
+/// Largest element size considered, in bytes.
+const MAX_ELEMENT_SIZE: usize = 8;
+
+/// Byte planes whose entropies differ by less than this are considered to hold the same kind
+/// of byte when looking for the element size.
+const BYTE_PLANE_ENTROPY_TOLERANCE_BITS: f64 = 0.5;
+
+/// Byte grouping is only chosen if some byte plane has an entropy below this.
+const BG4_MAX_PLANE_ENTROPY_BITS: f64 = 4.0;
+
+/// The distribution of byte values at each position modulo 8 in the data, used to guess the size
+/// of the elements (e.g. 2 for f16 and bf16, 4 for f32, 8 for f64) of an array of numbers without
+/// knowing its type.  Each position modulo the element size is a byte plane; the byte planes of
+/// floats differ in entropy, from near 8 bits for the low mantissa bytes down to a few bits for
+/// the sign and exponent bytes.
+///
+/// Chunk boundaries don't fall on element boundaries, so which position holds which plane is
+/// unknown; only the period of the plane entropies is used, which doesn't depend on it.
+pub struct BytePlaneStats {
+    histograms: [[u32; 256]; MAX_ELEMENT_SIZE],
+}
+
+impl BytePlaneStats {
+    /// Collects the byte statistics of a sample of the data: if the data is longer than
+    /// `BYTE_PLANE_MAX_SAMPLE_BLOCKS` blocks of `BYTE_PLANE_SAMPLE_BLOCK_SIZE` bytes, that many
+    /// blocks are sampled at evenly spaced offsets, each a multiple of the block size so the
+    /// positions modulo 8 are preserved; otherwise all of the data is used.
+    pub fn from_sample(data: &[u8]) -> Self {
+        let mut stats = Self {
+            histograms: [[0u32; 256]; MAX_ELEMENT_SIZE],
+        };
+
+        let num_blocks = data.len().div_ceil(BYTE_PLANE_SAMPLE_BLOCK_SIZE);
+        if num_blocks <= BYTE_PLANE_MAX_SAMPLE_BLOCKS {
+            stats.add_block(data);
+        } else {
+            for i in 0..BYTE_PLANE_MAX_SAMPLE_BLOCKS {
+                let start = (i * num_blocks / BYTE_PLANE_MAX_SAMPLE_BLOCKS) * BYTE_PLANE_SAMPLE_BLOCK_SIZE;
+                let end = (start + BYTE_PLANE_SAMPLE_BLOCK_SIZE).min(data.len());
+                stats.add_block(&data[start..end]);
+            }
+        }
+        stats
+    }
+
+    fn add_block(&mut self, block: &[u8]) {
+        for (i, &b) in block.iter().enumerate() {
+            self.histograms[i % MAX_ELEMENT_SIZE][b as usize] += 1;
+        }
+    }
+
+    /// The Shannon entropy, in bits, of the bytes at each position modulo 8.
+    pub fn entropies(&self) -> [f64; MAX_ELEMENT_SIZE] {
+        self.histograms.map(|histogram| {
+            let total = histogram.iter().sum::<u32>() as f64;
+            histogram
+                .iter()
+                .filter(|&&c| c != 0)
+                .map(|&c| {
+                    let p = c as f64 / total;
+                    -p * p.log2()
+                })
+                .sum()
+        })
+    }
+
+    /// The likely element size of the data: the smallest of 1, 2, 4 and 8 with which the byte plane
+    /// entropies repeat.
+    pub fn element_size(&self) -> usize {
+        let entropies = self.entropies();
+        [1, 2, 4]
+            .into_iter()
+            .find(|&size| {
+                (0..MAX_ELEMENT_SIZE - size)
+                    .all(|i| (entropies[i] - entropies[i + size]).abs() < BYTE_PLANE_ENTROPY_TOLERANCE_BITS)
+            })
+            .unwrap_or(MAX_ELEMENT_SIZE)
+    }
+
+    /// The entropy, in bits, of the lowest entropy byte plane.
+    pub fn min_entropy(&self) -> f64 {
+        self.entropies().into_iter().fold(f64::INFINITY, f64::min)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::mem::size_of;
 
     use half::prelude::*;
-    use rand::Rng;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
 
     use super::*;
 
@@ -266,6 +599,7 @@ mod tests {
         assert_eq!(Into::<&str>::into(CompressionScheme::None), "none");
         assert_eq!(Into::<&str>::into(CompressionScheme::LZ4), "lz4");
         assert_eq!(Into::<&str>::into(CompressionScheme::ByteGrouping4LZ4), "bg4-lz4");
+        assert_eq!(Into::<&str>::into(CompressionScheme::ByteGrouping2LZ4), "bg2-lz4");
     }
 
     #[test]
@@ -273,7 +607,91 @@ mod tests {
         assert_eq!(CompressionScheme::try_from(0u8), Ok(CompressionScheme::None));
         assert_eq!(CompressionScheme::try_from(1u8), Ok(CompressionScheme::LZ4));
         assert_eq!(CompressionScheme::try_from(2u8), Ok(CompressionScheme::ByteGrouping4LZ4));
-        assert!(CompressionScheme::try_from(3u8).is_err());
+        assert_eq!(CompressionScheme::try_from(3u8), Ok(CompressionScheme::ByteGrouping2LZ4));
+        assert!(CompressionScheme::try_from(4u8).is_err());
+    }
+
+    #[test]
+    fn test_none_scheme_passthrough() {
+        let data: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+
+        let compressed = CompressionScheme::None.compress_from_slice(&data).unwrap();
+        assert!(matches!(compressed, Cow::Borrowed(_)));
+        assert_eq!(compressed.as_ptr(), data.as_ptr());
+
+        let decompressed = CompressionScheme::None.decompress_from_slice(&compressed).unwrap();
+        assert!(matches!(decompressed, Cow::Borrowed(_)));
+        assert_eq!(decompressed.as_ref(), &data[..]);
+    }
+
+    #[test]
+    fn test_compress_into() {
+        let data: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+
+        // None appends the input unchanged, without reallocating when capacity suffices.
+        let mut dest = Vec::with_capacity(2 * data.len());
+        dest.extend_from_slice(b"header");
+        let (ptr, capacity) = (dest.as_ptr(), dest.capacity());
+        let n = CompressionScheme::None.compress_into(&data, &mut dest).unwrap();
+        assert_eq!(n, data.len());
+        assert_eq!(&dest[..6], b"header");
+        assert_eq!(&dest[6..], &data[..]);
+        assert_eq!(dest.as_ptr(), ptr);
+        assert_eq!(dest.capacity(), capacity);
+
+        for scheme in [
+            CompressionScheme::LZ4,
+            CompressionScheme::ByteGrouping4LZ4,
+            CompressionScheme::ByteGrouping2LZ4,
+        ] {
+            let expected = scheme.compress_from_slice(&data).unwrap();
+
+            let mut dest = Vec::with_capacity(2 * data.len());
+            let (ptr, capacity) = (dest.as_ptr(), dest.capacity());
+            let n = scheme.compress_into(&data, &mut dest).unwrap();
+            assert_eq!(n, expected.len());
+            assert_eq!(dest, expected.as_ref());
+            assert_eq!(dest.as_ptr(), ptr);
+            assert_eq!(dest.capacity(), capacity);
+            assert_eq!(scheme.decompress_from_slice(&dest).unwrap().as_ref(), &data[..]);
+        }
+    }
+
+    #[test]
+    fn test_choose_from_data_by_element_size() {
+        let mut rng = StdRng::seed_from_u64(0);
+        // Normally distributed, like model weights; Box-Muller transform.
+        let values: Vec<f64> = (0..64 * 1024)
+            .map(|_| {
+                let (u1, u2) = (rng.gen_range(f64::EPSILON..1.0), rng.gen::<f64>());
+                0.02 * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+            })
+            .collect();
+
+        let f16s: Vec<u8> = values.iter().flat_map(|&v| f16::from_f64(v).to_le_bytes()).collect();
+        let bf16s: Vec<u8> = values.iter().flat_map(|&v| bf16::from_f64(v).to_le_bytes()).collect();
+        let f32s: Vec<u8> = values.iter().flat_map(|&v| (v as f32).to_le_bytes()).collect();
+        let f64s: Vec<u8> = values.iter().flat_map(|&v| v.to_le_bytes()).collect();
+
+        // Grouping only pays off for bf16 and f32, where the sign and exponent byte has low entropy.
+        let cases = [
+            (&f16s, 2, CompressionScheme::LZ4),
+            (&bf16s, 2, CompressionScheme::ByteGrouping2LZ4),
+            (&f32s, 4, CompressionScheme::ByteGrouping4LZ4),
+            (&f64s, 8, CompressionScheme::LZ4),
+        ];
+        for (data, element_size, scheme) in cases {
+            // Chunks don't start on element boundaries.
+            for offset in [0, 1, 3] {
+                let data = &data[offset..64 * 1024 + offset];
+                assert_eq!(BytePlaneStats::from_sample(data).element_size(), element_size);
+                assert_eq!(CompressionScheme::choose_from_data(data), scheme, "{element_size} byte elements");
+            }
+        }
+
+        let random_u8s: Vec<u8> = (0..64 * 1024).map(|_| rng.gen()).collect();
+        assert_eq!(BytePlaneStats::from_sample(&random_u8s).element_size(), 1);
+        assert_eq!(CompressionScheme::choose_from_data(&random_u8s), CompressionScheme::LZ4);
     }
 
     #[test]
@@ -0,0 +1,90 @@
+use std::sync::Mutex;
+
+/// A pool of buffers reused as scratch space when decompressing chunks, so that decompressing many
+/// chunks, e.g. during a large download, doesn't allocate and free buffers of the same sizes for
+/// every chunk; see `CompressionScheme::decompress_from_reader_with_pool`.
+///
+/// The pool can be shared between threads.  It keeps at most `max_buffers` buffers: more can be
+/// taken at once, with the extra ones allocated as usual and dropped when given back.
+#[derive(Debug)]
+pub struct DecompressionBufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    max_buffers: usize,
+}
+
+impl DecompressionBufferPool {
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            buffers: Mutex::new(Vec::with_capacity(max_buffers)),
+            max_buffers,
+        }
+    }
+
+    /// Takes an empty buffer from the pool, or a new one if the pool has none left.
+    pub fn take(&self) -> Vec<u8> {
+        self.buffers.lock().unwrap().pop().unwrap_or_default()
+    }
+
+    /// Returns a buffer to the pool, emptied, or drops it if the pool is full.
+    pub fn give_back(&self, mut buffer: Vec<u8>) {
+        buffer.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buffer);
+        }
+    }
+
+    /// The number of buffers in the pool, waiting to be taken.
+    pub fn num_pooled(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn test_buffers_reused_up_to_max() {
+        let pool = DecompressionBufferPool::new(2);
+        let mut buffer = pool.take();
+        buffer.extend_from_slice(&[1; 100]);
+        let ptr = buffer.as_ptr();
+        pool.give_back(buffer);
+
+        let buffer = pool.take();
+        assert!(buffer.is_empty() && buffer.capacity() >= 100);
+        assert_eq!(buffer.as_ptr(), ptr);
+        assert_eq!(pool.num_pooled(), 0);
+
+        pool.give_back(buffer);
+        pool.give_back(vec![]);
+        pool.give_back(vec![]);
+        assert_eq!(pool.num_pooled(), 2);
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        let pool = Arc::new(DecompressionBufferPool::new(4));
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        let mut buffer = pool.take();
+                        assert!(buffer.is_empty());
+                        buffer.resize(1000, i);
+                        assert!(buffer.iter().all(|&b| b == i));
+                        pool.give_back(buffer);
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert!(pool.num_pooled() <= 4);
+    }
+}
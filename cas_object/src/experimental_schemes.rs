@@ -0,0 +1,121 @@
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, RwLock};
+
+use anyhow::anyhow;
+
+use crate::error::{CasObjectError, Result};
+use crate::CompressionScheme;
+
+/// The compression scheme values reserved for experimental schemes.  Stable schemes are variants of
+/// `CompressionScheme` with values below this range, so the two never collide in chunk headers.
+pub const EXPERIMENTAL_SCHEME_IDS: RangeInclusive<u8> = 200..=254;
+
+/// The compression or decompression function of an experimental scheme.
+pub type ExperimentalSchemeFn = Arc<dyn Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync>;
+
+struct ExperimentalScheme {
+    name: &'static str,
+    compress: ExperimentalSchemeFn,
+    decompress: ExperimentalSchemeFn,
+}
+
+static EXPERIMENTAL_SCHEMES: RwLock<BTreeMap<u8, ExperimentalScheme>> = RwLock::new(BTreeMap::new());
+
+/// Registers an experimental compression scheme for this process under `id`, which must be in
+/// `EXPERIMENTAL_SCHEME_IDS` and not already registered, returning the `CompressionScheme` that
+/// compresses and decompresses with `compress` and `decompress`.
+///
+/// Chunks compressed with the scheme record `id` in their header, so they can only be read by a
+/// process that registered the same scheme under the same id; reading them elsewhere fails as for
+/// any unknown scheme.
+pub fn register_experimental_scheme(
+    id: u8,
+    name: &'static str,
+    compress: impl Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync + 'static,
+    decompress: impl Fn(&[u8]) -> Result<Vec<u8>> + Send + Sync + 'static,
+) -> Result<CompressionScheme> {
+    if !EXPERIMENTAL_SCHEME_IDS.contains(&id) {
+        return Err(CasObjectError::InvalidArguments);
+    }
+
+    let mut schemes = EXPERIMENTAL_SCHEMES
+        .write()
+        .map_err(|e| CasObjectError::InternalError(anyhow!("{e}")))?;
+    if let Some(existing) = schemes.get(&id) {
+        return Err(CasObjectError::InternalError(anyhow!(
+            "experimental compression scheme {id} is already registered as {}",
+            existing.name
+        )));
+    }
+    schemes.insert(
+        id,
+        ExperimentalScheme {
+            name,
+            compress: Arc::new(compress),
+            decompress: Arc::new(decompress),
+        },
+    );
+    Ok(CompressionScheme::Experimental(id))
+}
+
+pub(crate) fn is_registered(id: u8) -> bool {
+    EXPERIMENTAL_SCHEMES.read().is_ok_and(|schemes| schemes.contains_key(&id))
+}
+
+pub(crate) fn scheme_name(id: u8) -> Option<&'static str> {
+    EXPERIMENTAL_SCHEMES.read().ok()?.get(&id).map(|scheme| scheme.name)
+}
+
+pub(crate) fn compress(id: u8, data: &[u8]) -> Result<Vec<u8>> {
+    (lookup(id, |scheme| &scheme.compress)?)(data)
+}
+
+pub(crate) fn decompress(id: u8, data: &[u8]) -> Result<Vec<u8>> {
+    (lookup(id, |scheme| &scheme.decompress)?)(data)
+}
+
+/// Clones one of the functions of the scheme `id`, so it runs without holding the registry lock.
+fn lookup(id: u8, f: impl Fn(&ExperimentalScheme) -> &ExperimentalSchemeFn) -> Result<ExperimentalSchemeFn> {
+    let schemes = EXPERIMENTAL_SCHEMES
+        .read()
+        .map_err(|e| CasObjectError::InternalError(anyhow!("{e}")))?;
+    schemes
+        .get(&id)
+        .map(|scheme| f(scheme).clone())
+        .ok_or_else(|| CasObjectError::FormatError(anyhow!("experimental compression scheme {id} is not registered")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xor(data: &[u8]) -> Result<Vec<u8>> {
+        Ok(data.iter().map(|b| b ^ 0x5A).collect())
+    }
+
+    #[test]
+    fn test_experimental_scheme_round_trip() {
+        let scheme = register_experimental_scheme(250, "xor", xor, xor).unwrap();
+        assert_eq!(scheme.to_string(), "xor");
+        assert_eq!(u8::from(scheme), 250);
+        assert_eq!(CompressionScheme::try_from(250).unwrap(), scheme);
+
+        let data = b"some data to compress, some data to compress".to_vec();
+        let compressed = scheme.compress_from_slice(&data).unwrap();
+        assert_eq!(compressed, xor(&data).unwrap());
+        assert_eq!(scheme.decompress_from_slice(&compressed).unwrap(), data);
+
+        let mut dest = vec![1, 2];
+        assert_eq!(scheme.compress_into(&data, &mut dest).unwrap(), data.len());
+        let mut decompressed = Vec::new();
+        scheme.decompress_from_reader(&mut &dest[2..], &mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+
+        // The id is taken, ids outside the reserved range can't be registered and unregistered ids
+        // aren't schemes.
+        assert!(register_experimental_scheme(250, "xor", xor, xor).is_err());
+        assert!(matches!(register_experimental_scheme(3, "xor", xor, xor), Err(CasObjectError::InvalidArguments)));
+        assert!(CompressionScheme::try_from(251).is_err());
+    }
+}
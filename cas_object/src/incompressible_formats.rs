@@ -0,0 +1,54 @@
+/// Magic bytes of file formats whose contents are already compressed, with the offset they're
+/// found at.
+///
+/// Archive formats that may store their entries uncompressed are left out: zip in particular,
+/// which PyTorch checkpoints and numpy .npz files use to store raw tensors.
+const INCOMPRESSIBLE_FORMAT_SIGNATURES: &[(&str, usize, &[u8])] = &[
+    ("jpeg", 0, &[0xFF, 0xD8, 0xFF]),
+    ("png", 0, &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]),
+    ("gif", 0, b"GIF87a"),
+    ("gif", 0, b"GIF89a"),
+    ("webp", 8, b"WEBP"),
+    ("mp4", 4, b"ftyp"),
+    ("matroska", 0, &[0x1A, 0x45, 0xDF, 0xA3]),
+    ("ogg", 0, b"OggS"),
+    ("flac", 0, b"fLaC"),
+    ("mp3", 0, b"ID3"),
+    ("gzip", 0, &[0x1F, 0x8B, 0x08]),
+    ("zstd", 0, &[0x28, 0xB5, 0x2F, 0xFD]),
+    ("xz", 0, &[0xFD, b'7', b'z', b'X', b'Z', 0x00]),
+    ("lz4", 0, &[0x04, 0x22, 0x4D, 0x18]),
+    ("7z", 0, &[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C]),
+    ("rar", 0, &[b'R', b'a', b'r', b'!', 0x1A, 0x07]),
+];
+
+/// Returns the name of the already compressed format `data` starts with, if its magic bytes are
+/// those of one, e.g. "jpeg".
+///
+/// This only looks at the first few bytes, so it's cheap enough to run on every chunk: a chunk that
+/// starts a file carries the file's magic bytes.
+pub fn sniff_incompressible_format(data: &[u8]) -> Option<&'static str> {
+    INCOMPRESSIBLE_FORMAT_SIGNATURES
+        .iter()
+        .find(|(_, offset, magic)| data.get(*offset..offset + magic.len()) == Some(*magic))
+        .map(|(name, ..)| *name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff_incompressible_format() {
+        assert_eq!(sniff_incompressible_format(&[0xFF, 0xD8, 0xFF, 0xE0, 0, 0x10, b'J', b'F']), Some("jpeg"));
+        assert_eq!(sniff_incompressible_format(b"\0\0\0\x20ftypisom\0\0\x02\0"), Some("mp4"));
+        assert_eq!(sniff_incompressible_format(b"RIFF\x24\0\0\0WEBPVP8 "), Some("webp"));
+        assert_eq!(sniff_incompressible_format(b"GIF89a\x01\0"), Some("gif"));
+
+        // Zip archives may store their entries uncompressed.
+        assert_eq!(sniff_incompressible_format(b"PK\x03\x04\x14\0\0\0"), None);
+        assert_eq!(sniff_incompressible_format(b"some text"), None);
+        assert_eq!(sniff_incompressible_format(&[0xFF, 0xD8]), None);
+        assert_eq!(sniff_incompressible_format(&[]), None);
+    }
+}
@@ -2,10 +2,20 @@ pub mod byte_grouping;
 mod cas_chunk_format;
 mod cas_object_format;
 mod compression_scheme;
+mod decompression_buffers;
 pub mod error;
+#[cfg(any(test, feature = "experimental_schemes"))]
+mod experimental_schemes;
+mod incompressible_formats;
+mod scheme_learner;
 mod validate_xorb_stream;
 
 pub use cas_chunk_format::*;
 pub use cas_object_format::*;
 pub use compression_scheme::*;
+pub use decompression_buffers::*;
+#[cfg(any(test, feature = "experimental_schemes"))]
+pub use experimental_schemes::*;
+pub use incompressible_formats::*;
+pub use scheme_learner::*;
 pub use validate_xorb_stream::*;
@@ -0,0 +1,114 @@
+use std::sync::Mutex;
+
+use crate::error::Result;
+use crate::CompressionScheme;
+
+/// After this many trial compressed xorbs in a row choose the same scheme, later xorbs use it
+/// without being trial compressed.
+const SCHEME_AGREEMENT_XORBS: usize = 3;
+/// A learned scheme is checked again by trial compressing every this many xorbs.
+const SCHEME_REEVALUATION_INTERVAL: usize = 16;
+/// A xorb compressed with the learned scheme whose compressed size, relative to its raw size, is
+/// more than this fraction above that of the xorbs the scheme was learned from makes the next xorb
+/// trial compressed.
+const SCHEME_RATIO_TOLERANCE: f64 = 0.1;
+
+/// Learns the compression scheme the xorbs of an upload session settle on, so that the expensive
+/// trial compression of `CompressionScheme::choose_for_xorb` runs on a few xorbs rather than on
+/// every one of them.
+///
+/// Once `SCHEME_AGREEMENT_XORBS` xorbs in a row choose the same scheme, it's used for the following
+/// xorbs, with a xorb trial compressed again every `SCHEME_REEVALUATION_INTERVAL` xorbs or after one
+/// compresses noticeably worse than those the scheme was learned from.  The scheme is still
+/// recorded in each chunk's header, so reading xorbs doesn't depend on any of this.
+#[derive(Debug, Default)]
+pub struct XorbSchemeLearner {
+    state: Mutex<LearnerState>,
+}
+
+#[derive(Debug, Default)]
+struct LearnerState {
+    /// The scheme chosen by the latest trial compressed xorbs, and how many of them in a row chose it.
+    candidate: Option<CompressionScheme>,
+    agreeing_xorbs: usize,
+    /// The sum and count of the compression ratios of the agreeing xorbs.
+    ratio_sum: f64,
+    num_ratios: usize,
+    /// The xorbs given the learned scheme since the last trial compression.
+    learned_xorbs: usize,
+}
+
+/// The scheme picked for a xorb by `XorbSchemeLearner::choose`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchemeChoice {
+    pub scheme: CompressionScheme,
+    /// Whether the scheme is the learned one rather than the result of trial compressing the xorb.
+    pub learned: bool,
+}
+
+impl XorbSchemeLearner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Picks the compression scheme of a xorb's data: the learned scheme if there is one and it's not
+    /// due for a check, otherwise the one chosen by trial compressing the data.
+    pub fn choose(&self, data: &[u8]) -> Result<SchemeChoice> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(scheme) = state.learned_scheme() {
+                if state.learned_xorbs < SCHEME_REEVALUATION_INTERVAL {
+                    state.learned_xorbs += 1;
+                    return Ok(SchemeChoice { scheme, learned: true });
+                }
+            }
+        }
+
+        // Trial compress without holding the lock, so concurrent xorbs aren't serialized behind it.
+        let scheme = CompressionScheme::choose_for_xorb(data)?;
+
+        let mut state = self.state.lock().unwrap();
+        if state.candidate != Some(scheme) {
+            *state = LearnerState {
+                candidate: Some(scheme),
+                ..Default::default()
+            };
+        }
+        state.agreeing_xorbs += 1;
+        state.learned_xorbs = 0;
+        Ok(SchemeChoice { scheme, learned: false })
+    }
+
+    /// Records how well a xorb compressed with the scheme `choice` picked for it, as `compressed_len`
+    /// bytes of chunks for `raw_len` bytes of data.
+    pub fn record(&self, choice: SchemeChoice, raw_len: usize, compressed_len: usize) {
+        if raw_len == 0 {
+            return;
+        }
+        let ratio = compressed_len as f64 / raw_len as f64;
+
+        let mut state = self.state.lock().unwrap();
+        if state.candidate != Some(choice.scheme) {
+            return;
+        }
+        if !choice.learned {
+            state.ratio_sum += ratio;
+            state.num_ratios += 1;
+        } else if state.num_ratios > 0 {
+            let learned_ratio = state.ratio_sum / state.num_ratios as f64;
+            if ratio > learned_ratio * (1. + SCHEME_RATIO_TOLERANCE) {
+                // Trial compress the next xorb, which learns the scheme again, along with its ratio,
+                // if it agrees.
+                state.agreeing_xorbs = SCHEME_AGREEMENT_XORBS - 1;
+                state.ratio_sum = 0.;
+                state.num_ratios = 0;
+            }
+        }
+    }
+}
+
+impl LearnerState {
+    fn learned_scheme(&self) -> Option<CompressionScheme> {
+        self.candidate.filter(|_| self.agreeing_xorbs >= SCHEME_AGREEMENT_XORBS)
+    }
+}
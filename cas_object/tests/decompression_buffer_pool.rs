@@ -0,0 +1,125 @@
+//! Compares the allocations of deserializing chunks with and without a `DecompressionBufferPool`.
+//! This is its own test binary as it installs a counting global allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::Cursor;
+use std::sync::Arc;
+
+use cas_object::{
+    deserialize_chunks, deserialize_chunks_with_pool, serialize_chunk, CompressionScheme, DecompressionBufferPool,
+};
+
+/// Counts the allocations made on the current thread, so that tests running concurrently don't
+/// count each other's.
+struct CountingAllocator;
+
+thread_local! {
+    static NUM_ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = NUM_ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let _ = NUM_ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = NUM_ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = NUM_ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (result, NUM_ALLOCATIONS.with(Cell::get) - before)
+}
+
+/// Serializes `num_chunks` chunks of f32 values, compressed with `scheme`.
+fn serialized_chunks(num_chunks: usize, scheme: CompressionScheme) -> (Vec<u8>, Vec<u8>) {
+    let mut raw = Vec::new();
+    let mut serialized = Vec::new();
+    for i in 0..num_chunks {
+        let chunk: Vec<u8> = (0..16 * 1024)
+            .flat_map(|j| ((i * 7 + j % 100) as f32 / 10.).to_le_bytes())
+            .collect();
+        serialize_chunk(&chunk, &mut serialized, Some(scheme)).unwrap();
+        raw.extend_from_slice(&chunk);
+    }
+    (raw, serialized)
+}
+
+#[test]
+fn test_pooled_deserialization_allocates_less() {
+    const NUM_CHUNKS: usize = 32;
+
+    for scheme in [CompressionScheme::ByteGrouping4LZ4, CompressionScheme::ByteGrouping2LZ4] {
+        let (raw, serialized) = serialized_chunks(NUM_CHUNKS, scheme);
+        // every chunk is compressed with the scheme rather than stored uncompressed
+        assert!(serialized.len() < raw.len() / 2, "{scheme}");
+
+        let ((data, chunk_byte_indices), unpooled_allocations) =
+            count_allocations(|| deserialize_chunks(&mut Cursor::new(&serialized)).unwrap());
+        assert_eq!(data, raw);
+
+        let pool = DecompressionBufferPool::new(4);
+        let ((pooled_data, pooled_chunk_byte_indices), pooled_allocations) =
+            count_allocations(|| deserialize_chunks_with_pool(&mut Cursor::new(&serialized), &pool).unwrap());
+        assert_eq!(pooled_data, raw);
+        assert_eq!(pooled_chunk_byte_indices, chunk_byte_indices);
+
+        // the two scratch buffers of each chunk are allocated for the first chunk only.
+        assert!(
+            pooled_allocations + 2 * (NUM_CHUNKS - 1) <= unpooled_allocations,
+            "{scheme}: {pooled_allocations} allocations with the pool, {unpooled_allocations} without"
+        );
+        assert_eq!(pool.num_pooled(), 2);
+
+        // a warm pool doesn't allocate scratch buffers at all.
+        let (_, warm_allocations) =
+            count_allocations(|| deserialize_chunks_with_pool(&mut Cursor::new(&serialized), &pool).unwrap());
+        assert!(warm_allocations + 2 * NUM_CHUNKS <= unpooled_allocations, "{scheme}");
+    }
+}
+
+#[test]
+fn test_pool_shared_between_threads() {
+    let (raw, serialized) = serialized_chunks(8, CompressionScheme::ByteGrouping4LZ4);
+    let serialized = Arc::new(serialized);
+    let pool = Arc::new(DecompressionBufferPool::new(4));
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let serialized = serialized.clone();
+            let pool = pool.clone();
+            std::thread::spawn(move || {
+                (0..10)
+                    .map(|_| {
+                        deserialize_chunks_with_pool(&mut Cursor::new(&serialized[..]), &pool)
+                            .unwrap()
+                            .0
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .collect();
+    for handle in handles {
+        for data in handle.join().unwrap() {
+            assert_eq!(data, raw);
+        }
+    }
+    assert!(pool.num_pooled() <= 4);
+}
@@ -5,4 +5,10 @@ use thiserror::Error;
 pub enum CasTypesError {
     #[error("Invalid key: {0}")]
     InvalidKey(String),
+
+    #[error("Invalid merkle hash: expected 64 hex chars, got {0}")]
+    InvalidHashLength(usize),
+
+    #[error("Invalid merkle hash: non-hex character {1:?} at position {0}")]
+    InvalidHashCharacter(usize, char),
 }
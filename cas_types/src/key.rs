@@ -3,7 +3,8 @@ use std::str::FromStr;
 
 use merklehash::data_hash::hex;
 use merklehash::MerkleHash;
-use serde::{Deserialize, Serialize};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use crate::error::CasTypesError;
 
@@ -11,6 +12,7 @@ use crate::error::CasTypesError;
 #[derive(Debug, PartialEq, Default, Serialize, Deserialize, Ord, PartialOrd, Eq, Hash, Clone)]
 pub struct Key {
     pub prefix: String,
+    #[serde(with = "hex::serde")]
     pub hash: MerkleHash,
 }
 
@@ -38,8 +40,10 @@ impl FromStr for Key {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Hash)]
-pub struct HexMerkleHash(#[serde(with = "hex::serde")] pub MerkleHash);
+/// A MerkleHash that is serialized as a 64 character lowercase hex string.  The serialized form
+/// is identical to the `Display` output, and parsing it with `FromStr` gives back the same hash.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct HexMerkleHash(pub MerkleHash);
 
 impl Display for HexMerkleHash {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
@@ -47,6 +51,49 @@ impl Display for HexMerkleHash {
     }
 }
 
+impl FromStr for HexMerkleHash {
+    type Err = CasTypesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((pos, c)) = s.char_indices().find(|(_, c)| !c.is_ascii_hexdigit()) {
+            return Err(CasTypesError::InvalidHashCharacter(pos, c));
+        }
+        if s.len() != 64 {
+            return Err(CasTypesError::InvalidHashLength(s.len()));
+        }
+
+        // All characters are validated above, so this cannot fail.
+        let hash = MerkleHash::from_hex(s).map_err(|_| CasTypesError::InvalidHashLength(s.len()))?;
+        Ok(HexMerkleHash(hash))
+    }
+}
+
+impl Serialize for HexMerkleHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HexMerkleHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct HexMerkleHashVisitor;
+
+        impl Visitor<'_> for HexMerkleHashVisitor {
+            type Value = HexMerkleHash;
+
+            fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+                formatter.write_str("a 64 character hex merkle hash")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(HexMerkleHashVisitor)
+    }
+}
+
 impl From<MerkleHash> for HexMerkleHash {
     fn from(value: MerkleHash) -> Self {
         HexMerkleHash(value)
@@ -89,3 +136,38 @@ impl From<Key> for HexKey {
         HexKey { prefix, hash }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_HEX: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_hex_merkle_hash_valid() {
+        let hash: HexMerkleHash = HASH_HEX.parse().unwrap();
+        assert_eq!(hash.0, MerkleHash::from_hex(HASH_HEX).unwrap());
+        assert_eq!(hash.to_string(), HASH_HEX);
+
+        let json = serde_json::to_string(&hash).unwrap();
+        assert_eq!(json, format!("\"{HASH_HEX}\""));
+        assert_eq!(serde_json::from_str::<HexMerkleHash>(&json).unwrap(), hash);
+    }
+
+    #[test]
+    fn test_hex_merkle_hash_invalid() {
+        let short = &HASH_HEX[..10];
+        let err = short.parse::<HexMerkleHash>().unwrap_err();
+        assert!(matches!(err, CasTypesError::InvalidHashLength(10)));
+        assert_eq!(err.to_string(), "Invalid merkle hash: expected 64 hex chars, got 10");
+
+        let long = format!("{HASH_HEX}00");
+        assert!(matches!(long.parse::<HexMerkleHash>(), Err(CasTypesError::InvalidHashLength(66))));
+
+        let non_hex = HASH_HEX.replacen('a', "g", 1);
+        assert!(matches!(non_hex.parse::<HexMerkleHash>(), Err(CasTypesError::InvalidHashCharacter(10, 'g'))));
+
+        let err = serde_json::from_str::<HexMerkleHash>(&format!("\"{short}\"")).unwrap_err();
+        assert!(err.to_string().contains("expected 64 hex chars, got 10"), "{err}");
+    }
+}
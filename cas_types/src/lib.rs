@@ -1,12 +1,12 @@
 use core::fmt;
 use std::collections::{HashMap, HashSet};
 
-use merklehash::MerkleHash;
 use serde::{Deserialize, Serialize};
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
 mod error;
 mod key;
+pub use error::CasTypesError;
 pub use key::*;
 
 /// Indicates a "session id" that clients can use to group together related requests
@@ -14,6 +14,12 @@ pub use key::*;
 pub const SESSION_ID_HEADER: &str = "X-Xet-Session-Id";
 /// Request id generated by CAS for a request.
 pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Request id generated by the client for a logical request, sent with every attempt of it so the
+/// retries of a request can be correlated.
+pub const CLIENT_REQUEST_ID_HEADER: &str = "X-Xet-Client-Request-Id";
+/// Key identifying a single logical upload (of a xorb or shard), sent with every attempt of
+/// that upload so CAS can recognize retries of a request it has already processed.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UploadXorbResponse {
@@ -41,6 +47,27 @@ impl<Idx: fmt::Display> fmt::Display for Range<Idx> {
     }
 }
 
+impl<Idx: Copy + PartialEq> Range<Idx> {
+    /// Returns the union of this exclusive-end range and `other` if `other` starts where this range
+    /// ends.
+    pub fn coalesce(&self, other: &Self) -> Option<Self> {
+        (self.end == other.start).then_some(Range {
+            start: self.start,
+            end: other.end,
+        })
+    }
+}
+
+impl<Idx: Copy + PartialEq + std::ops::Add<Output = Idx> + From<u8>> Range<Idx> {
+    /// Like `coalesce`, for inclusive-end ranges such as `HttpRange`.
+    pub fn coalesce_inclusive(&self, other: &Self) -> Option<Self> {
+        (self.end + Idx::from(1) == other.start).then_some(Range {
+            start: self.start,
+            end: other.end,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub enum RangeParseError<Idx: std::str::FromStr> {
     InvalidFormat,
@@ -141,5 +168,82 @@ pub struct UploadShardResponse {
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct QueryChunkResponse {
-    pub shard: MerkleHash,
+    pub shard: HexMerkleHash,
+}
+
+#[cfg(test)]
+mod tests {
+    use merklehash::MerkleHash;
+    use serde::de::DeserializeOwned;
+    use serde_json::Value;
+
+    use super::*;
+
+    const HASH_HEX: &str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    fn hash() -> HexMerkleHash {
+        HASH_HEX.parse().unwrap()
+    }
+
+    fn assert_hex_hash(value: &Value) {
+        assert_eq!(value.as_str(), Some(HASH_HEX), "{value}");
+    }
+
+    /// Serializes `value` to JSON, checks its hashes with `check`, and checks that it deserializes
+    /// back to the same JSON.
+    fn round_trip<T: Serialize + DeserializeOwned>(value: &T, check: impl Fn(&Value)) {
+        let json = serde_json::to_value(value).unwrap();
+        check(&json);
+        let parsed: T = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(parsed).unwrap(), json);
+    }
+
+    #[test]
+    fn test_hash_fields_serialize_as_hex() {
+        let term = CASReconstructionTerm {
+            hash: hash(),
+            unpacked_length: 100,
+            range: ChunkRange { start: 0, end: 2 },
+        };
+        round_trip(&term, |json| assert_hex_hash(&json["hash"]));
+
+        let fetch_info = vec![CASReconstructionFetchInfo {
+            range: ChunkRange { start: 0, end: 2 },
+            url: "http://localhost/xorb".to_owned(),
+            url_range: HttpRange { start: 0, end: 99 },
+        }];
+        round_trip(
+            &QueryReconstructionResponse {
+                offset_into_first_range: 0,
+                terms: vec![term.clone()],
+                fetch_info: HashMap::from([(hash(), fetch_info.clone())]),
+            },
+            |json| {
+                assert_hex_hash(&json["terms"][0]["hash"]);
+                assert!(json["fetch_info"].get(HASH_HEX).is_some(), "{json}");
+            },
+        );
+        round_trip(
+            &BatchQueryReconstructionResponse {
+                files: HashMap::from([(hash(), vec![term])]),
+                fetch_info: HashMap::from([(hash(), fetch_info)]),
+            },
+            |json| {
+                assert_hex_hash(&json["files"][HASH_HEX][0]["hash"]);
+                assert!(json["fetch_info"].get(HASH_HEX).is_some(), "{json}");
+            },
+        );
+
+        round_trip(&QueryChunkResponse { shard: hash() }, |json| assert_hex_hash(&json["shard"]));
+
+        let key = Key {
+            prefix: "default".to_owned(),
+            hash: hash().into(),
+        };
+        round_trip(&key, |json| assert_hex_hash(&json["hash"]));
+        round_trip(&HexKey::from(key), |json| assert_hex_hash(&json["hash"]));
+        round_trip(&BatchQueryReconstructionRequest::from([HexKey::from(Key::default())]), |json| {
+            assert_eq!(json[0]["hash"].as_str(), Some(MerkleHash::default().hex().as_str()));
+        });
+    }
 }
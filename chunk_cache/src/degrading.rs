@@ -0,0 +1,64 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use cas_types::{ChunkRange, Key};
+use tracing::warn;
+
+use crate::error::ChunkCacheError;
+use crate::ChunkCache;
+
+/// DegradingCache is a ChunkCache implementor that turns itself off the first time a write to the
+/// cache it wraps fails with an IO error, e.g. because the cache directory is on a read-only or full
+/// volume: the failed put and every later put succeed without caching anything, and every later get
+/// is a miss.  The cache is an optimization, so a transfer using it goes on without it rather than
+/// failing.
+///
+/// Other put errors, such as invalid arguments, are returned as they are.
+pub struct DegradingCache {
+    inner: Arc<dyn ChunkCache>,
+    disabled: AtomicBool,
+}
+
+impl DegradingCache {
+    pub fn new(inner: Arc<dyn ChunkCache>) -> Self {
+        Self {
+            inner,
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    /// whether a failed write has turned the cache off.
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.load(Ordering::Relaxed)
+    }
+}
+
+impl ChunkCache for DegradingCache {
+    fn get(&self, key: &Key, range: &ChunkRange) -> Result<Option<Vec<u8>>, ChunkCacheError> {
+        if self.is_disabled() {
+            return Ok(None);
+        }
+        self.inner.get(key, range)
+    }
+
+    fn put(
+        &self,
+        key: &Key,
+        range: &ChunkRange,
+        chunk_byte_indices: &[u32],
+        data: &[u8],
+    ) -> Result<(), ChunkCacheError> {
+        if self.is_disabled() {
+            return Ok(());
+        }
+        match self.inner.put(key, range, chunk_byte_indices, data) {
+            Err(ChunkCacheError::IO(e)) => {
+                if !self.disabled.swap(true, Ordering::Relaxed) {
+                    warn!("failed to write {key} {range} to the chunk cache ({e}); not using the cache from now on");
+                }
+                Ok(())
+            },
+            result => result,
+        }
+    }
+}
@@ -1,16 +1,18 @@
 use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fs::{DirEntry, File};
 use std::io::{self, Cursor, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::mem::size_of;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 
 use base64::engine::general_purpose::URL_SAFE;
 use base64::engine::GeneralPurpose;
 use base64::Engine;
 use cas_types::{ChunkRange, Key};
 use error_printer::ErrorPrinter;
-use file_utils::SafeFileCreator;
+use file_utils::{create_file, SafeFileCreator};
 use merklehash::MerkleHash;
 use tracing::{debug, warn};
 #[cfg(feature = "analysis")]
@@ -18,17 +20,27 @@ use utils::output_bytes;
 
 use crate::disk::cache_file_header::CacheFileHeader;
 use crate::disk::cache_item::{CacheItem, VerificationCell};
+use crate::disk::file_lock::FileLock;
 use crate::error::ChunkCacheError;
-use crate::{CacheConfig, ChunkCache};
+use crate::{CacheConfig, ChunkCache, CHUNK_CACHE_LOCK_TIMEOUT_MS};
 
 mod cache_file_header;
 mod cache_item;
+mod file_lock;
 pub mod test_utils;
 
 // consistently use URL_SAFE (also file path safe) base64 codec
 pub(crate) const BASE64_ENGINE: GeneralPurpose = URL_SAFE;
 pub const DEFAULT_CHUNK_CACHE_CAPACITY: u64 = 10 << 30; // 10 GB
 const PREFIX_DIR_NAME_LEN: usize = 2;
+// sidecar file in the cache root listing the pinned keys, one per line
+const PINNED_KEYS_FILE_NAME: &str = "pinned_keys";
+// suffix of the temporary files cache files are written to before being renamed into place
+const TEMP_FILE_SUFFIX: &str = ".tmp";
+// directory in the cache root holding the lock files, one per key prefix directory and one for the
+// pinned keys file
+const LOCKS_DIR_NAME: &str = "locks";
+const LOCK_FILE_SUFFIX: &str = ".lock";
 
 type OptionResult<T, E> = Result<Option<T>, E>;
 
@@ -37,6 +49,8 @@ struct CacheState {
     inner: HashMap<Key, Vec<VerificationCell<CacheItem>>>,
     num_items: usize,
     total_bytes: u64,
+    // keys whose items are never chosen for eviction
+    pinned: HashSet<Key>,
 }
 
 impl CacheState {
@@ -45,16 +59,36 @@ impl CacheState {
             inner: state,
             num_items,
             total_bytes,
+            pinned: HashSet::new(),
         }
     }
+
+    fn key_bytes(&self, key: &Key) -> u64 {
+        self.inner
+            .get(key)
+            .map(|items| items.iter().map(|item| item.len).sum())
+            .unwrap_or(0)
+    }
+
+    fn pinned_bytes(&self) -> u64 {
+        self.pinned.iter().map(|key| self.key_bytes(key)).sum()
+    }
+
+    fn num_pinned_items(&self) -> usize {
+        self.pinned.iter().filter_map(|key| self.inner.get(key)).map(Vec::len).sum()
+    }
 }
 
 /// DiskCache is a ChunkCache implementor that saves data on the file system
+///
+/// several processes may share a cache directory: each keeps its own in-memory state, and they
+/// coordinate their changes to the directory with advisory file locks, see `initialize`.
 #[derive(Debug, Clone)]
 pub struct DiskCache {
     cache_root: PathBuf,
     capacity: u64,
     state: Arc<Mutex<CacheState>>,
+    lock_timeout: Duration,
 }
 
 // helper for analysis binary to print inner state
@@ -102,12 +136,110 @@ impl DiskCache {
         Ok(state.total_bytes)
     }
 
+    /// pins the given key so that its items, including items put after pinning, are never evicted.
+    /// the set of pinned keys is persisted in the cache directory and restored on initialize.
+    ///
+    /// the items of all pinned keys may take up at most the capacity less the largest allowed item
+    /// size (10% of capacity), so that unpinned items can still be cached; pinning a key beyond
+    /// that fails with ChunkCacheError::PinCapacityExceeded and leaves the key unpinned.
+    ///
+    /// the keys pinned or unpinned since by other processes sharing the cache directory are picked
+    /// up first, so that none of their changes are lost.
+    pub fn pin(&self, key: &Key) -> Result<(), ChunkCacheError> {
+        let _lock = self.lock_pinned_keys()?;
+        let mut state = self.state.lock()?;
+        state.pinned = read_pinned_keys(&self.cache_root)?;
+        if state.pinned.contains(key) {
+            return Ok(());
+        }
+
+        let pinned_bytes = state.pinned_bytes() + state.key_bytes(key);
+        if pinned_bytes > self.max_pinned_bytes() {
+            warn!(
+                "refusing to pin {key}: pinned cache items would take {pinned_bytes} bytes, over the limit of {} bytes",
+                self.max_pinned_bytes()
+            );
+            return Err(ChunkCacheError::PinCapacityExceeded);
+        }
+
+        state.pinned.insert(key.clone());
+        self.write_pinned_keys(&state.pinned)
+    }
+
+    /// unpins the given key, making its items eligible for eviction again.
+    pub fn unpin(&self, key: &Key) -> Result<(), ChunkCacheError> {
+        let _lock = self.lock_pinned_keys()?;
+        let mut state = self.state.lock()?;
+        state.pinned = read_pinned_keys(&self.cache_root)?;
+        if state.pinned.remove(key) {
+            self.write_pinned_keys(&state.pinned)?;
+        }
+        Ok(())
+    }
+
+    /// whether the given key is pinned, as of the last pin or unpin by this cache.
+    pub fn is_pinned(&self, key: &Key) -> Result<bool, ChunkCacheError> {
+        let state = self.state.lock()?;
+        Ok(state.pinned.contains(key))
+    }
+
+    /// like get, but also returns the chunk byte indices of the returned data, as they would be
+    /// passed to put, so that the range can be copied into another cache.
+    pub fn get_with_chunk_byte_indices(
+        &self,
+        key: &Key,
+        range: &ChunkRange,
+    ) -> OptionResult<(Vec<u8>, Vec<u32>), ChunkCacheError> {
+        self.get_impl(key, range)
+    }
+
+    fn max_pinned_bytes(&self) -> u64 {
+        self.capacity - self.capacity / 10
+    }
+
+    /// sets how long a write waits for another process sharing the cache directory to release a
+    /// lock before giving up; defaults to CHUNK_CACHE_LOCK_TIMEOUT_MS.
+    pub fn with_lock_timeout(mut self, lock_timeout: Duration) -> Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    // locks the pinned keys file against other processes, or fails with LockTimeout
+    fn lock_pinned_keys(&self) -> Result<FileLock, ChunkCacheError> {
+        FileLock::lock(&lock_path(&self.cache_root, PINNED_KEYS_FILE_NAME), self.lock_timeout)?
+            .ok_or(ChunkCacheError::LockTimeout)
+    }
+
+    // locks the prefix directory of the given key against other processes, returning None on timeout
+    fn lock_key_dir(&self, key: &Key) -> OptionResult<FileLock, ChunkCacheError> {
+        FileLock::lock(&lock_path(&self.cache_root, &key_prefix_dir_name(key)), self.lock_timeout)
+    }
+
+    fn write_pinned_keys(&self, pinned: &HashSet<Key>) -> Result<(), ChunkCacheError> {
+        let mut fw = SafeFileCreator::new(self.cache_root.join(PINNED_KEYS_FILE_NAME))?;
+        for key in pinned {
+            writeln!(fw, "{key}")?;
+        }
+        fw.close()?;
+        Ok(())
+    }
+
     /// initialize will create a new DiskCache with the capacity and cache root based on the config
     /// the cache file system layout is rooted at the provided config.cache_directory and initialize
     /// will attempt to load any pre-existing cache state into memory.
     ///
     /// an configured size of 0 caused initialization to fail
     ///
+    /// temporary files left by writes interrupted by a crash are removed while loading; see
+    /// `write_cache_file`.
+    ///
+    /// processes sharing the cache directory coordinate through lock files in its "locks"
+    /// subdirectory: writing or removing the files of a key takes the lock of its prefix directory,
+    /// and changing the pinned keys takes the lock of the pinned keys file, waiting for up to the lock
+    /// timeout for another process to release it.  a put that times out doesn't cache its item.
+    /// reads take no lock, as files are only ever renamed into place complete, so they never wait on
+    /// a write.
+    ///
     /// The cache layout is as follows:
     ///
     /// each key (cas hash) in the cache is a directory, containing "cache items" that each provide
@@ -137,12 +269,14 @@ impl DiskCache {
         let capacity = config.cache_size;
         let cache_root = config.cache_directory.clone();
 
-        let state = Self::initialize_state(&cache_root, capacity)?;
+        let mut state = Self::initialize_state(&cache_root, capacity)?;
+        state.pinned = read_pinned_keys(&cache_root)?;
 
         Ok(Self {
             state: Arc::new(Mutex::new(state)),
             cache_root,
             capacity,
+            lock_timeout: Duration::from_millis(*CHUNK_CACHE_LOCK_TIMEOUT_MS),
         })
     }
 
@@ -159,6 +293,17 @@ impl DiskCache {
         // loop through cache root directory, first level containing "prefix" directories
         // each of which may contain key directories with cache items
         for key_prefix_dir in cache_root_readdir {
+            // the pinned keys file is written through a temporary file in the cache root, which is
+            // only left over if no other process is writing the file
+            if let Ok(entry) = &key_prefix_dir {
+                if is_temp_file_name(&entry.file_name()) {
+                    if let Some(_lock) = FileLock::try_lock(&lock_path(cache_root, PINNED_KEYS_FILE_NAME))? {
+                        remove_temp_file(&entry.path())?;
+                    }
+                    continue;
+                }
+            }
+
             let Some(key_prefix_dir) = is_ok_dir(key_prefix_dir)? else {
                 continue;
             };
@@ -173,6 +318,11 @@ impl DiskCache {
                 continue;
             };
 
+            // temporary files under the prefix directory are only left over if no other process is
+            // writing to it
+            let prefix_lock = FileLock::try_lock(&lock_path(cache_root, &key_prefix_dir_name.to_string_lossy()))?;
+            let remove_temp_files = prefix_lock.is_some();
+
             // loop throught key directories inside prefix directory
             for key_dir in key_prefix_readdir {
                 let key_dir = match is_ok_dir(key_dir) {
@@ -208,7 +358,7 @@ impl DiskCache {
 
                 // loop through cache items inside key directory
                 for item in key_readdir {
-                    let cache_item = match try_parse_cache_file(item, capacity) {
+                    let cache_item = match try_parse_cache_file(item, capacity, remove_temp_files) {
                         Ok(Some(ci)) => ci,
                         Ok(None) => continue,
                         Err(e) => return Err(e),
@@ -234,7 +384,7 @@ impl DiskCache {
         Ok(CacheState::new(state, num_items, total_bytes))
     }
 
-    fn get_impl(&self, key: &Key, range: &ChunkRange) -> OptionResult<Vec<u8>, ChunkCacheError> {
+    fn get_impl(&self, key: &Key, range: &ChunkRange) -> OptionResult<(Vec<u8>, Vec<u32>), ChunkCacheError> {
         if range.start >= range.end {
             return Err(ChunkCacheError::InvalidArguments);
         }
@@ -280,7 +430,13 @@ impl DiskCache {
 
             let start = cache_item.range.start;
             let result_buf = get_range_from_cache_file(&header, &mut file_reader, range, start)?;
-            return Ok(Some(result_buf));
+            let first_byte = header.chunk_byte_indices[(range.start - start) as usize];
+            let chunk_byte_indices = header.chunk_byte_indices
+                [(range.start - start) as usize..=(range.end - start) as usize]
+                .iter()
+                .map(|index| index - first_byte)
+                .collect();
+            return Ok(Some((result_buf, chunk_byte_indices)));
         }
     }
 
@@ -341,13 +497,27 @@ impl DiskCache {
             checksum,
         };
 
+        // pinned items can't be evicted to make room, in which case the item is not cached.
         {
-            // write cache item file
-            let path = self.item_path(key, &cache_item)?;
-            let mut fw = SafeFileCreator::new(path)?;
-            fw.write_all(&header_buf)?;
-            fw.write_all(data)?;
-            fw.close()?;
+            let state = self.state.lock()?;
+            if state.pinned_bytes() + cache_item.len > self.capacity {
+                debug!("not caching {key}/{cache_item}: no room left besides pinned items");
+                return Ok(());
+            }
+        }
+
+        // write cache item file, holding the lock of its directory so that no other process removes
+        // the directory or the temporary file during the write
+        let path = self.item_path(key, &cache_item)?;
+        let Some(lock) = self.lock_key_dir(key)? else {
+            debug!("not caching {key}/{cache_item}: timed out waiting for another process writing to the cache");
+            return Ok(());
+        };
+        let written = write_cache_file(&path, &header_buf, data)?;
+        drop(lock);
+        if !written {
+            debug!("not caching {key}/{cache_item}: its temporary file was removed while being written");
+            return Ok(());
         }
 
         // evict items after ensuring the file write but before committing to cache state
@@ -392,13 +562,10 @@ impl DiskCache {
 
         // remove files after done with modifying in memory state and releasing lock
         for path in overlapping_item_paths {
-            remove_file(&path)?;
+            self.remove_item_file(key, &path)?;
         }
-        for path in evicted_paths {
-            remove_file(&path)?;
-            // check and try to remove key path if all items evicted for key
-            let dir_path = path.parent().ok_or(ChunkCacheError::Infallible)?;
-            check_remove_dir(dir_path)?;
+        for (evicted_key, path) in evicted_paths {
+            self.remove_item_file(&evicted_key, &path)?;
         }
 
         Ok(())
@@ -473,26 +640,28 @@ impl DiskCache {
     }
 
     /// removed items from the cache (including deleting from file system)
-    /// until at least to_remove number of bytes have been removed
+    /// until at least to_remove number of bytes have been removed, or only pinned items remain
     ///
-    /// removes data from in memory state and returns a list of file paths to delete
+    /// removes data from in memory state and returns a list of keys and file paths to delete
     /// (so that deletion can occur after the locked state is dropped)
     fn maybe_evict(
         &self,
         state: &mut MutexGuard<'_, CacheState>,
         expected_add: u64,
-    ) -> Result<Vec<PathBuf>, ChunkCacheError> {
+    ) -> Result<Vec<(Key, PathBuf)>, ChunkCacheError> {
         let total_bytes = state.total_bytes;
         let to_remove = total_bytes as i64 - self.capacity as i64 + expected_add as i64;
         let mut bytes_removed = 0;
         let mut paths = Vec::new();
         while to_remove > bytes_removed {
-            let (key, idx) = self.random_item(state);
+            let Some((key, idx)) = self.random_unpinned_item(state) else {
+                debug!("only pinned items remain, stopping eviction");
+                break;
+            };
             let items = state.inner.get_mut(&key).ok_or(ChunkCacheError::Infallible)?;
             let cache_item = &items[idx];
             let len = cache_item.len;
             let path = self.item_path(&key, cache_item)?;
-            paths.push(path);
             items.remove(idx);
             if items.is_empty() {
                 state.inner.remove(&key);
@@ -500,19 +669,24 @@ impl DiskCache {
             state.total_bytes -= len;
             state.num_items -= 1;
             bytes_removed += len as i64;
+            paths.push((key, path));
         }
 
         Ok(paths)
     }
 
-    /// returns the key and index within that key for a random item
-    fn random_item(&self, state: &MutexGuard<'_, CacheState>) -> (Key, usize) {
-        let num_items = state.num_items;
+    /// returns the key and index within that key for a random item that is not pinned,
+    /// or None if all items are pinned
+    fn random_unpinned_item(&self, state: &MutexGuard<'_, CacheState>) -> Option<(Key, usize)> {
+        let num_items = state.num_items - state.num_pinned_items();
+        if num_items == 0 {
+            return None;
+        }
         let random_item = rand::random::<usize>() % num_items;
         let mut count = 0;
-        for (key, items) in state.inner.iter() {
+        for (key, items) in state.inner.iter().filter(|(key, _)| !state.pinned.contains(key)) {
             if random_item < count + items.len() {
-                return (key.clone(), random_item - count);
+                return Some((key.clone(), random_item - count));
             }
             count += items.len();
         }
@@ -544,7 +718,18 @@ impl DiskCache {
         if !path.exists() {
             return Ok(());
         }
-        remove_file(&path)?;
+        self.remove_item_file(key, &path)
+    }
+
+    /// removes a file of the given key and, if that leaves it empty, the key's directory, under the
+    /// lock of the directory.  if another process holds the lock past the timeout the file is left
+    /// in place; it's then only found again by a later initialize.
+    fn remove_item_file(&self, key: &Key, path: &Path) -> Result<(), ChunkCacheError> {
+        let Some(_lock) = self.lock_key_dir(key)? else {
+            warn!("leaving cache file {path:?}: timed out waiting for another process writing to the cache");
+            return Ok(());
+        };
+        remove_file(path)?;
         let dir_path = path.parent().ok_or(ChunkCacheError::Infallible)?;
         check_remove_dir(dir_path)
     }
@@ -554,6 +739,20 @@ impl DiskCache {
     }
 }
 
+// reads the pinned keys sidecar file, skipping any lines that fail to parse as a key
+fn read_pinned_keys(cache_root: &Path) -> Result<HashSet<Key>, ChunkCacheError> {
+    let contents = match std::fs::read_to_string(cache_root.join(PINNED_KEYS_FILE_NAME)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.parse::<Key>().debug_error("failed to parse pinned cache key").ok())
+        .collect())
+}
+
 fn crc32_from_reader(reader: &mut impl Read) -> Result<u32, ChunkCacheError> {
     const CRC_BUFFER_SIZE: usize = 4096;
     let mut buf = [0u8; CRC_BUFFER_SIZE];
@@ -656,7 +855,12 @@ fn is_ok_dir(dir_result: Result<DirEntry, io::Error>) -> OptionResult<DirEntry,
 // given a result from readdir attempts to parse it as a cache file handle
 // i.e. validate its file name against the contents (excluding file-hash-validation)
 // validate that it is a file, correct len, and is not too large.
-fn try_parse_cache_file(file_result: io::Result<DirEntry>, capacity: u64) -> OptionResult<CacheItem, ChunkCacheError> {
+// temporary files are skipped, and removed if remove_temp_files is set.
+fn try_parse_cache_file(
+    file_result: io::Result<DirEntry>,
+    capacity: u64,
+    remove_temp_files: bool,
+) -> OptionResult<CacheItem, ChunkCacheError> {
     let item = match file_result {
         Ok(item) => item,
         Err(e) => {
@@ -679,6 +883,12 @@ fn try_parse_cache_file(file_result: io::Result<DirEntry>, capacity: u64) -> Opt
     if !md.is_file() {
         return Ok(None);
     }
+    if is_temp_file_name(&item.file_name()) {
+        if remove_temp_files {
+            remove_temp_file(&item.path())?;
+        }
+        return Ok(None);
+    }
     if md.len() > DEFAULT_CHUNK_CACHE_CAPACITY {
         return Err(ChunkCacheError::general(format!(
             "Cache directory contains a file larger than {} GB, cache directory state is invalid",
@@ -715,6 +925,46 @@ fn try_parse_cache_file(file_result: io::Result<DirEntry>, capacity: u64) -> Opt
     Ok(Some(cache_item))
 }
 
+/// writes a cache file so that it's never seen partially written under its name, even after a crash:
+/// the contents go to a temporary file next to it, which is synced to disk and then renamed into place.
+/// the temporary file is removed if the write fails, and by `DiskCache::initialize` if left by a crash.
+///
+/// returns false if the temporary file was removed before it could be renamed, which happens if
+/// another process sharing the cache directory, without locking it, initialized its cache in the
+/// meantime.
+fn write_cache_file(path: &Path, header: &[u8], data: &[u8]) -> Result<bool, ChunkCacheError> {
+    let file_name = path.file_name().ok_or(ChunkCacheError::Infallible)?.to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{file_name}.{:016x}{TEMP_FILE_SUFFIX}", rand::random::<u64>()));
+
+    let write = || -> io::Result<()> {
+        let mut file = create_file(&temp_path)?;
+        file.write_all(header)?;
+        file.write_all(data)?;
+        file.sync_data()?;
+        std::fs::rename(&temp_path, path)
+    };
+    match write() {
+        Ok(()) => Ok(true),
+        Err(e) => {
+            remove_file(&temp_path)?;
+            if e.kind() == ErrorKind::NotFound {
+                Ok(false)
+            } else {
+                Err(e.into())
+            }
+        },
+    }
+}
+
+fn is_temp_file_name(file_name: &OsStr) -> bool {
+    file_name.as_encoded_bytes().ends_with(TEMP_FILE_SUFFIX.as_bytes())
+}
+
+fn remove_temp_file(path: &Path) -> Result<(), ChunkCacheError> {
+    debug!("removing temporary file left by an interrupted cache write: {path:?}");
+    remove_file(path)
+}
+
 /// removes a file but disregards a "NotFound" error if the file is already gone
 fn remove_file(path: impl AsRef<Path>) -> Result<(), ChunkCacheError> {
     if let Err(e) = std::fs::remove_file(path) {
@@ -763,6 +1013,11 @@ fn check_remove_dir(dir_path: impl AsRef<Path>) -> Result<(), ChunkCacheError> {
     remove_dir(prefix_dir)
 }
 
+// path of the lock file named `name` under the cache root
+fn lock_path(cache_root: &Path, name: &str) -> PathBuf {
+    cache_root.join(LOCKS_DIR_NAME).join(format!("{name}{LOCK_FILE_SUFFIX}"))
+}
+
 /// tries to parse just a Key from a file name encoded by fn `key_dir`
 /// expects only the key portion of the file path, with the prefix not present.
 fn try_parse_key(file_name: &[u8]) -> Result<Key, ChunkCacheError> {
@@ -775,19 +1030,30 @@ fn try_parse_key(file_name: &[u8]) -> Result<Key, ChunkCacheError> {
 /// key_dir returns a directory name string formed from the key
 /// the format is BASE64_encode([ key.hash[..], key.prefix.as_bytes()[..] ])
 fn key_dir(key: &Key) -> PathBuf {
+    let encoded = key_dir_name(key);
+    let prefix_dir = &encoded[..PREFIX_DIR_NAME_LEN];
+    let dir_str = format!("{prefix_dir}/{encoded}");
+    PathBuf::from(dir_str)
+}
+
+fn key_dir_name(key: &Key) -> String {
     let prefix_bytes = key.prefix.as_bytes();
     let mut buf = vec![0u8; size_of::<MerkleHash>() + prefix_bytes.len()];
     buf[..size_of::<MerkleHash>()].copy_from_slice(key.hash.as_bytes());
     buf[size_of::<MerkleHash>()..].copy_from_slice(prefix_bytes);
-    let encoded = BASE64_ENGINE.encode(&buf);
-    let prefix_dir = &encoded[..PREFIX_DIR_NAME_LEN];
-    let dir_str = format!("{prefix_dir}/{encoded}");
-    PathBuf::from(dir_str)
+    BASE64_ENGINE.encode(&buf)
+}
+
+// name of the prefix directory of the key directory of the given key
+fn key_prefix_dir_name(key: &Key) -> String {
+    let mut name = key_dir_name(key);
+    name.truncate(PREFIX_DIR_NAME_LEN);
+    name
 }
 
 impl ChunkCache for DiskCache {
     fn get(&self, key: &Key, range: &ChunkRange) -> Result<Option<Vec<u8>>, ChunkCacheError> {
-        self.get_impl(key, range)
+        Ok(self.get_impl(key, range)?.map(|(data, _)| data))
     }
 
     fn put(
@@ -804,15 +1070,21 @@ impl ChunkCache for DiskCache {
 #[cfg(test)]
 mod tests {
     use std::collections::BTreeSet;
+    use std::time::Duration;
 
     use cas_types::{ChunkRange, Key};
     use rand::rngs::StdRng;
     use rand::SeedableRng;
     use tempdir::TempDir;
 
-    use super::{DiskCache, DEFAULT_CHUNK_CACHE_CAPACITY};
+    use super::{
+        key_prefix_dir_name, lock_path, DiskCache, DEFAULT_CHUNK_CACHE_CAPACITY, PINNED_KEYS_FILE_NAME,
+        TEMP_FILE_SUFFIX,
+    };
+    use crate::disk::file_lock::FileLock;
     use crate::disk::test_utils::*;
     use crate::disk::try_parse_key;
+    use crate::error::ChunkCacheError;
     use crate::{CacheConfig, ChunkCache};
 
     const RANDOM_SEED: u64 = 9089 << 20 | 120043;
@@ -1016,6 +1288,55 @@ mod tests {
         assert_eq!(cache_keys, cache2_keys);
     }
 
+    #[test]
+    fn test_initialize_removes_partial_writes() {
+        let cache_root = TempDir::new("initialize_removes_partial_writes").unwrap();
+        let config = CacheConfig {
+            cache_directory: cache_root.path().to_path_buf(),
+            cache_size: DEFAULT_CHUNK_CACHE_CAPACITY,
+        };
+        let cache = DiskCache::initialize(&config).unwrap();
+        let (key, range, offsets, data) = RandomEntryIterator::std_from_seed(RANDOM_SEED).next().unwrap();
+        cache.put(&key, &range, &offsets, &data).unwrap();
+        let item_path = {
+            let state = cache.state.lock().unwrap();
+            cache.item_path(&key, &state.inner[&key][0]).unwrap()
+        };
+        let item_len = std::fs::metadata(&item_path).unwrap().len();
+        drop(cache);
+
+        // A crash mid-write leaves a partial temporary file, here next to a cache item and next to
+        // the pinned keys file, or, for a write that wasn't atomic, a partial item.
+        let item_temp_path = item_path.with_file_name(format!(
+            ".{}.0123456789abcdef{TEMP_FILE_SUFFIX}",
+            item_path.file_name().unwrap().to_string_lossy()
+        ));
+        std::fs::write(&item_temp_path, &data[..data.len() / 2]).unwrap();
+        let pinned_temp_path = cache_root
+            .path()
+            .join(format!(".{PINNED_KEYS_FILE_NAME}.abc{TEMP_FILE_SUFFIX}"));
+        std::fs::write(&pinned_temp_path, "partial").unwrap();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&item_path)
+            .unwrap()
+            .set_len(item_len / 2)
+            .unwrap();
+
+        // On restart, the partial files are removed and nothing is served from them.
+        let cache2 = DiskCache::initialize(&config).unwrap();
+        assert!(!item_temp_path.exists());
+        assert!(!pinned_temp_path.exists());
+        assert!(!item_path.exists());
+        assert_eq!(cache2.num_items().unwrap(), 0);
+        assert_eq!(cache2.get(&key, &range).unwrap(), None);
+
+        // Complete writes still round trip.
+        cache2.put(&key, &range, &offsets, &data).unwrap();
+        let cache3 = DiskCache::initialize(&config).unwrap();
+        assert_eq!(cache3.get(&key, &range).unwrap(), Some(data));
+    }
+
     #[test]
     fn test_initialize_too_large_file() {
         const LARGE_FILE: u64 = 1000;
@@ -1166,6 +1487,132 @@ mod tests {
         assert_eq!(total_bytes, cache.total_bytes().unwrap());
     }
 
+    #[test]
+    fn test_pinned_items_survive_eviction() {
+        const CAP: u64 = (RANGE_LEN * 16) as u64;
+        let cache_root = TempDir::new("pinned_eviction").unwrap();
+        let config = CacheConfig {
+            cache_directory: cache_root.path().to_path_buf(),
+            cache_size: CAP,
+        };
+        let cache = DiskCache::initialize(&config).unwrap();
+        let mut it = RandomEntryIterator::std_from_seed(RANDOM_SEED);
+
+        let pinned: Vec<_> = (0..3).map(|_| it.next().unwrap()).collect();
+        for (key, range, offsets, data) in pinned.iter() {
+            cache.put(key, range, offsets, data).unwrap();
+            cache.pin(key).unwrap();
+        }
+
+        // fill the cache well past its capacity
+        let unpinned: Vec<_> = (0..40).map(|_| it.next().unwrap()).collect();
+        for (key, range, offsets, data) in unpinned.iter() {
+            cache.put(key, range, offsets, data).unwrap();
+            assert!(cache.total_bytes().unwrap() <= CAP);
+        }
+
+        for (key, range, _, data) in pinned.iter() {
+            assert_eq!(cache.get(key, range).unwrap().as_ref(), Some(data));
+        }
+        let num_unpinned_hits = unpinned
+            .iter()
+            .filter(|(key, range, _, _)| cache.get(key, range).unwrap().is_some())
+            .count();
+        assert!(num_unpinned_hits < unpinned.len());
+
+        // pins are restored on initialize
+        let cache2 = DiskCache::initialize(&config).unwrap();
+        for (key, range, _, data) in pinned.iter() {
+            assert!(cache2.is_pinned(key).unwrap());
+            assert_eq!(cache2.get(key, range).unwrap().as_ref(), Some(data));
+        }
+        assert!(!cache2.is_pinned(&unpinned[0].0).unwrap());
+
+        // once unpinned, the items can be evicted again
+        for (key, _, _, _) in pinned.iter() {
+            cache2.unpin(key).unwrap();
+        }
+        for _ in 0..100 {
+            let (key, range, offsets, data) = it.next().unwrap();
+            cache2.put(&key, &range, &offsets, &data).unwrap();
+        }
+        assert!(pinned
+            .iter()
+            .any(|(key, range, _, _)| cache2.get(key, range).unwrap().is_none()));
+        assert!(!DiskCache::initialize(&config).unwrap().is_pinned(&pinned[0].0).unwrap());
+    }
+
+    #[test]
+    fn test_locked_by_another_process() {
+        let cache_root = TempDir::new("locked_by_another_process").unwrap();
+        let config = CacheConfig {
+            cache_directory: cache_root.path().to_path_buf(),
+            cache_size: DEFAULT_CHUNK_CACHE_CAPACITY,
+        };
+        let cache = DiskCache::initialize(&config)
+            .unwrap()
+            .with_lock_timeout(Duration::from_millis(10));
+        let mut it = RandomEntryIterator::std_from_seed(RANDOM_SEED);
+        let (key, range, offsets, data) = it.next().unwrap();
+        cache.put(&key, &range, &offsets, &data).unwrap();
+
+        // another process writing under the key's prefix directory, and to the pinned keys file.
+        let _key_lock = FileLock::try_lock(&lock_path(cache_root.path(), &key_prefix_dir_name(&key)))
+            .unwrap()
+            .unwrap();
+        let _pinned_keys_lock = FileLock::try_lock(&lock_path(cache_root.path(), PINNED_KEYS_FILE_NAME))
+            .unwrap()
+            .unwrap();
+
+        // reads don't wait for the lock, while a write gives up on caching after the timeout.
+        assert_eq!(cache.get(&key, &range).unwrap(), Some(data.clone()));
+        let other_range = ChunkRange {
+            start: range.end,
+            end: range.end + 1,
+        };
+        let other_data = vec![1u8; 16];
+        cache.put(&key, &other_range, &[0, 16], &other_data).unwrap();
+        assert_eq!(cache.get(&key, &other_range).unwrap(), None);
+        assert!(matches!(cache.pin(&key), Err(ChunkCacheError::LockTimeout)));
+
+        // keys under other prefix directories are still written.
+        let (other_key, range, offsets, data) = it
+            .find(|(other_key, ..)| key_prefix_dir_name(other_key) != key_prefix_dir_name(&key))
+            .unwrap();
+        cache.put(&other_key, &range, &offsets, &data).unwrap();
+        assert_eq!(cache.get(&other_key, &range).unwrap(), Some(data));
+    }
+
+    #[test]
+    fn test_pin_beyond_capacity_refused() {
+        const CAP: u64 = (RANGE_LEN * 16) as u64;
+        let cache_root = TempDir::new("pin_beyond_capacity").unwrap();
+        let config = CacheConfig {
+            cache_directory: cache_root.path().to_path_buf(),
+            cache_size: CAP,
+        };
+        let cache = DiskCache::initialize(&config).unwrap();
+        let mut it = RandomEntryIterator::std_from_seed(RANDOM_SEED);
+
+        let mut refused = None;
+        for _ in 0..20 {
+            let (key, range, offsets, data) = it.next().unwrap();
+            cache.put(&key, &range, &offsets, &data).unwrap();
+            match cache.pin(&key) {
+                Ok(()) => {},
+                Err(ChunkCacheError::PinCapacityExceeded) => {
+                    refused = Some(key);
+                    break;
+                },
+                Err(e) => panic!("unexpected error {e:?}"),
+            }
+        }
+
+        let refused = refused.expect("pinning should be refused before pinning the whole cache");
+        assert!(!cache.is_pinned(&refused).unwrap());
+        assert!(cache.total_bytes().unwrap() <= CAP);
+    }
+
     #[test]
     fn test_evictions_with_multiple_range_per_key() {
         const NUM: u32 = 12;
@@ -1229,6 +1676,48 @@ mod concurrency_tests {
     use crate::disk::DEFAULT_CHUNK_CACHE_CAPACITY;
     use crate::{CacheConfig, ChunkCache, RandomEntryIterator, RANGE_LEN};
 
+    #[test]
+    fn test_processes_sharing_cache_dir() {
+        const NUM_PROCESSES: usize = 4;
+
+        let cache_root = TempDir::new("processes_sharing_cache_dir").unwrap();
+        let config = CacheConfig {
+            cache_directory: cache_root.path().to_path_buf(),
+            cache_size: DEFAULT_CHUNK_CACHE_CAPACITY,
+        };
+        let entries: Vec<_> = RandomEntryIterator::std_from_seed(RANDOM_SEED).take(40).collect();
+
+        // each thread initializes its own cache on the directory, as a separate process would, while
+        // the others are writing to it, and pins a share of the keys.
+        let handles: Vec<_> = (0..NUM_PROCESSES)
+            .map(|i| {
+                let config = config.clone();
+                let entries = entries.clone();
+                std::thread::spawn(move || {
+                    let cache = DiskCache::initialize(&config).unwrap();
+                    for (j, (key, range, chunk_byte_indices, data)) in entries.iter().enumerate() {
+                        cache.put(key, range, chunk_byte_indices, data).unwrap();
+                        assert_eq!(cache.get(key, range).unwrap().as_ref(), Some(data));
+                        if j % NUM_PROCESSES == i {
+                            cache.pin(key).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // every item is intact, and no process lost the pins of another.
+        let cache = DiskCache::initialize(&config).unwrap();
+        assert_eq!(cache.num_items().unwrap(), entries.len());
+        for (key, range, _, data) in &entries {
+            assert_eq!(cache.get(key, range).unwrap().as_ref(), Some(data));
+            assert!(cache.is_pinned(key).unwrap());
+        }
+    }
+
     const NUM_ITEMS_PER_TASK: usize = 20;
     const RANDOM_SEED: u64 = 878987298749287;
 
@@ -0,0 +1,86 @@
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::error::ChunkCacheError;
+
+// the longest wait between two attempts to take a lock held by another process
+const MAX_LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// an exclusive advisory lock on a lock file, coordinating the processes sharing a cache directory.
+/// the lock is held by the open file, so it's released when dropped, or when the process exits.
+///
+/// the lock is taken on a separate open of the file, so two locks on the same file exclude each
+/// other even within a process.
+#[derive(Debug)]
+pub(crate) struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// locks the file at `path`, creating it and its directory if needed, waiting for up to `timeout`
+    /// while another process holds the lock; returns None if it's still held after `timeout`.
+    pub(crate) fn lock(path: &Path, timeout: Duration) -> Result<Option<Self>, ChunkCacheError> {
+        let file = open_lock_file(path)?;
+        let start = Instant::now();
+        let mut retry_interval = Duration::from_millis(1);
+        loop {
+            if file_utils::try_lock_exclusive(&file)? {
+                return Ok(Some(Self { _file: file }));
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Ok(None);
+            }
+            sleep(retry_interval.min(timeout - elapsed));
+            retry_interval = (retry_interval * 2).min(MAX_LOCK_RETRY_INTERVAL);
+        }
+    }
+
+    /// locks the file at `path` if no other process holds the lock, without waiting.
+    pub(crate) fn try_lock(path: &Path) -> Result<Option<Self>, ChunkCacheError> {
+        Self::lock(path, Duration::ZERO)
+    }
+}
+
+fn open_lock_file(path: &Path) -> Result<File, ChunkCacheError> {
+    let open = || OpenOptions::new().write(true).create(true).truncate(false).open(path);
+    match open() {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            std::fs::create_dir_all(path.parent().ok_or(ChunkCacheError::Infallible)?)?;
+            Ok(open()?)
+        },
+        result => Ok(result?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tempdir::TempDir;
+
+    use super::FileLock;
+
+    #[test]
+    fn test_lock_waits_for_holder() {
+        let dir = TempDir::new("file_lock").unwrap();
+        let path = dir.path().join("locks").join("a.lock");
+
+        let held = FileLock::try_lock(&path).unwrap().unwrap();
+        assert!(FileLock::try_lock(&path).unwrap().is_none());
+        let start = Instant::now();
+        assert!(FileLock::lock(&path, Duration::from_millis(20)).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // a lock on another file isn't affected
+        assert!(FileLock::try_lock(&dir.path().join("locks").join("b.lock")).unwrap().is_some());
+
+        let waiter = std::thread::spawn(move || FileLock::lock(&path, Duration::from_secs(10)).unwrap().is_some());
+        std::thread::sleep(Duration::from_millis(20));
+        drop(held);
+        assert!(waiter.join().unwrap());
+    }
+}
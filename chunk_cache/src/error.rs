@@ -23,6 +23,10 @@ pub enum ChunkCacheError {
     LockPoison,
     #[error("invalid arguments")]
     InvalidArguments,
+    #[error("pinned entries would exceed the cache capacity")]
+    PinCapacityExceeded,
+    #[error("timed out waiting for another process to release a cache lock")]
+    LockTimeout,
 }
 
 impl ChunkCacheError {
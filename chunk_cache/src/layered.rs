@@ -0,0 +1,216 @@
+use std::sync::Arc;
+
+use cas_types::{ChunkRange, Key};
+use error_printer::ErrorPrinter;
+
+use crate::error::ChunkCacheError;
+use crate::{ChunkCache, DiskCache};
+
+/// Which tier of a LayeredCache serves reads and receives writes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheTierPolicy {
+    /// reads consult memory then disk, and a disk hit is promoted into memory; writes only go to
+    /// memory, so the disk tier only serves what was put in it by other users of the directory or
+    /// by earlier processes.
+    MemoryFirst,
+    /// reads consult disk then memory with no promotion; writes only go to disk.
+    DiskFirst,
+    /// reads consult memory then disk, and a disk hit is promoted into memory; writes go to both
+    /// tiers.
+    #[default]
+    WriteThrough,
+}
+
+/// LayeredCache is a ChunkCache implementor over an in-memory tier and an on-disk tier, with a
+/// CacheTierPolicy governing which tier is consulted first and which tiers are written.
+///
+/// each tier evicts on its own; a range evicted from one tier but still present in the other is
+/// served by the other, and is copied back into memory on a disk hit if the policy promotes. the
+/// data of a range never changes, so a hit in either tier is always valid.
+pub struct LayeredCache {
+    memory: Arc<dyn ChunkCache>,
+    disk: DiskCache,
+    policy: CacheTierPolicy,
+}
+
+impl LayeredCache {
+    pub fn new(memory: Arc<dyn ChunkCache>, disk: DiskCache, policy: CacheTierPolicy) -> Self {
+        Self { memory, disk, policy }
+    }
+
+    pub fn policy(&self) -> CacheTierPolicy {
+        self.policy
+    }
+
+    /// reads the range from disk, promoting it into memory; a failed promotion still returns the
+    /// data read from disk.
+    fn get_from_disk_promoting(&self, key: &Key, range: &ChunkRange) -> Result<Option<Vec<u8>>, ChunkCacheError> {
+        let Some((data, chunk_byte_indices)) = self.disk.get_with_chunk_byte_indices(key, range)? else {
+            return Ok(None);
+        };
+        let _ = self
+            .memory
+            .put(key, range, &chunk_byte_indices, &data)
+            .debug_error(format!("failed to promote {key} {range} into the memory cache"));
+        Ok(Some(data))
+    }
+}
+
+impl ChunkCache for LayeredCache {
+    fn get(&self, key: &Key, range: &ChunkRange) -> Result<Option<Vec<u8>>, ChunkCacheError> {
+        match self.policy {
+            CacheTierPolicy::MemoryFirst | CacheTierPolicy::WriteThrough => {
+                if let Some(data) = self.memory.get(key, range)? {
+                    return Ok(Some(data));
+                }
+                self.get_from_disk_promoting(key, range)
+            },
+            CacheTierPolicy::DiskFirst => {
+                if let Some(data) = self.disk.get(key, range)? {
+                    return Ok(Some(data));
+                }
+                self.memory.get(key, range)
+            },
+        }
+    }
+
+    fn put(
+        &self,
+        key: &Key,
+        range: &ChunkRange,
+        chunk_byte_indices: &[u32],
+        data: &[u8],
+    ) -> Result<(), ChunkCacheError> {
+        match self.policy {
+            CacheTierPolicy::MemoryFirst => self.memory.put(key, range, chunk_byte_indices, data),
+            CacheTierPolicy::DiskFirst => self.disk.put(key, range, chunk_byte_indices, data),
+            CacheTierPolicy::WriteThrough => {
+                // both tiers are written even if the first fails, so that either can serve the range.
+                let memory_result = self.memory.put(key, range, chunk_byte_indices, data);
+                let disk_result = self.disk.put(key, range, chunk_byte_indices, data);
+                memory_result.and(disk_result)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::{CacheConfig, MockChunkCache, RandomEntryIterator, DEFAULT_CHUNK_CACHE_CAPACITY};
+
+    fn disk_cache(dir: &TempDir) -> DiskCache {
+        DiskCache::initialize(&CacheConfig {
+            cache_directory: dir.path().to_path_buf(),
+            cache_size: DEFAULT_CHUNK_CACHE_CAPACITY,
+        })
+        .unwrap()
+    }
+
+    /// expects a single put of exactly the given range into the mock.
+    fn expect_put_of(
+        memory: &mut MockChunkCache,
+        key: &Key,
+        range: &ChunkRange,
+        chunk_byte_indices: &[u32],
+        data: &[u8],
+    ) {
+        let expected = (key.clone(), range.clone(), chunk_byte_indices.to_vec(), data.to_vec());
+        memory
+            .expect_put()
+            .withf(move |k, r, i, d| (k, r, i, d) == (&expected.0, &expected.1, &expected.2[..], &expected.3[..]))
+            .times(1)
+            .returning(|_, _, _, _| Ok(()));
+    }
+
+    #[test]
+    fn test_memory_first() {
+        let dir = TempDir::new("memory_first").unwrap();
+        let disk = disk_cache(&dir);
+        let mut entries = RandomEntryIterator::std_from_seed(0).with_range_len(1024);
+        let (key, range, chunk_byte_indices, data) = entries.next().unwrap();
+        disk.put(&key, &range, &chunk_byte_indices, &data).unwrap();
+        let (new_key, new_range, new_indices, new_data) = entries.next().unwrap();
+
+        // a memory miss falls back to disk and the disk hit is promoted; writes only go to memory.
+        let mut memory = MockChunkCache::new();
+        memory.expect_get().times(1).returning(|_, _| Ok(None));
+        expect_put_of(&mut memory, &key, &range, &chunk_byte_indices, &data);
+        expect_put_of(&mut memory, &new_key, &new_range, &new_indices, &new_data);
+
+        let cache = LayeredCache::new(Arc::new(memory), disk.clone(), CacheTierPolicy::MemoryFirst);
+        assert_eq!(cache.get(&key, &range).unwrap(), Some(data));
+        cache.put(&new_key, &new_range, &new_indices, &new_data).unwrap();
+        assert_eq!(disk.get(&new_key, &new_range).unwrap(), None);
+    }
+
+    #[test]
+    fn test_memory_hit_skips_disk() {
+        let (key, range, _, data) = RandomEntryIterator::std_from_seed(1).with_range_len(1024).next().unwrap();
+
+        // a range evicted from (or never written to) disk is still served by memory.
+        for policy in [CacheTierPolicy::MemoryFirst, CacheTierPolicy::WriteThrough] {
+            let dir = TempDir::new("memory_hit").unwrap();
+            let mut memory = MockChunkCache::new();
+            let served = data.clone();
+            memory.expect_get().times(1).returning(move |_, _| Ok(Some(served.clone())));
+            memory.expect_put().never();
+
+            let cache = LayeredCache::new(Arc::new(memory), disk_cache(&dir), policy);
+            assert_eq!(cache.get(&key, &range).unwrap(), Some(data.clone()));
+        }
+    }
+
+    #[test]
+    fn test_disk_first() {
+        let dir = TempDir::new("disk_first").unwrap();
+        let disk = disk_cache(&dir);
+        let mut entries = RandomEntryIterator::std_from_seed(2).with_range_len(1024);
+        let (key, range, chunk_byte_indices, data) = entries.next().unwrap();
+        disk.put(&key, &range, &chunk_byte_indices, &data).unwrap();
+        let (other_key, other_range, _, other_data) = entries.next().unwrap();
+        let (new_key, new_range, new_indices, new_data) = entries.next().unwrap();
+
+        // a disk hit is served without consulting memory or promoting; a disk miss falls back to
+        // memory; writes only go to disk.
+        let mut memory = MockChunkCache::new();
+        let served = other_data.clone();
+        memory.expect_get().times(1).returning(move |_, _| Ok(Some(served.clone())));
+        memory.expect_put().never();
+
+        let cache = LayeredCache::new(Arc::new(memory), disk.clone(), CacheTierPolicy::DiskFirst);
+        assert_eq!(cache.get(&key, &range).unwrap(), Some(data));
+        assert_eq!(cache.get(&other_key, &other_range).unwrap(), Some(other_data));
+        cache.put(&new_key, &new_range, &new_indices, &new_data).unwrap();
+        assert_eq!(disk.get(&new_key, &new_range).unwrap(), Some(new_data));
+    }
+
+    #[test]
+    fn test_write_through() {
+        let dir = TempDir::new("write_through").unwrap();
+        let disk = disk_cache(&dir);
+        let (key, range, chunk_byte_indices, data) =
+            RandomEntryIterator::std_from_seed(3).with_range_len(1024).next().unwrap();
+
+        // writes go to both tiers; once evicted from memory, a range is served from disk and promoted
+        // back, here a sub range of the one written, with its chunk byte indices shifted to start at 0.
+        let sub_range = ChunkRange {
+            start: range.start + 1,
+            end: range.end,
+        };
+        let sub_indices: Vec<u32> = chunk_byte_indices[1..].iter().map(|i| i - chunk_byte_indices[1]).collect();
+        let sub_data = data[chunk_byte_indices[1] as usize..].to_vec();
+
+        let mut memory = MockChunkCache::new();
+        expect_put_of(&mut memory, &key, &range, &chunk_byte_indices, &data);
+        memory.expect_get().times(1).returning(|_, _| Ok(None));
+        expect_put_of(&mut memory, &key, &sub_range, &sub_indices, &sub_data);
+
+        let cache = LayeredCache::new(Arc::new(memory), disk.clone(), CacheTierPolicy::WriteThrough);
+        cache.put(&key, &range, &chunk_byte_indices, &data).unwrap();
+        assert_eq!(disk.get(&key, &range).unwrap(), Some(data));
+        assert_eq!(cache.get(&key, &sub_range).unwrap(), Some(sub_data));
+    }
+}
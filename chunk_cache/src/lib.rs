@@ -1,20 +1,28 @@
 mod cache_manager;
+mod degrading;
 mod disk;
 pub mod error;
+mod layered;
 
 use std::path::PathBuf;
 
 pub use cache_manager::get_cache;
 use cas_types::{ChunkRange, Key};
+pub use degrading::DegradingCache;
 pub use disk::test_utils::*;
 pub use disk::DiskCache;
 use error::ChunkCacheError;
+pub use layered::{CacheTierPolicy, LayeredCache};
 use mockall::automock;
 
 pub use crate::disk::DEFAULT_CHUNK_CACHE_CAPACITY;
 
 utils::configurable_constants! {
     ref CHUNK_CACHE_SIZE_BYTES: u64 = DEFAULT_CHUNK_CACHE_CAPACITY;
+
+// How long a chunk cache write waits for another process sharing the cache directory to release
+// its lock before giving up, in milliseconds.
+    ref CHUNK_CACHE_LOCK_TIMEOUT_MS: u64 = 10_000;
 }
 
 /// ChunkCache is a trait for storing and fetching Xorb ranges.
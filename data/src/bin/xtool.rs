@@ -3,11 +3,15 @@ use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use cas_object::CompressionScheme;
+use cas_types::FileRange;
 use clap::{Args, Parser, Subcommand};
-use data::migration_tool::hub_client::HubClient;
+use data::data_client::explain_reconstruction;
+use data::migration_tool::hub_client::{HubClient, HubClientTokenRefresher};
 use data::migration_tool::migrate::migrate_files_impl;
+use merklehash::MerkleHash;
+use utils::auth::TokenRefresher;
 use walkdir::WalkDir;
 use xet_threadpool::ThreadPool;
 
@@ -95,6 +99,13 @@ struct DedupArg {
 struct QueryArg {
     /// Xet-hash of a file
     hash: String,
+    /// Byte range of the file to query, as "start-end" with an exclusive end.
+    #[clap(long)]
+    bytes: Option<String>,
+    /// Print every term of the reconstruction plan: the xorb, chunk range and fetch url, and
+    /// the range of the output it writes.
+    #[clap(long)]
+    explain: bool,
 }
 
 impl Command {
@@ -135,7 +146,40 @@ impl Command {
 
                 Ok(())
             },
-            Command::Query(_arg) => unimplemented!(),
+            Command::Query(arg) => {
+                let file_hash =
+                    MerkleHash::from_hex(&arg.hash).map_err(|_| anyhow!("invalid file hash {}", arg.hash))?;
+                let range = arg
+                    .bytes
+                    .map(|bytes| FileRange::try_from(bytes.as_str()).map_err(|_| anyhow!("invalid byte range {bytes}")))
+                    .transpose()?;
+
+                let token_type = "read";
+                let (endpoint, jwt_token, jwt_token_expiry) = hub_client.get_jwt_token(token_type).await?;
+                let token_refresher = Arc::new(HubClientTokenRefresher {
+                    threadpool: threadpool.clone(),
+                    token_type: token_type.to_owned(),
+                    client: Arc::new(hub_client),
+                }) as Arc<dyn TokenRefresher>;
+
+                let plan = explain_reconstruction(
+                    threadpool,
+                    &file_hash,
+                    range,
+                    Some(endpoint),
+                    Some((jwt_token, jwt_token_expiry)),
+                    Some(token_refresher),
+                )
+                .await?;
+
+                if arg.explain {
+                    print!("{plan}");
+                } else {
+                    println!("{}: {} bytes in {} terms", arg.hash, plan.total_length(), plan.terms.len());
+                }
+
+                Ok(())
+            },
         }
     }
 }
@@ -1,13 +1,15 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 
 use cas_client::remote_client::PREFIX_DEFAULT;
-use cas_client::{CacheConfig, CHUNK_CACHE_SIZE_BYTES};
+use cas_client::{CacheConfig, CHUNK_CACHE_SIZE_BYTES, MAX_RECONSTRUCTION_TERMS, MAX_RESPONSE_BYTES};
 use cas_object::CompressionScheme;
+pub use deduplication::ChunkerConfig;
 use utils::auth::AuthConfig;
 
-use crate::errors::Result;
+use crate::errors::{DataProcessingError, Result};
 use crate::repo_salt::RepoSalt;
 
 #[derive(Debug)]
@@ -24,6 +26,55 @@ pub struct DataConfig {
     pub prefix: String,
     pub cache_config: CacheConfig,
     pub staging_directory: Option<PathBuf>,
+    /// The chunk boundary parameters used when cleaning files.  Anything but the
+    /// default breaks deduplication against data chunked elsewhere, so this is
+    /// meant for tests that need exact chunk boundaries.
+    pub chunker_config: ChunkerConfig,
+    /// The maximum number of terms accepted in a reconstruction response from the server.
+    pub max_reconstruction_terms: usize,
+    /// The maximum size in bytes of the body of a response from the server.
+    pub max_response_bytes: u64,
+    /// Whether to verify the chunks downloaded from the server against the chunk hashes recorded in
+    /// the shard cache.  Chunks of xorbs not in the shard cache are not verified.
+    pub verify_chunks: bool,
+    /// Whether to check, once a download completes, that the file didn't change on the server
+    /// since its reconstruction was queried, failing the download if it did.
+    pub validate_file_version: bool,
+    /// Whether to sync each downloaded file to disk before its download returns, so that it survives
+    /// a crash right after; see `RemoteClient::with_durable_writes` for the cost.
+    pub durable_writes: bool,
+    /// Headers sent with every request to the server and the blob store, e.g. those a gateway in
+    /// front of the server requires.
+    pub extra_headers: HashMap<String, String>,
+    /// The hashes computed over every uploaded file besides those every upload computes.
+    pub extra_hashes: ExtraHashes,
+}
+
+/// Hashes of uploaded files that are computed while the files are chunked, besides the Merkle hash
+/// and SHA-256 every upload computes.  Each costs CPU time, so none is computed by default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ExtraHashes {
+    /// The BLAKE3 digest of each file, reported by `PointerFile::blake3`.
+    pub blake3: bool,
+}
+
+impl ExtraHashes {
+    /// Parses a list of hash names, e.g. `["blake3"]`.
+    pub fn from_names(names: &[impl AsRef<str>]) -> Result<Self> {
+        let mut extra_hashes = Self::default();
+        for name in names {
+            match name.as_ref().to_lowercase().as_str() {
+                "blake3" => extra_hashes.blake3 = true,
+                _ => {
+                    return Err(DataProcessingError::ParameterError(format!(
+                        "unknown hash {:?}, expected one of: blake3",
+                        name.as_ref()
+                    )))
+                },
+            }
+        }
+        Ok(extra_hashes)
+    }
 }
 
 #[derive(Debug)]
@@ -68,6 +119,11 @@ pub struct ShardConfig {
     pub cache_directory: PathBuf,
     pub global_dedup_policy: GlobalDedupPolicy,
     pub repo_salt: RepoSalt,
+    /// Whether the server registers each uploaded shard before acknowledging it.  A synced upload
+    /// (PUT) makes the files of the shard downloadable as soon as the upload returns, at the cost of
+    /// a slower upload; otherwise (POST) the server registers the shard in the background, so the
+    /// files may not be found right away, and a server failure before registration loses the shard.
+    pub force_sync: bool,
 }
 
 #[derive(Debug)]
@@ -77,7 +133,51 @@ pub struct TranslatorConfig {
     pub repo_info: Option<RepoInfo>,
 }
 
+/// The maximum length of a storage prefix set with `TranslatorConfig::set_prefix`.
+const MAX_PREFIX_LENGTH: usize = 64;
+
 impl TranslatorConfig {
+    /// Stores the xorbs and shards of this configuration under `prefix` instead of the default
+    /// namespace, e.g. to isolate the objects of different tenants.  As the prefix becomes part of
+    /// storage keys and urls, it must be 1 to 64 ASCII letters, digits, '-' or '_'.
+    pub fn set_prefix(&mut self, prefix: &str) -> Result<()> {
+        if prefix.is_empty()
+            || prefix.len() > MAX_PREFIX_LENGTH
+            || !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(DataProcessingError::ParameterError(format!(
+                "invalid prefix {prefix:?}: must be 1 to {MAX_PREFIX_LENGTH} ASCII letters, digits, '-' or '_'"
+            )));
+        }
+        self.data_config.prefix = prefix.to_owned();
+        self.shard_config.prefix = prefix.to_owned();
+        Ok(())
+    }
+
+    /// Chunks uploaded files with `chunker_config` instead of the default parameters.  Files only
+    /// dedup against data chunked with the same parameters.  The target chunk size, `2^mask_bits`,
+    /// must be 128 B to 2 GiB, and must lie strictly between the minimum and maximum chunk sizes.
+    pub fn set_chunker_config(&mut self, chunker_config: ChunkerConfig) -> Result<()> {
+        let ChunkerConfig {
+            mask_bits,
+            minimum_chunk,
+            maximum_chunk,
+        } = chunker_config;
+        if !(7..=31).contains(&mask_bits) {
+            return Err(DataProcessingError::ParameterError(format!(
+                "invalid chunker mask bits {mask_bits}: must be 7 to 31"
+            )));
+        }
+        let target_chunk = chunker_config.target_chunk_size();
+        if !(minimum_chunk < target_chunk && target_chunk < maximum_chunk) {
+            return Err(DataProcessingError::ParameterError(format!(
+                "invalid chunk sizes: expected minimum ({minimum_chunk}) < target ({target_chunk}) < maximum ({maximum_chunk})"
+            )));
+        }
+        self.data_config.chunker_config = chunker_config;
+        Ok(())
+    }
+
     pub fn local_config(base_dir: impl AsRef<Path>) -> Result<Arc<Self>> {
         let path = base_dir.as_ref().join("xet");
         std::fs::create_dir_all(&path)?;
@@ -93,6 +193,14 @@ impl TranslatorConfig {
                     cache_size: *CHUNK_CACHE_SIZE_BYTES,
                 },
                 staging_directory: None,
+                chunker_config: Default::default(),
+                max_reconstruction_terms: *MAX_RECONSTRUCTION_TERMS,
+                max_response_bytes: *MAX_RESPONSE_BYTES,
+                verify_chunks: false,
+                validate_file_version: false,
+                durable_writes: false,
+                extra_headers: HashMap::new(),
+                extra_hashes: Default::default(),
             },
             shard_config: ShardConfig {
                 prefix: PREFIX_DEFAULT.into(),
@@ -100,6 +208,7 @@ impl TranslatorConfig {
                 session_directory: path.join("shard-session"),
                 global_dedup_policy: Default::default(),
                 repo_salt: RepoSalt::default(),
+                force_sync: false,
             },
             repo_info: Some(RepoInfo {
                 repo_paths: vec!["".into()],
@@ -108,4 +217,12 @@ impl TranslatorConfig {
 
         Ok(Arc::new(translator_config))
     }
+
+    /// Keeps the chunk cache of the clients created from this configuration in `cache_directory`.
+    /// Clients with different chunk cache directories don't share cached chunks, e.g. concurrent
+    /// sessions of different users against the same endpoint.
+    pub fn with_chunk_cache_directory(mut self, cache_directory: impl Into<PathBuf>) -> Self {
+        self.data_config.cache_config.cache_directory = cache_directory.into();
+        self
+    }
 }
@@ -26,4 +26,12 @@ utils::configurable_constants! {
     /// The maximum block size from a file to process at once.
     ref INGESTION_BLOCK_SIZE : usize = 8 * 1024 * 1024;
 
+    /// The number of files uploaded per session when an upload is journaled; the files of a session
+    /// are recorded in the journal once it is finalized, so at most this many are redone on resume.
+    ref UPLOAD_JOURNAL_BATCH_FILES : usize = 256;
+
+    /// The fraction of the chunks probed for deduplication by an upload estimate, unless another
+    /// sample rate is given.
+    ref UPLOAD_ESTIMATE_SAMPLE_RATE : f64 = 0.1;
+
 }
@@ -1,25 +1,44 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::env::current_dir;
 use std::fs::File;
-use std::io::Read;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 
 use cas_client::remote_client::PREFIX_DEFAULT;
-use cas_client::{CacheConfig, FileProvider, OutputProvider};
+use cas_client::{
+    normalize_endpoint, CacheConfig, FileProvider, OutputProvider, ReconstructionPlan, RemoteClient,
+    MAX_RECONSTRUCTION_TERMS, MAX_RESPONSE_BYTES,
+};
 use cas_object::CompressionScheme;
-use deduplication::DeduplicationMetrics;
+use cas_types::FileRange;
+use deduplication::{Chunk, DeduplicationMetrics};
 use dirs::home_dir;
+use file_utils::SafeFileCreator;
+use mdb_shard::file_structs::MDBFileInfo;
+use merklehash::MerkleHash;
 use parutils::{tokio_par_for_each, ParallelError};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
 use utils::auth::{AuthConfig, TokenRefresher};
 use utils::progress::ProgressUpdater;
 use xet_threadpool::ThreadPool;
 
 use crate::configurations::*;
-use crate::constants::{INGESTION_BLOCK_SIZE, MAX_CONCURRENT_DOWNLOADS, MAX_CONCURRENT_FILE_INGESTION};
-use crate::errors::DataProcessingError;
+use crate::constants::{
+    INGESTION_BLOCK_SIZE, MAX_CONCURRENT_DOWNLOADS, MAX_CONCURRENT_FILE_INGESTION, UPLOAD_ESTIMATE_SAMPLE_RATE,
+    UPLOAD_JOURNAL_BATCH_FILES,
+};
+use crate::errors::{DataProcessingError, UploadError};
+use crate::file_cleaner::hash_file_contents;
+use crate::local_cache::{self, CacheStats};
+use crate::manifest::{parse_manifest, resolve_in_dir, write_upload_manifest};
 use crate::repo_salt::RepoSalt;
-use crate::{errors, FileDownloader, FileUploadSession, PointerFile};
+use crate::upload_estimate::{self, UploadEstimate};
+use crate::upload_journal::{FileStamp, UploadJournal};
+use crate::{errors, DownloadStream, FileDownloader, FileUploadSession, PointerFile, XorbProgressCallback};
 
 utils::configurable_constants! {
     ref DEFAULT_CAS_ENDPOINT: String = "http://localhost:8080".to_string();
@@ -31,6 +50,15 @@ pub fn default_config(
     token_info: Option<(String, u64)>,
     token_refresher: Option<Arc<dyn TokenRefresher>>,
 ) -> errors::Result<Arc<TranslatorConfig>> {
+    Ok(Arc::new(default_translator_config(endpoint, xorb_compression, token_info, token_refresher)?))
+}
+
+fn default_translator_config(
+    endpoint: String,
+    xorb_compression: Option<CompressionScheme>,
+    token_info: Option<(String, u64)>,
+    token_refresher: Option<Arc<dyn TokenRefresher>>,
+) -> errors::Result<TranslatorConfig> {
     // if HF_HOME is set use that instead of ~/.cache/huggingface
     // if HF_XET_CACHE is set use that instead of ~/.cache/huggingface/xet
     // HF_XET_CACHE takes precedence over HF_HOME
@@ -44,6 +72,10 @@ pub fn default_config(
         home.join(".cache").join("huggingface").join("xet")
     };
 
+    // Reject malformed endpoints before anything is requested, and key the cache on the normalized
+    // endpoint so that e.g. a trailing slash doesn't start a new cache.
+    let endpoint = normalize_endpoint(&endpoint)?;
+
     let (token, token_expiration) = token_info.unzip();
     let auth_cfg = AuthConfig::maybe_new(token, token_expiration, token_refresher);
 
@@ -78,6 +110,14 @@ pub fn default_config(
                 cache_size: 10 * 1024 * 1024 * 1024, // 10 GiB
             },
             staging_directory: None,
+            chunker_config: Default::default(),
+            max_reconstruction_terms: *MAX_RECONSTRUCTION_TERMS,
+            max_response_bytes: *MAX_RESPONSE_BYTES,
+            verify_chunks: false,
+            validate_file_version: false,
+            durable_writes: false,
+            extra_headers: Default::default(),
+            extra_hashes: Default::default(),
         },
         shard_config: ShardConfig {
             prefix: PREFIX_DEFAULT.into(),
@@ -85,6 +125,7 @@ pub fn default_config(
             session_directory: staging_root.join("shard-session"),
             global_dedup_policy: Default::default(),
             repo_salt: RepoSalt::default(),
+            force_sync: false,
         },
         repo_info: Some(RepoInfo {
             repo_paths: vec!["".into()],
@@ -92,44 +133,590 @@ pub fn default_config(
     };
 
     // Return the temp dir so that it's not dropped and thus the directory deleted.
-    Ok(Arc::new(translator_config))
+    Ok(translator_config)
 }
 
-pub async fn upload_async(
-    threadpool: Arc<ThreadPool>,
-    file_paths: Vec<String>,
+/// The settings of an upload with `upload_async` or `upload_directory`.  The defaults upload to
+/// `DEFAULT_CAS_ENDPOINT` without authentication or progress reporting, abort on the first failing
+/// file, and write nothing besides the uploaded data.
+#[derive(Clone)]
+pub struct UploadOptions {
     endpoint: Option<String>,
     token_info: Option<(String, u64)>,
     token_refresher: Option<Arc<dyn TokenRefresher>>,
     progress_updater: Option<Arc<dyn ProgressUpdater>>,
-) -> errors::Result<Vec<PointerFile>> {
+    fail_fast: bool,
+    pointer_output: Option<PointerOutput>,
+    xorb_progress_callback: Option<XorbProgressCallback>,
+    prefix: Option<String>,
+    chunker_config: Option<ChunkerConfig>,
+    journal_path: Option<PathBuf>,
+    force_shard_sync: bool,
+    manifest_path: Option<PathBuf>,
+    extra_hashes: ExtraHashes,
+}
+
+impl Default for UploadOptions {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            token_info: None,
+            token_refresher: None,
+            progress_updater: None,
+            fail_fast: true,
+            pointer_output: None,
+            xorb_progress_callback: None,
+            prefix: None,
+            chunker_config: None,
+            journal_path: None,
+            force_shard_sync: false,
+            manifest_path: None,
+            extra_hashes: Default::default(),
+        }
+    }
+}
+
+impl UploadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The CAS server to upload to, if not `DEFAULT_CAS_ENDPOINT`.
+    pub fn with_endpoint(mut self, endpoint: Option<String>) -> Self {
+        self.endpoint = endpoint;
+        self
+    }
+
+    /// The token to authenticate with and its expiration, and how to refresh it once expired.
+    pub fn with_auth(
+        mut self,
+        token_info: Option<(String, u64)>,
+        token_refresher: Option<Arc<dyn TokenRefresher>>,
+    ) -> Self {
+        self.token_info = token_info;
+        self.token_refresher = token_refresher;
+        self
+    }
+
+    pub fn with_progress_updater(mut self, progress_updater: Option<Arc<dyn ProgressUpdater>>) -> Self {
+        self.progress_updater = progress_updater;
+        self
+    }
+
+    /// If true, the first file that fails aborts the whole batch and its error is returned, so all
+    /// the per-file results are Ok.  Otherwise, a failing file only produces an error in its own
+    /// entry, and all the other files are still uploaded, unless the server is out of storage or
+    /// quota, which aborts the batch either way.
+    pub fn with_fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Writes the pointer file of each uploaded file to disk once the upload has completed; failing
+    /// to write one counts as a failure of that file.
+    pub fn with_pointer_output(mut self, pointer_output: Option<PointerOutput>) -> Self {
+        self.pointer_output = pointer_output;
+        self
+    }
+
+    /// Called once for each xorb uploaded by the session, as a coarser alternative to the byte-level
+    /// updates of the progress updater.
+    pub fn with_xorb_progress_callback(mut self, xorb_progress_callback: Option<XorbProgressCallback>) -> Self {
+        self.xorb_progress_callback = xorb_progress_callback;
+        self
+    }
+
+    /// Stores the xorbs and shards under this namespace instead of the default one; see
+    /// `TranslatorConfig::set_prefix` for the allowed characters.
+    pub fn with_prefix(mut self, prefix: Option<String>) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// Chunks the files with this instead of the default parameters; see
+    /// `TranslatorConfig::set_chunker_config`.
+    pub fn with_chunker_config(mut self, chunker_config: Option<ChunkerConfig>) -> Self {
+        self.chunker_config = chunker_config;
+        self
+    }
+
+    /// Makes the upload resumable after an interruption: the files are uploaded in batches, and each
+    /// file of a batch is recorded in the journal once the batch is fully uploaded.  Rerunning with
+    /// the same journal skips the recorded files whose path, size and modification time are
+    /// unchanged, returning their pointer files from the journal.
+    pub fn with_journal_path(mut self, journal_path: Option<PathBuf>) -> Self {
+        self.journal_path = journal_path;
+        self
+    }
+
+    /// If true, each shard is registered by the server before its upload returns, so the files are
+    /// downloadable as soon as the upload returns; see `ShardConfig::force_sync`.
+    pub fn with_force_shard_sync(mut self, force_shard_sync: bool) -> Self {
+        self.force_shard_sync = force_shard_sync;
+        self
+    }
+
+    /// Also writes a JSON manifest of the batch here, before any pointer file: an object with a
+    /// `files` array listing the path, hash, size and sha256 of every uploaded file, in the order of
+    /// the files.  The manifest is written atomically, and only once it's checked to parse back to
+    /// the same entries.
+    pub fn with_manifest_path(mut self, manifest_path: Option<PathBuf>) -> Self {
+        self.manifest_path = manifest_path;
+        self
+    }
+
+    /// The hashes to compute over every file while it's uploaded, besides its Merkle hash.
+    pub fn with_extra_hashes(mut self, extra_hashes: ExtraHashes) -> Self {
+        self.extra_hashes = extra_hashes;
+        self
+    }
+
+    /// The translator config of the upload, from the default one for its endpoint.
+    fn translator_config(&self) -> errors::Result<TranslatorConfig> {
+        let mut config = default_translator_config(
+            self.endpoint.clone().unwrap_or(DEFAULT_CAS_ENDPOINT.clone()),
+            None,
+            self.token_info.clone(),
+            self.token_refresher.clone(),
+        )?;
+        if let Some(prefix) = &self.prefix {
+            config.set_prefix(prefix)?;
+        }
+        if let Some(chunker_config) = &self.chunker_config {
+            config.set_chunker_config(*chunker_config)?;
+        }
+        config.shard_config.force_sync = self.force_shard_sync;
+        config.data_config.extra_hashes = self.extra_hashes;
+        Ok(config)
+    }
+
+    async fn new_session(
+        &self,
+        config: Arc<TranslatorConfig>,
+        threadpool: Arc<ThreadPool>,
+    ) -> errors::Result<Arc<FileUploadSession>> {
+        FileUploadSession::new_with_xorb_progress(
+            config,
+            threadpool,
+            self.progress_updater.clone(),
+            self.xorb_progress_callback.clone(),
+        )
+        .await
+    }
+}
+
+/// Cleans and uploads the given files in a single upload session, returning the result for each file in
+/// the order of `file_paths`, whatever order the concurrent uploads complete in, as do the batches of
+/// a journaled upload.
+///
+/// Chunks from all files in the session are packed together into shared xorbs, so a batch of many
+/// small files produces xorbs of up to `MAX_XORB_BYTES` / `MAX_XORB_CHUNKS` rather than one xorb per file.
+/// Each file's reconstruction is recorded in the session shard, so every returned pointer file can be
+/// smudged independently.
+///
+/// See `UploadOptions` for how failures are handled and what else is written.
+pub async fn upload_async(
+    threadpool: Arc<ThreadPool>,
+    file_paths: Vec<String>,
+    options: UploadOptions,
+) -> errors::Result<Vec<errors::Result<PointerFile>>> {
     // chunk files
     // produce Xorbs + Shards
     // upload shards and xorbs
     // for each file, return the filehash
-    let config = default_config(endpoint.unwrap_or(DEFAULT_CAS_ENDPOINT.clone()), None, token_info, token_refresher)?;
+    let config = options.translator_config()?;
+    upload_with_outputs(Arc::new(config), threadpool, file_paths, &options).await
+}
+
+/// Uploads the files with `config`, then writes the manifest and pointer files; see `upload_async`.
+async fn upload_with_outputs(
+    config: Arc<TranslatorConfig>,
+    threadpool: Arc<ThreadPool>,
+    file_paths: Vec<String>,
+    options: &UploadOptions,
+) -> errors::Result<Vec<errors::Result<PointerFile>>> {
+    // The file info holds the sha256 of each uploaded file, for the manifest.
+    let return_file_info = options.manifest_path.is_some();
+    let (mut results, file_info) = match &options.journal_path {
+        Some(journal_path) => {
+            let journal = UploadJournal::open(journal_path)?;
+            upload_files_journaled(
+                config,
+                threadpool,
+                options,
+                journal,
+                file_paths,
+                *UPLOAD_JOURNAL_BATCH_FILES,
+                return_file_info,
+            )
+            .await?
+        },
+        None => {
+            let upload_session = options.new_session(config, threadpool).await?;
+            upload_files_in_session_impl(upload_session, file_paths, options.fail_fast, return_file_info).await?
+        },
+    };
+
+    // Before the pointer files, which may replace the uploaded files a sha256 is computed from.
+    if let Some(manifest_path) = &options.manifest_path {
+        write_upload_manifest(manifest_path, &results, &file_info)?;
+    }
+
+    if let Some(pointer_output) = &options.pointer_output {
+        write_pointer_files(&mut results, pointer_output, options.fail_fast)?;
+    }
 
-    let upload_session = FileUploadSession::new(config, threadpool, progress_updater).await?;
+    Ok(results)
+}
+
+/// Where `upload_async` writes the pointer file of each uploaded file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PointerOutput {
+    /// Replace each uploaded file with its pointer file.
+    InPlace,
+    /// Write each pointer file into this directory, under the name of the uploaded file.
+    Directory(PathBuf),
+}
+
+/// Writes the pointer file of each successful upload in `results` as directed by `pointer_output`.
+///
+/// A pointer file that can't be written replaces the Ok entry of its file with the error, or, if
+/// `fail_fast` is true, is returned as an error right away.  Existing files are only ever replaced
+/// by their own pointer file: a pointer file is never written over a read-only file, over a file
+/// that exists in the output directory, or over the pointer file of another upload in the batch.
+fn write_pointer_files(
+    results: &mut [errors::Result<PointerFile>],
+    pointer_output: &PointerOutput,
+    fail_fast: bool,
+) -> errors::Result<()> {
+    if let PointerOutput::Directory(dir) = pointer_output {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    // Destination -> source of the pointer files written so far.
+    let mut written = HashMap::<PathBuf, String>::new();
+
+    for result in results.iter_mut() {
+        let Ok(pf) = result else {
+            continue;
+        };
+
+        let dest = match pointer_output {
+            PointerOutput::InPlace => PathBuf::from(pf.path()),
+            PointerOutput::Directory(dir) => match Path::new(pf.path()).file_name() {
+                Some(name) => dir.join(name),
+                None => PathBuf::new(),
+            },
+        };
+
+        let write_result = if let Some(other) = written.get(&dest) {
+            Err(DataProcessingError::PointerFileError(format!(
+                "pointer files of {other} and {} would both be written to {dest:?}",
+                pf.path()
+            )))
+        } else {
+            write_pointer_file(pf, &dest, matches!(pointer_output, PointerOutput::InPlace))
+        };
+
+        match write_result {
+            Ok(()) => {
+                written.insert(dest, pf.path().to_owned());
+            },
+            Err(e) if fail_fast => return Err(e),
+            Err(e) => {
+                warn!("Failed to write pointer file for {}: {e}", pf.path());
+                *result = Err(e);
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the pointer file to `dest` in the pointer file text format.  Unless `replace` is set,
+/// an existing file at `dest` is an error.
+fn write_pointer_file(pf: &PointerFile, dest: &Path, replace: bool) -> errors::Result<()> {
+    if dest.file_name().is_none() {
+        return Err(DataProcessingError::PointerFileError(format!(
+            "no file name to write the pointer file of {} under",
+            pf.path()
+        )));
+    }
+
+    if let Ok(metadata) = std::fs::metadata(dest) {
+        if !replace {
+            return Err(DataProcessingError::PointerFileError(format!(
+                "not writing the pointer file of {} over the existing file {dest:?}",
+                pf.path()
+            )));
+        }
+        if metadata.permissions().readonly() {
+            return Err(DataProcessingError::PointerFileError(format!(
+                "can't write the pointer file of {} to {dest:?}: the file is read-only",
+                pf.path()
+            )));
+        }
+    }
+
+    let mut writer = SafeFileCreator::replace_existing(dest)?;
+    writer.write_all(pf.to_string().as_bytes())?;
+    writer.close()?;
+    Ok(())
+}
+
+/// Cleans all the given files in the upload session, then finalizes the session; see `upload_async`.
+pub(crate) async fn upload_files_in_session(
+    upload_session: Arc<FileUploadSession>,
+    file_paths: Vec<String>,
+    fail_fast: bool,
+) -> errors::Result<Vec<errors::Result<PointerFile>>> {
+    Ok(upload_files_in_session_impl(upload_session, file_paths, fail_fast, false)
+        .await?
+        .0)
+}
 
-    // for all files, clean them, producing pointer files.
-    let pointers = tokio_par_for_each(file_paths, *MAX_CONCURRENT_FILE_INGESTION, |f, _| async {
-        let (pf, _metrics) = clean_file(upload_session.clone(), f).await?;
-        Ok(pf)
+/// Like `upload_files_in_session`, also returning the info of the files in the session if
+/// `return_file_info` is set.
+async fn upload_files_in_session_impl(
+    upload_session: Arc<FileUploadSession>,
+    file_paths: Vec<String>,
+    fail_fast: bool,
+    return_file_info: bool,
+) -> errors::Result<(Vec<errors::Result<PointerFile>>, Vec<MDBFileInfo>)> {
+    // for all files, clean them, producing pointer files.  The results are wrapped in an Option
+    // as tokio_par_for_each needs a default output value; every task returns Some.  Each output is
+    // stored at the index of its input, so the results stay in the order of file_paths.
+    let pointers = tokio_par_for_each(file_paths, *MAX_CONCURRENT_FILE_INGESTION, |f, _| {
+        let upload_session = upload_session.clone();
+        async move {
+            match clean_file(upload_session, &f).await.map_err(|e| UploadError::new(&f, e).into()) {
+                Ok((pf, _metrics)) => Ok(Some(Ok(pf))),
+                // Out of storage, the remaining files would fail too.
+                Err(e @ DataProcessingError::FileUploadError(UploadError::QuotaExceeded { .. })) => Err(e),
+                Err(e) if fail_fast => Err(e),
+                Err(e) => {
+                    warn!("Failed to upload {f}, continuing with the remaining files: {e}");
+                    Ok(Some(Err(e)))
+                },
+            }
+        }
     })
     .await
     .map_err(|e| match e {
         ParallelError::JoinError => DataProcessingError::InternalError("Join error".to_string()),
         ParallelError::TaskError(e) => e,
-    })?;
+    })?
+    .into_iter()
+    .flatten()
+    .collect();
 
     // Push the CAS blocks and flush the mdb to disk
-    let _metrics = upload_session.finalize().await?;
+    let file_info = if return_file_info {
+        upload_session.finalize_with_file_info().await?.1
+    } else {
+        upload_session.finalize().await?;
+        Vec::new()
+    };
 
     // TODO: Report on metrics
 
-    Ok(pointers)
+    Ok((pointers, file_info))
+}
+
+/// Uploads the files that `journal` doesn't have as already uploaded, `batch_files` at a time, with a
+/// session per batch.  Each batch is recorded in the journal once its session is finalized.  If
+/// `return_file_info` is set, the info of the files uploaded by every batch is returned too.
+async fn upload_files_journaled(
+    config: Arc<TranslatorConfig>,
+    threadpool: Arc<ThreadPool>,
+    options: &UploadOptions,
+    mut journal: UploadJournal,
+    file_paths: Vec<String>,
+    batch_files: usize,
+    return_file_info: bool,
+) -> errors::Result<(Vec<errors::Result<PointerFile>>, Vec<MDBFileInfo>)> {
+    let mut file_info = Vec::new();
+    let mut results: Vec<_> = file_paths.iter().map(|path| journal.completed(path).map(Ok)).collect();
+    let pending: Vec<_> = results
+        .iter()
+        .enumerate()
+        .filter(|(_, result)| result.is_none())
+        .map(|(idx, _)| idx)
+        .collect();
+    if pending.len() < file_paths.len() {
+        info!("Skipping {} of {} files already uploaded", file_paths.len() - pending.len(), file_paths.len());
+    }
+
+    for batch in pending.chunks(batch_files.max(1)) {
+        // The stamps are taken before the files are read, so that a file changing during its upload
+        // is uploaded again on resume.
+        let stamps: Vec<_> = batch.iter().map(|&idx| FileStamp::read(&file_paths[idx]).ok()).collect();
+
+        let upload_session = options.new_session(config.clone(), threadpool.clone()).await?;
+        let batch_paths = batch.iter().map(|&idx| file_paths[idx].clone()).collect();
+        let (batch_results, batch_file_info) =
+            upload_files_in_session_impl(upload_session, batch_paths, options.fail_fast, return_file_info).await?;
+        file_info.extend(batch_file_info);
+
+        journal.record(
+            batch
+                .iter()
+                .zip(&stamps)
+                .zip(&batch_results)
+                .filter_map(|((&idx, stamp), result)| {
+                    Some((file_paths[idx].as_str(), (*stamp)?, result.as_ref().ok()?))
+                }),
+        )?;
+        for (&idx, result) in batch.iter().zip(batch_results) {
+            results[idx] = Some(result);
+        }
+    }
+
+    Ok((results.into_iter().flatten().collect(), file_info))
+}
+
+/// How `upload_directory` walks the tree under its root.
+#[derive(Debug, Clone, Default)]
+pub struct DirectoryWalkOptions {
+    /// Glob patterns of the files and directories to skip, e.g. `*.tmp` or `.git`.  Each pattern is
+    /// matched against both the path relative to the root and the name of the entry; the contents
+    /// of a skipped directory are never walked.
+    pub ignore_globs: Vec<String>,
+    /// Whether to walk into symlinked directories and upload symlinked files; if false, symlinks are
+    /// skipped.
+    pub follow_symlinks: bool,
+}
+
+/// Uploads every file in the directory tree under `root`, returning the result of each file keyed by
+/// its path relative to `root`, with `/` separators.
+///
+/// The tree is walked in Rust and all the files are uploaded as with `upload_async`.  If the options
+/// don't fail fast, entries that can't be read while walking are skipped with a warning and failed
+/// uploads are returned in the map; otherwise the first error is returned.
+pub async fn upload_directory(
+    threadpool: Arc<ThreadPool>,
+    root: String,
+    walk_options: DirectoryWalkOptions,
+    options: UploadOptions,
+) -> errors::Result<BTreeMap<String, errors::Result<PointerFile>>> {
+    let config = options.translator_config()?;
+    upload_directory_with_config(Arc::new(config), threadpool, PathBuf::from(root), walk_options, &options).await
+}
+
+async fn upload_directory_with_config(
+    config: Arc<TranslatorConfig>,
+    threadpool: Arc<ThreadPool>,
+    root: PathBuf,
+    walk_options: DirectoryWalkOptions,
+    options: &UploadOptions,
+) -> errors::Result<BTreeMap<String, errors::Result<PointerFile>>> {
+    let fail_fast = options.fail_fast;
+    let files = tokio::task::spawn_blocking(move || list_directory_files(&root, &walk_options, fail_fast)).await??;
+    let (relative_paths, file_paths): (Vec<_>, Vec<_>) = files.into_iter().unzip();
+
+    let results = upload_with_outputs(config, threadpool, file_paths, options).await?;
+    Ok(relative_paths.into_iter().zip(results).collect())
+}
+
+/// Lists the files under `root` as (path relative to `root`, full path) pairs.
+fn list_directory_files(
+    root: &Path,
+    walk_options: &DirectoryWalkOptions,
+    fail_fast: bool,
+) -> errors::Result<Vec<(String, String)>> {
+    if !root.is_dir() {
+        return Err(DataProcessingError::ParameterError(format!("{root:?} is not a directory")));
+    }
+    let ignore_patterns = walk_options
+        .ignore_globs
+        .iter()
+        .map(|g| {
+            glob::Pattern::new(g).map_err(|e| DataProcessingError::ParameterError(format!("invalid glob {g:?}: {e}")))
+        })
+        .collect::<errors::Result<Vec<_>>>()?;
+
+    let relative_path = |path: &Path| {
+        path.strip_prefix(root)
+            .unwrap_or(path)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    };
+    let is_ignored = |entry: &walkdir::DirEntry| {
+        let relative = relative_path(entry.path());
+        let name = entry.file_name().to_string_lossy();
+        ignore_patterns.iter().any(|p| p.matches(&relative) || p.matches(&name))
+    };
+
+    let walker = walkdir::WalkDir::new(root)
+        .min_depth(1)
+        .follow_links(walk_options.follow_symlinks)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| !is_ignored(entry));
+
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if fail_fast => return Err(std::io::Error::from(e).into()),
+            Err(e) => {
+                warn!("Skipping unreadable entry while walking {root:?}: {e}");
+                continue;
+            },
+        };
+        // Without following symlinks, symlinks are reported as such rather than as files.
+        if entry.file_type().is_file() {
+            files.push((relative_path(entry.path()), entry.path().to_string_lossy().to_string()));
+        }
+    }
+    Ok(files)
+}
+
+/// Estimates the size of the new data that uploading `file_paths` to `endpoint` would transfer, and
+/// how much of the files deduplicates, without uploading anything.
+///
+/// Only a sample of about `sample_rate` of the chunks (by default, UPLOAD_ESTIMATE_SAMPLE_RATE) is
+/// probed for deduplication against stored data; see `upload_estimate::estimate_upload`.
+pub async fn estimate_upload(
+    threadpool: Arc<ThreadPool>,
+    file_paths: Vec<String>,
+    endpoint: Option<String>,
+    token_info: Option<(String, u64)>,
+    token_refresher: Option<Arc<dyn TokenRefresher>>,
+    sample_rate: Option<f64>,
+) -> errors::Result<UploadEstimate> {
+    let config =
+        default_translator_config(endpoint.unwrap_or(DEFAULT_CAS_ENDPOINT.clone()), None, token_info, token_refresher)?;
+    upload_estimate::estimate_upload(
+        Arc::new(config),
+        threadpool,
+        &file_paths,
+        sample_rate.unwrap_or(*UPLOAD_ESTIMATE_SAMPLE_RATE),
+    )
+    .await
+}
+
+/// Returns the size of the local shard and chunk caches used for `endpoint`.
+pub fn cache_stats(endpoint: Option<String>) -> errors::Result<CacheStats> {
+    let config = default_translator_config(endpoint.unwrap_or(DEFAULT_CAS_ENDPOINT.clone()), None, None, None)?;
+    local_cache::cache_stats(&config)
+}
+
+/// Deletes everything in the local shard and chunk caches used for `endpoint`.  Only call this while
+/// no uploads or downloads are running.
+pub async fn clear_cache(endpoint: Option<String>) -> errors::Result<()> {
+    let config = default_translator_config(endpoint.unwrap_or(DEFAULT_CAS_ENDPOINT.clone()), None, None, None)?;
+    local_cache::clear_cache(&config).await
 }
 
+/// Downloads the files of the pointer files, returning their paths.
+///
+/// With `max_total_bytes`, a batch whose files total more than that many bytes fails with
+/// `DataProcessingError::DownloadLimitExceeded` before anything is downloaded, and the batch is
+/// aborted with the same error as soon as the bytes written by its files pass the limit, removing
+/// the partial outputs of the files still in flight.
 pub async fn download_async(
     threadpool: Arc<ThreadPool>,
     pointer_files: Vec<PointerFile>,
@@ -137,6 +724,7 @@ pub async fn download_async(
     token_info: Option<(String, u64)>,
     token_refresher: Option<Arc<dyn TokenRefresher>>,
     progress_updaters: Option<Vec<Arc<dyn ProgressUpdater>>>,
+    max_total_bytes: Option<u64>,
 ) -> errors::Result<Vec<String>> {
     if let Some(updaters) = &progress_updaters {
         if updaters.len() != pointer_files.len() {
@@ -154,125 +742,621 @@ pub async fn download_async(
     };
     let pointer_files_plus = pointer_files.into_iter().zip(updaters).collect::<Vec<_>>();
 
-    let processor = &Arc::new(FileDownloader::new(config, threadpool).await?);
-    let paths =
-        tokio_par_for_each(pointer_files_plus, *MAX_CONCURRENT_DOWNLOADS, |(pointer_file, updater), _| async move {
-            let proc = processor.clone();
-            smudge_file(&proc, &pointer_file, updater).await
-        })
-        .await
-        .map_err(|e| match e {
-            ParallelError::JoinError => DataProcessingError::InternalError("Join error".to_string()),
-            ParallelError::TaskError(e) => e,
-        })?;
+    let processor = Arc::new(FileDownloader::new(config, threadpool).await?);
+    match max_total_bytes {
+        None => download_pointer_files(processor, pointer_files_plus).await,
+        Some(max_total_bytes) => download_pointer_files_within(processor, pointer_files_plus, max_total_bytes).await,
+    }
+}
 
-    Ok(paths)
+/// Starts downloading the file of `pointer_file`, or the `range` of it, returning a stream of its bytes
+/// in order as they're downloaded; see `FileDownloader::stream_file`.
+pub async fn download_stream_async(
+    threadpool: Arc<ThreadPool>,
+    pointer_file: PointerFile,
+    range: Option<FileRange>,
+    endpoint: Option<String>,
+    token_info: Option<(String, u64)>,
+    token_refresher: Option<Arc<dyn TokenRefresher>>,
+) -> errors::Result<DownloadStream> {
+    let config =
+        default_config(endpoint.unwrap_or(DEFAULT_CAS_ENDPOINT.to_string()), None, token_info, token_refresher)?;
+    let downloader = Arc::new(FileDownloader::new(config, threadpool).await?);
+    Ok(downloader.stream_file(&pointer_file.hash()?, range))
 }
 
-pub async fn clean_file(
-    processor: Arc<FileUploadSession>,
-    filename: impl AsRef<Path>,
-) -> errors::Result<(PointerFile, DeduplicationMetrics)> {
-    let mut reader = File::open(&filename)?;
+#[cfg(feature = "blocking")]
+pub use blocking::{download_blocking, upload_blocking};
 
-    let n = reader.metadata()?.len() as usize;
-    let mut buffer = vec![0u8; usize::min(n, *INGESTION_BLOCK_SIZE)];
+/// Synchronous wrappers of the upload and download API for callers that don't run an async runtime.
+/// They run on a threadpool shared by all the blocking calls, created on first use.
+#[cfg(feature = "blocking")]
+mod blocking {
+    use std::future::Future;
+    use std::sync::Mutex;
 
-    let mut handle = processor.start_clean(filename.as_ref().to_string_lossy().into());
+    use super::*;
 
-    loop {
-        let bytes = reader.read(&mut buffer)?;
-        if bytes == 0 {
-            break;
+    lazy_static::lazy_static! {
+        static ref BLOCKING_THREADPOOL: Mutex<Option<Arc<ThreadPool>>> = Mutex::new(None);
+    }
+
+    fn blocking_threadpool() -> errors::Result<Arc<ThreadPool>> {
+        let mut threadpool = BLOCKING_THREADPOOL
+            .lock()
+            .map_err(|e| DataProcessingError::InternalError(format!("blocking threadpool lock poisoned: {e}")))?;
+        if let Some(threadpool) = threadpool.as_ref() {
+            return Ok(threadpool.clone());
         }
+        let new_threadpool = Arc::new(ThreadPool::new()?);
+        *threadpool = Some(new_threadpool.clone());
+        Ok(new_threadpool)
+    }
 
-        handle.add_data(&buffer[0..bytes]).await?;
+    /// Runs the task returned by `task` on the shared threadpool, blocking until it completes.
+    fn run_blocking<F, Fut, T>(task: F) -> errors::Result<T>
+    where
+        F: FnOnce(Arc<ThreadPool>) -> Fut,
+        Fut: Future<Output = errors::Result<T>> + Send + 'static,
+        T: Send + Sync,
+    {
+        // Blocking on the threadpool from a task of a runtime would stall that runtime's worker
+        // thread, and can deadlock it, so async callers have to use the async API.
+        if tokio::runtime::Handle::try_current().is_ok() {
+            return Err(DataProcessingError::BlockingCallInAsyncContext);
+        }
+        let threadpool = blocking_threadpool()?;
+        threadpool.external_run_async_task(task(threadpool.clone()))?
     }
 
-    handle.finish().await
-}
+    /// Like `upload_async`, but blocks until the upload completes.  Returns
+    /// `DataProcessingError::BlockingCallInAsyncContext` if called from within an async runtime.
+    pub fn upload_blocking(
+        file_paths: Vec<String>,
+        options: UploadOptions,
+    ) -> errors::Result<Vec<errors::Result<PointerFile>>> {
+        run_blocking(move |threadpool| upload_async(threadpool, file_paths, options))
+    }
 
-async fn smudge_file(
-    downloader: &FileDownloader,
-    pointer_file: &PointerFile,
-    progress_updater: Option<Arc<dyn ProgressUpdater>>,
-) -> errors::Result<String> {
-    let path = PathBuf::from(pointer_file.path());
-    if let Some(parent_dir) = path.parent() {
-        std::fs::create_dir_all(parent_dir)?;
+    /// Like `download_async`, but blocks until the downloads complete.  Returns
+    /// `DataProcessingError::BlockingCallInAsyncContext` if called from within an async runtime.
+    pub fn download_blocking(
+        pointer_files: Vec<PointerFile>,
+        endpoint: Option<String>,
+        token_info: Option<(String, u64)>,
+        token_refresher: Option<Arc<dyn TokenRefresher>>,
+        progress_updaters: Option<Vec<Arc<dyn ProgressUpdater>>>,
+        max_total_bytes: Option<u64>,
+    ) -> errors::Result<Vec<String>> {
+        run_blocking(move |threadpool| {
+            download_async(
+                threadpool,
+                pointer_files,
+                endpoint,
+                token_info,
+                token_refresher,
+                progress_updaters,
+                max_total_bytes,
+            )
+        })
     }
-    let output = OutputProvider::File(FileProvider::new(path));
-    downloader
-        .smudge_file_from_pointer(pointer_file, &output, None, progress_updater)
-        .await?;
-    Ok(pointer_file.path().to_string())
-}
 
-#[cfg(test)]
-mod tests {
-    use std::env;
+    #[cfg(test)]
+    mod tests {
+        use serial_test::serial;
+        use tempfile::tempdir;
 
-    use serial_test::serial;
-    use tempfile::tempdir;
+        use super::*;
 
-    use super::*;
+        #[test]
+        #[serial(default_config_env)]
+        fn test_blocking_api_without_runtime() {
+            let temp_dir = tempdir().unwrap();
+            env::set_var("HF_XET_CACHE", temp_dir.path().to_str().unwrap());
+            let endpoint = Some("http://localhost:8080".to_string());
 
-    #[test]
-    #[serial(default_config_env)]
-    fn test_default_config_with_hf_home() {
-        let temp_dir = tempdir().unwrap();
-        env::set_var("HF_HOME", temp_dir.path().to_str().unwrap());
+            let missing = temp_dir.path().join("missing").to_string_lossy().to_string();
+            let options = UploadOptions::new().with_endpoint(endpoint.clone()).with_fail_fast(false);
+            let results = upload_blocking(vec![missing], options).unwrap();
+            assert!(matches!(results[..], [Err(DataProcessingError::FileUploadError(UploadError::NotFound { .. }))]));
 
-        let endpoint = "http://localhost:8080".to_string();
-        let result = default_config(endpoint, None, None, None);
+            let pointer_files = download_blocking(vec![], endpoint, None, None, None, None).unwrap();
+            assert!(pointer_files.is_empty());
 
-        assert!(result.is_ok());
-        let config = result.unwrap();
-        assert!(config.data_config.cache_config.cache_directory.starts_with(&temp_dir.path()));
+            env::remove_var("HF_XET_CACHE");
+        }
 
-        env::remove_var("HF_HOME");
+        #[tokio::test]
+        async fn test_blocking_api_in_runtime() {
+            let result = download_blocking(vec![], Some("http://localhost:8080".to_string()), None, None, None, None);
+            assert!(matches!(result, Err(DataProcessingError::BlockingCallInAsyncContext)));
+        }
     }
+}
 
-    #[test]
-    #[serial(default_config_env)]
-    fn test_default_config_with_hf_xet_cache_and_hf_home() {
-        let temp_dir_xet_cache = tempdir().unwrap();
-        let temp_dir_hf_home = tempdir().unwrap();
-        env::set_var("HF_XET_CACHE", temp_dir_xet_cache.path().to_str().unwrap());
-        env::set_var("HF_HOME", temp_dir_hf_home.path().to_str().unwrap());
-
-        let endpoint = "http://localhost:8080".to_string();
-        let result = default_config(endpoint, None, None, None);
-
-        assert!(result.is_ok());
-        let config = result.unwrap();
-        assert!(config
-            .data_config
-            .cache_config
-            .cache_directory
-            .starts_with(&temp_dir_xet_cache.path()));
-
-        env::remove_var("HF_XET_CACHE");
-        env::remove_var("HF_HOME");
-
-        let temp_dir = tempdir().unwrap();
-        env::set_var("HF_HOME", temp_dir.path().to_str().unwrap());
-
-        let endpoint = "http://localhost:8080".to_string();
-        let result = default_config(endpoint, None, None, None);
+/// Cancels the download of a single file of a batch started with `download_async_cancelable`; the
+/// other files of the batch carry on.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadCancelHandle {
+    token: CancellationToken,
+}
 
-        assert!(result.is_ok());
-        let config = result.unwrap();
-        assert!(config.data_config.cache_config.cache_directory.starts_with(&temp_dir.path()));
+impl DownloadCancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        env::remove_var("HF_HOME");
+    /// Stops the download of the file: its in-flight term fetches are dropped, its partial output is
+    /// removed, leaving any file already at its destination as it was, and its result is
+    /// `DataProcessingError::DownloadCanceled`.
+    pub fn cancel(&self) {
+        self.token.cancel();
     }
 
-    #[test]
-    #[serial(default_config_env)]
-    fn test_default_config_with_hf_xet_cache() {
-        let temp_dir = tempdir().unwrap();
-        env::set_var("HF_XET_CACHE", temp_dir.path().to_str().unwrap());
+    pub fn is_canceled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+}
+
+/// Like `download_async`, but each file can be canceled through its entry in `cancel_handles`, and
+/// the result of each file is returned separately so that a canceled or failed file doesn't abort
+/// the others.
+pub async fn download_async_cancelable(
+    threadpool: Arc<ThreadPool>,
+    pointer_files: Vec<PointerFile>,
+    endpoint: Option<String>,
+    token_info: Option<(String, u64)>,
+    token_refresher: Option<Arc<dyn TokenRefresher>>,
+    progress_updaters: Option<Vec<Arc<dyn ProgressUpdater>>>,
+    cancel_handles: Vec<DownloadCancelHandle>,
+) -> errors::Result<Vec<errors::Result<String>>> {
+    if cancel_handles.len() != pointer_files.len() {
+        return Err(DataProcessingError::ParameterError(
+            "cancel_handles are not same length as pointer_files".to_string(),
+        ));
+    }
+    if let Some(updaters) = &progress_updaters {
+        if updaters.len() != pointer_files.len() {
+            return Err(DataProcessingError::ParameterError(
+                "updaters are not same length as pointer_files".to_string(),
+            ));
+        }
+    }
+    let config =
+        default_config(endpoint.unwrap_or(DEFAULT_CAS_ENDPOINT.to_string()), None, token_info, token_refresher)?;
+
+    let updaters = match progress_updaters {
+        None => vec![None; pointer_files.len()],
+        Some(updaters) => updaters.into_iter().map(Some).collect(),
+    };
+    let files = pointer_files
+        .into_iter()
+        .zip(updaters)
+        .zip(cancel_handles)
+        .map(|((pf, updater), cancel)| (pf, updater, cancel))
+        .collect();
+
+    let processor = Arc::new(FileDownloader::new(config, threadpool).await?);
+    download_pointer_files_cancelable(processor, files).await
+}
+
+/// A file to download, with its progress updater and cancel handle.
+type CancelableDownload = (PointerFile, Option<Arc<dyn ProgressUpdater>>, DownloadCancelHandle);
+
+async fn download_pointer_files_cancelable(
+    processor: Arc<FileDownloader>,
+    files: Vec<CancelableDownload>,
+) -> errors::Result<Vec<errors::Result<String>>> {
+    let processor = &processor;
+    let results =
+        tokio_par_for_each(files, *MAX_CONCURRENT_DOWNLOADS, |(pointer_file, updater, cancel), _| async move {
+            Ok::<_, DataProcessingError>(Some(smudge_file_cancelable(processor, &pointer_file, updater, &cancel).await))
+        })
+        .await
+        .map_err(|e| match e {
+            ParallelError::JoinError => DataProcessingError::InternalError("Join error".to_string()),
+            ParallelError::TaskError(e) => e,
+        })?;
+
+    Ok(results.into_iter().flatten().collect())
+}
+
+/// Fails if the files of a download total more than `max_total_bytes`, going by the summed unpacked
+/// length of the reconstruction terms of each file rather than the size its pointer file claims.
+async fn check_planned_download_size(
+    processor: &Arc<FileDownloader>,
+    pointer_files_plus: &[(PointerFile, Option<Arc<dyn ProgressUpdater>>)],
+    max_total_bytes: u64,
+) -> errors::Result<()> {
+    let hashes = pointer_files_plus
+        .iter()
+        .map(|(pf, _)| pf.hash().map_err(Into::into))
+        .collect::<errors::Result<Vec<_>>>()?;
+    let sizes =
+        tokio_par_for_each(
+            hashes,
+            *MAX_CONCURRENT_DOWNLOADS,
+            |hash, _| async move { processor.file_size(&hash).await },
+        )
+        .await
+        .map_err(|e| match e {
+            ParallelError::JoinError => DataProcessingError::InternalError("Join error".to_string()),
+            ParallelError::TaskError(e) => e,
+        })?;
+
+    let planned_bytes: u64 = sizes.into_iter().sum();
+    if planned_bytes > max_total_bytes {
+        return Err(DataProcessingError::DownloadLimitExceeded(format!(
+            "the files to download total {planned_bytes} bytes, more than the limit of {max_total_bytes} bytes"
+        )));
+    }
+    Ok(())
+}
+
+/// The bytes written so far by the files of a download capped at `max_total_bytes`, and the handle
+/// that cancels all of them once the total passes the cap.
+#[derive(Debug)]
+struct DownloadBudget {
+    max_total_bytes: u64,
+    written_bytes: AtomicU64,
+    cancel: DownloadCancelHandle,
+}
+
+/// Counts the bytes written by one file of a download against the download's budget, passing the
+/// progress on to the file's own updater.
+#[derive(Debug)]
+struct BudgetedProgressUpdater {
+    budget: Arc<DownloadBudget>,
+    inner: Option<Arc<dyn ProgressUpdater>>,
+}
+
+impl ProgressUpdater for BudgetedProgressUpdater {
+    fn update(&self, increment: u64) {
+        if let Some(inner) = &self.inner {
+            inner.update(increment);
+        }
+        let written_bytes = self.budget.written_bytes.fetch_add(increment, Ordering::Relaxed) + increment;
+        if written_bytes > self.budget.max_total_bytes {
+            self.budget.cancel.cancel();
+        }
+    }
+}
+
+/// Like `download_pointer_files`, but fails without downloading anything if the files total more
+/// than `max_total_bytes`, and aborts once the bytes written pass it, e.g. because a file changed
+/// on the server since its size was checked.
+async fn download_pointer_files_within(
+    processor: Arc<FileDownloader>,
+    pointer_files_plus: Vec<(PointerFile, Option<Arc<dyn ProgressUpdater>>)>,
+    max_total_bytes: u64,
+) -> errors::Result<Vec<String>> {
+    check_planned_download_size(&processor, &pointer_files_plus, max_total_bytes).await?;
+    download_pointer_files_budgeted(processor, pointer_files_plus, max_total_bytes).await
+}
+
+/// Downloads the files, aborting once the bytes written pass `max_total_bytes`.
+async fn download_pointer_files_budgeted(
+    processor: Arc<FileDownloader>,
+    pointer_files_plus: Vec<(PointerFile, Option<Arc<dyn ProgressUpdater>>)>,
+    max_total_bytes: u64,
+) -> errors::Result<Vec<String>> {
+    let budget = Arc::new(DownloadBudget {
+        max_total_bytes,
+        written_bytes: AtomicU64::new(0),
+        cancel: DownloadCancelHandle::new(),
+    });
+    let files = pointer_files_plus
+        .into_iter()
+        .map(|(pointer_file, updater)| {
+            let updater = Arc::new(BudgetedProgressUpdater {
+                budget: budget.clone(),
+                inner: updater,
+            });
+            (pointer_file, Some(updater as Arc<dyn ProgressUpdater>), budget.cancel.clone())
+        })
+        .collect();
+    let results = download_pointer_files_cancelable(processor, files).await?;
+
+    let written_bytes = budget.written_bytes.load(Ordering::Relaxed);
+    if written_bytes > max_total_bytes {
+        return Err(DataProcessingError::DownloadLimitExceeded(format!(
+            "the download was aborted after writing {written_bytes} bytes, more than the limit of {max_total_bytes} bytes"
+        )));
+    }
+    results.into_iter().collect()
+}
+
+/// Downloads the files described by pointer file contents, e.g. as piped in on stdin, writing each
+/// file to the path paired with its contents.
+///
+/// Contents that aren't a valid pointer file are skipped, and their entry in the result is the error
+/// describing why; the entry of every other file is its path once downloaded, or the error
+/// downloading it.
+pub async fn download_from_pointer_text_async(
+    threadpool: Arc<ThreadPool>,
+    pointer_texts: Vec<(String, String)>,
+    endpoint: Option<String>,
+    token_info: Option<(String, u64)>,
+    token_refresher: Option<Arc<dyn TokenRefresher>>,
+) -> errors::Result<Vec<errors::Result<String>>> {
+    let config =
+        default_config(endpoint.unwrap_or(DEFAULT_CAS_ENDPOINT.to_string()), None, token_info, token_refresher)?;
+
+    let processor = Arc::new(FileDownloader::new(config, threadpool).await?);
+    download_pointer_texts(processor, pointer_texts).await
+}
+
+async fn download_pointer_texts(
+    processor: Arc<FileDownloader>,
+    pointer_texts: Vec<(String, String)>,
+) -> errors::Result<Vec<errors::Result<String>>> {
+    let parsed: Vec<_> = pointer_texts
+        .iter()
+        .enumerate()
+        .map(|(i, (contents, path))| {
+            PointerFile::parse(contents, path).inspect_err(|e| warn!("Skipping pointer text {i}: {e}"))
+        })
+        .collect();
+
+    let valid: Vec<_> = parsed.iter().filter_map(|r| r.as_ref().ok()).cloned().collect();
+    let processor = &processor;
+    let mut downloaded = tokio_par_for_each(valid, *MAX_CONCURRENT_DOWNLOADS, |pointer_file, _| async move {
+        Ok::<_, DataProcessingError>(Some(smudge_file(processor, &pointer_file, None).await))
+    })
+    .await
+    .map_err(|e| match e {
+        ParallelError::JoinError => DataProcessingError::InternalError("Join error".to_string()),
+        ParallelError::TaskError(e) => e,
+    })?
+    .into_iter()
+    .flatten();
+
+    Ok(parsed.into_iter().map(|r| r.and_then(|_| downloaded.next().unwrap())).collect())
+}
+
+async fn download_pointer_files(
+    processor: Arc<FileDownloader>,
+    pointer_files_plus: Vec<(PointerFile, Option<Arc<dyn ProgressUpdater>>)>,
+) -> errors::Result<Vec<String>> {
+    let processor = &processor;
+    let paths =
+        tokio_par_for_each(pointer_files_plus, *MAX_CONCURRENT_DOWNLOADS, |(pointer_file, updater), _| async move {
+            let proc = processor.clone();
+            smudge_file(&proc, &pointer_file, updater).await
+        })
+        .await
+        .map_err(|e| match e {
+            ParallelError::JoinError => DataProcessingError::InternalError("Join error".to_string()),
+            ParallelError::TaskError(e) => e,
+        })?;
+
+    Ok(paths)
+}
+
+/// Downloads the files listed in a manifest into `dest_dir`, at their paths relative to it.
+///
+/// The manifest is a JSON object with a `files` array of entries, each holding the path of a file
+/// relative to `dest_dir`, its hash and its size:
+///
+/// ```json
+/// {"files": [{"path": "data/train.bin", "hash": "<hex merkle hash>", "size": 1024}]}
+/// ```
+///
+/// Entries that are malformed, have a path outside of `dest_dir` or repeat an earlier path are
+/// skipped, and their entry in the result is the error describing why; the entry of every other file
+/// is its path once downloaded, or the error downloading it.
+pub async fn download_manifest(
+    threadpool: Arc<ThreadPool>,
+    manifest_json: &str,
+    dest_dir: impl AsRef<Path>,
+    endpoint: Option<String>,
+    token_info: Option<(String, u64)>,
+    token_refresher: Option<Arc<dyn TokenRefresher>>,
+) -> errors::Result<Vec<errors::Result<String>>> {
+    let config =
+        default_config(endpoint.unwrap_or(DEFAULT_CAS_ENDPOINT.to_string()), None, token_info, token_refresher)?;
+
+    let processor = Arc::new(FileDownloader::new(config, threadpool).await?);
+    download_manifest_entries(processor, manifest_json, dest_dir.as_ref()).await
+}
+
+async fn download_manifest_entries(
+    processor: Arc<FileDownloader>,
+    manifest_json: &str,
+    dest_dir: &Path,
+) -> errors::Result<Vec<errors::Result<String>>> {
+    let mut seen_paths = HashSet::new();
+    let parsed: Vec<_> = parse_manifest(manifest_json)?
+        .into_iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            entry
+                .and_then(|entry| {
+                    let path = resolve_in_dir(dest_dir, &entry.path)?;
+                    if !seen_paths.insert(path.clone()) {
+                        return Err(DataProcessingError::ParameterError(format!(
+                            "manifest path {:?} is listed more than once",
+                            entry.path
+                        )));
+                    }
+                    Ok(PointerFile::init_from_info(&path.to_string_lossy(), &entry.hash, entry.size))
+                })
+                .inspect_err(|e| warn!("Skipping manifest entry {i}: {e}"))
+        })
+        .collect();
+
+    let valid: Vec<_> = parsed.iter().filter_map(|r| r.as_ref().ok()).cloned().collect();
+    let processor = &processor;
+    let mut downloaded = tokio_par_for_each(valid, *MAX_CONCURRENT_DOWNLOADS, |pointer_file, _| async move {
+        Ok::<_, DataProcessingError>(Some(smudge_manifest_file(processor, &pointer_file).await))
+    })
+    .await
+    .map_err(|e| match e {
+        ParallelError::JoinError => DataProcessingError::InternalError("Join error".to_string()),
+        ParallelError::TaskError(e) => e,
+    })?
+    .into_iter()
+    .flatten();
+
+    Ok(parsed.into_iter().map(|r| r.and_then(|_| downloaded.next().unwrap())).collect())
+}
+
+/// Like `smudge_file`, but fails if the downloaded file doesn't have the size listed in the manifest.
+async fn smudge_manifest_file(downloader: &FileDownloader, pointer_file: &PointerFile) -> errors::Result<String> {
+    let path = smudge_file(downloader, pointer_file, None).await?;
+    let size = std::fs::metadata(&path)?.len();
+    if size != pointer_file.filesize() {
+        return Err(DataProcessingError::ParameterError(format!(
+            "{path} is {size} bytes, but the manifest lists it as {} bytes",
+            pointer_file.filesize()
+        )));
+    }
+    Ok(path)
+}
+
+/// Returns the plan that downloading `range` of the file `file_hash` (or the whole file if None)
+/// would execute, without fetching any of the file data.  Meant for tooling that debugs downloads.
+pub async fn explain_reconstruction(
+    threadpool: Arc<ThreadPool>,
+    file_hash: &MerkleHash,
+    range: Option<FileRange>,
+    endpoint: Option<String>,
+    token_info: Option<(String, u64)>,
+    token_refresher: Option<Arc<dyn TokenRefresher>>,
+) -> errors::Result<ReconstructionPlan> {
+    let config =
+        default_config(endpoint.unwrap_or(DEFAULT_CAS_ENDPOINT.to_string()), None, token_info, token_refresher)?;
+    // Query the endpoint as normalized by the config.
+    let Endpoint::Server(endpoint) = &config.data_config.endpoint else {
+        return Err(DataProcessingError::InternalError("default config has no server endpoint".to_owned()));
+    };
+
+    let client = RemoteClient::new(
+        threadpool,
+        endpoint,
+        None,
+        &config.data_config.auth,
+        &None,
+        config.shard_config.cache_directory.clone(),
+        false,
+    )?
+    .with_max_reconstruction_terms(config.data_config.max_reconstruction_terms)
+    .with_max_response_bytes(config.data_config.max_response_bytes);
+    Ok(client.explain_reconstruction(file_hash, range).await?)
+}
+
+pub async fn clean_file(
+    processor: Arc<FileUploadSession>,
+    filename: impl AsRef<Path>,
+) -> errors::Result<(PointerFile, DeduplicationMetrics)> {
+    let mut reader = File::open(&filename)?;
+
+    let n = reader.metadata()?.len() as usize;
+    let mut buffer = vec![0u8; usize::min(n, *INGESTION_BLOCK_SIZE)];
+
+    let mut handle = processor.start_clean(filename.as_ref().to_string_lossy().into());
+
+    loop {
+        let bytes = reader.read(&mut buffer)?;
+        if bytes == 0 {
+            break;
+        }
+
+        handle.add_data(&buffer[0..bytes]).await?;
+    }
+
+    handle.finish().await
+}
+
+/// Uploads a file named `name` given as its chunks, as hashes and data, in order, without chunking it
+/// again; for tools that already chunked the content, e.g. in an earlier session.  The hashes are
+/// checked against the data before they're used; see `SingleFileCleaner::add_chunks`.
+pub async fn upload_prechunked(
+    processor: Arc<FileUploadSession>,
+    name: impl Into<String>,
+    chunks: Vec<(MerkleHash, Vec<u8>)>,
+) -> errors::Result<(PointerFile, DeduplicationMetrics)> {
+    let chunks = chunks
+        .into_iter()
+        .map(|(hash, data)| Chunk {
+            hash,
+            data: data.into(),
+        })
+        .collect();
+
+    let mut handle = processor.start_clean(name.into());
+    handle.add_chunks(chunks).await?;
+    handle.finish().await
+}
+
+/// Computes the hash a file with the contents of `reader` gets when it's uploaded with `config`,
+/// without uploading anything, e.g. to check whether a local file matches a hash.  The data is
+/// chunked as by an upload, so only the chunker configuration and the repo salt of `config` matter.
+pub fn compute_file_hash_from_reader<R: Read>(reader: R, config: &TranslatorConfig) -> errors::Result<MerkleHash> {
+    hash_file_contents(reader, config.data_config.chunker_config, &config.shard_config.repo_salt)
+}
+
+/// Computes the hash of the file at `path` as uploaded with the default chunking and repo salt; see
+/// `compute_file_hash_from_reader`.
+pub fn compute_file_hash(path: impl AsRef<Path>) -> errors::Result<MerkleHash> {
+    hash_file_contents(File::open(path)?, ChunkerConfig::default(), &RepoSalt::default())
+}
+
+async fn smudge_file(
+    downloader: &FileDownloader,
+    pointer_file: &PointerFile,
+    progress_updater: Option<Arc<dyn ProgressUpdater>>,
+) -> errors::Result<String> {
+    let path = PathBuf::from(pointer_file.path());
+    if let Some(parent_dir) = path.parent() {
+        std::fs::create_dir_all(parent_dir)?;
+    }
+    let output = OutputProvider::File(FileProvider::new(path));
+    downloader
+        .smudge_file_from_pointer(pointer_file, &output, None, progress_updater)
+        .await?;
+    Ok(pointer_file.path().to_string())
+}
+
+/// Like `smudge_file`, but stops as soon as `cancel` is triggered.  The file is downloaded to a
+/// temporary file beside its destination and only renamed into place once complete, so a canceled
+/// download removes its partial output without touching an existing file at the destination.
+async fn smudge_file_cancelable(
+    downloader: &FileDownloader,
+    pointer_file: &PointerFile,
+    progress_updater: Option<Arc<dyn ProgressUpdater>>,
+    cancel: &DownloadCancelHandle,
+) -> errors::Result<String> {
+    if cancel.is_canceled() {
+        return Err(DataProcessingError::DownloadCanceled);
+    }
+
+    let path = PathBuf::from(pointer_file.path());
+    if let Some(parent_dir) = path.parent() {
+        std::fs::create_dir_all(parent_dir)?;
+    }
+    let output = OutputProvider::File(FileProvider::new(path));
+    downloader
+        .smudge_file_from_hash_cancelable(&pointer_file.hash()?, &output, None, progress_updater, &cancel.token)
+        .await?;
+    Ok(pointer_file.path().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use serial_test::serial;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    #[serial(default_config_env)]
+    fn test_default_config_with_hf_home() {
+        let temp_dir = tempdir().unwrap();
+        env::set_var("HF_HOME", temp_dir.path().to_str().unwrap());
 
         let endpoint = "http://localhost:8080".to_string();
         let result = default_config(endpoint, None, None, None);
@@ -281,23 +1365,1491 @@ mod tests {
         let config = result.unwrap();
         assert!(config.data_config.cache_config.cache_directory.starts_with(&temp_dir.path()));
 
-        env::remove_var("HF_XET_CACHE");
+        env::remove_var("HF_HOME");
     }
 
     #[test]
     #[serial(default_config_env)]
-    fn test_default_config_without_env_vars() {
+    fn test_default_config_with_hf_xet_cache_and_hf_home() {
+        let temp_dir_xet_cache = tempdir().unwrap();
+        let temp_dir_hf_home = tempdir().unwrap();
+        env::set_var("HF_XET_CACHE", temp_dir_xet_cache.path().to_str().unwrap());
+        env::set_var("HF_HOME", temp_dir_hf_home.path().to_str().unwrap());
+
         let endpoint = "http://localhost:8080".to_string();
         let result = default_config(endpoint, None, None, None);
 
-        let expected = home_dir()
-            .unwrap_or(std::env::current_dir().unwrap())
-            .join(".cache")
-            .join("huggingface")
-            .join("xet");
+        assert!(result.is_ok());
+        let config = result.unwrap();
+        assert!(config
+            .data_config
+            .cache_config
+            .cache_directory
+            .starts_with(&temp_dir_xet_cache.path()));
+
+        env::remove_var("HF_XET_CACHE");
+        env::remove_var("HF_HOME");
+
+        let temp_dir = tempdir().unwrap();
+        env::set_var("HF_HOME", temp_dir.path().to_str().unwrap());
+
+        let endpoint = "http://localhost:8080".to_string();
+        let result = default_config(endpoint, None, None, None);
 
         assert!(result.is_ok());
         let config = result.unwrap();
-        assert!(config.data_config.cache_config.cache_directory.starts_with(&expected));
+        assert!(config.data_config.cache_config.cache_directory.starts_with(&temp_dir.path()));
+
+        env::remove_var("HF_HOME");
+    }
+
+    #[test]
+    #[serial(default_config_env)]
+    fn test_default_config_with_hf_xet_cache() {
+        let temp_dir = tempdir().unwrap();
+        env::set_var("HF_XET_CACHE", temp_dir.path().to_str().unwrap());
+
+        let endpoint = "http://localhost:8080".to_string();
+        let result = default_config(endpoint, None, None, None);
+
+        assert!(result.is_ok());
+        let config = result.unwrap();
+        assert!(config.data_config.cache_config.cache_directory.starts_with(&temp_dir.path()));
+
+        env::remove_var("HF_XET_CACHE");
+    }
+
+    #[test]
+    #[serial(default_config_env)]
+    fn test_default_config_without_env_vars() {
+        let endpoint = "http://localhost:8080".to_string();
+        let result = default_config(endpoint, None, None, None);
+
+        let expected = home_dir()
+            .unwrap_or(std::env::current_dir().unwrap())
+            .join(".cache")
+            .join("huggingface")
+            .join("xet");
+
+        assert!(result.is_ok());
+        let config = result.unwrap();
+        assert!(config.data_config.cache_config.cache_directory.starts_with(&expected));
+    }
+
+    /// Counts the bytes reported by an upload or a download.
+    #[derive(Debug, Default)]
+    struct ByteCounter(AtomicU64);
+
+    impl ProgressUpdater for ByteCounter {
+        fn update(&self, increment: u64) {
+            self.0.fetch_add(increment, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_resume_journaled_upload() {
+        let temp_dir = tempdir().unwrap();
+        let config = TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap();
+        let journal_path = temp_dir.path().join("upload.journal");
+        let paths: Vec<_> = (0..4).map(|i| temp_dir.path().join(format!("file_{i}"))).collect();
+        let file_paths: Vec<_> = paths.iter().map(|p| p.to_string_lossy().to_string()).collect();
+        for (i, path) in paths.iter().enumerate() {
+            if i != 2 {
+                std::fs::write(path, format!("contents of file {i}")).unwrap();
+            }
+        }
+
+        let upload = || {
+            let config = config.clone();
+            let journal = UploadJournal::open(&journal_path).unwrap();
+            let file_paths = file_paths.clone();
+            async move {
+                let counter = Arc::new(ByteCounter::default());
+                let options = UploadOptions::new()
+                    .with_progress_updater(Some(counter.clone()))
+                    .with_fail_fast(true);
+                let results = upload_files_journaled(
+                    config,
+                    ThreadPool::from_current_runtime(),
+                    &options,
+                    journal,
+                    file_paths,
+                    2,
+                    false,
+                )
+                .await
+                .map(|(results, _)| results);
+                (results, counter.0.load(Ordering::Relaxed))
+            }
+        };
+
+        // Each upload appends the files it uploaded to the journal.
+        let journaled_paths = || -> Vec<String> {
+            std::fs::read_to_string(&journal_path)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    serde_json::from_str::<serde_json::Value>(line).unwrap()["path"]
+                        .as_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect()
+        };
+
+        // The missing third file fails the second batch, after the first batch is uploaded.
+        let (results, _) = upload().await;
+        assert!(results.is_err());
+        assert_eq!(journaled_paths(), &file_paths[..2]);
+
+        std::fs::write(&paths[2], "contents of file 2").unwrap();
+        std::fs::write(&paths[0], "changed contents of file 0").unwrap();
+        let (results, _) = upload().await;
+        let results: Vec<_> = results.unwrap().into_iter().map(|r| r.unwrap()).collect();
+        for (pf, path) in results.iter().zip(&paths) {
+            assert_eq!(pf.path(), path.to_str().unwrap());
+            assert_eq!(pf.filesize(), std::fs::metadata(path).unwrap().len());
+        }
+
+        // The second file is skipped, the changed first file is uploaded again.
+        let resumed = [0, 2, 3].map(|i| file_paths[i].clone());
+        assert_eq!(journaled_paths()[2..], resumed);
+
+        // With every file recorded in its current version, nothing is uploaded.
+        let (results_again, bytes_uploaded) = upload().await;
+        assert_eq!(bytes_uploaded, 0);
+        assert_eq!(journaled_paths().len(), 5);
+        let results_again: Vec<_> = results_again.unwrap().into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(results_again, results);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_options_fail_fast_by_default() {
+        let temp_dir = tempdir().unwrap();
+        let config = TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap();
+        let present = temp_dir.path().join("present");
+        std::fs::write(&present, "some data").unwrap();
+        let file_paths: Vec<_> = [temp_dir.path().join("missing"), present]
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+
+        let upload = |options: UploadOptions| {
+            let config = config.clone();
+            let file_paths = file_paths.clone();
+            async move { upload_with_outputs(config, ThreadPool::from_current_runtime(), file_paths, &options).await }
+        };
+
+        // The missing file aborts the whole upload, as with `upload_files` from Python.
+        let result = upload(UploadOptions::new()).await;
+        assert!(matches!(result, Err(DataProcessingError::FileUploadError(UploadError::NotFound { .. }))));
+
+        let results = upload(UploadOptions::new().with_fail_fast(false)).await.unwrap();
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_manifest() {
+        let temp_dir = tempdir().unwrap();
+        let config = TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap();
+        let contents = ["first file", "second file", "", "first file"];
+        let mut file_paths: Vec<_> = contents
+            .iter()
+            .enumerate()
+            .map(|(i, data)| {
+                let path = temp_dir.path().join(format!("file_{i}"));
+                std::fs::write(&path, data).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        file_paths.insert(1, temp_dir.path().join("missing").to_string_lossy().to_string());
+        let manifest_path = temp_dir.path().join("out").join("manifest.json");
+
+        let upload = |journal_path: Option<PathBuf>, pointer_output: Option<PointerOutput>| {
+            let config = config.clone();
+            let file_paths = file_paths.clone();
+            let options = UploadOptions::new()
+                .with_fail_fast(false)
+                .with_journal_path(journal_path)
+                .with_manifest_path(Some(manifest_path.clone()))
+                .with_pointer_output(pointer_output);
+            async move { upload_with_outputs(config, ThreadPool::from_current_runtime(), file_paths, &options).await }
+        };
+
+        // Every uploaded file is listed, with the sha256 of its contents, but not the missing one.
+        let journal_path = temp_dir.path().join("upload.journal");
+        let results = upload(Some(journal_path.clone()), None).await.unwrap();
+        assert!(results[1].is_err());
+        let pointers: Vec<_> = results.into_iter().filter_map(|r| r.ok()).collect();
+        let check_manifest = || {
+            let entries: Vec<_> = parse_manifest(&std::fs::read_to_string(&manifest_path).unwrap())
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.unwrap())
+                .collect();
+            assert_eq!(entries.len(), pointers.len());
+            for ((entry, pf), data) in entries.iter().zip(&pointers).zip(contents) {
+                assert_eq!(entry.path, pf.path());
+                assert_eq!(&entry.hash, pf.hash_string());
+                assert_eq!(entry.size, pf.filesize());
+                let sha256 = PointerFile::compute_sha_from_reader(&mut data.as_bytes()).unwrap();
+                assert_eq!(entry.sha256.as_ref(), Some(&sha256));
+            }
+        };
+        check_manifest();
+
+        // Resumed from the journal, the skipped files are still listed, before their files are
+        // replaced by their pointer files.
+        let results = upload(Some(journal_path), Some(PointerOutput::InPlace)).await.unwrap();
+        assert_eq!(results.into_iter().filter_map(|r| r.ok()).collect::<Vec<_>>(), pointers);
+        check_manifest();
+        assert_eq!(PointerFile::init_from_path(&file_paths[0]), pointers[0]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_directory() {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join("root");
+        let contents = [
+            ("a.txt", "file a"),
+            ("sub/b.txt", "file b"),
+            ("sub/deeper/c.txt", "file c"),
+            ("sub/skip.tmp", "ignored by name"),
+            (".git/config", "ignored with its directory"),
+        ];
+        for (path, data) in contents {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, data).unwrap();
+        }
+        std::os::unix::fs::symlink(root.join("sub"), root.join("link")).unwrap();
+
+        let config = TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap();
+        let upload = |follow_symlinks| {
+            let config = config.clone();
+            let root = root.clone();
+            async move {
+                let walk_options = DirectoryWalkOptions {
+                    ignore_globs: vec!["*.tmp".to_string(), ".git".to_string()],
+                    follow_symlinks,
+                };
+                let options = UploadOptions::new().with_fail_fast(true);
+                upload_directory_with_config(config, ThreadPool::from_current_runtime(), root, walk_options, &options)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let results = upload(false).await;
+        assert_eq!(results.keys().collect::<Vec<_>>(), ["a.txt", "sub/b.txt", "sub/deeper/c.txt"]);
+        for (path, data) in &contents[..3] {
+            assert_eq!(results[*path].as_ref().unwrap().filesize(), data.len() as u64);
+        }
+
+        // Following symlinks uploads the linked directory under the path of the link.
+        let results = upload(true).await;
+        assert_eq!(
+            results.keys().collect::<Vec<_>>(),
+            [
+                "a.txt",
+                "link/b.txt",
+                "link/deeper/c.txt",
+                "sub/b.txt",
+                "sub/deeper/c.txt"
+            ]
+        );
+        assert_eq!(
+            results["link/b.txt"].as_ref().unwrap().hash_string(),
+            results["sub/b.txt"].as_ref().unwrap().hash_string()
+        );
+    }
+
+    #[test]
+    #[serial(default_config_env)]
+    fn test_default_config_normalizes_endpoint() {
+        let temp_dir = tempdir().unwrap();
+        env::set_var("HF_XET_CACHE", temp_dir.path().to_str().unwrap());
+
+        let config = default_config("http://localhost:8080/".to_string(), None, None, None).unwrap();
+        let Endpoint::Server(endpoint) = &config.data_config.endpoint else {
+            panic!("expected a server endpoint");
+        };
+        assert_eq!(endpoint, "http://localhost:8080");
+        let same = default_config("http://localhost:8080".to_string(), None, None, None).unwrap();
+        assert_eq!(config.data_config.cache_config.cache_directory, same.data_config.cache_config.cache_directory);
+
+        let err = default_config("localhost:8080".to_string(), None, None, None).unwrap_err();
+        assert!(matches!(
+            err,
+            DataProcessingError::CasClientError(cas_client::CasClientError::ConfigurationError(_))
+        ));
+
+        env::remove_var("HF_XET_CACHE");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_best_effort() {
+        let temp_dir = tempdir().unwrap();
+        let cas_dir = temp_dir.path().join("cas");
+
+        let mut file_paths = Vec::new();
+        for i in 0..3 {
+            let path = temp_dir.path().join(format!("file_{i}"));
+            std::fs::write(&path, format!("contents of file {i}")).unwrap();
+            file_paths.push(path.to_string_lossy().to_string());
+        }
+        file_paths.insert(1, temp_dir.path().join("missing").to_string_lossy().to_string());
+
+        // By default, the missing file fails the whole batch.
+        let session = FileUploadSession::new(
+            TranslatorConfig::local_config(&cas_dir).unwrap(),
+            ThreadPool::from_current_runtime(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(upload_files_in_session(session, file_paths.clone(), true).await.is_err());
+
+        // Otherwise, the other files are still uploaded.
+        let session = FileUploadSession::new(
+            TranslatorConfig::local_config(&cas_dir).unwrap(),
+            ThreadPool::from_current_runtime(),
+            None,
+        )
+        .await
+        .unwrap();
+        let results = upload_files_in_session(session, file_paths.clone(), false).await.unwrap();
+        assert_eq!(results.len(), 4);
+        assert!(results[1].is_err());
+
+        let downloader =
+            FileDownloader::new(TranslatorConfig::local_config(&cas_dir).unwrap(), ThreadPool::from_current_runtime())
+                .await
+                .unwrap();
+        for (i, result) in [&results[0], &results[2], &results[3]].into_iter().enumerate() {
+            let pf = result.as_ref().unwrap();
+            let out_path = temp_dir.path().join(format!("out_{i}"));
+            let output = OutputProvider::File(FileProvider::new(out_path.clone()));
+            downloader.smudge_file_from_pointer(pf, &output, None, None).await.unwrap();
+            assert_eq!(std::fs::read_to_string(out_path).unwrap(), format!("contents of file {i}"));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_prechunked() {
+        use rand::rngs::StdRng;
+        use rand::{RngCore, SeedableRng};
+
+        let temp_dir = tempdir().unwrap();
+        let cas_dir = temp_dir.path().join("cas");
+        let config = TranslatorConfig::local_config(&cas_dir).unwrap();
+
+        let mut data = vec![0u8; 1 << 20];
+        StdRng::seed_from_u64(0).fill_bytes(&mut data);
+        let path = temp_dir.path().join("data.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let session = FileUploadSession::new(config.clone(), ThreadPool::from_current_runtime(), None)
+            .await
+            .unwrap();
+        let (pointer_file, _) = clean_file(session.clone(), &path).await.unwrap();
+        session.finalize().await.unwrap();
+
+        let mut chunker = deduplication::Chunker::from_config(config.data_config.chunker_config);
+        let mut chunks = chunker.next_block(&data, false);
+        chunks.extend(chunker.finish());
+        let chunks: Vec<_> = chunks.into_iter().map(|c| (c.hash, c.data.to_vec())).collect();
+        assert!(chunks.len() > 1);
+
+        let session = FileUploadSession::new(config.clone(), ThreadPool::from_current_runtime(), None)
+            .await
+            .unwrap();
+        let (prechunked_pointer_file, _) =
+            upload_prechunked(session.clone(), "data.bin", chunks.clone()).await.unwrap();
+        assert_eq!(prechunked_pointer_file.hash_string(), pointer_file.hash_string());
+        assert_eq!(prechunked_pointer_file.filesize(), data.len() as u64);
+
+        // A chunk whose hash doesn't match its data is rejected.
+        let mut tampered = chunks;
+        tampered[1].1[0] ^= 1;
+        assert!(matches!(
+            upload_prechunked(session.clone(), "tampered.bin", tampered).await,
+            Err(DataProcessingError::ParameterError(_))
+        ));
+        session.finalize().await.unwrap();
+
+        let out_path = temp_dir.path().join("out.bin");
+        FileDownloader::new(config, ThreadPool::from_current_runtime())
+            .await
+            .unwrap()
+            .smudge_file_from_pointer(
+                &prechunked_pointer_file,
+                &OutputProvider::File(FileProvider::new(out_path.clone())),
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(out_path).unwrap(), data);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_compute_file_hash_matches_upload() {
+        use rand::rngs::StdRng;
+        use rand::{RngCore, SeedableRng};
+
+        let temp_dir = tempdir().unwrap();
+        let config = TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap();
+
+        // Larger than an ingestion block, so it's read in several blocks.
+        let mut data = vec![0u8; *INGESTION_BLOCK_SIZE + (3 << 20)];
+        StdRng::seed_from_u64(0).fill_bytes(&mut data);
+        let path = temp_dir.path().join("data.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let session = FileUploadSession::new(config.clone(), ThreadPool::from_current_runtime(), None)
+            .await
+            .unwrap();
+        let (pointer_file, _) = clean_file(session.clone(), &path).await.unwrap();
+        session.finalize().await.unwrap();
+
+        let hash = compute_file_hash_from_reader(&data[..], &config).unwrap();
+        assert_eq!(&hash.hex(), pointer_file.hash_string());
+        assert_eq!(compute_file_hash(&path).unwrap(), hash);
+
+        // The hash of an empty file is that of an empty upload too.
+        let empty_path = temp_dir.path().join("empty.bin");
+        std::fs::write(&empty_path, b"").unwrap();
+        let session = FileUploadSession::new(config.clone(), ThreadPool::from_current_runtime(), None)
+            .await
+            .unwrap();
+        let (empty_pointer_file, _) = clean_file(session.clone(), &empty_path).await.unwrap();
+        session.finalize().await.unwrap();
+        assert_eq!(&compute_file_hash(&empty_path).unwrap().hex(), empty_pointer_file.hash_string());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_upload_results_in_input_order() {
+        use rand::rngs::StdRng;
+        use rand::{RngCore, SeedableRng};
+
+        let temp_dir = tempdir().unwrap();
+        let config = TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap();
+
+        // Large files come first, so the small ones after them finish uploading earlier.
+        let sizes = [8 << 20, 1, 4 << 20, 100, 2 << 20, 0, 1 << 20, 10_000];
+        let mut rng = StdRng::seed_from_u64(0);
+        let file_paths: Vec<_> = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| {
+                let mut data = vec![0u8; size];
+                rng.fill_bytes(&mut data);
+                let path = temp_dir.path().join(format!("file_{i}"));
+                std::fs::write(&path, &data).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+
+        let session = FileUploadSession::new(config, ThreadPool::from_current_runtime(), None)
+            .await
+            .unwrap();
+        let results = upload_files_in_session(session, file_paths.clone(), true).await.unwrap();
+
+        assert_eq!(results.len(), file_paths.len());
+        for ((result, path), size) in results.iter().zip(&file_paths).zip(sizes) {
+            let pointer_file = result.as_ref().unwrap();
+            assert_eq!(pointer_file.path(), path);
+            assert_eq!(pointer_file.filesize(), size as u64);
+            assert_eq!(&compute_file_hash(path).unwrap().hex(), pointer_file.hash_string());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_errors_identify_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempdir().unwrap();
+        let cas_dir = temp_dir.path().join("cas");
+
+        let readable = temp_dir.path().join("readable");
+        std::fs::write(&readable, "contents").unwrap();
+        let missing = temp_dir.path().join("missing");
+        let directory = temp_dir.path().join("directory");
+        std::fs::create_dir(&directory).unwrap();
+        let locked = temp_dir.path().join("locked");
+        std::fs::write(&locked, "secret").unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+        // Permissions don't stop root from reading the file.
+        let locked_is_unreadable = File::open(&locked).is_err();
+
+        let file_paths: Vec<_> = [&readable, &missing, &directory, &locked]
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect();
+        let session = FileUploadSession::new(
+            TranslatorConfig::local_config(&cas_dir).unwrap(),
+            ThreadPool::from_current_runtime(),
+            None,
+        )
+        .await
+        .unwrap();
+        let results = upload_files_in_session(session, file_paths.clone(), false).await.unwrap();
+
+        assert!(results[0].is_ok());
+        let upload_error = |i: usize| match &results[i] {
+            Err(DataProcessingError::FileUploadError(e)) => {
+                assert_eq!(e.path(), file_paths[i]);
+                assert!(e.to_string().starts_with(&file_paths[i]));
+                e
+            },
+            other => panic!("expected an upload error for {}, got {other:?}", file_paths[i]),
+        };
+        assert!(matches!(upload_error(1), UploadError::NotFound { .. }));
+        assert!(matches!(upload_error(2), UploadError::Io { .. }));
+        if locked_is_unreadable {
+            assert!(matches!(upload_error(3), UploadError::PermissionDenied { .. }));
+        } else {
+            assert!(results[3].is_ok());
+        }
+
+        // Failing fast reports the same error.
+        let session = FileUploadSession::new(
+            TranslatorConfig::local_config(&cas_dir).unwrap(),
+            ThreadPool::from_current_runtime(),
+            None,
+        )
+        .await
+        .unwrap();
+        let result = upload_files_in_session(session, file_paths[..2].to_vec(), true).await;
+        assert!(
+            matches!(result, Err(DataProcessingError::FileUploadError(UploadError::NotFound { ref path, .. })) if *path == file_paths[1])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_write_pointer_files() {
+        let temp_dir = tempdir().unwrap();
+        let cas_dir = temp_dir.path().join("cas");
+
+        // Two files with the same name in different directories collide in an output directory.
+        let mut file_paths = Vec::new();
+        for (i, rel_path) in ["a/data.bin", "other.bin", "b/data.bin"].into_iter().enumerate() {
+            let path = temp_dir.path().join(rel_path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, format!("contents of file {i}")).unwrap();
+            file_paths.push(path.to_string_lossy().to_string());
+        }
+
+        let session = FileUploadSession::new(
+            TranslatorConfig::local_config(&cas_dir).unwrap(),
+            ThreadPool::from_current_runtime(),
+            None,
+        )
+        .await
+        .unwrap();
+        let uploaded = upload_files_in_session(session, file_paths.clone(), true).await.unwrap();
+
+        let assert_pointer_written = |path: &Path, pf: &PointerFile| {
+            let written = PointerFile::init_from_path(path);
+            assert!(written.is_valid());
+            assert_eq!(written.hash_string(), pf.hash_string());
+            assert_eq!(written.filesize(), pf.filesize());
+        };
+
+        let out_dir = temp_dir.path().join("pointers");
+        let mut results: Vec<_> = uploaded.iter().map(|r| Ok(r.as_ref().unwrap().clone())).collect();
+        write_pointer_files(&mut results, &PointerOutput::Directory(out_dir.clone()), false).unwrap();
+        assert_pointer_written(&out_dir.join("data.bin"), results[0].as_ref().unwrap());
+        assert_pointer_written(&out_dir.join("other.bin"), results[1].as_ref().unwrap());
+        assert!(matches!(results[2], Err(DataProcessingError::PointerFileError(_))));
+
+        // Existing files in the output directory are not overwritten.
+        let mut results: Vec<_> = uploaded.iter().map(|r| Ok(r.as_ref().unwrap().clone())).collect();
+        assert!(matches!(
+            write_pointer_files(&mut results, &PointerOutput::Directory(out_dir), true),
+            Err(DataProcessingError::PointerFileError(_))
+        ));
+
+        // In place, the uploaded files are replaced, except for the read-only one.
+        let read_only_path = Path::new(&file_paths[1]);
+        let mut permissions = std::fs::metadata(read_only_path).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(read_only_path, permissions).unwrap();
+
+        let mut results: Vec<_> = uploaded.iter().map(|r| Ok(r.as_ref().unwrap().clone())).collect();
+        write_pointer_files(&mut results, &PointerOutput::InPlace, false).unwrap();
+        for i in [0, 2] {
+            assert_pointer_written(Path::new(&file_paths[i]), results[i].as_ref().unwrap());
+        }
+        assert!(matches!(results[1], Err(DataProcessingError::PointerFileError(_))));
+        assert_eq!(std::fs::read_to_string(read_only_path).unwrap(), "contents of file 1");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_download_from_pointer_text() {
+        let temp_dir = tempdir().unwrap();
+        let cas_dir = temp_dir.path().join("cas");
+
+        let mut file_paths = Vec::new();
+        for i in 0..2 {
+            let path = temp_dir.path().join(format!("file_{i}"));
+            std::fs::write(&path, format!("contents of file {i}")).unwrap();
+            file_paths.push(path.to_string_lossy().to_string());
+        }
+        let session = FileUploadSession::new(
+            TranslatorConfig::local_config(&cas_dir).unwrap(),
+            ThreadPool::from_current_runtime(),
+            None,
+        )
+        .await
+        .unwrap();
+        let uploaded = upload_files_in_session(session, file_paths, true).await.unwrap();
+
+        let bad_hash = PointerFile::init_from_info("", "not a hash", 3);
+        let missing = PointerFile::init_from_info("", &"ab".repeat(32), 3);
+        let texts = [
+            uploaded[0].as_ref().unwrap().to_string(),
+            "garbage".to_owned(),
+            uploaded[1].as_ref().unwrap().to_string(),
+            bad_hash.to_string(),
+            missing.to_string(),
+        ];
+        let out_paths: Vec<_> = (0..texts.len()).map(|i| temp_dir.path().join(format!("out/{i}"))).collect();
+        let pointer_texts = texts
+            .into_iter()
+            .zip(&out_paths)
+            .map(|(text, path)| (text, path.to_string_lossy().to_string()))
+            .collect();
+
+        let downloader =
+            FileDownloader::new(TranslatorConfig::local_config(&cas_dir).unwrap(), ThreadPool::from_current_runtime())
+                .await
+                .unwrap();
+        let results = download_pointer_texts(Arc::new(downloader), pointer_texts).await.unwrap();
+
+        assert_eq!(results.len(), 5);
+        for (i, file_i) in [(0, 0), (2, 1)] {
+            assert_eq!(results[i].as_ref().unwrap(), &out_paths[i].to_string_lossy());
+            assert_eq!(std::fs::read_to_string(&out_paths[i]).unwrap(), format!("contents of file {file_i}"));
+        }
+        for i in [1, 3] {
+            assert!(matches!(results[i], Err(DataProcessingError::PointerFileError(_))));
+            assert!(!out_paths[i].exists());
+        }
+        // A file that fails to download only fails its own entry.
+        assert!(results[4].is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_download_size_limit() {
+        let temp_dir = tempdir().unwrap();
+        let cas_dir = temp_dir.path().join("cas");
+
+        let file_paths: Vec<_> = (0..2)
+            .map(|i| {
+                let path = temp_dir.path().join(format!("file_{i}"));
+                std::fs::write(&path, vec![i as u8; 64 * 1024]).unwrap();
+                path.to_string_lossy().to_string()
+            })
+            .collect();
+        let session = FileUploadSession::new(
+            TranslatorConfig::local_config(&cas_dir).unwrap(),
+            ThreadPool::from_current_runtime(),
+            None,
+        )
+        .await
+        .unwrap();
+        let uploaded: Vec<_> = upload_files_in_session(session, file_paths, true)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        let downloader = Arc::new(
+            FileDownloader::new(TranslatorConfig::local_config(&cas_dir).unwrap(), ThreadPool::from_current_runtime())
+                .await
+                .unwrap(),
+        );
+
+        // Pointer files to the uploaded files at new paths, with the given sizes; the progress
+        // updater counts the bytes written.
+        let written = Arc::new(ByteCounter::default());
+        let files = |name: &str, sizes: [u64; 2]| -> Vec<(PointerFile, Option<Arc<dyn ProgressUpdater>>)> {
+            uploaded
+                .iter()
+                .zip(sizes)
+                .enumerate()
+                .map(|(i, (pf, size))| {
+                    let path = temp_dir.path().join(format!("{name}_{i}"));
+                    let pf = PointerFile::init_from_info(path.to_str().unwrap(), pf.hash_string(), size);
+                    (pf, Some(written.clone() as Arc<dyn ProgressUpdater>))
+                })
+                .collect()
+        };
+
+        // The planned total is over the limit, so nothing is downloaded.
+        let result = download_pointer_files_within(downloader.clone(), files("over", [64 * 1024; 2]), 100 * 1024).await;
+        assert!(matches!(result, Err(DataProcessingError::DownloadLimitExceeded(_))));
+        assert_eq!(written.0.load(Ordering::Relaxed), 0);
+        assert!((0..2).all(|i| !temp_dir.path().join(format!("over_{i}")).exists()));
+
+        // Pointer files understating the sizes don't get past the check, which goes by the terms.
+        let result = download_pointer_files_within(downloader.clone(), files("understated", [1; 2]), 100 * 1024).await;
+        assert!(matches!(result, Err(DataProcessingError::DownloadLimitExceeded(_))));
+        assert_eq!(written.0.load(Ordering::Relaxed), 0);
+
+        // Past the check, the download is still aborted once the bytes written pass the limit.
+        let result = download_pointer_files_budgeted(downloader.clone(), files("aborted", [1; 2]), 100 * 1024).await;
+        assert!(matches!(result, Err(DataProcessingError::DownloadLimitExceeded(_))));
+        assert!(written.0.load(Ordering::Relaxed) <= 2 * 64 * 1024);
+
+        written.0.store(0, Ordering::Relaxed);
+        let paths = download_pointer_files_within(downloader, files("within", [64 * 1024; 2]), 128 * 1024)
+            .await
+            .unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(written.0.load(Ordering::Relaxed), 128 * 1024);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_download_manifest() {
+        let temp_dir = tempdir().unwrap();
+        let cas_dir = temp_dir.path().join("cas");
+
+        let mut file_paths = Vec::new();
+        for i in 0..2 {
+            let path = temp_dir.path().join(format!("file_{i}"));
+            std::fs::write(&path, format!("contents of file {i}")).unwrap();
+            file_paths.push(path.to_string_lossy().to_string());
+        }
+        let session = FileUploadSession::new(
+            TranslatorConfig::local_config(&cas_dir).unwrap(),
+            ThreadPool::from_current_runtime(),
+            None,
+        )
+        .await
+        .unwrap();
+        let uploaded: Vec<_> = upload_files_in_session(session, file_paths, true)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect();
+
+        let entry = |path: &str, i: usize| serde_json::json!({"path": path, "hash": uploaded[i].hash_string(), "size": uploaded[i].filesize()});
+        let manifest = serde_json::json!({"files": [
+            entry("a/b/first.txt", 0),
+            entry("../escaped.txt", 1),
+            entry("./second.txt", 1),
+            {"path": "no_hash.txt", "size": 3},
+            entry("a/b/first.txt", 1),
+            entry("/absolute.txt", 0),
+        ]});
+
+        let dest_dir = temp_dir.path().join("dest");
+        let downloader = Arc::new(
+            FileDownloader::new(TranslatorConfig::local_config(&cas_dir).unwrap(), ThreadPool::from_current_runtime())
+                .await
+                .unwrap(),
+        );
+        let results = download_manifest_entries(downloader.clone(), &manifest.to_string(), &dest_dir)
+            .await
+            .unwrap();
+
+        assert_eq!(results.len(), 6);
+        for (i, rel_path, file_i) in [(0, "a/b/first.txt", 0), (2, "second.txt", 1)] {
+            let path = dest_dir.join(rel_path);
+            assert_eq!(results[i].as_ref().unwrap(), &path.to_string_lossy());
+            assert_eq!(std::fs::read_to_string(path).unwrap(), format!("contents of file {file_i}"));
+        }
+        for i in [1, 3, 4, 5] {
+            assert!(matches!(results[i], Err(DataProcessingError::ParameterError(_))));
+        }
+        assert!(!temp_dir.path().join("escaped.txt").exists());
+        assert_eq!(std::fs::read_to_string(dest_dir.join("a/b/first.txt")).unwrap(), "contents of file 0");
+
+        // A manifest that isn't an object with a files array fails as a whole.
+        assert!(download_manifest_entries(downloader, "[]", &dest_dir).await.is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_download_to_cas_cache() {
+        use std::os::unix::fs::MetadataExt;
+
+        use cas_client::CasCacheWriteProvider;
+
+        let temp_dir = tempdir().unwrap();
+        let cas_dir = temp_dir.path().join("cas");
+        let cache_dir = temp_dir.path().join("cache");
+
+        let path = temp_dir.path().join("data.bin");
+        std::fs::write(&path, "contents of the cached file").unwrap();
+        let session = FileUploadSession::new(
+            TranslatorConfig::local_config(&cas_dir).unwrap(),
+            ThreadPool::from_current_runtime(),
+            None,
+        )
+        .await
+        .unwrap();
+        let results = upload_files_in_session(session, vec![path.to_string_lossy().to_string()], true)
+            .await
+            .unwrap();
+        let hash = results[0].as_ref().unwrap().hash().unwrap();
+
+        let downloader =
+            FileDownloader::new(TranslatorConfig::local_config(&cas_dir).unwrap(), ThreadPool::from_current_runtime())
+                .await
+                .unwrap();
+        let out_paths = [temp_dir.path().join("a/out.bin"), temp_dir.path().join("b/out.bin")];
+        let mut total_bytes = vec![];
+        for out_path in &out_paths {
+            let output = OutputProvider::CasCache(CasCacheWriteProvider::new(&cache_dir, &hash, out_path.clone()));
+            let stats = downloader
+                .smudge_file_from_hash_with_stats(&hash, &output, None, None)
+                .await
+                .unwrap();
+            total_bytes.push(stats.total_bytes);
+            assert_eq!(std::fs::read_to_string(out_path).unwrap(), "contents of the cached file");
+        }
+        // The second download is served from the cache without fetching.
+        assert_eq!(total_bytes, vec![27, 0]);
+
+        // Both destinations are links to the single copy in the cache.
+        let hex = hash.hex();
+        let cache_path = cache_dir.join(&hex[..2]).join(&hex[2..]);
+        let cache_meta = std::fs::metadata(&cache_path).unwrap();
+        assert_eq!(cache_meta.nlink(), 3);
+        for out_path in &out_paths {
+            assert_eq!(std::fs::metadata(out_path).unwrap().ino(), cache_meta.ino());
+        }
+        assert_eq!(std::fs::read_dir(cache_path.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_set_prefix_validation() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = Arc::try_unwrap(TranslatorConfig::local_config(temp_dir.path()).unwrap()).unwrap();
+
+        for prefix in ["", "a/b", "../x", "a.b", "tenant a", &"x".repeat(65)] {
+            assert!(matches!(config.set_prefix(prefix), Err(DataProcessingError::ParameterError(_))));
+        }
+        assert_eq!(config.data_config.prefix, PREFIX_DEFAULT);
+
+        config.set_prefix("Tenant-1_a").unwrap();
+        assert_eq!(config.data_config.prefix, "Tenant-1_a");
+        assert_eq!(config.shard_config.prefix, "Tenant-1_a");
+    }
+
+    /// Records the prefix of every call that takes one, and the `force_sync` flag of every shard
+    /// upload, on top of an in-memory client.
+    struct PrefixRecordingClient {
+        inner: cas_client::MemoryLocalClient,
+        prefixes: std::sync::Mutex<Vec<(&'static str, String)>>,
+        shard_syncs: std::sync::Mutex<Vec<bool>>,
+    }
+
+    impl PrefixRecordingClient {
+        fn record(&self, call: &'static str, prefix: &str) {
+            self.prefixes.lock().unwrap().push((call, prefix.to_owned()));
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::UploadClient for PrefixRecordingClient {
+        async fn put(
+            &self,
+            prefix: &str,
+            hash: &MerkleHash,
+            data: Vec<u8>,
+            chunk_and_boundaries: Vec<(MerkleHash, u32)>,
+        ) -> std::result::Result<usize, cas_client::CasClientError> {
+            self.record("put", prefix);
+            self.inner.put(prefix, hash, data, chunk_and_boundaries).await
+        }
+
+        async fn exists(
+            &self,
+            prefix: &str,
+            hash: &MerkleHash,
+        ) -> std::result::Result<bool, cas_client::CasClientError> {
+            self.record("exists", prefix);
+            self.inner.exists(prefix, hash).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::ReconstructionClient for PrefixRecordingClient {
+        async fn get_file(
+            &self,
+            hash: &MerkleHash,
+            byte_range: Option<FileRange>,
+            output_provider: &OutputProvider,
+            progress_updater: Option<Arc<dyn ProgressUpdater>>,
+        ) -> std::result::Result<u64, cas_client::CasClientError> {
+            self.inner.get_file(hash, byte_range, output_provider, progress_updater).await
+        }
+
+        async fn get_file_size(&self, hash: &MerkleHash) -> std::result::Result<u64, cas_client::CasClientError> {
+            self.inner.get_file_size(hash).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::RegistrationClient for PrefixRecordingClient {
+        async fn upload_shard(
+            &self,
+            prefix: &str,
+            hash: &MerkleHash,
+            force_sync: bool,
+            shard_data: &[u8],
+            salt: &[u8; 32],
+        ) -> std::result::Result<bool, cas_client::CasClientError> {
+            self.record("upload_shard", prefix);
+            self.shard_syncs.lock().unwrap().push(force_sync);
+            self.inner.upload_shard(prefix, hash, force_sync, shard_data, salt).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::ShardDedupProber for PrefixRecordingClient {
+        async fn query_for_global_dedup_shard(
+            &self,
+            prefix: &str,
+            chunk_hash: &MerkleHash,
+            salt: &[u8; 32],
+        ) -> std::result::Result<Option<PathBuf>, cas_client::CasClientError> {
+            self.record("query_for_global_dedup_shard", prefix);
+            self.inner.query_for_global_dedup_shard(prefix, chunk_hash, salt).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl mdb_shard::shard_file_reconstructor::FileReconstructor<cas_client::CasClientError> for PrefixRecordingClient {
+        async fn get_file_reconstruction_info(
+            &self,
+            file_hash: &MerkleHash,
+        ) -> std::result::Result<
+            Option<(mdb_shard::file_structs::MDBFileInfo, Option<MerkleHash>)>,
+            cas_client::CasClientError,
+        > {
+            self.inner.get_file_reconstruction_info(file_hash).await
+        }
+    }
+
+    impl cas_client::ShardClientInterface for PrefixRecordingClient {}
+    impl cas_client::Client for PrefixRecordingClient {}
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_with_prefix() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("data.bin");
+        std::fs::write(&path, "contents uploaded by each tenant").unwrap();
+
+        let inner = cas_client::MemoryLocalClient::new();
+
+        for prefix in ["tenant_a", "tenant-b"] {
+            let mut config =
+                Arc::try_unwrap(TranslatorConfig::local_config(temp_dir.path().join(prefix)).unwrap()).unwrap();
+            config.set_prefix(prefix).unwrap();
+            config.shard_config.global_dedup_policy = GlobalDedupPolicy::Never;
+            let client = Arc::new(PrefixRecordingClient {
+                inner: inner.clone(),
+                prefixes: Default::default(),
+                shard_syncs: Default::default(),
+            });
+            let session = FileUploadSession::new_with_client(
+                Arc::new(config),
+                ThreadPool::from_current_runtime(),
+                client.clone(),
+            )
+            .await
+            .unwrap();
+            upload_files_in_session(session, vec![path.to_string_lossy().to_string()], true)
+                .await
+                .unwrap();
+
+            let prefixes = client.prefixes.lock().unwrap();
+            assert!(prefixes.iter().any(|(call, _)| *call == "put"));
+            assert!(prefixes.iter().any(|(call, _)| *call == "upload_shard"));
+            assert!(prefixes.iter().all(|(_, p)| p == prefix), "{prefixes:?}");
+        }
+
+        // The same xorb is stored separately under each prefix.
+        let entries = inner.get_all_entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries
+                .iter()
+                .map(|key| key.prefix.as_str())
+                .collect::<std::collections::HashSet<_>>(),
+            ["tenant_a", "tenant-b"].into()
+        );
+        assert_eq!(entries[0].hash, entries[1].hash);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_force_shard_sync() {
+        let temp_dir = tempdir().unwrap();
+
+        for force_sync in [false, true] {
+            let path = temp_dir.path().join(format!("data-{force_sync}.bin"));
+            std::fs::write(&path, format!("contents uploaded with force_sync = {force_sync}")).unwrap();
+
+            let mut config = Arc::try_unwrap(
+                TranslatorConfig::local_config(temp_dir.path().join(format!("cas-{force_sync}"))).unwrap(),
+            )
+            .unwrap();
+            config.shard_config.force_sync = force_sync;
+            let client = Arc::new(PrefixRecordingClient {
+                inner: cas_client::MemoryLocalClient::new(),
+                prefixes: Default::default(),
+                shard_syncs: Default::default(),
+            });
+            let session = FileUploadSession::new_with_client(
+                Arc::new(config),
+                ThreadPool::from_current_runtime(),
+                client.clone(),
+            )
+            .await
+            .unwrap();
+            upload_files_in_session(session, vec![path.to_string_lossy().to_string()], true)
+                .await
+                .unwrap();
+
+            assert_eq!(*client.shard_syncs.lock().unwrap(), vec![force_sync]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_extra_hashes() {
+        use rand::rngs::StdRng;
+        use rand::{RngCore, SeedableRng};
+
+        let temp_dir = tempdir().unwrap();
+        // Larger than an ingestion block, so that the file is hashed in several parts.
+        let mut data = vec![0u8; *INGESTION_BLOCK_SIZE * 2 + 1000];
+        StdRng::seed_from_u64(0).fill_bytes(&mut data);
+        let path = temp_dir.path().join("data.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        for compute_blake3 in [false, true] {
+            let mut config = Arc::try_unwrap(
+                TranslatorConfig::local_config(temp_dir.path().join(format!("cas-{compute_blake3}"))).unwrap(),
+            )
+            .unwrap();
+            config.data_config.extra_hashes =
+                ExtraHashes::from_names(if compute_blake3 { &["BLAKE3"][..] } else { &[] }).unwrap();
+            let session = FileUploadSession::new(Arc::new(config), ThreadPool::from_current_runtime(), None)
+                .await
+                .unwrap();
+            let results = upload_files_in_session(session, vec![path.to_string_lossy().to_string()], true)
+                .await
+                .unwrap();
+
+            let pointer_file = results[0].as_ref().unwrap();
+            let expected = compute_blake3.then(|| blake3::hash(&data).to_hex().to_string());
+            assert_eq!(pointer_file.blake3(), expected.as_deref());
+        }
+
+        assert!(matches!(ExtraHashes::from_names(&["md5"]), Err(DataProcessingError::ParameterError(_))));
+    }
+
+    type BufferedPut = (String, MerkleHash, Vec<u8>, Vec<(MerkleHash, u32)>);
+
+    /// Buffers puts in memory until flushed into an in-memory client, recording how many puts were
+    /// still buffered at each shard upload.
+    struct BufferingClient {
+        inner: cas_client::MemoryLocalClient,
+        buffered: std::sync::Mutex<Vec<BufferedPut>>,
+        buffered_at_shard_upload: std::sync::Mutex<Vec<usize>>,
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::UploadClient for BufferingClient {
+        async fn put(
+            &self,
+            prefix: &str,
+            hash: &MerkleHash,
+            data: Vec<u8>,
+            chunk_and_boundaries: Vec<(MerkleHash, u32)>,
+        ) -> std::result::Result<usize, cas_client::CasClientError> {
+            let len = data.len();
+            self.buffered
+                .lock()
+                .unwrap()
+                .push((prefix.to_owned(), *hash, data, chunk_and_boundaries));
+            Ok(len)
+        }
+
+        async fn exists(
+            &self,
+            prefix: &str,
+            hash: &MerkleHash,
+        ) -> std::result::Result<bool, cas_client::CasClientError> {
+            self.inner.exists(prefix, hash).await
+        }
+
+        async fn flush(&self) -> std::result::Result<(), cas_client::CasClientError> {
+            let buffered = std::mem::take(&mut *self.buffered.lock().unwrap());
+            for (prefix, hash, data, chunk_and_boundaries) in buffered {
+                self.inner.put(&prefix, &hash, data, chunk_and_boundaries).await?;
+            }
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::ReconstructionClient for BufferingClient {
+        async fn get_file(
+            &self,
+            hash: &MerkleHash,
+            byte_range: Option<FileRange>,
+            output_provider: &OutputProvider,
+            progress_updater: Option<Arc<dyn ProgressUpdater>>,
+        ) -> std::result::Result<u64, cas_client::CasClientError> {
+            self.inner.get_file(hash, byte_range, output_provider, progress_updater).await
+        }
+
+        async fn get_file_size(&self, hash: &MerkleHash) -> std::result::Result<u64, cas_client::CasClientError> {
+            self.inner.get_file_size(hash).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::RegistrationClient for BufferingClient {
+        async fn upload_shard(
+            &self,
+            prefix: &str,
+            hash: &MerkleHash,
+            force_sync: bool,
+            shard_data: &[u8],
+            salt: &[u8; 32],
+        ) -> std::result::Result<bool, cas_client::CasClientError> {
+            let buffered = self.buffered.lock().unwrap().len();
+            self.buffered_at_shard_upload.lock().unwrap().push(buffered);
+            self.inner.upload_shard(prefix, hash, force_sync, shard_data, salt).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::ShardDedupProber for BufferingClient {
+        async fn query_for_global_dedup_shard(
+            &self,
+            prefix: &str,
+            chunk_hash: &MerkleHash,
+            salt: &[u8; 32],
+        ) -> std::result::Result<Option<PathBuf>, cas_client::CasClientError> {
+            self.inner.query_for_global_dedup_shard(prefix, chunk_hash, salt).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl mdb_shard::shard_file_reconstructor::FileReconstructor<cas_client::CasClientError> for BufferingClient {
+        async fn get_file_reconstruction_info(
+            &self,
+            file_hash: &MerkleHash,
+        ) -> std::result::Result<
+            Option<(mdb_shard::file_structs::MDBFileInfo, Option<MerkleHash>)>,
+            cas_client::CasClientError,
+        > {
+            self.inner.get_file_reconstruction_info(file_hash).await
+        }
+    }
+
+    impl cas_client::ShardClientInterface for BufferingClient {}
+    impl cas_client::Client for BufferingClient {}
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_upload_flushes_buffered_puts() {
+        use cas_client::{ReconstructionClient, UploadClient};
+        use merklehash::compute_data_hash;
+
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().join("data.bin");
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let mut config = Arc::try_unwrap(TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap()).unwrap();
+        config.shard_config.global_dedup_policy = GlobalDedupPolicy::Never;
+        let config = Arc::new(config);
+        let inner = cas_client::MemoryLocalClient::new();
+        let client = Arc::new(BufferingClient {
+            inner: inner.clone(),
+            buffered: Default::default(),
+            buffered_at_shard_upload: Default::default(),
+        });
+
+        // A put isn't durable until flushed.
+        let xorb_data = b"buffered xorb".to_vec();
+        let xorb_hash = compute_data_hash(&xorb_data);
+        let boundaries = vec![(xorb_hash, xorb_data.len() as u32)];
+        client.put("default", &xorb_hash, xorb_data, boundaries).await.unwrap();
+        assert!(!inner.exists("default", &xorb_hash).await.unwrap());
+        client.flush().await.unwrap();
+        assert!(inner.exists("default", &xorb_hash).await.unwrap());
+
+        // Finalizing an upload flushes the xorbs before the shard referencing them is uploaded.
+        let session =
+            FileUploadSession::new_with_client(config.clone(), ThreadPool::from_current_runtime(), client.clone())
+                .await
+                .unwrap();
+        let results = upload_files_in_session(session, vec![path.to_string_lossy().to_string()], true)
+            .await
+            .unwrap();
+        let pf = results[0].as_ref().unwrap();
+        assert!(client.buffered.lock().unwrap().is_empty());
+        assert_eq!(*client.buffered_at_shard_upload.lock().unwrap(), vec![0]);
+
+        let out_path = temp_dir.path().join("out.bin");
+        let output = OutputProvider::File(FileProvider::new(out_path.clone()));
+        inner.get_file(&pf.hash().unwrap(), None, &output, None).await.unwrap();
+        assert_eq!(std::fs::read(&out_path).unwrap(), data);
+    }
+
+    /// Downloads through an in-memory client, except that the download of one file creates its
+    /// output and then never completes.
+    struct StallingClient {
+        inner: cas_client::MemoryLocalClient,
+        stalled_file: MerkleHash,
+        stalled: Arc<tokio::sync::Notify>,
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::UploadClient for StallingClient {
+        async fn put(
+            &self,
+            prefix: &str,
+            hash: &MerkleHash,
+            data: Vec<u8>,
+            chunk_and_boundaries: Vec<(MerkleHash, u32)>,
+        ) -> std::result::Result<usize, cas_client::CasClientError> {
+            self.inner.put(prefix, hash, data, chunk_and_boundaries).await
+        }
+
+        async fn exists(
+            &self,
+            prefix: &str,
+            hash: &MerkleHash,
+        ) -> std::result::Result<bool, cas_client::CasClientError> {
+            self.inner.exists(prefix, hash).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::ReconstructionClient for StallingClient {
+        async fn get_file(
+            &self,
+            hash: &MerkleHash,
+            byte_range: Option<FileRange>,
+            output_provider: &OutputProvider,
+            progress_updater: Option<Arc<dyn ProgressUpdater>>,
+        ) -> std::result::Result<u64, cas_client::CasClientError> {
+            if *hash == self.stalled_file {
+                output_provider.write_empty()?;
+                self.stalled.notify_one();
+                std::future::pending::<()>().await;
+            }
+            self.inner.get_file(hash, byte_range, output_provider, progress_updater).await
+        }
+
+        async fn get_file_size(&self, hash: &MerkleHash) -> std::result::Result<u64, cas_client::CasClientError> {
+            self.inner.get_file_size(hash).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::RegistrationClient for StallingClient {
+        async fn upload_shard(
+            &self,
+            prefix: &str,
+            hash: &MerkleHash,
+            force_sync: bool,
+            shard_data: &[u8],
+            salt: &[u8; 32],
+        ) -> std::result::Result<bool, cas_client::CasClientError> {
+            self.inner.upload_shard(prefix, hash, force_sync, shard_data, salt).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl cas_client::ShardDedupProber for StallingClient {
+        async fn query_for_global_dedup_shard(
+            &self,
+            prefix: &str,
+            chunk_hash: &MerkleHash,
+            salt: &[u8; 32],
+        ) -> std::result::Result<Option<PathBuf>, cas_client::CasClientError> {
+            self.inner.query_for_global_dedup_shard(prefix, chunk_hash, salt).await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl mdb_shard::shard_file_reconstructor::FileReconstructor<cas_client::CasClientError> for StallingClient {
+        async fn get_file_reconstruction_info(
+            &self,
+            file_hash: &MerkleHash,
+        ) -> std::result::Result<
+            Option<(mdb_shard::file_structs::MDBFileInfo, Option<MerkleHash>)>,
+            cas_client::CasClientError,
+        > {
+            self.inner.get_file_reconstruction_info(file_hash).await
+        }
+    }
+
+    impl cas_client::ShardClientInterface for StallingClient {}
+    impl cas_client::Client for StallingClient {}
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_cancel_one_download() {
+        let temp_dir = tempdir().unwrap();
+        let mut config = Arc::try_unwrap(TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap()).unwrap();
+        config.shard_config.global_dedup_policy = GlobalDedupPolicy::Never;
+        let config = Arc::new(config);
+        let inner = cas_client::MemoryLocalClient::new();
+
+        let paths: Vec<_> = (0..3).map(|i| temp_dir.path().join(format!("file_{i}.bin"))).collect();
+        for (i, path) in paths.iter().enumerate() {
+            std::fs::write(path, format!("contents of file {i}")).unwrap();
+        }
+        let session = FileUploadSession::new_with_client(
+            config.clone(),
+            ThreadPool::from_current_runtime(),
+            Arc::new(inner.clone()),
+        )
+        .await
+        .unwrap();
+        let pointer_files: Vec<_> =
+            upload_files_in_session(session, paths.iter().map(|p| p.to_string_lossy().to_string()).collect(), true)
+                .await
+                .unwrap()
+                .into_iter()
+                .zip(&paths)
+                .map(|(pf, path)| {
+                    let pf = pf.unwrap();
+                    let out_path = path.with_extension("out");
+                    PointerFile::init_from_info(
+                        out_path.to_str().unwrap(),
+                        &pf.hash_string().to_string(),
+                        pf.filesize(),
+                    )
+                })
+                .collect();
+
+        let stalled = Arc::new(tokio::sync::Notify::new());
+        let client = StallingClient {
+            inner,
+            stalled_file: pointer_files[1].hash().unwrap(),
+            stalled: stalled.clone(),
+        };
+        let downloader = Arc::new(FileDownloader::new_with_client(config, Arc::new(client)));
+
+        // The second file's destination already exists, and it's canceled once its download has
+        // created its output.
+        std::fs::write(pointer_files[1].path(), "existing file").unwrap();
+        let handles: Vec<_> = (0..3).map(|_| DownloadCancelHandle::new()).collect();
+        let canceled = handles[1].clone();
+        tokio::spawn(async move {
+            stalled.notified().await;
+            canceled.cancel();
+        });
+
+        let files = pointer_files
+            .iter()
+            .cloned()
+            .zip(handles)
+            .map(|(pf, cancel)| (pf, None, cancel))
+            .collect();
+        let results = download_pointer_files_cancelable(downloader, files).await.unwrap();
+
+        assert!(matches!(results[1], Err(DataProcessingError::DownloadCanceled)));
+        assert_eq!(std::fs::read_to_string(pointer_files[1].path()).unwrap(), "existing file");
+        // No temporary file is left behind: the dir holds the cas dir, the 3 inputs and 3 outputs.
+        assert_eq!(std::fs::read_dir(temp_dir.path()).unwrap().count(), 7);
+        for i in [0, 2] {
+            assert_eq!(results[i].as_ref().unwrap(), pointer_files[i].path());
+            assert_eq!(std::fs::read_to_string(pointer_files[i].path()).unwrap(), format!("contents of file {i}"));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_empty_file_round_trip() {
+        let temp_dir = tempdir().unwrap();
+        let cas_dir = temp_dir.path().join("cas");
+        let path = temp_dir.path().join("empty.bin");
+        std::fs::write(&path, "").unwrap();
+
+        let session = FileUploadSession::new(
+            TranslatorConfig::local_config(&cas_dir).unwrap(),
+            ThreadPool::from_current_runtime(),
+            None,
+        )
+        .await
+        .unwrap();
+        let results = upload_files_in_session(session, vec![path.to_string_lossy().to_string()], true)
+            .await
+            .unwrap();
+        let pf = results[0].as_ref().unwrap();
+        assert!(pf.is_valid());
+        assert_eq!(pf.filesize(), 0);
+        assert_eq!(pf.hash().unwrap(), MerkleHash::default());
+        // No xorb is produced for an empty file.
+        let xorb_dir = cas_dir.join("xet").join("xorbs").join("xorbs");
+        assert_eq!(std::fs::read_dir(&xorb_dir).unwrap().count(), 0);
+
+        // Downloading it doesn't contact the CAS, which here is unreachable, and replaces any
+        // existing contents of the output.
+        let mut config = Arc::try_unwrap(TranslatorConfig::local_config(&cas_dir).unwrap()).unwrap();
+        config.data_config.endpoint = Endpoint::Server("http://127.0.0.1:1".to_owned());
+        let downloader = FileDownloader::new(Arc::new(config), ThreadPool::from_current_runtime())
+            .await
+            .unwrap();
+        let out_path = temp_dir.path().join("out.bin");
+        std::fs::write(&out_path, "stale contents").unwrap();
+        let output = OutputProvider::File(FileProvider::new(out_path.clone()));
+        assert_eq!(downloader.smudge_file_from_pointer(pf, &output, None, None).await.unwrap(), 0);
+        assert_eq!(std::fs::read(&out_path).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_round_trip_with_min_stack_size() {
+        use rand::rngs::StdRng;
+        use rand::{RngCore, SeedableRng};
+        use xet_threadpool::{ThreadPoolConfig, THREADPOOL_MIN_STACK_SIZE};
+
+        let temp_dir = tempdir().unwrap();
+        let cas_dir = temp_dir.path().join("cas");
+        let path = temp_dir.path().join("data.bin");
+        let mut data = vec![0u8; 16 << 20];
+        StdRng::seed_from_u64(0).fill_bytes(&mut data);
+        std::fs::write(&path, &data).unwrap();
+
+        let threadpool = Arc::new(
+            ThreadPool::new_with_config(ThreadPoolConfig::default().with_stack_size(THREADPOOL_MIN_STACK_SIZE))
+                .unwrap(),
+        );
+        // The upload and download run as tasks on the worker threads of the pool, not on the test thread.
+        let pool = threadpool.clone();
+        let out_path = temp_dir.path().join("out.bin");
+        let round_trip = threadpool.spawn({
+            let path = path.to_string_lossy().to_string();
+            let out_path = out_path.clone();
+            async move {
+                let config = TranslatorConfig::local_config(&cas_dir).unwrap();
+                let session = FileUploadSession::new(config.clone(), pool.clone(), None).await.unwrap();
+                let results = upload_files_in_session(session, vec![path], true).await.unwrap();
+                let pf = results[0].as_ref().unwrap();
+
+                let downloader = FileDownloader::new(config, pool).await.unwrap();
+                let output = OutputProvider::File(FileProvider::new(out_path));
+                downloader.smudge_file_from_pointer(pf, &output, None, None).await.unwrap();
+            }
+        });
+        threadpool.external_run_async_task(round_trip).unwrap().unwrap();
+
+        assert_eq!(std::fs::read(&out_path).unwrap(), data);
     }
 }
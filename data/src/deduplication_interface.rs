@@ -38,7 +38,7 @@ impl DeduplicationDataInterface for UploadSessionDataManager {
         &self,
         query_hashes: &[MerkleHash],
     ) -> Result<Option<(usize, FileDataSequenceEntry)>> {
-        Ok(self.session.shard_interface.chunk_hash_dedup_query(query_hashes).await?)
+        self.session.chunk_hash_dedup_query(query_hashes).await
     }
 
     /// Registers a new query for more information about the
@@ -1,12 +1,15 @@
+use std::io::ErrorKind;
 use std::string::FromUtf8Error;
 use std::sync::mpsc::RecvError;
 
 use cas_client::CasClientError;
+use chunk_cache::error::ChunkCacheError;
 use mdb_shard::error::MDBShardError;
 use merkledb::error::MerkleDBError;
 use thiserror::Error;
 use tracing::error;
 use utils::errors::{AuthError, SingleflightError};
+use xet_threadpool::errors::MultithreadedRuntimeError;
 
 #[derive(Error, Debug)]
 pub enum DataProcessingError {
@@ -49,6 +52,9 @@ pub enum DataProcessingError {
     #[error("CAS service error : {0}")]
     CasClientError(#[from] CasClientError),
 
+    #[error("Chunk cache error: {0}")]
+    ChunkCacheError(#[from] ChunkCacheError),
+
     #[error("Subtask scheduling error: {0}")]
     JoinError(#[from] tokio::task::JoinError),
 
@@ -61,9 +67,24 @@ pub enum DataProcessingError {
     #[error("Hash not found")]
     HashNotFound,
 
+    #[error("Download canceled")]
+    DownloadCanceled,
+
+    #[error("Download size limit exceeded: {0}")]
+    DownloadLimitExceeded(String),
+
+    #[error("Blocking API called from within an async runtime; use the async API instead")]
+    BlockingCallInAsyncContext,
+
+    #[error("Runtime error: {0}")]
+    RuntimeError(#[from] MultithreadedRuntimeError),
+
     #[error("Parameter error: {0}")]
     ParameterError(String),
 
+    #[error("Pointer file error: {0}")]
+    PointerFileError(String),
+
     #[error("Unable to parse string as hex hash value")]
     HashStringParsingFailure(#[from] merklehash::DataHashHexParseError),
 
@@ -72,6 +93,101 @@ pub enum DataProcessingError {
 
     #[error("AuthError: {0}")]
     AuthError(#[from] AuthError),
+
+    #[error("Upload error: {0}")]
+    FileUploadError(#[from] UploadError),
+}
+
+/// Why the upload of a single file failed, with the path of the file.
+#[derive(Error, Debug)]
+pub enum UploadError {
+    #[error("{path}: file not found: {source}")]
+    NotFound { path: String, source: std::io::Error },
+
+    #[error("{path}: permission denied: {source}")]
+    PermissionDenied { path: String, source: std::io::Error },
+
+    #[error("{path}: I/O error: {source}")]
+    Io { path: String, source: std::io::Error },
+
+    #[error("{path}: authentication failed: {source}")]
+    Auth {
+        path: String,
+        source: Box<DataProcessingError>,
+    },
+
+    #[error("{path}: storage quota exceeded: {source}")]
+    QuotaExceeded {
+        path: String,
+        source: Box<DataProcessingError>,
+    },
+
+    #[error("{path}: network error: {source}")]
+    Network {
+        path: String,
+        source: Box<DataProcessingError>,
+    },
+
+    #[error("{path}: {source}")]
+    Other {
+        path: String,
+        source: Box<DataProcessingError>,
+    },
+}
+
+impl UploadError {
+    /// Attaches the path of the file being uploaded to `error`, classifying it by its cause.
+    pub fn new(path: impl Into<String>, error: DataProcessingError) -> Self {
+        let path = path.into();
+        match error {
+            DataProcessingError::IOError(source) => match source.kind() {
+                ErrorKind::NotFound => UploadError::NotFound { path, source },
+                ErrorKind::PermissionDenied => UploadError::PermissionDenied { path, source },
+                _ => UploadError::Io { path, source },
+            },
+            DataProcessingError::AuthError(_) => UploadError::Auth {
+                path,
+                source: Box::new(error),
+            },
+            DataProcessingError::CasClientError(CasClientError::RequestFailed(ref failure))
+                if failure.status.is_some_and(|status| matches!(status.as_u16(), 401 | 403)) =>
+            {
+                UploadError::Auth {
+                    path,
+                    source: Box::new(error),
+                }
+            },
+            DataProcessingError::CasClientError(CasClientError::QuotaExceeded(_)) => UploadError::QuotaExceeded {
+                path,
+                source: Box::new(error),
+            },
+            DataProcessingError::CasClientError(
+                CasClientError::RequestFailed(_)
+                | CasClientError::ReqwestMiddlewareError(_)
+                | CasClientError::ReqwestError(_),
+            ) => UploadError::Network {
+                path,
+                source: Box::new(error),
+            },
+            error => UploadError::Other {
+                path,
+                source: Box::new(error),
+            },
+        }
+    }
+
+    /// The path of the file whose upload failed.
+    pub fn path(&self) -> &str {
+        match self {
+            UploadError::NotFound { path, .. }
+            | UploadError::PermissionDenied { path, .. }
+            | UploadError::Io { path, .. }
+            | UploadError::Auth { path, .. }
+            | UploadError::QuotaExceeded { path, .. }
+            | UploadError::Network { path, .. }
+            | UploadError::Other { path, .. } => path,
+        }
+    }
 }
 
 pub type Result<T> = std::result::Result<T, DataProcessingError>;
@@ -1,15 +1,18 @@
+use std::io::Read;
 use std::sync::Arc;
 
 use chrono::{DateTime, Utc};
-use deduplication::{Chunk, Chunker, DeduplicationMetrics, FileDeduper};
+use deduplication::{Chunk, Chunker, ChunkerConfig, DeduplicationMetrics, FileDeduper, FileHasher};
 use mdb_shard::file_structs::FileMetadataExt;
-use merklehash::MerkleHash;
+use merkledb::constants::MAXIMUM_CHUNK_SIZE;
+use merklehash::{compute_data_hash, MerkleHash};
 use tracing::info;
 
 use crate::constants::INGESTION_BLOCK_SIZE;
 use crate::deduplication_interface::UploadSessionDataManager;
-use crate::errors::Result;
+use crate::errors::{DataProcessingError, Result};
 use crate::file_upload_session::FileUploadSession;
+use crate::repo_salt::RepoSalt;
 use crate::sha256::ShaGenerator;
 use crate::PointerFile;
 
@@ -30,6 +33,9 @@ pub struct SingleFileCleaner {
     // Generating the sha256 hash
     sha_generator: ShaGenerator,
 
+    // Generating the BLAKE3 digest, if configured
+    blake3_hasher: Option<blake3::Hasher>,
+
     // Start time
     start_time: DateTime<Utc>,
 }
@@ -39,8 +45,9 @@ impl SingleFileCleaner {
         Self {
             file_name,
             dedup_manager: FileDeduper::new(UploadSessionDataManager::new(session.clone())),
+            chunker: Chunker::from_config(session.config.data_config.chunker_config),
+            blake3_hasher: session.config.data_config.extra_hashes.blake3.then(blake3::Hasher::new),
             session,
-            chunker: deduplication::Chunker::default(),
             sha_generator: ShaGenerator::new(),
             start_time: Utc::now(),
         }
@@ -62,9 +69,52 @@ impl SingleFileCleaner {
     }
 
     async fn add_data_impl(&mut self, data: &[u8]) -> Result<()> {
+        if let Some(hasher) = self.blake3_hasher.as_mut() {
+            hasher.update(data);
+        }
+
         // Chunk the data.
         let chunks: Arc<[Chunk]> = Arc::from(self.chunker.next_block(data, false));
 
+        self.process_chunks(chunks).await
+    }
+
+    /// Adds data that was already chunked, e.g. by an earlier session, bypassing the chunker; the
+    /// chunks are the next chunks of the file, in order.  Every chunk's hash is checked against its
+    /// data, and chunks that are empty or larger than a chunk can be are rejected, with
+    /// `DataProcessingError::ParameterError`.
+    ///
+    /// Don't mix this with `add_data` in the same file: data added there may still be waiting in
+    /// the chunker for its chunk boundary.  For the file to dedup against, and hash the same as, the
+    /// file uploaded with `add_data`, the chunks must be those the session's chunker would produce.
+    pub async fn add_chunks(&mut self, chunks: Vec<Chunk>) -> Result<()> {
+        for chunk in &chunks {
+            if chunk.data.is_empty() || chunk.data.len() > MAXIMUM_CHUNK_SIZE {
+                return Err(DataProcessingError::ParameterError(format!(
+                    "chunk {} of {} has invalid size {}",
+                    chunk.hash,
+                    self.file_name,
+                    chunk.data.len()
+                )));
+            }
+            if compute_data_hash(&chunk.data) != chunk.hash {
+                return Err(DataProcessingError::ParameterError(format!(
+                    "chunk {} of {} doesn't match its data",
+                    chunk.hash, self.file_name
+                )));
+            }
+        }
+
+        if let Some(hasher) = self.blake3_hasher.as_mut() {
+            for chunk in &chunks {
+                hasher.update(&chunk.data);
+            }
+        }
+
+        self.process_chunks(chunks.into()).await
+    }
+
+    async fn process_chunks(&mut self, chunks: Arc<[Chunk]>) -> Result<()> {
         // It's possible this didn't actually add any data in.
         if chunks.is_empty() {
             return Ok(());
@@ -102,7 +152,8 @@ impl SingleFileCleaner {
             self.dedup_manager.finalize(repo_salt, Some(metadata_ext));
 
         let pointer_file =
-            PointerFile::init_from_info(&self.file_name, &file_hash.hex(), deduplication_metrics.total_bytes as u64);
+            PointerFile::init_from_info(&self.file_name, &file_hash.hex(), deduplication_metrics.total_bytes as u64)
+                .with_blake3(self.blake3_hasher.as_ref().map(|hasher| hasher.finalize().to_hex().to_string()));
 
         // Let's check some things that should be invarients
         #[cfg(debug_assertions)]
@@ -140,3 +191,26 @@ impl SingleFileCleaner {
         Ok((pointer_file, deduplication_metrics))
     }
 }
+
+/// Computes the hash a `SingleFileCleaner` with the given chunker configuration and repo salt gives
+/// the file with the contents of `reader`, chunking it the same way but without deduplicating or
+/// uploading anything.
+pub(crate) fn hash_file_contents(
+    mut reader: impl Read,
+    chunker_config: ChunkerConfig,
+    repo_salt: &RepoSalt,
+) -> Result<MerkleHash> {
+    let mut chunker = Chunker::from_config(chunker_config);
+    let mut file_hasher = FileHasher::default();
+    let mut buffer = vec![0u8; *INGESTION_BLOCK_SIZE];
+    loop {
+        let bytes = reader.read(&mut buffer)?;
+        if bytes == 0 {
+            break;
+        }
+        file_hasher.add_chunks(&chunker.next_block(&buffer[..bytes], false));
+    }
+    file_hasher.add_chunks(chunker.finish().as_slice());
+
+    Ok(file_hasher.finalize(repo_salt))
+}
@@ -1,15 +1,22 @@
 use std::sync::Arc;
 
-use cas_client::{Client, OutputProvider};
+use cas_client::{
+    CasCacheWriteProvider, CasClientError, ChunkHashSource, Client, OutputProvider, StreamOutputProvider, TransferStats,
+};
 use cas_types::FileRange;
+use mdb_shard::shard_file_manager::ShardFileManager;
 use merklehash::MerkleHash;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use utils::progress::ProgressUpdater;
 use xet_threadpool::ThreadPool;
 
 use crate::configurations::TranslatorConfig;
 use crate::errors::*;
+use crate::metrics::{metrics_sink, FILTER_BYTES_SMUDGED};
 use crate::remote_client_interface::create_remote_client;
-use crate::{prometheus_metrics, PointerFile};
+use crate::PointerFile;
 
 /// Manages the download of files based on a hash or pointer file.
 ///
@@ -25,11 +32,23 @@ pub struct FileDownloader {
 /// Smudge operations
 impl FileDownloader {
     pub async fn new(config: Arc<TranslatorConfig>, threadpool: Arc<ThreadPool>) -> Result<Self> {
-        let client = create_remote_client(&config, threadpool.clone(), false)?;
+        // Downloaded chunks are verified against the chunk hashes in the shard cache.
+        let chunk_hash_source: Option<ChunkHashSource> = if config.data_config.verify_chunks {
+            Some(ShardFileManager::new_in_cache_directory(&config.shard_config.cache_directory).await?)
+        } else {
+            None
+        };
+        let client = create_remote_client(&config, threadpool.clone(), false, chunk_hash_source)?;
 
         Ok(Self { config, client })
     }
 
+    /// Like `new`, but downloads through the given client instead of one created from the config.
+    #[cfg(any(test, feature = "bench"))]
+    pub(crate) fn new_with_client(config: Arc<TranslatorConfig>, client: Arc<dyn Client + Send + Sync>) -> Self {
+        Self { config, client }
+    }
+
     pub async fn smudge_file_from_pointer(
         &self,
         pointer: &PointerFile,
@@ -48,11 +67,238 @@ impl FileDownloader {
         range: Option<FileRange>,
         progress_updater: Option<Arc<dyn ProgressUpdater>>,
     ) -> Result<u64> {
+        let stats = self
+            .smudge_file_from_hash_with_stats(file_id, output, range, progress_updater)
+            .await?;
+        Ok(stats.total_bytes)
+    }
+
+    /// Like `smudge_file_from_hash`, but also returns the transfer stats (per-term fetch
+    /// latencies, decompression time and total wall clock) of the download.
+    pub async fn smudge_file_from_hash_with_stats(
+        &self,
+        file_id: &MerkleHash,
+        output: &OutputProvider,
+        range: Option<FileRange>,
+        progress_updater: Option<Arc<dyn ProgressUpdater>>,
+    ) -> Result<TransferStats> {
+        if let OutputProvider::CasCache(cache) = output {
+            return self.smudge_file_into_cache(file_id, cache, range, progress_updater).await;
+        }
+
+        if is_empty_file(file_id) {
+            output.write_empty()?;
+            return Ok(TransferStats::default());
+        }
+
         // Currently, this works by always directly querying the remote server.
-        let n_bytes = self.client.get_file(file_id, range, output, progress_updater).await?;
+        let stats = self
+            .client
+            .get_file_with_stats(file_id, range, output, progress_updater)
+            .await?;
+
+        metrics_sink().counter(FILTER_BYTES_SMUDGED, stats.total_bytes);
+
+        Ok(stats)
+    }
+
+    /// Like `smudge_file_from_hash`, but gives up as soon as `cancel` is triggered, failing with
+    /// `DataProcessingError::DownloadCanceled`.  A file output is only moved into place once the
+    /// download completes, so canceling it leaves an existing file at the destination as it was;
+    /// see `ReconstructionClient::get_file_cancelable`.
+    pub async fn smudge_file_from_hash_cancelable(
+        &self,
+        file_id: &MerkleHash,
+        output: &OutputProvider,
+        range: Option<FileRange>,
+        progress_updater: Option<Arc<dyn ProgressUpdater>>,
+        cancel: &CancellationToken,
+    ) -> Result<u64> {
+        if let OutputProvider::CasCache(_) = output {
+            return Err(DataProcessingError::ParameterError(
+                "cancelable downloads can't be written to the content addressed cache".to_owned(),
+            ));
+        }
+        if cancel.is_cancelled() {
+            return Err(DataProcessingError::DownloadCanceled);
+        }
+
+        if is_empty_file(file_id) {
+            output.write_empty()?;
+            return Ok(0);
+        }
+
+        let total_bytes = self
+            .client
+            .get_file_cancelable(file_id, range, output, progress_updater, cancel)
+            .await
+            .map_err(|e| match e {
+                CasClientError::Canceled => DataProcessingError::DownloadCanceled,
+                e => e.into(),
+            })?;
+
+        metrics_sink().counter(FILTER_BYTES_SMUDGED, total_bytes);
+
+        Ok(total_bytes)
+    }
+
+    /// The size of the file as reconstructed from its terms; see `ReconstructionClient::get_file_size`.
+    pub async fn file_size(&self, file_id: &MerkleHash) -> Result<u64> {
+        if is_empty_file(file_id) {
+            return Ok(0);
+        }
+        Ok(self.client.get_file_size(file_id).await?)
+    }
+
+    /// Starts downloading the file, or the `range` of it, in the background, returning a stream of its
+    /// bytes in order as they arrive.  Dropping the stream aborts the download.
+    pub fn stream_file(self: &Arc<Self>, file_id: &MerkleHash, range: Option<FileRange>) -> DownloadStream {
+        let (output, receiver) = StreamOutputProvider::new();
+        let downloader = self.clone();
+        let file_id = *file_id;
+        let download = tokio::spawn(async move {
+            downloader
+                .smudge_file_from_hash(&file_id, &OutputProvider::Stream(output), range, None)
+                .await
+        });
+        DownloadStream {
+            receiver,
+            download: Some(download),
+        }
+    }
+
+    /// Downloads the file into the content addressed cache unless it's already there, then links
+    /// it to the destination.
+    async fn smudge_file_into_cache(
+        &self,
+        file_id: &MerkleHash,
+        cache: &CasCacheWriteProvider,
+        range: Option<FileRange>,
+        progress_updater: Option<Arc<dyn ProgressUpdater>>,
+    ) -> Result<TransferStats> {
+        if range.is_some() {
+            return Err(DataProcessingError::ParameterError(
+                "byte range downloads can't be written to the content addressed cache".to_owned(),
+            ));
+        }
+        if cache.file_hash() != file_id {
+            return Err(DataProcessingError::ParameterError(format!(
+                "cache output for {} used to download {file_id}",
+                cache.file_hash()
+            )));
+        }
+
+        if cache.is_cached() {
+            cache.link_to_destination()?;
+            return Ok(TransferStats::default());
+        }
+
+        if is_empty_file(file_id) {
+            // commit creates the empty file in the cache
+            cache.commit()?;
+            return Ok(TransferStats::default());
+        }
+
+        let stats = self
+            .client
+            .get_file_with_stats(file_id, None, &OutputProvider::CasCache(cache.clone()), progress_updater)
+            .await
+            .inspect_err(|_| cache.discard())?;
+        cache.commit().inspect_err(|_| cache.discard())?;
+
+        metrics_sink().counter(FILTER_BYTES_SMUDGED, stats.total_bytes);
+
+        Ok(stats)
+    }
+}
+
+/// The bytes of a file being downloaded, in order; see `FileDownloader::stream_file`.
+pub struct DownloadStream {
+    receiver: UnboundedReceiver<Vec<u8>>,
+    download: Option<JoinHandle<Result<u64>>>,
+}
+
+impl DownloadStream {
+    /// Returns the next bytes of the file, waiting for them to be downloaded, or None once all of
+    /// the file was returned.  Fails if the download does.
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if let Some(bytes) = self.receiver.recv().await {
+            return Ok(Some(bytes));
+        }
+        // The channel is closed once the download is done; report how it ended, once.
+        if let Some(download) = self.download.take() {
+            download.await??;
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for DownloadStream {
+    fn drop(&mut self) {
+        if let Some(download) = &self.download {
+            download.abort();
+        }
+    }
+}
+
+/// Whether `file_id` is the hash of an empty file, i.e. of no chunks.  Uploading an empty file
+/// produces no xorb, so there is nothing to reconstruct and the CAS isn't queried for it.
+fn is_empty_file(file_id: &MerkleHash) -> bool {
+    *file_id == MerkleHash::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::data_client::upload_files_in_session;
+    use crate::FileUploadSession;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_stream_file() {
+        let temp_dir = tempdir().unwrap();
+        let config = TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap();
+        let threadpool = ThreadPool::from_current_runtime();
+
+        let mut data = vec![0u8; 3 << 20];
+        StdRng::seed_from_u64(0).fill_bytes(&mut data);
+        let path = temp_dir.path().join("data.bin");
+        std::fs::write(&path, &data).unwrap();
+        let session = FileUploadSession::new(config.clone(), threadpool.clone(), None).await.unwrap();
+        let pointer_file = upload_files_in_session(session, vec![path.to_string_lossy().to_string()], true)
+            .await
+            .unwrap()
+            .remove(0)
+            .unwrap();
+
+        let downloader = Arc::new(FileDownloader::new(config, threadpool).await.unwrap());
+        let read_all = |mut stream: DownloadStream| async move {
+            let mut bytes = Vec::new();
+            while let Some(chunk) = stream.next_chunk().await.unwrap() {
+                assert!(!chunk.is_empty());
+                bytes.extend_from_slice(&chunk);
+            }
+            assert!(stream.next_chunk().await.unwrap().is_none());
+            bytes
+        };
 
-        prometheus_metrics::FILTER_BYTES_SMUDGED.inc_by(n_bytes);
+        let file_hash = pointer_file.hash().unwrap();
+        assert_eq!(read_all(downloader.stream_file(&file_hash, None)).await, data);
+        let range = FileRange {
+            start: 1 << 20,
+            end: (2 << 20) + 12345,
+        };
+        assert_eq!(
+            read_all(downloader.stream_file(&file_hash, Some(range.clone()))).await,
+            &data[range.start as usize..range.end as usize]
+        );
+        assert!(read_all(downloader.stream_file(&MerkleHash::default(), None)).await.is_empty());
 
-        Ok(n_bytes)
+        // A failed download fails the stream.
+        let mut stream = downloader.stream_file(&MerkleHash::from_hex(&"ab".repeat(32)).unwrap(), None);
+        assert!(stream.next_chunk().await.is_err());
     }
 }
@@ -5,7 +5,7 @@ use cas_client::Client;
 use deduplication::constants::{MAX_XORB_BYTES, MAX_XORB_CHUNKS};
 use deduplication::{DataAggregator, DeduplicationMetrics, RawXorbData};
 use jsonwebtoken::{decode, DecodingKey, Validation};
-use mdb_shard::file_structs::MDBFileInfo;
+use mdb_shard::file_structs::{FileDataSequenceEntry, MDBFileInfo};
 use merklehash::MerkleHash;
 use more_asserts::*;
 use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
@@ -17,10 +17,15 @@ use crate::configurations::*;
 use crate::constants::MAX_CONCURRENT_UPLOADS;
 use crate::errors::*;
 use crate::file_cleaner::SingleFileCleaner;
-use crate::prometheus_metrics;
+use crate::metrics::{metrics_sink, FILTER_BYTES_CLEANED, FILTER_CAS_BYTES_PRODUCED};
 use crate::remote_client_interface::create_remote_client;
 use crate::shard_interface::SessionShardInterface;
 
+/// Called after each xorb of an upload session is uploaded, with the xorb hash, the size of the xorb
+/// data, the total size of the xorbs uploaded so far in the session, and whether the CAS already
+/// had the xorb.  Calls are made one at a time, in the order of the totals.
+pub type XorbProgressCallback = Arc<dyn Fn(MerkleHash, u64, u64, bool) + Send + Sync>;
+
 lazy_static::lazy_static! {
      static ref UPLOAD_CONCURRENCY_LIMITER: Arc<Semaphore> = Arc::new(Semaphore::new(*MAX_CONCURRENT_UPLOADS));
 }
@@ -54,6 +59,10 @@ pub struct FileUploadSession {
 
     pub(crate) upload_progress_updater: Option<Arc<dyn ProgressUpdater>>,
 
+    /// Called once per uploaded xorb, with the total of the xorb sizes reported so far.
+    xorb_progress_callback: Option<XorbProgressCallback>,
+    xorb_progress_total: Mutex<u64>,
+
     /// Threadpool to use for the execution.
     pub(crate) threadpool: Arc<ThreadPool>,
 
@@ -80,7 +89,18 @@ impl FileUploadSession {
         threadpool: Arc<ThreadPool>,
         upload_progress_updater: Option<Arc<dyn ProgressUpdater>>,
     ) -> Result<Arc<FileUploadSession>> {
-        FileUploadSession::new_impl(config, threadpool, upload_progress_updater, false).await
+        FileUploadSession::new_impl(config, threadpool, upload_progress_updater, None, None, false).await
+    }
+
+    /// Like `new`, but also reports each xorb uploaded by the session to `xorb_progress_callback`.
+    pub async fn new_with_xorb_progress(
+        config: Arc<TranslatorConfig>,
+        threadpool: Arc<ThreadPool>,
+        upload_progress_updater: Option<Arc<dyn ProgressUpdater>>,
+        xorb_progress_callback: Option<XorbProgressCallback>,
+    ) -> Result<Arc<FileUploadSession>> {
+        FileUploadSession::new_impl(config, threadpool, upload_progress_updater, xorb_progress_callback, None, false)
+            .await
     }
 
     pub async fn dry_run(
@@ -88,16 +108,31 @@ impl FileUploadSession {
         threadpool: Arc<ThreadPool>,
         upload_progress_updater: Option<Arc<dyn ProgressUpdater>>,
     ) -> Result<Arc<FileUploadSession>> {
-        FileUploadSession::new_impl(config, threadpool, upload_progress_updater, true).await
+        FileUploadSession::new_impl(config, threadpool, upload_progress_updater, None, None, true).await
+    }
+
+    /// Like `new`, but uploads through the given client instead of one created from the config.
+    #[cfg(any(test, feature = "bench"))]
+    pub(crate) async fn new_with_client(
+        config: Arc<TranslatorConfig>,
+        threadpool: Arc<ThreadPool>,
+        client: Arc<dyn Client + Send + Sync>,
+    ) -> Result<Arc<FileUploadSession>> {
+        FileUploadSession::new_impl(config, threadpool, None, None, Some(client), false).await
     }
 
     async fn new_impl(
         config: Arc<TranslatorConfig>,
         threadpool: Arc<ThreadPool>,
         upload_progress_updater: Option<Arc<dyn ProgressUpdater>>,
+        xorb_progress_callback: Option<XorbProgressCallback>,
+        client: Option<Arc<dyn Client + Send + Sync>>,
         dry_run: bool,
     ) -> Result<Arc<FileUploadSession>> {
-        let client = create_remote_client(&config, threadpool.clone(), dry_run)?;
+        let client = match client {
+            Some(client) => client,
+            None => create_remote_client(&config, threadpool.clone(), dry_run, None)?,
+        };
 
         let shard_interface = SessionShardInterface::new(config.clone(), client.clone(), dry_run).await?;
 
@@ -122,6 +157,8 @@ impl FileUploadSession {
             shard_interface,
             client,
             upload_progress_updater,
+            xorb_progress_callback,
+            xorb_progress_total: Mutex::new(0),
             threadpool,
             repo_id,
             config,
@@ -156,6 +193,7 @@ impl FileUploadSession {
         }
 
         let xorb_hash = xorb.hash();
+        let xorb_num_bytes = xorb.num_bytes() as u64;
         let xorb_data = xorb.to_vec();
         let chunks_and_boundaries = xorb.cas_info.chunks_and_boundaries();
 
@@ -166,9 +204,9 @@ impl FileUploadSession {
         let cas_prefix = session.config.data_config.prefix.clone();
 
         self.xorb_upload_tasks.lock().await.spawn(async move {
-            let n_bytes_transmitted = session
+            let (was_inserted, n_bytes_transmitted) = session
                 .client
-                .put(&cas_prefix, &xorb_hash, xorb_data, chunks_and_boundaries)
+                .put_with_status(&cas_prefix, &xorb_hash, xorb_data, chunks_and_boundaries)
                 .await?;
 
             drop(upload_permit);
@@ -177,6 +215,13 @@ impl FileUploadSession {
                 updater.update(n_bytes_transmitted as u64);
             }
 
+            if let Some(callback) = session.xorb_progress_callback.as_ref() {
+                // Call back under the lock so the totals are reported in order.
+                let mut total = session.xorb_progress_total.lock().await;
+                *total += xorb_num_bytes;
+                callback(xorb_hash, xorb_num_bytes, *total, !was_inserted);
+            }
+
             session.deduplication_metrics.lock().await.xorb_bytes_uploaded += n_bytes_transmitted;
             Ok(())
         });
@@ -184,6 +229,33 @@ impl FileUploadSession {
         Ok(())
     }
 
+    /// Queries for deduplication against all data known to this session; this includes xorbs already
+    /// cut by earlier files, but also the data from completed files that is still waiting to be
+    /// packed into a xorb.  In the latter case, the pending data is cut into a xorb immediately so
+    /// the chunks can be referenced by their xorb hash.  No remote queries are made here.
+    pub(crate) async fn chunk_hash_dedup_query(
+        self: &Arc<Self>,
+        query_hashes: &[MerkleHash],
+    ) -> Result<Option<(usize, FileDataSequenceEntry)>> {
+        let res = self.shard_interface.chunk_hash_dedup_query(query_hashes).await?;
+
+        if res.is_some() || query_hashes.is_empty() {
+            return Ok(res);
+        }
+
+        let pending_data = {
+            let mut current_session_data = self.current_session_data.lock().await;
+            if !current_session_data.contains_chunk(&query_hashes[0]) {
+                return Ok(None);
+            }
+            take(&mut *current_session_data)
+        };
+
+        self.process_aggregated_data_as_xorb(pending_data).await?;
+
+        self.shard_interface.chunk_hash_dedup_query(query_hashes).await
+    }
+
     /// Meant to be called by the finalize() method of the SingleFileCleaner
     pub(crate) async fn register_single_file_clean_completion(
         self: &Arc<Self>,
@@ -236,6 +308,11 @@ impl FileUploadSession {
         debug_assert_le!(xorb.num_bytes(), *MAX_XORB_BYTES);
         debug_assert_le!(xorb.data.len(), *MAX_XORB_CHUNKS);
 
+        // Register the xorb in the session shard so that later files can deduplicate against it.
+        if xorb.num_bytes() != 0 {
+            self.shard_interface.add_cas_block(xorb.cas_info.clone()).await?;
+        }
+
         self.register_new_xorb_for_upload(xorb).await?;
 
         for fi in new_files {
@@ -261,6 +338,9 @@ impl FileUploadSession {
             result??;
         }
 
+        // Make sure the xorbs are durable before registering the shards that reference them.
+        self.client.flush().await?;
+
         // Now that all the tasks there are completed, there shouldn't be any other references to this session
         // hanging around; i.e. the self in this shession should be used as if it's consuming the class, as it
         // effectively empties all the states.
@@ -278,8 +358,9 @@ impl FileUploadSession {
         metrics.total_bytes_uploaded = metrics.shard_bytes_uploaded + metrics.xorb_bytes_uploaded;
 
         // Update the global counters
-        prometheus_metrics::FILTER_CAS_BYTES_PRODUCED.inc_by(metrics.new_bytes as u64);
-        prometheus_metrics::FILTER_BYTES_CLEANED.inc_by(metrics.total_bytes as u64);
+        let sink = metrics_sink();
+        sink.counter(FILTER_CAS_BYTES_PRODUCED, metrics.new_bytes as u64);
+        sink.counter(FILTER_BYTES_CLEANED, metrics.total_bytes as u64);
 
         Ok((metrics, all_file_info))
     }
@@ -373,6 +454,8 @@ mod tests {
     use std::fs::{read, write};
 
     use cas_client::{FileProvider, OutputProvider};
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
     use tempfile::tempdir;
 
     use super::*;
@@ -407,4 +490,223 @@ mod tests {
             })
             .unwrap();
     }
+
+    #[test]
+    fn test_clean_with_chunker_config() {
+        let temp = tempdir().unwrap();
+        let runtime = get_threadpool();
+
+        runtime
+            .clone()
+            .external_run_async_task(async move {
+                let mut config = Arc::try_unwrap(TranslatorConfig::local_config(temp.path()).unwrap()).unwrap();
+                // A mask this wide effectively never matches, so every chunk ends at the maximum size.
+                config.data_config.chunker_config = ChunkerConfig {
+                    mask_bits: 31,
+                    minimum_chunk: 128,
+                    maximum_chunk: 256,
+                };
+
+                let upload_session = FileUploadSession::new(Arc::new(config), runtime, None).await.unwrap();
+                let mut cleaner = upload_session.start_clean("test".to_owned());
+                cleaner.add_data(&[7u8; 4096 + 100]).await.unwrap();
+                let (_, metrics) = cleaner.finish().await.unwrap();
+                upload_session.finalize().await.unwrap();
+
+                assert_eq!(metrics.total_bytes, 4096 + 100);
+                assert_eq!(metrics.total_chunks, 4096 / 256 + 1);
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_custom_chunk_sizes_change_boundaries() {
+        let temp = tempdir().unwrap();
+        let runtime = get_threadpool();
+
+        runtime
+            .clone()
+            .external_run_async_task(async move {
+                let mut data = vec![0u8; 1 << 20];
+                StdRng::seed_from_u64(0).fill_bytes(&mut data);
+
+                let mut custom = Arc::try_unwrap(TranslatorConfig::local_config(temp.path()).unwrap()).unwrap();
+                custom
+                    .set_chunker_config(ChunkerConfig {
+                        mask_bits: 12,
+                        minimum_chunk: 1024,
+                        maximum_chunk: 16 * 1024,
+                    })
+                    .unwrap();
+
+                let mut results = Vec::new();
+                for config in [TranslatorConfig::local_config(temp.path()).unwrap(), Arc::new(custom)] {
+                    let upload_session = FileUploadSession::new(config, runtime.clone(), None).await.unwrap();
+                    let mut cleaner = upload_session.start_clean("data".to_owned());
+                    cleaner.add_data(&data).await.unwrap();
+                    let (pf, metrics) = cleaner.finish().await.unwrap();
+                    upload_session.finalize().await.unwrap();
+                    results.push((pf, metrics));
+                }
+
+                // The file hash is built from the chunk hashes, so different boundaries give a
+                // different hash for the same data.
+                let (default_pf, default_metrics) = &results[0];
+                let (custom_pf, custom_metrics) = &results[1];
+                assert!(custom_metrics.total_chunks > 4 * default_metrics.total_chunks);
+                assert_ne!(custom_pf.hash_string(), default_pf.hash_string());
+                assert_eq!(custom_pf.filesize(), default_pf.filesize());
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_invalid_chunker_config() {
+        let temp = tempdir().unwrap();
+        let mut config = Arc::try_unwrap(TranslatorConfig::local_config(temp.path()).unwrap()).unwrap();
+        let invalid = [
+            (6, 16, 1024),
+            (32, 16, usize::MAX),
+            (12, 4096, 8192),
+            (12, 1024, 4096),
+            (12, 8192, 1024),
+        ];
+        for (mask_bits, minimum_chunk, maximum_chunk) in invalid {
+            let chunker_config = ChunkerConfig {
+                mask_bits,
+                minimum_chunk,
+                maximum_chunk,
+            };
+            assert!(matches!(config.set_chunker_config(chunker_config), Err(DataProcessingError::ParameterError(_))));
+        }
+        assert_eq!(config.data_config.chunker_config, ChunkerConfig::default());
+        config.set_chunker_config(ChunkerConfig::default()).unwrap();
+    }
+
+    #[test]
+    fn test_dedup_across_files_in_session() {
+        let temp = tempdir().unwrap();
+        let runtime = get_threadpool();
+
+        runtime
+            .clone()
+            .external_run_async_task(async move {
+                let cas_path = temp.path().join("cas");
+
+                let mut rng = StdRng::seed_from_u64(0);
+                let mut data = vec![0u8; 1 << 20];
+                rng.fill_bytes(&mut data);
+
+                // The second file is the first with a header prepended; all but the first few chunks
+                // are shared.
+                let header = [b'#'; 1000];
+                let prefixed_data = [&header[..], &data[..]].concat();
+
+                let upload_session =
+                    FileUploadSession::new(TranslatorConfig::local_config(&cas_path).unwrap(), runtime.clone(), None)
+                        .await
+                        .unwrap();
+
+                let mut pointers = Vec::new();
+                let mut metrics = Vec::new();
+                for (name, contents) in [("a", &data), ("b", &prefixed_data)] {
+                    let mut cleaner = upload_session.start_clean(name.to_owned());
+                    cleaner.add_data(contents).await.unwrap();
+                    let (pf, file_metrics) = cleaner.finish().await.unwrap();
+                    pointers.push(pf);
+                    metrics.push(file_metrics);
+                }
+                let session_metrics = upload_session.finalize().await.unwrap();
+
+                // The first file is all new data; the first file's data is still pending in the session
+                // when the second file is cleaned, so this also checks dedup against data not yet cut into a xorb.
+                let max_chunk = ChunkerConfig::default().maximum_chunk;
+                assert_eq!(metrics[0].new_bytes, data.len());
+                assert!(metrics[1].new_bytes <= header.len() + max_chunk);
+                assert!(metrics[1].deduped_bytes >= data.len() - max_chunk);
+                assert_eq!(session_metrics.new_bytes, metrics[0].new_bytes + metrics[1].new_bytes);
+
+                for (pf, contents) in pointers.iter().zip([&data, &prefixed_data]) {
+                    let pointer_path = temp.path().join("pointer");
+                    let hydrated_path = temp.path().join("hydrated");
+                    write(&pointer_path, pf.to_string()).unwrap();
+                    test_smudge_file(runtime.clone(), &cas_path, &pointer_path, &hydrated_path).await;
+                    assert_eq!(&read(&hydrated_path).unwrap(), contents);
+                }
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_xorb_progress_callback() {
+        let temp = tempdir().unwrap();
+        let runtime = get_threadpool();
+
+        runtime
+            .clone()
+            .external_run_async_task(async move {
+                // With every chunk at 256 bytes, a xorb holds at most MAX_XORB_CHUNKS * 256 bytes, so
+                // this data spans several xorbs.
+                let mut data = vec![0u8; 20_000 * 256];
+                StdRng::seed_from_u64(0).fill_bytes(&mut data);
+
+                let session_config = |shard_dir: &str| {
+                    let mut config = Arc::try_unwrap(TranslatorConfig::local_config(temp.path()).unwrap()).unwrap();
+                    config.data_config.chunker_config = ChunkerConfig {
+                        mask_bits: 31,
+                        minimum_chunk: 128,
+                        maximum_chunk: 256,
+                    };
+                    config.shard_config.cache_directory = temp.path().join(shard_dir).join("cache");
+                    config.shard_config.session_directory = temp.path().join(shard_dir).join("session");
+                    Arc::new(config)
+                };
+
+                let upload = |config: Arc<TranslatorConfig>| {
+                    let runtime = runtime.clone();
+                    let data = &data;
+                    async move {
+                        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+                        let recorded = events.clone();
+                        let callback: XorbProgressCallback = Arc::new(move |hash, bytes, total, deduped| {
+                            recorded.lock().unwrap().push((hash, bytes, total, deduped));
+                        });
+
+                        let upload_session =
+                            FileUploadSession::new_with_xorb_progress(config, runtime, None, Some(callback))
+                                .await
+                                .unwrap();
+                        let mut cleaner = upload_session.start_clean("data".to_owned());
+                        cleaner.add_data(data).await.unwrap();
+                        cleaner.finish().await.unwrap();
+                        upload_session.finalize().await.unwrap();
+
+                        Arc::try_unwrap(events).unwrap().into_inner().unwrap()
+                    }
+                };
+
+                let events = upload(session_config("shards_a")).await;
+                assert!(events.len() >= 3, "{events:?}");
+
+                let mut total = 0;
+                for &(_, bytes, reported_total, deduped) in &events {
+                    total += bytes;
+                    assert_eq!(reported_total, total);
+                    assert!(!deduped);
+                }
+                assert_eq!(total, data.len() as u64);
+
+                // Without the shards of the first session, the same xorbs are uploaded again and found
+                // already present.
+                let repeat_events = upload(session_config("shards_b")).await;
+                let mut hashes: Vec<_> = events.iter().map(|e| e.0).collect();
+                let mut repeat_hashes: Vec<_> = repeat_events.iter().map(|e| e.0).collect();
+                hashes.sort();
+                repeat_hashes.sort();
+                assert_eq!(hashes, repeat_hashes);
+                assert!(repeat_events.iter().all(|&(_, _, _, deduped)| deduped));
+                assert_eq!(repeat_events.last().unwrap().2, data.len() as u64);
+            })
+            .unwrap();
+    }
 }
@@ -7,15 +7,23 @@ pub mod errors;
 mod file_cleaner;
 mod file_downloader;
 mod file_upload_session;
+pub mod local_cache;
+mod manifest;
+pub mod metrics;
 pub mod migration_tool;
 mod pointer_file;
 mod prometheus_metrics;
 mod remote_client_interface;
 mod repo_salt;
+#[cfg(any(test, feature = "bench"))]
+pub mod roundtrip;
 mod sha256;
 mod shard_interface;
+pub mod upload_estimate;
+mod upload_journal;
 
 pub use cas_client::CacheConfig;
-pub use file_downloader::FileDownloader;
-pub use file_upload_session::FileUploadSession;
+pub use cas_types::FileRange;
+pub use file_downloader::{DownloadStream, FileDownloader};
+pub use file_upload_session::{FileUploadSession, XorbProgressCallback};
 pub use pointer_file::PointerFile;
@@ -0,0 +1,134 @@
+use std::io::ErrorKind;
+use std::path::Path;
+
+use chunk_cache::{CacheConfig, DiskCache};
+use mdb_shard::shard_file_manager::ShardFileManager;
+use mdb_shard::utils::{list_shard_hashes_in_directory, shard_file_name};
+
+use crate::configurations::TranslatorConfig;
+use crate::errors::Result;
+
+/// The number of entries in each of the local caches.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheEntryCounts {
+    /// Shards in the shard cache.
+    pub shards: usize,
+    /// Cached xorb ranges in the chunk cache.
+    pub chunk_ranges: usize,
+}
+
+/// The size of the local shard and chunk caches of a configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub shard_bytes: u64,
+    pub chunk_bytes: u64,
+    pub entry_counts: CacheEntryCounts,
+}
+
+/// Reads the size of the shard and chunk caches of `config` from disk.
+pub fn cache_stats(config: &TranslatorConfig) -> Result<CacheStats> {
+    let shard_dir = &config.shard_config.cache_directory;
+    let mut shard_bytes = 0;
+    let mut shards = 0;
+    if shard_dir.exists() {
+        for hash in list_shard_hashes_in_directory(shard_dir)? {
+            shard_bytes += std::fs::metadata(shard_dir.join(shard_file_name(&hash)))?.len();
+            shards += 1;
+        }
+    }
+
+    // Loading the chunk cache with no effective capacity limit counts every item in it.
+    let chunk_cache = DiskCache::initialize(&CacheConfig {
+        cache_directory: config.data_config.cache_config.cache_directory.clone(),
+        cache_size: u64::MAX / 2,
+    })?;
+
+    Ok(CacheStats {
+        shard_bytes,
+        chunk_bytes: chunk_cache.total_bytes()?,
+        entry_counts: CacheEntryCounts {
+            shards,
+            chunk_ranges: chunk_cache.num_items()?,
+        },
+    })
+}
+
+/// Deletes everything in the shard and chunk caches of `config`.
+///
+/// Only call this while no transfers using these caches are running: they may keep using entries
+/// they've already loaded, and their new entries may be partially cleared.
+pub async fn clear_cache(config: &TranslatorConfig) -> Result<()> {
+    let shard_dir = &config.shard_config.cache_directory;
+    remove_dir_all_if_exists(shard_dir)?;
+    ShardFileManager::forget_cache_directory(shard_dir).await?;
+
+    remove_dir_all_if_exists(&config.data_config.cache_config.cache_directory)?;
+    Ok(())
+}
+
+fn remove_dir_all_if_exists(dir: &Path) -> Result<()> {
+    match std::fs::remove_dir_all(dir) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chunk_cache::{ChunkCache, RandomEntryIterator};
+    use tempfile::tempdir;
+    use xet_threadpool::ThreadPool;
+
+    use super::*;
+    use crate::data_client::upload_files_in_session;
+    use crate::FileUploadSession;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_cache_stats_and_clear() {
+        let temp_dir = tempdir().unwrap();
+        let config = TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap();
+        assert_eq!(cache_stats(&config).unwrap(), CacheStats::default());
+
+        // An upload moves its session shard into the shard cache.
+        let upload = |name: &'static str| {
+            let config = config.clone();
+            let path = temp_dir.path().join(name);
+            async move {
+                std::fs::write(&path, format!("contents of {name}")).unwrap();
+                let session = FileUploadSession::new(config, ThreadPool::from_current_runtime(), None)
+                    .await
+                    .unwrap();
+                upload_files_in_session(session, vec![path.to_string_lossy().to_string()], true)
+                    .await
+                    .unwrap();
+            }
+        };
+        upload("a.bin").await;
+
+        let chunk_cache = DiskCache::initialize(&config.data_config.cache_config).unwrap();
+        for (key, range, chunk_byte_indices, data) in RandomEntryIterator::std_from_seed(0).with_range_len(1024).take(3)
+        {
+            chunk_cache.put(&key, &range, &chunk_byte_indices, &data).unwrap();
+        }
+        let chunk_bytes = chunk_cache.total_bytes().unwrap();
+        drop(chunk_cache);
+
+        let stats = cache_stats(&config).unwrap();
+        assert_eq!(
+            stats.entry_counts,
+            CacheEntryCounts {
+                shards: 1,
+                chunk_ranges: 3
+            }
+        );
+        assert!(stats.shard_bytes > 0);
+        assert_eq!(stats.chunk_bytes, chunk_bytes);
+
+        clear_cache(&config).await.unwrap();
+        assert_eq!(cache_stats(&config).unwrap(), CacheStats::default());
+
+        // Later uploads start from the cleared cache.
+        upload("b.bin").await;
+        assert_eq!(cache_stats(&config).unwrap().entry_counts.shards, 1);
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Component, Path, PathBuf};
+
+use file_utils::write_all_safe;
+use mdb_shard::file_structs::MDBFileInfo;
+use serde_json::{json, Value};
+
+use crate::errors::{DataProcessingError, Result};
+use crate::PointerFile;
+
+/// A file listed in a manifest.  In a download manifest, `path` is relative to the destination
+/// directory; an upload manifest also has the sha256 of each file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ManifestEntry {
+    pub path: String,
+    pub hash: String,
+    pub size: u64,
+    pub sha256: Option<String>,
+}
+
+/// Parses the entries of a manifest.  Fails if the manifest isn't a JSON object with a `files`
+/// array; an entry that is malformed only fails that entry.
+pub(crate) fn parse_manifest(manifest_json: &str) -> Result<Vec<Result<ManifestEntry>>> {
+    let manifest: Value = serde_json::from_str(manifest_json)
+        .map_err(|e| DataProcessingError::ParameterError(format!("invalid manifest: {e}")))?;
+    let Some(files) = manifest["files"].as_array() else {
+        return Err(DataProcessingError::ParameterError("manifest has no \"files\" array".to_owned()));
+    };
+
+    Ok(files
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| {
+            let parsed = (|| {
+                Some(ManifestEntry {
+                    path: entry["path"].as_str()?.to_owned(),
+                    hash: entry["hash"].as_str()?.to_owned(),
+                    size: entry["size"].as_u64()?,
+                    sha256: match &entry["sha256"] {
+                        Value::Null => None,
+                        sha256 => Some(sha256.as_str()?.to_owned()),
+                    },
+                })
+            })();
+            parsed.ok_or_else(|| DataProcessingError::ParameterError(format!("malformed manifest entry {i}: {entry}")))
+        })
+        .collect())
+}
+
+/// Writes the manifest of the files uploaded in a batch to `path`: a JSON object with a `files` array
+/// listing the path, hash, size and sha256 of each successful upload in `results`, in order.
+///
+/// The sha256 of a file is taken from its file info in `file_info` if there, e.g. as returned by
+/// `FileUploadSession::finalize_with_file_info`, and computed from the file otherwise.  The manifest
+/// is checked to parse back to the same entries, then written atomically, so `path` either keeps
+/// its previous contents or holds the whole manifest.
+pub(crate) fn write_upload_manifest(
+    path: &Path,
+    results: &[Result<PointerFile>],
+    file_info: &[MDBFileInfo],
+) -> Result<()> {
+    let stored_shas: HashMap<_, _> = file_info
+        .iter()
+        .filter_map(|info| Some((info.metadata.file_hash.hex(), info.metadata_ext.as_ref()?.sha256.hex())))
+        .collect();
+
+    let entries = results
+        .iter()
+        .filter_map(|result| result.as_ref().ok())
+        .map(|pf| {
+            let sha256 = match stored_shas.get(pf.hash_string()) {
+                Some(sha256) => sha256.clone(),
+                None => PointerFile::compute_sha_from_reader(&mut File::open(pf.path())?)?,
+            };
+            Ok(ManifestEntry {
+                path: pf.path().to_owned(),
+                hash: pf.hash_string().clone(),
+                size: pf.filesize(),
+                sha256: Some(sha256),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let files: Vec<_> = entries
+        .iter()
+        .map(|entry| json!({"path": entry.path, "hash": entry.hash, "size": entry.size, "sha256": entry.sha256}))
+        .collect();
+    let manifest_json = serde_json::to_string_pretty(&json!({ "files": files }))
+        .map_err(|e| DataProcessingError::InternalError(format!("failed to serialize manifest: {e}")))?;
+
+    let parsed = parse_manifest(&manifest_json)?.into_iter().collect::<Result<Vec<_>>>()?;
+    if parsed != entries {
+        return Err(DataProcessingError::InternalError(format!(
+            "manifest written to {path:?} doesn't parse back to its entries"
+        )));
+    }
+
+    write_all_safe(path, manifest_json.as_bytes())?;
+    Ok(())
+}
+
+/// Returns where the manifest path `path` goes under `dest_dir`.  Only relative paths that stay
+/// within `dest_dir` are accepted, i.e. with no `..`, root or drive component.
+pub(crate) fn resolve_in_dir(dest_dir: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    let mut resolved = dest_dir.to_path_buf();
+    for component in relative.components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {},
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                return Err(DataProcessingError::ParameterError(format!(
+                    "manifest path {path:?} is outside of the destination directory"
+                )));
+            },
+        }
+    }
+    if resolved == dest_dir {
+        return Err(DataProcessingError::ParameterError(format!("manifest path {path:?} doesn't name a file")));
+    }
+    Ok(resolved)
+}
@@ -0,0 +1,122 @@
+//! The metrics reported by uploads and downloads.
+//!
+//! Metrics go to a process-wide sink, which reports them to Prometheus by default.  Embedders using
+//! other telemetry install their own sink with `set_metrics_sink`.
+
+use std::sync::{Arc, RwLock};
+
+pub use cas_client::{HTTP_CONNECTIONS_OPENED, HTTP_REQUESTS, HTTP_REQUESTS_IN_FLIGHT};
+use lazy_static::lazy_static;
+pub use utils::metrics::MetricsSink;
+
+pub use crate::prometheus_metrics::PrometheusMetricsSink;
+
+/// The number of bytes of new data produced by cleaning files.
+pub const FILTER_CAS_BYTES_PRODUCED: &str = "filter_process_cas_bytes_produced";
+/// The number of bytes of files cleaned.
+pub const FILTER_BYTES_CLEANED: &str = "filter_process_bytes_cleaned";
+/// The number of bytes of files smudged.
+pub const FILTER_BYTES_SMUDGED: &str = "filter_process_bytes_smudged";
+
+lazy_static! {
+    static ref METRICS_SINK: RwLock<Arc<dyn MetricsSink>> = RwLock::new(Arc::new(PrometheusMetricsSink));
+}
+
+/// Installs `sink` as the sink of all metrics reported from now on, replacing the current one.
+pub fn set_metrics_sink(sink: Arc<dyn MetricsSink>) {
+    *METRICS_SINK.write().unwrap() = sink;
+}
+
+/// Returns the installed metrics sink.
+pub fn metrics_sink() -> Arc<dyn MetricsSink> {
+    METRICS_SINK.read().unwrap().clone()
+}
+
+/// Reports every metric to the sink installed at the time, so that the HTTP clients of a session
+/// follow `set_metrics_sink`.
+pub(crate) struct InstalledMetricsSink;
+
+impl MetricsSink for InstalledMetricsSink {
+    fn counter(&self, name: &str, value: u64) {
+        metrics_sink().counter(name, value)
+    }
+
+    fn gauge(&self, name: &str, value: f64) {
+        metrics_sink().gauge(name, value)
+    }
+
+    fn histogram(&self, name: &str, value: f64) {
+        metrics_sink().histogram(name, value)
+    }
+
+    fn labeled_counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        metrics_sink().labeled_counter(name, labels, value)
+    }
+
+    fn labeled_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        metrics_sink().labeled_gauge(name, labels, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use cas_client::{FileProvider, OutputProvider};
+    use tempfile::tempdir;
+    use xet_threadpool::ThreadPool;
+
+    use super::*;
+    use crate::configurations::TranslatorConfig;
+    use crate::data_client::upload_files_in_session;
+    use crate::{FileDownloader, FileUploadSession};
+
+    #[derive(Default)]
+    struct RecordingSink {
+        counters: Mutex<Vec<(String, u64)>>,
+    }
+
+    impl MetricsSink for RecordingSink {
+        fn counter(&self, name: &str, value: u64) {
+            self.counters.lock().unwrap().push((name.to_owned(), value));
+        }
+
+        fn gauge(&self, _name: &str, _value: f64) {}
+
+        fn histogram(&self, _name: &str, _value: f64) {}
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_smudge_reports_to_installed_sink() {
+        let temp_dir = tempdir().unwrap();
+        let config = TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap();
+        let contents = "contents reported to a custom metrics sink";
+        let path = temp_dir.path().join("file.txt");
+        std::fs::write(&path, contents).unwrap();
+
+        let sink = Arc::new(RecordingSink::default());
+        set_metrics_sink(sink.clone());
+
+        let session = FileUploadSession::new(config.clone(), ThreadPool::from_current_runtime(), None)
+            .await
+            .unwrap();
+        let results = upload_files_in_session(session, vec![path.to_string_lossy().to_string()], true)
+            .await
+            .unwrap();
+        let pointer_file = results[0].as_ref().unwrap();
+
+        let downloader = FileDownloader::new(config, ThreadPool::from_current_runtime()).await.unwrap();
+        let output = OutputProvider::File(FileProvider::new(temp_dir.path().join("out.txt")));
+        downloader
+            .smudge_file_from_pointer(pointer_file, &output, None, None)
+            .await
+            .unwrap();
+
+        set_metrics_sink(Arc::new(PrometheusMetricsSink));
+
+        // Other tests may report to the sink while it's installed, so only look for this download.
+        let counters = sink.counters.lock().unwrap();
+        assert!(counters.contains(&(FILTER_BYTES_SMUDGED.to_owned(), contents.len() as u64)));
+        assert!(counters.contains(&(FILTER_BYTES_CLEANED.to_owned(), contents.len() as u64)));
+    }
+}
@@ -1,13 +1,17 @@
 #![cfg_attr(feature = "strict", deny(warnings))]
 use std::collections::BTreeMap;
 use std::fs;
+use std::io::Read;
 use std::path::Path;
 
 use merklehash::{DataHashHexParseError, MerkleHash};
+use sha2::{Digest, Sha256};
 use static_assertions::const_assert;
 use toml::Value;
 use tracing::{debug, error, warn};
 
+use crate::errors::{self, DataProcessingError};
+
 /// We put a limit on the pointer file size so that
 /// we don't ever try to read a whole giant blob into memory when
 /// trying to clean or smudge.
@@ -41,6 +45,10 @@ pub struct PointerFile {
 
     /// The size of the file pointed to by this pointer file
     filesize: u64,
+
+    /// The BLAKE3 digest of the file as a hex string, if it was computed while uploading the file; it
+    /// isn't part of the pointer file's contents.
+    blake3: Option<String>,
 }
 
 impl PointerFile {
@@ -67,6 +75,7 @@ impl PointerFile {
                 is_valid,
                 hash,
                 filesize,
+                blake3: None,
             };
         }
 
@@ -84,6 +93,7 @@ impl PointerFile {
                 is_valid,
                 hash,
                 filesize,
+                blake3: None,
             };
         }
 
@@ -128,9 +138,35 @@ impl PointerFile {
             is_valid,
             hash,
             filesize,
+            blake3: None,
         }
     }
 
+    /// Parses pointer file contents like `init_from_string`, but fails with the reason the contents
+    /// aren't a valid pointer file instead of returning an invalid one.
+    pub fn parse(contents: &str, path: &str) -> errors::Result<PointerFile> {
+        if contents.len() > POINTER_FILE_LIMIT {
+            return Err(DataProcessingError::PointerFileError(format!(
+                "contents for {path:?} are larger than a pointer file"
+            )));
+        }
+
+        let pointer_file = PointerFile::init_from_string(contents, path);
+        if !pointer_file.is_valid() {
+            return Err(DataProcessingError::PointerFileError(format!(
+                "contents for {path:?} are not a valid pointer file"
+            )));
+        }
+        if MerkleHash::from_hex(&pointer_file.hash).is_err() {
+            return Err(DataProcessingError::PointerFileError(format!(
+                "pointer file for {path:?} has an invalid hash {:?}",
+                pointer_file.hash
+            )));
+        }
+
+        Ok(pointer_file)
+    }
+
     /// Initialize a pointer file by the contents in the file.
     /// This will quickly check the file size before trying to read the
     /// entire file. Any I/O failure or file size exceeding a limit means
@@ -145,6 +181,7 @@ impl PointerFile {
             is_valid: false,
             hash: empty_string,
             filesize: 0,
+            blake3: None,
         };
 
         let Ok(file_meta) = fs::metadata(path).map_err(|e| {
@@ -174,6 +211,7 @@ impl PointerFile {
             is_valid: true,
             hash: hash.to_string(),
             filesize,
+            blake3: None,
         }
     }
 
@@ -196,12 +234,44 @@ impl PointerFile {
         }
     }
 
+    /// Computes the sha256 of a file's contents as the upload path stores it in the file's metadata
+    /// extension: the plain SHA256 of the raw bytes (not a git blob SHA, which hashes a
+    /// "blob <size>\0" header too), as a lowercase hex string.  As during upload, an empty file
+    /// gets the all-zero hash rather than the SHA256 of empty input.
+    pub fn compute_sha_from_reader<R: Read>(r: &mut R) -> errors::Result<String> {
+        let mut hasher = Sha256::default();
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut total_bytes = 0;
+        loop {
+            let n = r.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+            total_bytes += n;
+        }
+
+        if total_bytes == 0 {
+            return Ok(MerkleHash::default().hex());
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
     pub fn path(&self) -> &str {
         &self.path
     }
     pub fn filesize(&self) -> u64 {
         self.filesize
     }
+
+    pub fn blake3(&self) -> Option<&str> {
+        self.blake3.as_deref()
+    }
+
+    pub(crate) fn with_blake3(mut self, blake3: Option<String>) -> Self {
+        self.blake3 = blake3;
+        self
+    }
 }
 
 pub fn is_xet_pointer_file(data: &[u8]) -> bool {
@@ -317,4 +387,28 @@ mod tests {
         let test = PointerFile::init_from_string(&test_contents, &empty_string);
         assert!(!test.is_valid()); // new version is not valid
     }
+
+    #[tokio::test]
+    async fn test_compute_sha_from_reader() {
+        use rand::{thread_rng, Rng};
+
+        use crate::sha256::ShaGenerator;
+
+        // `echo -n "some data" | sha256sum`
+        let sha = PointerFile::compute_sha_from_reader(&mut "some data".as_bytes()).unwrap();
+        assert_eq!(sha, "1307990e6ba5ca145eb35e99182a9bec46531bc54ddf656a602c780fa0240dee");
+
+        // Matches the hash the upload path stores, including for an empty file.
+        let mut data = vec![0u8; 200_000];
+        thread_rng().fill(&mut data[..]);
+        for data in [&data[..], &[]] {
+            let mut sha_generator = ShaGenerator::new();
+            for chunk in data.chunks(4096) {
+                sha_generator.update_with_bytes(chunk).await.unwrap();
+            }
+            let stored = sha_generator.finalize().await.unwrap();
+
+            assert_eq!(PointerFile::compute_sha_from_reader(&mut &data[..]).unwrap(), stored.hex());
+        }
+    }
 }
@@ -1,13 +1,110 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
 use lazy_static::lazy_static;
-use prometheus::{register_int_counter, IntCounter};
+use prometheus::{
+    register_gauge, register_gauge_vec, register_histogram, register_int_counter, register_int_counter_vec, Gauge,
+    GaugeVec, Histogram, IntCounter, IntCounterVec,
+};
+use tracing::warn;
+
+use crate::metrics::{
+    MetricsSink, FILTER_BYTES_CLEANED, FILTER_BYTES_SMUDGED, FILTER_CAS_BYTES_PRODUCED, HTTP_CONNECTIONS_OPENED,
+    HTTP_REQUESTS, HTTP_REQUESTS_IN_FLIGHT,
+};
 
-// Some of the common tracking things
+// The metrics reported so far, registered in the default registry the first time they're reported.
 lazy_static! {
-    pub static ref FILTER_CAS_BYTES_PRODUCED: IntCounter =
-        register_int_counter!("filter_process_cas_bytes_produced", "Number of CAS bytes produced during cleaning")
-            .unwrap();
-    pub static ref FILTER_BYTES_CLEANED: IntCounter =
-        register_int_counter!("filter_process_bytes_cleaned", "Number of bytes cleaned").unwrap();
-    pub static ref FILTER_BYTES_SMUDGED: IntCounter =
-        register_int_counter!("filter_process_bytes_smudged", "Number of bytes smudged").unwrap();
+    static ref COUNTERS: Mutex<HashMap<String, IntCounter>> = Mutex::new(HashMap::new());
+    static ref GAUGES: Mutex<HashMap<String, Gauge>> = Mutex::new(HashMap::new());
+    static ref HISTOGRAMS: Mutex<HashMap<String, Histogram>> = Mutex::new(HashMap::new());
+    static ref LABELED_COUNTERS: Mutex<HashMap<String, IntCounterVec>> = Mutex::new(HashMap::new());
+    static ref LABELED_GAUGES: Mutex<HashMap<String, GaugeVec>> = Mutex::new(HashMap::new());
+}
+
+/// The default metrics sink, which reports metrics to the default Prometheus registry.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PrometheusMetricsSink;
+
+impl MetricsSink for PrometheusMetricsSink {
+    fn counter(&self, name: &str, value: u64) {
+        if let Some(counter) = registered(&COUNTERS, name, |name| register_int_counter!(name, description(name))) {
+            counter.inc_by(value);
+        }
+    }
+
+    fn gauge(&self, name: &str, value: f64) {
+        if let Some(gauge) = registered(&GAUGES, name, |name| register_gauge!(name, description(name))) {
+            gauge.set(value);
+        }
+    }
+
+    fn histogram(&self, name: &str, value: f64) {
+        if let Some(histogram) = registered(&HISTOGRAMS, name, |name| register_histogram!(name, description(name))) {
+            histogram.observe(value);
+        }
+    }
+
+    fn labeled_counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let (label_names, label_values) = split_labels(labels);
+        if let Some(counters) =
+            registered(&LABELED_COUNTERS, name, |name| register_int_counter_vec!(name, description(name), &label_names))
+        {
+            match counters.get_metric_with_label_values(&label_values) {
+                Ok(counter) => counter.inc_by(value),
+                Err(e) => warn!("Dropping metric {name}: {e}"),
+            }
+        }
+    }
+
+    fn labeled_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let (label_names, label_values) = split_labels(labels);
+        if let Some(gauges) =
+            registered(&LABELED_GAUGES, name, |name| register_gauge_vec!(name, description(name), &label_names))
+        {
+            match gauges.get_metric_with_label_values(&label_values) {
+                Ok(gauge) => gauge.set(value),
+                Err(e) => warn!("Dropping metric {name}: {e}"),
+            }
+        }
+    }
+}
+
+fn split_labels<'a>(labels: &[(&'a str, &'a str)]) -> (Vec<&'a str>, Vec<&'a str>) {
+    labels.iter().copied().unzip()
+}
+
+/// Returns the metric `name`, registering it if it's reported for the first time.  A metric that
+/// can't be registered, e.g. because of an invalid name, is dropped with a warning.
+fn registered<M: Clone>(
+    metrics: &Mutex<HashMap<String, M>>,
+    name: &str,
+    register: impl FnOnce(&str) -> prometheus::Result<M>,
+) -> Option<M> {
+    let mut metrics = metrics.lock().unwrap();
+    if let Some(metric) = metrics.get(name) {
+        return Some(metric.clone());
+    }
+    match register(name) {
+        Ok(metric) => {
+            metrics.insert(name.to_owned(), metric.clone());
+            Some(metric)
+        },
+        Err(e) => {
+            warn!("Dropping metric {name}: failed to register it with Prometheus: {e}");
+            None
+        },
+    }
+}
+
+fn description(name: &str) -> &str {
+    match name {
+        FILTER_CAS_BYTES_PRODUCED => "Number of CAS bytes produced during cleaning",
+        FILTER_BYTES_CLEANED => "Number of bytes cleaned",
+        FILTER_BYTES_SMUDGED => "Number of bytes smudged",
+        HTTP_REQUESTS => "Number of HTTP requests sent, per host",
+        HTTP_CONNECTIONS_OPENED => "Number of HTTP connections opened, per host",
+        HTTP_REQUESTS_IN_FLIGHT => "Number of HTTP requests awaiting or reading their response, per host",
+        _ => name,
+    }
 }
@@ -1,29 +1,40 @@
 use std::sync::Arc;
 
 pub use cas_client::Client;
-use cas_client::{LocalClient, RemoteClient};
+use cas_client::{ChunkHashSource, ClientBuilder, HttpClientConfig};
 use xet_threadpool::ThreadPool;
 
 use crate::configurations::*;
 use crate::errors::Result;
+use crate::metrics::InstalledMetricsSink;
 
 pub(crate) fn create_remote_client(
     config: &TranslatorConfig,
     threadpool: Arc<ThreadPool>,
     dry_run: bool,
+    chunk_hash_source: Option<ChunkHashSource>,
 ) -> Result<Arc<dyn Client + Send + Sync>> {
     let cas_storage_config = &config.data_config;
 
-    match cas_storage_config.endpoint {
-        Endpoint::Server(ref endpoint) => Ok(Arc::new(RemoteClient::new(
-            threadpool,
-            endpoint,
-            cas_storage_config.compression,
-            &cas_storage_config.auth,
-            &Some(cas_storage_config.cache_config.clone()),
-            config.shard_config.cache_directory.clone(),
-            dry_run,
-        ))),
-        Endpoint::FileSystem(ref path) => Ok(Arc::new(LocalClient::new(path, None)?)),
-    }
+    let builder = match cas_storage_config.endpoint {
+        Endpoint::Server(ref endpoint) => ClientBuilder::new().with_endpoint(endpoint),
+        Endpoint::FileSystem(ref path) => ClientBuilder::new().with_local_path(path),
+    };
+    Ok(builder
+        .with_compression(cas_storage_config.compression)
+        .with_auth(cas_storage_config.auth.clone())
+        .with_cache_config(Some(cas_storage_config.cache_config.clone()))
+        .with_shard_cache_directory(config.shard_config.cache_directory.clone())
+        .with_max_reconstruction_terms(cas_storage_config.max_reconstruction_terms)
+        .with_max_response_bytes(cas_storage_config.max_response_bytes)
+        .with_version_validation(cas_storage_config.validate_file_version)
+        .with_durable_writes(cas_storage_config.durable_writes)
+        .with_chunk_verification(chunk_hash_source)
+        .with_http_config(
+            HttpClientConfig::default()
+                .with_metrics_sink(Some(Arc::new(InstalledMetricsSink)))
+                .with_extra_headers(cas_storage_config.extra_headers.clone())?,
+        )
+        .with_dry_run(dry_run)
+        .build(threadpool)?)
 }
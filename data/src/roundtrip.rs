@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cas_client::{FileProvider, MemoryLocalClient, OutputProvider};
+use xet_threadpool::ThreadPool;
+
+use crate::configurations::TranslatorConfig;
+use crate::errors::{DataProcessingError, Result};
+use crate::{FileDownloader, FileUploadSession};
+
+/// What a `roundtrip` transferred and how long each half of it took.
+#[derive(Debug, Clone, Default)]
+pub struct RoundTripReport {
+    /// The size of the data.
+    pub data_bytes: u64,
+    /// The bytes of xorbs and shards the upload sent to the client.
+    pub upload_bytes: u64,
+    /// The bytes the download wrote back.
+    pub download_bytes: u64,
+    /// The fraction of the data deduplicated by the upload, against itself.
+    pub dedup_ratio: f64,
+    /// The time from starting the upload session to finalizing it.
+    pub upload_duration: Duration,
+    /// The time to download the data back, not counting reading it for the comparison.
+    pub download_duration: Duration,
+}
+
+impl RoundTripReport {
+    /// The data bytes uploaded per second.
+    pub fn upload_throughput(&self) -> f64 {
+        self.data_bytes as f64 / self.upload_duration.as_secs_f64()
+    }
+
+    /// The data bytes downloaded per second.
+    pub fn download_throughput(&self) -> f64 {
+        self.download_bytes as f64 / self.download_duration.as_secs_f64()
+    }
+}
+
+/// Uploads `data` as a file to an in-memory client with the chunking and dedup settings of `config`,
+/// downloads it back and checks that it's unchanged, reporting the bytes transferred and the time
+/// taken; a single entry point for measuring the throughput of the data path.
+///
+/// The shard session and cache directories of `config` are used as in any upload, and the download
+/// goes through a file in a temporary directory.
+pub async fn roundtrip(data: &[u8], config: Arc<TranslatorConfig>) -> Result<RoundTripReport> {
+    let client = Arc::new(MemoryLocalClient::new());
+
+    let upload_start = Instant::now();
+    let session =
+        FileUploadSession::new_with_client(config.clone(), ThreadPool::from_current_runtime(), client.clone()).await?;
+    let mut cleaner = session.start_clean("roundtrip".to_string());
+    cleaner.add_data(data).await?;
+    let (pointer_file, _) = cleaner.finish().await?;
+    let metrics = session.finalize().await?;
+    let upload_duration = upload_start.elapsed();
+
+    let output_dir = tempfile::tempdir()?;
+    let output_path = output_dir.path().join("roundtrip");
+    let downloader = FileDownloader::new_with_client(config, client);
+    let download_start = Instant::now();
+    let download_bytes = downloader
+        .smudge_file_from_pointer(
+            &pointer_file,
+            &OutputProvider::File(FileProvider::new(output_path.clone())),
+            None,
+            None,
+        )
+        .await?;
+    let download_duration = download_start.elapsed();
+
+    if std::fs::read(&output_path)? != data {
+        return Err(DataProcessingError::InternalError(format!(
+            "round trip of {} bytes downloaded different data",
+            data.len()
+        )));
+    }
+
+    Ok(RoundTripReport {
+        data_bytes: data.len() as u64,
+        upload_bytes: metrics.total_bytes_uploaded as u64,
+        download_bytes,
+        dedup_ratio: if metrics.total_bytes == 0 {
+            0.
+        } else {
+            metrics.deduped_bytes as f64 / metrics.total_bytes as f64
+        },
+        upload_duration,
+        download_duration,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_roundtrip_report() {
+        let temp_dir = tempdir().unwrap();
+        let config = TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap();
+
+        // The second half repeats the first, so about half the data is deduplicated.
+        let mut half = vec![0u8; 1 << 20];
+        StdRng::seed_from_u64(0).fill_bytes(&mut half);
+        let data = [half.clone(), half].concat();
+
+        let report = roundtrip(&data, config).await.unwrap();
+        assert_eq!(report.data_bytes, data.len() as u64);
+        assert_eq!(report.download_bytes, data.len() as u64);
+        assert!(report.upload_bytes > 0 && report.upload_bytes < data.len() as u64, "{report:?}");
+        assert!(report.dedup_ratio > 0.4 && report.dedup_ratio <= 0.5, "{report:?}");
+        assert!(report.upload_duration > Duration::ZERO && report.download_duration > Duration::ZERO);
+        assert!(report.upload_throughput() > 0. && report.download_throughput() > 0.);
+    }
+}
@@ -134,6 +134,7 @@ impl SessionShardInterface {
             let cache_shard_manager = self.cache_shard_manager.clone();
             let shard_bytes_uploaded = shard_bytes_uploaded.clone();
             let dry_run = self.dry_run;
+            let force_sync = self.config.shard_config.force_sync;
 
             // Acquire a permit for uploading before we spawn the task; the acquired permit is dropped after the task
             // completes. The chosen Semaphore is fair, meaning xorbs added first will be scheduled to upload first.
@@ -156,7 +157,7 @@ impl SessionShardInterface {
 
                 // Upload the shard.
                 shard_client
-                    .upload_shard(&shard_prefix, &si.shard_hash, false, &data, &salt)
+                    .upload_shard(&shard_prefix, &si.shard_hash, force_sync, &data, &salt)
                     .await?;
 
                 // Done with the upload, drop the permit.
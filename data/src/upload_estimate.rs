@@ -0,0 +1,322 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+
+use cas_client::Client;
+use deduplication::constants::{MAX_XORB_BYTES, MAX_XORB_CHUNKS};
+use deduplication::{Chunk, Chunker};
+use error_printer::ErrorPrinter;
+use mdb_shard::{hash_is_global_dedup_eligible, ShardFileManager};
+use merklehash::MerkleHash;
+use xet_threadpool::ThreadPool;
+
+use crate::configurations::{GlobalDedupPolicy, TranslatorConfig};
+use crate::constants::INGESTION_BLOCK_SIZE;
+use crate::errors::{DataProcessingError, Result};
+use crate::remote_client_interface::create_remote_client;
+
+/// An estimate of what uploading a set of files would transfer; see `estimate_upload`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UploadEstimate {
+    /// The total size of the files.
+    pub total_bytes: u64,
+    /// The estimated size of the new data to upload, before compression.
+    pub bytes_to_upload: u64,
+    /// The estimated size of the data deduplicated, against the other files of the upload or against
+    /// data already stored.
+    pub bytes_deduped: u64,
+    /// The estimated number of xorbs the new data is packed into.
+    pub num_xorbs: u64,
+}
+
+impl UploadEstimate {
+    /// The estimated fraction of the data that is deduplicated, or 0 if there is no data.
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            0.
+        } else {
+            self.bytes_deduped as f64 / self.total_bytes as f64
+        }
+    }
+}
+
+/// Estimates what uploading `file_paths` with `config` would transfer, without uploading anything.
+///
+/// Every file is chunked, and chunks repeated within the files are counted exactly.  Of the other
+/// chunks, only a sample of about `sample_rate` of them, picked by hash, is probed against the local
+/// shard cache and, as during an upload, the server's global dedup; the deduplicated fraction of the
+/// sampled bytes is then applied to all of them.  Shards fetched by global dedup probes are kept in the
+/// shard cache, as they are during an upload.
+pub async fn estimate_upload(
+    config: Arc<TranslatorConfig>,
+    threadpool: Arc<ThreadPool>,
+    file_paths: &[String],
+    sample_rate: f64,
+) -> Result<UploadEstimate> {
+    if !(sample_rate > 0. && sample_rate <= 1.) {
+        return Err(DataProcessingError::ParameterError(format!(
+            "invalid sample rate {sample_rate}: expected a fraction in (0, 1]"
+        )));
+    }
+
+    let mut tally = EstimateTally {
+        prober: DedupProber::new(config.clone(), threadpool).await?,
+        sample_rate,
+        seen_chunks: HashSet::new(),
+        total_bytes: 0,
+        unique: ByteCount::default(),
+        sampled: ByteCount::default(),
+        sampled_known: ByteCount::default(),
+    };
+
+    let mut buffer = vec![0u8; *INGESTION_BLOCK_SIZE];
+    for path in file_paths {
+        let mut file = File::open(path)?;
+        let mut chunker = Chunker::from_config(config.data_config.chunker_config);
+        let mut first_chunk = true;
+        loop {
+            let num_read = file.read(&mut buffer)?;
+            if num_read == 0 {
+                break;
+            }
+            for chunk in chunker.next_block(&buffer[..num_read], false) {
+                tally.add_chunk(&chunk, first_chunk).await?;
+                first_chunk = false;
+            }
+        }
+        if let Some(chunk) = chunker.finish() {
+            tally.add_chunk(&chunk, first_chunk).await?;
+        }
+    }
+
+    Ok(tally.estimate())
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct ByteCount {
+    bytes: u64,
+    chunks: u64,
+}
+
+impl ByteCount {
+    fn add(&mut self, chunk: &Chunk) {
+        self.bytes += chunk.data.len() as u64;
+        self.chunks += 1;
+    }
+}
+
+struct EstimateTally {
+    prober: DedupProber,
+    sample_rate: f64,
+    seen_chunks: HashSet<MerkleHash>,
+    total_bytes: u64,
+    /// The chunks seen once in the upload.
+    unique: ByteCount,
+    /// The unique chunks probed for dedup, and those of them already stored.
+    sampled: ByteCount,
+    sampled_known: ByteCount,
+}
+
+impl EstimateTally {
+    async fn add_chunk(&mut self, chunk: &Chunk, first_in_file: bool) -> Result<()> {
+        self.total_bytes += chunk.data.len() as u64;
+        if !self.seen_chunks.insert(chunk.hash) {
+            return Ok(());
+        }
+        self.unique.add(chunk);
+
+        // Sample by hash, so that the same chunks are sampled in every estimate.
+        if (chunk.hash[0] as f64) < self.sample_rate * u64::MAX as f64 {
+            self.sampled.add(chunk);
+            if self.prober.is_known(&chunk.hash, first_in_file).await? {
+                self.sampled_known.add(chunk);
+            }
+        }
+        Ok(())
+    }
+
+    fn estimate(&self) -> UploadEstimate {
+        let new_fraction = |known: u64, sampled: u64| {
+            if sampled == 0 {
+                1.
+            } else {
+                1. - known as f64 / sampled as f64
+            }
+        };
+        let bytes_to_upload =
+            (self.unique.bytes as f64 * new_fraction(self.sampled_known.bytes, self.sampled.bytes)).round() as u64;
+        let new_chunks =
+            (self.unique.chunks as f64 * new_fraction(self.sampled_known.chunks, self.sampled.chunks)).round() as u64;
+
+        let num_xorbs = if bytes_to_upload == 0 {
+            0
+        } else {
+            bytes_to_upload
+                .div_ceil(*MAX_XORB_BYTES as u64)
+                .max(new_chunks.div_ceil(*MAX_XORB_CHUNKS as u64))
+        };
+
+        UploadEstimate {
+            total_bytes: self.total_bytes,
+            bytes_to_upload,
+            bytes_deduped: self.total_bytes - bytes_to_upload,
+            num_xorbs,
+        }
+    }
+}
+
+/// Looks up whether chunks are already stored, the way an upload session deduplicates them.
+struct DedupProber {
+    config: Arc<TranslatorConfig>,
+    client: Arc<dyn Client + Send + Sync>,
+    cache_shard_manager: Arc<ShardFileManager>,
+}
+
+impl DedupProber {
+    async fn new(config: Arc<TranslatorConfig>, threadpool: Arc<ThreadPool>) -> Result<Self> {
+        let client = create_remote_client(&config, threadpool, true, None)?;
+        let cache_shard_manager =
+            ShardFileManager::new_in_cache_directory(&config.shard_config.cache_directory).await?;
+        Ok(Self {
+            config,
+            client,
+            cache_shard_manager,
+        })
+    }
+
+    /// Returns whether the chunk is in the shard cache, querying the server's global dedup for it
+    /// first if an upload would.
+    async fn is_known(&self, chunk_hash: &MerkleHash, first_in_file: bool) -> Result<bool> {
+        if self.cache_shard_manager.chunk_hash_dedup_query(&[*chunk_hash]).await?.is_some() {
+            return Ok(true);
+        }
+
+        let global_dedup_eligible = matches!(self.config.shard_config.global_dedup_policy, GlobalDedupPolicy::Always)
+            && (first_in_file || hash_is_global_dedup_eligible(chunk_hash));
+        if !global_dedup_eligible {
+            return Ok(false);
+        }
+
+        let Ok(Some(shard_file)) = self
+            .client
+            .query_for_global_dedup_shard(
+                &self.config.shard_config.prefix,
+                chunk_hash,
+                &self.config.shard_config.repo_salt,
+            )
+            .await
+            .info_error("Error attempting to query global dedup lookup.")
+        else {
+            return Ok(false);
+        };
+        self.cache_shard_manager.register_shards_by_path(&[shard_file]).await?;
+
+        Ok(self.cache_shard_manager.chunk_hash_dedup_query(&[*chunk_hash]).await?.is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use deduplication::ChunkerConfig;
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::local_cache::cache_stats;
+    use crate::{FileUploadSession, XorbProgressCallback};
+
+    fn random_bytes(seed: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        StdRng::seed_from_u64(seed).fill_bytes(&mut data);
+        data
+    }
+
+    /// Uploads the files in one session, returning the bytes of new data and the number of xorbs.
+    async fn upload(config: Arc<TranslatorConfig>, file_paths: &[String]) -> (u64, u64) {
+        let num_xorbs = Arc::new(AtomicU64::new(0));
+        let xorb_counter = num_xorbs.clone();
+        let callback: XorbProgressCallback = Arc::new(move |_, _, _, _| {
+            xorb_counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let session =
+            FileUploadSession::new_with_xorb_progress(config, ThreadPool::from_current_runtime(), None, Some(callback))
+                .await
+                .unwrap();
+        for path in file_paths {
+            let mut cleaner = session.start_clean(path.clone());
+            cleaner.add_data(&std::fs::read(path).unwrap()).await.unwrap();
+            cleaner.finish().await.unwrap();
+        }
+        let metrics = session.finalize().await.unwrap();
+        (metrics.new_bytes as u64, num_xorbs.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_estimate_matches_upload() {
+        const MB: usize = 1 << 20;
+
+        let temp_dir = tempdir().unwrap();
+        // Small chunks, so that the new data takes more than one xorb.
+        let mut config = Arc::try_unwrap(TranslatorConfig::local_config(temp_dir.path().join("cas")).unwrap()).unwrap();
+        config
+            .set_chunker_config(ChunkerConfig {
+                mask_bits: 9,
+                minimum_chunk: 128,
+                maximum_chunk: 2048,
+            })
+            .unwrap();
+        let config = Arc::new(config);
+
+        let write = |name: &str, data: &[u8]| {
+            let path = temp_dir.path().join(name);
+            std::fs::write(&path, data).unwrap();
+            path.to_string_lossy().to_string()
+        };
+
+        // A file already uploaded, then a new version of it with a changed section, a new file, a copy
+        // of it and another new file.
+        let base = random_bytes(0, 4 * MB);
+        upload(config.clone(), &[write("base.bin", &base)]).await;
+        let mut changed = base;
+        changed[MB..MB + MB / 2].copy_from_slice(&random_bytes(1, MB / 2));
+        let new_data = random_bytes(2, 4 * MB);
+        let file_paths = vec![
+            write("changed.bin", &changed),
+            write("new.bin", &new_data),
+            write("copy.bin", &new_data),
+            write("other.bin", &random_bytes(3, 4 * MB)),
+        ];
+
+        let cache_before = cache_stats(&config).unwrap();
+        let threadpool = ThreadPool::from_current_runtime();
+        let full = estimate_upload(config.clone(), threadpool.clone(), &file_paths, 1.)
+            .await
+            .unwrap();
+        let sampled = estimate_upload(config.clone(), threadpool.clone(), &file_paths, 0.25)
+            .await
+            .unwrap();
+        assert_eq!(cache_stats(&config).unwrap(), cache_before);
+
+        let (new_bytes, num_xorbs) = upload(config.clone(), &file_paths).await;
+
+        for estimate in [full, sampled] {
+            assert_eq!(estimate.total_bytes, 16 * MB as u64);
+            assert_eq!(estimate.bytes_to_upload + estimate.bytes_deduped, estimate.total_bytes);
+            assert!(
+                estimate.bytes_to_upload.abs_diff(new_bytes) < estimate.total_bytes / 20,
+                "{estimate:?} vs {new_bytes} new bytes"
+            );
+            assert_eq!(estimate.num_xorbs, num_xorbs);
+        }
+        assert!(full.dedup_ratio() > 0.4 && full.dedup_ratio() < 0.5);
+
+        assert!(matches!(
+            estimate_upload(config, threadpool, &file_paths, 0.).await,
+            Err(DataProcessingError::ParameterError(_))
+        ));
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::errors::Result;
+use crate::PointerFile;
+
+/// The size and modification time of a file, which tell whether it changed since it was uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileStamp {
+    size: u64,
+    mtime_ns: u64,
+}
+
+impl FileStamp {
+    pub(crate) fn read(path: &str) -> std::io::Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let mtime_ns = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| u64::try_from(d.as_nanos()).unwrap_or(u64::MAX));
+        Ok(Self {
+            size: metadata.len(),
+            mtime_ns,
+        })
+    }
+}
+
+/// An on-disk record of the files of an upload that are fully uploaded, so that an interrupted upload
+/// can be resumed without processing them again.
+///
+/// Each line holds the path, size, modification time and hash of one file as a JSON object.  A file
+/// is only skipped while its size and modification time match its entry.  Lines that can't be parsed,
+/// such as a line cut short by a crash, are ignored.
+pub(crate) struct UploadJournal {
+    entries: HashMap<String, (FileStamp, String)>,
+    file: File,
+}
+
+impl UploadJournal {
+    /// Opens the journal at `path`, creating it if it doesn't exist.
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        let mut entries = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    match parse_entry(&line) {
+                        Some((file_path, stamp, hash)) => {
+                            entries.insert(file_path, (stamp, hash));
+                        },
+                        None => warn!("Ignoring malformed entry in upload journal {path:?}: {line:?}"),
+                    }
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {},
+            Err(e) => return Err(e.into()),
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { entries, file })
+    }
+
+    /// Returns the pointer file of `path` if the file was uploaded and hasn't changed since.
+    pub(crate) fn completed(&self, path: &str) -> Option<PointerFile> {
+        let (stamp, hash) = self.entries.get(path)?;
+        (FileStamp::read(path).ok()? == *stamp).then(|| PointerFile::init_from_info(path, hash, stamp.size))
+    }
+
+    /// Records the given files, each with its stamp from before it was read, as uploaded.  Only call
+    /// this once their data is durably stored, i.e. after their upload session is finalized.
+    pub(crate) fn record<'a>(
+        &mut self,
+        uploads: impl IntoIterator<Item = (&'a str, FileStamp, &'a PointerFile)>,
+    ) -> Result<()> {
+        let mut lines = String::new();
+        for (path, stamp, pointer_file) in uploads {
+            let entry = json!({
+                "path": path,
+                "size": stamp.size,
+                "mtime_ns": stamp.mtime_ns,
+                "hash": pointer_file.hash_string(),
+            });
+            lines.push_str(&entry.to_string());
+            lines.push('\n');
+            self.entries
+                .insert(path.to_string(), (stamp, pointer_file.hash_string().clone()));
+        }
+
+        self.file.write_all(lines.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+}
+
+fn parse_entry(line: &str) -> Option<(String, FileStamp, String)> {
+    let entry: Value = serde_json::from_str(line).ok()?;
+    let stamp = FileStamp {
+        size: entry["size"].as_u64()?,
+        mtime_ns: entry["mtime_ns"].as_u64()?,
+    };
+    Some((entry["path"].as_str()?.to_string(), stamp, entry["hash"].as_str()?.to_string()))
+}
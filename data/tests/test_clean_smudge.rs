@@ -169,6 +169,14 @@ async fn check_clean_smudge_files(file_list: &[(impl AsRef<str>, usize)]) {
     check_directories_match(&src_dir, &dest_dir);
 }
 
+/// Counts the xorbs stored by the local CAS client in `cas_dir`.
+fn count_local_xorbs(cas_dir: &Path) -> usize {
+    read_dir(cas_dir.join("xet").join("xorbs").join("xorbs"))
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("default."))
+        .count()
+}
+
 fn setup_env() {}
 
 #[cfg(test)]
@@ -233,4 +241,33 @@ mod tests {
         setup_env();
         check_clean_smudge_files(&files).await;
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_many_tiny_files_share_xorbs() {
+        setup_env();
+        let n = 1000;
+
+        let _temp_dir = TempDir::new().unwrap();
+        let temp_path = _temp_dir.path();
+
+        let cas_dir = temp_path.join("cas");
+        let src_dir = temp_path.join("src");
+        let ptr_dir = temp_path.join("pointers");
+        let dest_dir = temp_path.join("dest");
+
+        // Distinct contents so that nothing is deduplicated away.
+        for idx in 0..n {
+            create_random_file(&src_dir, &format!("f_{idx}"), 64, idx as u64);
+        }
+
+        dehydrate_directory(&cas_dir, &src_dir, &ptr_dir).await;
+
+        // Each tiny file is a single chunk, so the chunks of many files are packed into each xorb.
+        let num_xorbs = count_local_xorbs(&cas_dir);
+        assert!(num_xorbs > 0);
+        assert!(num_xorbs <= 2 * n / *MAX_XORB_CHUNKS, "{num_xorbs} xorbs for {n} tiny files");
+
+        hydrate_directory(&cas_dir, &ptr_dir, &dest_dir).await;
+        check_directories_match(&src_dir, &dest_dir);
+    }
 }
@@ -11,8 +11,59 @@ pub struct Chunk {
     pub data: Arc<[u8]>,
 }
 
+/// The parameters controlling where the chunker places chunk boundaries.
+///
+/// Changing any of these changes the chunk boundaries, and hence the chunk hashes,
+/// of all data; they must match between everything that is expected to dedup
+/// against each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkerConfig {
+    /// Number of bits in the boundary mask.  A boundary is placed where the highest
+    /// `mask_bits` bits of the rolling gear hash are all zero, so the expected
+    /// (target) chunk size is `2^mask_bits` bytes.  Must be in `7..=31`, i.e. the
+    /// target must exceed the 64 byte hash window.
+    pub mask_bits: u32,
+
+    /// The minimum chunk size.  The chunker skips this many bytes, less the 64 byte
+    /// hash window, at the start of each chunk before looking for a boundary, so
+    /// chunks other than the last one are at least `minimum_chunk - 64` bytes.
+    pub minimum_chunk: usize,
+
+    /// A boundary is always placed when a chunk reaches this many bytes.  Must be
+    /// larger than `minimum_chunk`.
+    pub maximum_chunk: usize,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        Self::from_target_chunk_size(*TARGET_CHUNK_SIZE)
+    }
+}
+
+impl ChunkerConfig {
+    /// The configuration for a power-of-two target chunk size, with the minimum and
+    /// maximum chunk sizes derived from it as for the default chunker.
+    pub fn from_target_chunk_size(target_chunk_size: usize) -> Self {
+        assert_eq!(target_chunk_size.count_ones(), 1);
+
+        Self {
+            mask_bits: target_chunk_size.trailing_zeros(),
+            minimum_chunk: target_chunk_size / *MINIMUM_CHUNK_DIVISOR,
+            maximum_chunk: target_chunk_size * *MAXIMUM_CHUNK_MULTIPLIER,
+        }
+    }
+
+    pub fn target_chunk_size(&self) -> usize {
+        1 << self.mask_bits
+    }
+}
+
 /// Chunk Generator given an input stream. Do not use directly.
 /// Use `chunk_target_default`.
+///
+/// Chunking is a single loop over the input with the chunk being built in a heap buffer, so it
+/// takes a small, constant amount of stack; it runs on threadpool threads, whose stack may be as
+/// small as 2MB.  Hashing a chunk recurses in blake3 only to a depth logarithmic in the chunk size.
 pub struct Chunker {
     // configs
     hash: gearhash::Hasher<'static>,
@@ -27,30 +78,36 @@ pub struct Chunker {
 
 impl Default for Chunker {
     fn default() -> Self {
-        Self::new(*TARGET_CHUNK_SIZE)
+        Self::from_config(ChunkerConfig::default())
     }
 }
 
 impl Chunker {
     pub fn new(target_chunk_size: usize) -> Self {
-        assert_eq!(target_chunk_size.count_ones(), 1);
+        Self::from_config(ChunkerConfig::from_target_chunk_size(target_chunk_size))
+    }
+
+    /// Creates a chunker with explicit boundary parameters; see [`ChunkerConfig`].
+    pub fn from_config(config: ChunkerConfig) -> Self {
+        let ChunkerConfig {
+            mask_bits,
+            minimum_chunk,
+            maximum_chunk,
+        } = config;
 
         // Some of the logic only works if the target_chunk_size is greater than the
         // window size of the hash.
-        assert!(target_chunk_size > 64);
+        assert!(mask_bits > 6);
 
-        // note the strict lesser than. Combined with count_ones() == 1,
-        // this limits to 2^31
-        assert!(target_chunk_size < u32::MAX as usize);
+        // this limits the target chunk size to 2^31
+        assert!(mask_bits < 32);
 
-        let mask = (target_chunk_size - 1) as u64;
+        let mask = (1u64 << mask_bits) - 1;
 
         // we will like to shift the mask left by a bunch since the right
         // bits of the gear hash are affected by only a small number of bytes
         // really. we just shift it all the way left.
         let mask = mask << mask.leading_zeros();
-        let minimum_chunk = target_chunk_size / *MINIMUM_CHUNK_DIVISOR;
-        let maximum_chunk = target_chunk_size * *MAXIMUM_CHUNK_MULTIPLIER;
 
         assert!(maximum_chunk > minimum_chunk);
 
@@ -277,6 +334,62 @@ mod tests {
         assert_eq!(chunks_1, chunks_2);
     }
 
+    /// Returns the end offsets of the chunks.
+    fn chunk_boundaries(chunks: &[Chunk]) -> Vec<usize> {
+        chunks
+            .iter()
+            .scan(0, |end, c| {
+                *end += c.data.len();
+                Some(*end)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_config_from_target_chunk_size() {
+        let config = ChunkerConfig::from_target_chunk_size(*TARGET_CHUNK_SIZE);
+        assert_eq!(config, ChunkerConfig::default());
+        assert_eq!(config.target_chunk_size(), *TARGET_CHUNK_SIZE);
+
+        // An explicit config equal to the derived one chunks identically.
+        let data = make_test_data(3, 100000);
+        let explicit = ChunkerConfig {
+            mask_bits: 10,
+            minimum_chunk: 1024 / *MINIMUM_CHUNK_DIVISOR,
+            maximum_chunk: 1024 * *MAXIMUM_CHUNK_MULTIPLIER,
+        };
+        assert_eq!(Chunker::from_config(explicit).next_block(&data, true), Chunker::new(1024).next_block(&data, true));
+    }
+
+    #[test]
+    fn test_exact_chunk_boundaries() {
+        // A fixed pseudo-random byte pattern from a xorshift generator, independent of any
+        // external rng implementation.
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let data: Vec<u8> = (0..8192)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 56) as u8
+            })
+            .collect();
+
+        let config = ChunkerConfig {
+            mask_bits: 8,
+            minimum_chunk: 96,
+            maximum_chunk: 512,
+        };
+        let chunks = Chunker::from_config(config).next_block(&data, true);
+        check_chunks_equal(&chunks, &data);
+
+        let expected_boundaries = vec![
+            49, 346, 858, 1124, 1156, 1202, 1583, 2095, 2127, 2159, 2191, 2703, 3215, 3413, 3498, 4010, 4457, 4802,
+            4988, 5239, 5271, 5433, 5728, 5760, 6272, 6682, 7062, 7360, 7779, 7858, 8157, 8192,
+        ];
+        assert_eq!(chunk_boundaries(&chunks), expected_boundaries);
+    }
+
     #[test]
     fn test_exact_maximum_chunk() {
         // If the data hits the maximum chunk size exactly, we should force a boundary.
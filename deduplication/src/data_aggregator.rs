@@ -1,3 +1,5 @@
+use std::collections::HashSet;
+
 use mdb_shard::file_structs::MDBFileInfo;
 use merklehash::MerkleHash;
 use more_asserts::*;
@@ -14,6 +16,10 @@ pub struct DataAggregator {
     // Number of bytes
     num_bytes: usize,
 
+    // The hashes of all the chunks above, allowing other files to check for deduplication against
+    // data that has not yet been cut into a xorb.
+    chunk_hashes: HashSet<MerkleHash>,
+
     // The file info of files that are still being processed.
     // As we're building this up, we assume that all files that do not have a size in the header are
     // not finished yet and thus cannot be uploaded.
@@ -33,9 +39,11 @@ impl DataAggregator {
         internally_referencing_entries: Vec<usize>,
     ) -> Self {
         let num_bytes = chunks.iter().map(|c| c.data.len()).sum();
+        let chunk_hashes = chunks.iter().map(|c| c.hash).collect();
         Self {
             chunks,
             num_bytes,
+            chunk_hashes,
             pending_file_info: vec![(pending_file_info, internally_referencing_entries)],
         }
     }
@@ -53,6 +61,11 @@ impl DataAggregator {
         self.num_bytes
     }
 
+    /// Returns true if a chunk with the given hash is part of the data held here.
+    pub fn contains_chunk(&self, chunk_hash: &MerkleHash) -> bool {
+        self.chunk_hashes.contains(chunk_hash)
+    }
+
     /// Finalize the result, returning the CAS info, xorb data, and the file info that's included in this.
     pub fn finalize(mut self) -> (RawXorbData, Vec<MDBFileInfo>) {
         // First, cut the xorb for this one.
@@ -92,6 +105,7 @@ impl DataAggregator {
         let shift = self.chunks.len() as u32;
         self.chunks.append(&mut other.chunks);
         self.num_bytes += other.num_bytes;
+        self.chunk_hashes.extend(other.chunk_hashes.drain());
 
         // Adjust the chunk indices and shifts for
         for file_info in other.pending_file_info.iter_mut() {
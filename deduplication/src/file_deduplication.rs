@@ -17,6 +17,24 @@ use crate::interface::DeduplicationDataInterface;
 use crate::raw_xorb_data::RawXorbData;
 use crate::Chunk;
 
+/// Computes the hash of a file from its chunks, as `FileDeduper` does, without deduplicating them.
+#[derive(Default)]
+pub struct FileHasher {
+    chunk_hashes: Vec<(MerkleHash, usize)>,
+}
+
+impl FileHasher {
+    /// Adds the next chunks of the file, in order.
+    pub fn add_chunks(&mut self, chunks: &[Chunk]) {
+        self.chunk_hashes.extend(chunks.iter().map(|c| (c.hash, c.data.len())));
+    }
+
+    /// The hash of the file of the chunks added so far, salted with `file_hash_salt`.
+    pub fn finalize(&self, file_hash_salt: &[u8; 32]) -> MerkleHash {
+        file_node_hash(&self.chunk_hashes, file_hash_salt).unwrap()
+    }
+}
+
 pub struct FileDeduper<DataInterfaceType: DeduplicationDataInterface> {
     data_mng: DataInterfaceType,
 
@@ -30,7 +48,7 @@ pub struct FileDeduper<DataInterfaceType: DeduplicationDataInterface> {
     new_data_hash_lookup: HashMap<MerkleHash, usize>,
 
     /// The current chunk hashes for this file.
-    chunk_hashes: Vec<(MerkleHash, usize)>,
+    file_hasher: FileHasher,
 
     /// The current file data entries.
     file_info: Vec<FileDataSequenceEntry>,
@@ -62,7 +80,7 @@ impl<DataInterfaceType: DeduplicationDataInterface> FileDeduper<DataInterfaceTyp
             new_data: Vec::new(),
             new_data_size: 0,
             new_data_hash_lookup: HashMap::new(),
-            chunk_hashes: Vec::new(),
+            file_hasher: FileHasher::default(),
             file_info: Vec::new(),
             internally_referencing_entries: Vec::new(),
             defrag_tracker: DefragPrevention::default(),
@@ -81,7 +99,7 @@ impl<DataInterfaceType: DeduplicationDataInterface> FileDeduper<DataInterfaceTyp
         let mut dedup_metrics = DeduplicationMetrics::default();
 
         // All the previous chunk are stored here, use it as the global chunk index start.
-        let global_chunk_index_start = self.chunk_hashes.len();
+        let global_chunk_index_start = self.file_hasher.chunk_hashes.len();
 
         let chunk_hashes = Vec::from_iter(chunks.iter().map(|c| c.hash));
 
@@ -242,7 +260,7 @@ impl<DataInterfaceType: DeduplicationDataInterface> FileDeduper<DataInterfaceTyp
         }
 
         self.deduplication_metrics.merge_in(&dedup_metrics);
-        self.chunk_hashes.extend(chunks.iter().map(|c| (c.hash, c.data.len())));
+        self.file_hasher.add_chunks(chunks);
 
         Ok(dedup_metrics)
     }
@@ -348,7 +366,7 @@ impl<DataInterfaceType: DeduplicationDataInterface> FileDeduper<DataInterfaceTyp
         file_hash_salt: [u8; 32],
         metadata_ext: Option<FileMetadataExt>,
     ) -> (MerkleHash, DataAggregator, DeduplicationMetrics, Vec<MerkleHash>) {
-        let file_hash = file_node_hash(&self.chunk_hashes, &file_hash_salt).unwrap();
+        let file_hash = self.file_hasher.finalize(&file_hash_salt);
 
         let metadata = FileDataSequenceHeader::new(file_hash, self.file_info.len(), true, metadata_ext.is_some());
 
@@ -360,7 +378,7 @@ impl<DataInterfaceType: DeduplicationDataInterface> FileDeduper<DataInterfaceTyp
             .iter()
             .map(|entry| {
                 let n_chunks = (entry.chunk_index_end - entry.chunk_index_start) as usize;
-                let chunk_hashes: Vec<_> = self.chunk_hashes[chunk_idx..chunk_idx + n_chunks]
+                let chunk_hashes: Vec<_> = self.file_hasher.chunk_hashes[chunk_idx..chunk_idx + n_chunks]
                     .iter()
                     .map(|(hash, _)| *hash)
                     .collect();
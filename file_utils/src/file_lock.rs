@@ -0,0 +1,78 @@
+use std::fs::File;
+
+/// Takes an exclusive advisory lock on `file` if no other open file holds one, without waiting.
+/// Returns false if the lock is held elsewhere.  The lock is released when the file is closed.
+///
+/// The lock is held by the open file rather than by the process, so two opens of the same path
+/// exclude each other even within a process.
+#[cfg(unix)]
+pub fn try_lock_exclusive(file: &File) -> std::io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let ret = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let err = std::io::Error::last_os_error();
+    if err.kind() == std::io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// Takes an exclusive advisory lock on `file` if no other open file holds one, without waiting.
+/// Returns false if the lock is held elsewhere.  The lock is released when the file is closed.
+///
+/// The lock is held by the open file rather than by the process, so two opens of the same path
+/// exclude each other even within a process.
+#[cfg(windows)]
+pub fn try_lock_exclusive(file: &File) -> std::io::Result<bool> {
+    use std::os::windows::io::AsRawHandle;
+
+    use winapi::shared::winerror::ERROR_LOCK_VIOLATION;
+    use winapi::um::fileapi::LockFileEx;
+    use winapi::um::minwinbase::{LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY, OVERLAPPED};
+
+    let ret = unsafe {
+        let mut overlapped: OVERLAPPED = std::mem::zeroed();
+        LockFileEx(
+            file.as_raw_handle() as _,
+            LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY,
+            0,
+            u32::MAX,
+            u32::MAX,
+            &mut overlapped,
+        )
+    };
+    if ret != 0 {
+        return Ok(true);
+    }
+    let err = std::io::Error::last_os_error();
+    if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION as i32) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+
+    use super::try_lock_exclusive;
+
+    #[test]
+    fn test_lock_excludes_other_opens() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.lock");
+        let open = || OpenOptions::new().write(true).create(true).truncate(false).open(&path).unwrap();
+
+        let held = open();
+        assert!(try_lock_exclusive(&held).unwrap());
+        assert!(!try_lock_exclusive(&open()).unwrap());
+
+        drop(held);
+        assert!(try_lock_exclusive(&open()).unwrap());
+    }
+}
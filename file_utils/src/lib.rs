@@ -1,6 +1,10 @@
+mod file_lock;
 mod file_metadata;
 mod privilege_context;
 mod safe_file_creator;
+mod sparse;
 
+pub use file_lock::try_lock_exclusive;
 pub use privilege_context::{create_dir_all, create_file, PrivilgedExecutionContext};
 pub use safe_file_creator::{write_all_safe, SafeFileCreator};
+pub use sparse::punch_hole;
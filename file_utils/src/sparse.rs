@@ -0,0 +1,34 @@
+use std::fs::File;
+
+/// Deallocates the `len` bytes of `file` at `offset`, which then read back as zeros, without
+/// changing the file's size.  Returns false, leaving the file unchanged, if the platform or the
+/// filesystem doesn't support punching holes.
+#[cfg(target_os = "linux")]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> std::io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+/// Deallocates the `len` bytes of `file` at `offset`, which then read back as zeros, without
+/// changing the file's size.  Returns false, leaving the file unchanged, if the platform or the
+/// filesystem doesn't support punching holes.
+#[cfg(not(target_os = "linux"))]
+pub fn punch_hole(_file: &File, _offset: u64, _len: u64) -> std::io::Result<bool> {
+    Ok(false)
+}
@@ -4,15 +4,20 @@ mod progress_update;
 mod runtime;
 mod token_refresh;
 
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::iter::IntoIterator;
 use std::sync::Arc;
 
+use data::configurations::ExtraHashes;
+use data::data_client::{DirectoryWalkOptions, PointerOutput, UploadOptions};
 use data::errors::DataProcessingError;
-use data::{data_client, PointerFile};
-use pyo3::exceptions::PyRuntimeError;
+use data::local_cache::CacheStats;
+use data::{data_client, DownloadStream, FileRange, PointerFile};
+use pyo3::exceptions::{PyKeyError, PyRuntimeError, PyTypeError, PyValueError};
 use pyo3::prelude::*;
 use pyo3::pyfunction;
+use pyo3::types::{PyBytes, PyDict, PyString, PyType};
 use runtime::async_run;
 use token_refresh::WrappedTokenRefresher;
 use utils::progress::ProgressUpdater;
@@ -31,8 +36,32 @@ fn convert_data_processing_error(e: DataProcessingError) -> PyErr {
     }
 }
 
+/// Uploads the given files, returning a pointer file for each.
+///
+/// With `fail_fast` (the default), the first failure raises an exception and aborts the whole batch.
+/// Otherwise, every file that can be uploaded is, and the entry for each file that failed is the
+/// exception describing the failure instead of a pointer file.
+///
+/// If `pointer_dir` is given, the pointer files are also written into that directory; otherwise, with
+/// `emit_pointers`, each uploaded file is replaced with its pointer file.
+///
+/// If `prefix` is given, the uploaded objects are stored under that namespace instead of the default
+/// one; it must be 1 to 64 ASCII letters, digits, '-' or '_'.
+///
+/// If `journal_path` is given, the files already uploaded by an interrupted call with the same journal
+/// are skipped, unless their size or modification time changed.
+///
+/// With `force_shard_sync`, the server registers the uploaded files before the call returns, so they
+/// can be downloaded right away; this makes the upload slower.
+///
+/// If `manifest_path` is given, a JSON manifest listing the path, hash, size and sha256 of every
+/// uploaded file is also written there.
+///
+/// `extra_hashes` names hashes to compute over every file while it's uploaded, besides its Merkle hash;
+/// the only one supported is "blake3", reported as the `blake3` of each pointer file.
 #[pyfunction]
-#[pyo3(signature = (file_paths, endpoint, token_info, token_refresher, progress_updater, _repo_type), text_signature = "(file_paths: List[str], endpoint: Optional[str], token_info: Optional[(str, int)], token_refresher: Optional[Callable[[], (str, int)]], progress_updater: Optional[Callable[[int], None]], _repo_type: Optional[str]) -> List[PyPointerFile]")]
+#[pyo3(signature = (file_paths, endpoint, token_info, token_refresher, progress_updater, _repo_type, fail_fast = true, emit_pointers = false, pointer_dir = None, prefix = None, journal_path = None, force_shard_sync = false, manifest_path = None, extra_hashes = None), text_signature = "(file_paths: List[str], endpoint: Optional[str], token_info: Optional[(str, int)], token_refresher: Optional[Callable[[], (str, int)]], progress_updater: Optional[Callable[[int], None]], _repo_type: Optional[str], fail_fast: bool = True, emit_pointers: bool = False, pointer_dir: Optional[str] = None, prefix: Optional[str] = None, journal_path: Optional[str] = None, force_shard_sync: bool = False, manifest_path: Optional[str] = None, extra_hashes: Optional[List[str]] = None) -> List[Union[PyPointerFile, Exception]]")]
+#[allow(clippy::too_many_arguments)]
 pub fn upload_files(
     py: Python,
     file_paths: Vec<String>,
@@ -41,33 +70,121 @@ pub fn upload_files(
     token_refresher: Option<Py<PyAny>>,
     progress_updater: Option<Py<PyAny>>,
     _repo_type: Option<String>,
-) -> PyResult<Vec<PyPointerFile>> {
+    fail_fast: bool,
+    emit_pointers: bool,
+    pointer_dir: Option<String>,
+    prefix: Option<String>,
+    journal_path: Option<String>,
+    force_shard_sync: bool,
+    manifest_path: Option<String>,
+    extra_hashes: Option<Vec<String>>,
+) -> PyResult<Vec<PyObject>> {
+    let extra_hashes =
+        ExtraHashes::from_names(&extra_hashes.unwrap_or_default()).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let pointer_output = match pointer_dir {
+        Some(dir) => Some(PointerOutput::Directory(dir.into())),
+        None => emit_pointers.then_some(PointerOutput::InPlace),
+    };
     let refresher = token_refresher.map(WrappedTokenRefresher::from_func).transpose()?.map(Arc::new);
     let updater = progress_updater
         .map(WrappedProgressUpdater::from_func)
         .transpose()?
         .map(Arc::new);
 
-    async_run(py, move |threadpool| async move {
-        let out: Vec<PyPointerFile> = data_client::upload_async(
-            threadpool,
-            file_paths,
-            endpoint,
-            token_info,
-            refresher.map(|v| v as Arc<_>),
-            updater.map(|v| v as Arc<_>),
-        )
-        .await
-        .map_err(convert_data_processing_error)?
+    let options = UploadOptions::new()
+        .with_endpoint(endpoint)
+        .with_auth(token_info, refresher.map(|v| v as Arc<_>))
+        .with_progress_updater(updater.map(|v| v as Arc<_>))
+        .with_fail_fast(fail_fast)
+        .with_pointer_output(pointer_output)
+        .with_prefix(prefix)
+        .with_journal_path(journal_path.map(Into::into))
+        .with_force_shard_sync(force_shard_sync)
+        .with_manifest_path(manifest_path.map(Into::into))
+        .with_extra_hashes(extra_hashes);
+
+    let results = async_run(py, move |threadpool| async move {
+        let out: Vec<Result<PyPointerFile, PyErr>> = data_client::upload_async(threadpool, file_paths, options)
+            .await
+            .map_err(convert_data_processing_error)?
+            .into_iter()
+            .map(|r| r.map(PyPointerFile::from).map_err(convert_data_processing_error))
+            .collect();
+        PyResult::Ok(out)
+    })?;
+
+    results
         .into_iter()
-        .map(PyPointerFile::from)
-        .collect();
+        .map(|r| match r {
+            Ok(pf) => Ok(Py::new(py, pf)?.into_any()),
+            Err(e) => Ok(e.into_value(py).into_any()),
+        })
+        .collect()
+}
+
+/// Uploads every file in the directory tree under `root`, returning a dict from the path of each file
+/// relative to `root` (with `/` separators) to its pointer file.
+///
+/// Files and directories matching any of the `ignore_globs`, by relative path or by name, are skipped.
+/// Symlinks are skipped unless `follow_symlinks` is set.  Failures are handled as in `upload_files`.
+#[pyfunction]
+#[pyo3(signature = (root, endpoint, token_info, token_refresher, progress_updater, ignore_globs = None, follow_symlinks = false, fail_fast = true, prefix = None), text_signature = "(root: str, endpoint: Optional[str], token_info: Optional[(str, int)], token_refresher: Optional[Callable[[], (str, int)]], progress_updater: Optional[Callable[[int], None]], ignore_globs: Optional[List[str]] = None, follow_symlinks: bool = False, fail_fast: bool = True, prefix: Optional[str] = None) -> Dict[str, Union[PyPointerFile, Exception]]")]
+#[allow(clippy::too_many_arguments)]
+pub fn upload_directory(
+    py: Python,
+    root: String,
+    endpoint: Option<String>,
+    token_info: Option<(String, u64)>,
+    token_refresher: Option<Py<PyAny>>,
+    progress_updater: Option<Py<PyAny>>,
+    ignore_globs: Option<Vec<String>>,
+    follow_symlinks: bool,
+    fail_fast: bool,
+    prefix: Option<String>,
+) -> PyResult<HashMap<String, PyObject>> {
+    let walk_options = DirectoryWalkOptions {
+        ignore_globs: ignore_globs.unwrap_or_default(),
+        follow_symlinks,
+    };
+    let refresher = token_refresher.map(WrappedTokenRefresher::from_func).transpose()?.map(Arc::new);
+    let updater = progress_updater
+        .map(WrappedProgressUpdater::from_func)
+        .transpose()?
+        .map(Arc::new);
+
+    let options = UploadOptions::new()
+        .with_endpoint(endpoint)
+        .with_auth(token_info, refresher.map(|v| v as Arc<_>))
+        .with_progress_updater(updater.map(|v| v as Arc<_>))
+        .with_fail_fast(fail_fast)
+        .with_prefix(prefix);
+
+    let results = async_run(py, move |threadpool| async move {
+        let out: Vec<(String, Result<PyPointerFile, PyErr>)> =
+            data_client::upload_directory(threadpool, root, walk_options, options)
+                .await
+                .map_err(convert_data_processing_error)?
+                .into_iter()
+                .map(|(path, r)| (path, r.map(PyPointerFile::from).map_err(convert_data_processing_error)))
+                .collect();
         PyResult::Ok(out)
-    })
+    })?;
+
+    results
+        .into_iter()
+        .map(|(path, r)| match r {
+            Ok(pf) => Ok((path, Py::new(py, pf)?.into_any())),
+            Err(e) => Ok((path, e.into_value(py).into_any())),
+        })
+        .collect()
 }
 
+/// Downloads the files of the given pointer files, returning their paths.
+///
+/// If `max_total_bytes` is given, the call fails without downloading anything if the files total more
+/// than that, and is aborted as soon as the bytes written pass it.
 #[pyfunction]
-#[pyo3(signature = (files, endpoint, token_info, token_refresher, progress_updater), text_signature = "(files: List[PyPointerFile], endpoint: Optional[str], token_info: Optional[(str, int)], token_refresher: Optional[Callable[[], (str, int)]], progress_updater: Optional[List[Callable[[int], None]]]) -> List[str]")]
+#[pyo3(signature = (files, endpoint, token_info, token_refresher, progress_updater, max_total_bytes = None), text_signature = "(files: List[PyPointerFile], endpoint: Optional[str], token_info: Optional[(str, int)], token_refresher: Optional[Callable[[], (str, int)]], progress_updater: Optional[List[Callable[[int], None]]], max_total_bytes: Optional[int] = None) -> List[str]")]
 pub fn download_files(
     py: Python,
     files: Vec<PyPointerFile>,
@@ -75,6 +192,7 @@ pub fn download_files(
     token_info: Option<(String, u64)>,
     token_refresher: Option<Py<PyAny>>,
     progress_updater: Option<Vec<Py<PyAny>>>,
+    max_total_bytes: Option<u64>,
 ) -> PyResult<Vec<String>> {
     let pfs = files.into_iter().map(PointerFile::from).collect();
 
@@ -89,6 +207,7 @@ pub fn download_files(
             token_info,
             refresher.map(|v| v as Arc<_>),
             updaters,
+            max_total_bytes,
         )
         .await
         .map_err(convert_data_processing_error)?;
@@ -97,6 +216,116 @@ pub fn download_files(
     })
 }
 
+/// Downloads the files described by pointer file contents, e.g. as read from stdin, writing the file
+/// of `contents[i]` to `paths[i]`.
+///
+/// Contents that aren't a valid pointer file are skipped; the entry for each of them is the exception
+/// describing why, and the entry for every other file is its path once downloaded.
+#[pyfunction]
+#[pyo3(signature = (contents, paths, endpoint, token_info, token_refresher), text_signature = "(contents: List[str], paths: List[str], endpoint: Optional[str], token_info: Optional[(str, int)], token_refresher: Optional[Callable[[], (str, int)]]) -> List[Union[str, Exception]]")]
+pub fn download_from_pointer_text(
+    py: Python,
+    contents: Vec<String>,
+    paths: Vec<String>,
+    endpoint: Option<String>,
+    token_info: Option<(String, u64)>,
+    token_refresher: Option<Py<PyAny>>,
+) -> PyResult<Vec<PyObject>> {
+    if contents.len() != paths.len() {
+        return Err(PyValueError::new_err("contents and paths must have the same length"));
+    }
+    let pointer_texts = contents.into_iter().zip(paths).collect();
+
+    let refresher = token_refresher.map(WrappedTokenRefresher::from_func).transpose()?.map(Arc::new);
+
+    let results = async_run(py, move |threadpool| async move {
+        let out: Vec<Result<String, PyErr>> = data_client::download_from_pointer_text_async(
+            threadpool,
+            pointer_texts,
+            endpoint,
+            token_info,
+            refresher.map(|v| v as Arc<_>),
+        )
+        .await
+        .map_err(convert_data_processing_error)?
+        .into_iter()
+        .map(|r| r.map_err(convert_data_processing_error))
+        .collect();
+        PyResult::Ok(out)
+    })?;
+
+    results
+        .into_iter()
+        .map(|r| match r {
+            Ok(path) => Ok(PyString::new(py, &path).into_any().unbind()),
+            Err(e) => Ok(e.into_value(py).into_any()),
+        })
+        .collect()
+}
+
+/// Starts downloading the file of a pointer file, returning an iterator over its bytes, in order, as
+/// they're downloaded.  If `range` is given as `(start, end)`, only the bytes in `[start, end)` are.
+#[pyfunction]
+#[pyo3(signature = (file, endpoint, token_info, token_refresher, range = None), text_signature = "(file: PyPointerFile, endpoint: Optional[str], token_info: Optional[(str, int)], token_refresher: Optional[Callable[[], (str, int)]], range: Optional[(int, int)] = None) -> PyDownloadStream")]
+pub fn download_stream(
+    py: Python,
+    file: PyPointerFile,
+    endpoint: Option<String>,
+    token_info: Option<(String, u64)>,
+    token_refresher: Option<Py<PyAny>>,
+    range: Option<(u64, u64)>,
+) -> PyResult<PyDownloadStream> {
+    let pf = PointerFile::from(file);
+    let range = match range {
+        Some((start, end)) if start > end => {
+            return Err(PyValueError::new_err(format!("invalid range ({start}, {end}): start is past end")))
+        },
+        Some((start, end)) => Some(FileRange { start, end }),
+        None => None,
+    };
+
+    let refresher = token_refresher.map(WrappedTokenRefresher::from_func).transpose()?.map(Arc::new);
+
+    let stream = async_run(py, move |threadpool| async move {
+        data_client::download_stream_async(threadpool, pf, range, endpoint, token_info, refresher.map(|v| v as Arc<_>))
+            .await
+            .map_err(convert_data_processing_error)
+    })?;
+
+    Ok(PyDownloadStream {
+        stream: Arc::new(tokio::sync::Mutex::new(stream)),
+    })
+}
+
+/// Returns the size of the local shard and chunk caches used for `endpoint`.
+#[pyfunction]
+#[pyo3(signature = (endpoint = None), text_signature = "(endpoint: Optional[str] = None) -> PyCacheStats")]
+pub fn cache_stats(endpoint: Option<String>) -> PyResult<PyCacheStats> {
+    data_client::cache_stats(endpoint)
+        .map(PyCacheStats::from)
+        .map_err(convert_data_processing_error)
+}
+
+/// Deletes everything in the local shard and chunk caches used for `endpoint`.  Only call this while no
+/// uploads or downloads are running.
+#[pyfunction]
+#[pyo3(signature = (endpoint = None), text_signature = "(endpoint: Optional[str] = None) -> None")]
+pub fn clear_cache(py: Python, endpoint: Option<String>) -> PyResult<()> {
+    async_run(py, move |_threadpool| async move {
+        data_client::clear_cache(endpoint).await.map_err(convert_data_processing_error)
+    })
+}
+
+/// Returns the hex hash `path` gets when it's uploaded, without uploading it, e.g. to check whether
+/// a local file matches a pointer file.
+#[pyfunction]
+#[pyo3(signature = (path), text_signature = "(path: str) -> str")]
+pub fn compute_file_hash(py: Python, path: String) -> PyResult<String> {
+    py.allow_threads(|| data_client::compute_file_hash(path))
+        .map(|hash| hash.hex())
+        .map_err(convert_data_processing_error)
+}
+
 fn try_parse_progress_updaters(funcs: Vec<Py<PyAny>>) -> PyResult<Vec<Arc<dyn ProgressUpdater>>> {
     let mut updaters = Vec::with_capacity(funcs.len());
     for updater_func in funcs {
@@ -115,6 +344,9 @@ pub struct PyPointerFile {
     hash: String,
     #[pyo3(get)]
     filesize: u64,
+    /// The BLAKE3 digest of the file, if it was computed while uploading it.
+    #[pyo3(get)]
+    blake3: Option<String>,
 }
 
 impl From<PointerFile> for PyPointerFile {
@@ -123,10 +355,14 @@ impl From<PointerFile> for PyPointerFile {
             path: pf.path().to_string(),
             hash: pf.hash_string().to_string(),
             filesize: pf.filesize(),
+            blake3: pf.blake3().map(str::to_owned),
         }
     }
 }
 
+/// The keys of the dict form of a `PyPointerFile`; see `PyPointerFile::to_dict`.
+const POINTER_FILE_DICT_KEYS: [&str; 3] = ["path", "hash", "filesize"];
+
 impl From<PyPointerFile> for PointerFile {
     fn from(pf: PyPointerFile) -> Self {
         PointerFile::init_from_info(&pf.path, &pf.hash, pf.filesize)
@@ -137,7 +373,12 @@ impl From<PyPointerFile> for PointerFile {
 impl PyPointerFile {
     #[new]
     pub fn new(path: String, hash: String, filesize: u64) -> Self {
-        Self { path, hash, filesize }
+        Self {
+            path,
+            hash,
+            filesize,
+            blake3: None,
+        }
     }
 
     fn __str__(&self) -> String {
@@ -147,13 +388,113 @@ impl PyPointerFile {
     fn __repr__(&self) -> String {
         format!("PyPointerFile({}, {}, {})", self.path, self.hash, self.filesize)
     }
+
+    /// Returns the pointer file as a dict with the keys "path", "hash" and "filesize", e.g. to store
+    /// it as JSON.
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new(py);
+        dict.set_item("path", &self.path)?;
+        dict.set_item("hash", &self.hash)?;
+        dict.set_item("filesize", self.filesize)?;
+        Ok(dict)
+    }
+
+    /// Builds a pointer file from a dict of the form returned by `to_dict`.  Raises KeyError if a
+    /// key is missing, ValueError if there is any other key and TypeError if a value has the wrong
+    /// type.
+    #[classmethod]
+    fn from_dict(_cls: &Bound<'_, PyType>, d: &Bound<'_, PyDict>) -> PyResult<Self> {
+        for key in d.keys() {
+            if !key
+                .extract::<String>()
+                .is_ok_and(|key| POINTER_FILE_DICT_KEYS.contains(&key.as_str()))
+            {
+                return Err(PyValueError::new_err(format!(
+                    "unexpected key {} in pointer file dict, expected only {POINTER_FILE_DICT_KEYS:?}",
+                    key.repr()?
+                )));
+            }
+        }
+
+        fn field<'py, T: FromPyObject<'py>>(d: &Bound<'py, PyDict>, key: &str) -> PyResult<T> {
+            let value = d
+                .get_item(key)?
+                .ok_or_else(|| PyKeyError::new_err(format!("pointer file dict is missing the key \"{key}\"")))?;
+            value
+                .extract()
+                .map_err(|e| PyTypeError::new_err(format!("invalid \"{key}\" in pointer file dict: {e}")))
+        }
+        Ok(Self::new(field(d, "path")?, field(d, "hash")?, field(d, "filesize")?))
+    }
+}
+
+/// A file being downloaded by `download_stream`; iterating over it yields the file's bytes as `bytes`
+/// objects.  Dropping it before the end cancels the download.
+#[pyclass]
+pub struct PyDownloadStream {
+    stream: Arc<tokio::sync::Mutex<DownloadStream>>,
+}
+
+#[pymethods]
+impl PyDownloadStream {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python) -> PyResult<Option<PyObject>> {
+        let stream = self.stream.clone();
+        let chunk = async_run(py, move |_threadpool| async move {
+            stream.lock().await.next_chunk().await.map_err(convert_data_processing_error)
+        })?;
+        Ok(chunk.map(|bytes| PyBytes::new(py, &bytes).into_any().unbind()))
+    }
+}
+
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct PyCacheStats {
+    #[pyo3(get)]
+    shard_bytes: u64,
+    #[pyo3(get)]
+    chunk_bytes: u64,
+    /// The number of entries in each cache, keyed by "shards" and "chunk_ranges".
+    #[pyo3(get)]
+    entry_counts: HashMap<String, usize>,
+}
+
+impl From<CacheStats> for PyCacheStats {
+    fn from(stats: CacheStats) -> Self {
+        Self {
+            shard_bytes: stats.shard_bytes,
+            chunk_bytes: stats.chunk_bytes,
+            entry_counts: HashMap::from([
+                ("shards".to_owned(), stats.entry_counts.shards),
+                ("chunk_ranges".to_owned(), stats.entry_counts.chunk_ranges),
+            ]),
+        }
+    }
+}
+
+#[pymethods]
+impl PyCacheStats {
+    fn __repr__(&self) -> String {
+        format!("{self:?}")
+    }
 }
 
 #[pymodule]
 pub fn hf_xet(py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(upload_files, m)?)?;
+    m.add_function(wrap_pyfunction!(upload_directory, m)?)?;
     m.add_function(wrap_pyfunction!(download_files, m)?)?;
+    m.add_function(wrap_pyfunction!(download_from_pointer_text, m)?)?;
+    m.add_function(wrap_pyfunction!(download_stream, m)?)?;
+    m.add_function(wrap_pyfunction!(cache_stats, m)?)?;
+    m.add_function(wrap_pyfunction!(clear_cache, m)?)?;
+    m.add_function(wrap_pyfunction!(compute_file_hash, m)?)?;
     m.add_class::<PyPointerFile>()?;
+    m.add_class::<PyCacheStats>()?;
+    m.add_class::<PyDownloadStream>()?;
 
     // Init the threadpool
     runtime::init_threadpool(py)?;
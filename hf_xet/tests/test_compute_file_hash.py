@@ -0,0 +1,24 @@
+import os
+
+from hf_xet import compute_file_hash
+
+
+def test_compute_file_hash(tmp_path):
+    data = os.urandom(1 << 20)
+    a = tmp_path / "a.bin"
+    b = tmp_path / "b.bin"
+    a.write_bytes(data)
+    b.write_bytes(data)
+
+    hash = compute_file_hash(str(a))
+    assert len(hash) == 64
+    assert compute_file_hash(str(b)) == hash
+
+    b.write_bytes(data[:-1])
+    assert compute_file_hash(str(b)) != hash
+
+
+def test_compute_file_hash_of_empty_file(tmp_path):
+    empty = tmp_path / "empty.bin"
+    empty.write_bytes(b"")
+    assert compute_file_hash(str(empty)) == "0" * 64
@@ -0,0 +1,42 @@
+import os
+
+import pytest
+
+from hf_xet import download_stream, upload_files
+
+ENDPOINT = os.environ.get("HF_XET_TEST_ENDPOINT")
+TOKEN = os.environ.get("HF_XET_TEST_TOKEN")
+
+pytestmark = pytest.mark.skipif(ENDPOINT is None, reason="HF_XET_TEST_ENDPOINT is not set")
+
+
+def token_info():
+    return (TOKEN, 2**62) if TOKEN else None
+
+
+@pytest.fixture
+def uploaded_file(tmp_path):
+    data = os.urandom(3 * 1024 * 1024)
+    path = tmp_path / "data.bin"
+    path.write_bytes(data)
+    [pointer_file] = upload_files([str(path)], ENDPOINT, token_info(), None, None, None)
+    return pointer_file, data
+
+
+def test_stream_whole_file(uploaded_file):
+    pointer_file, data = uploaded_file
+
+    chunks = list(download_stream(pointer_file, ENDPOINT, token_info(), None))
+    assert all(isinstance(chunk, bytes) for chunk in chunks)
+    assert b"".join(chunks) == data
+
+
+def test_stream_range(uploaded_file):
+    pointer_file, data = uploaded_file
+
+    start, end = 1000, 2 * 1024 * 1024 + 17
+    streamed = b"".join(download_stream(pointer_file, ENDPOINT, token_info(), None, range=(start, end)))
+    assert streamed == data[start:end]
+
+    with pytest.raises(ValueError):
+        download_stream(pointer_file, ENDPOINT, token_info(), None, range=(end, start))
@@ -0,0 +1,34 @@
+import json
+
+import pytest
+
+from hf_xet import PyPointerFile
+
+HASH = "6a9ff0dfc1e0d4bc50bfb1ad16f2db36d5c4dc3cba30ed0e05d30d2ac24b2a5c"
+
+
+def test_round_trip_through_dict_and_json():
+    original = PyPointerFile("models/weights.bin", HASH, 1234)
+
+    d = original.to_dict()
+    assert d == {"path": "models/weights.bin", "hash": HASH, "filesize": 1234}
+
+    restored = PyPointerFile.from_dict(json.loads(json.dumps(d)))
+    assert restored.to_dict() == original.to_dict()
+    assert (restored.path, restored.hash, restored.filesize) == (original.path, original.hash, original.filesize)
+    assert repr(restored) == repr(original)
+
+
+def test_from_dict_rejects_bad_dicts():
+    d = PyPointerFile("a.bin", HASH, 1).to_dict()
+
+    missing = dict(d)
+    del missing["hash"]
+    with pytest.raises(KeyError, match="hash"):
+        PyPointerFile.from_dict(missing)
+
+    with pytest.raises(ValueError, match="extra"):
+        PyPointerFile.from_dict({**d, "extra": 1})
+
+    with pytest.raises(TypeError, match="filesize"):
+        PyPointerFile.from_dict({**d, "filesize": "1"})
@@ -6,17 +6,19 @@ pub mod file_structs;
 pub mod interpolation_search;
 pub mod session_directory;
 pub mod set_operations;
+pub mod shard_dedup_index;
 pub mod shard_file_handle;
 pub mod shard_file_manager;
 pub mod shard_file_reconstructor;
 pub mod shard_format;
 pub mod shard_in_memory;
+pub mod shard_range_reader;
 pub mod utils;
 
 pub use constants::{hash_is_global_dedup_eligible, MDB_SHARD_TARGET_SIZE};
 pub use shard_file_handle::MDBShardFile;
 pub use shard_file_manager::ShardFileManager;
-pub use shard_format::{MDBShardFileFooter, MDBShardFileHeader, MDBShardInfo};
+pub use shard_format::{MDBShardFileFooter, MDBShardFileHeader, MDBShardInfo, ShardSummary};
 
 // Temporary to transition dependent code to new location
 pub mod shard_file;
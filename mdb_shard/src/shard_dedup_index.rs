@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{Cursor, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+
+use merklehash::MerkleHash;
+use utils::serialization_utils::*;
+use uuid::Uuid;
+
+use crate::error::{MDBShardError, Result};
+
+/// Name of the dedup index file in a shard cache directory.  It doesn't match the shard file
+/// name pattern, so shard directory scans skip it.
+pub const SHARD_DEDUP_INDEX_FILE_NAME: &str = "shard_dedup_index.bin";
+
+const SHARD_DEDUP_INDEX_MAGIC: [u8; 8] = *b"XETSDIDX";
+const SHARD_DEDUP_INDEX_VERSION: u32 = 1;
+const SHARD_DEDUP_INDEX_HEADER_SIZE: usize = SHARD_DEDUP_INDEX_MAGIC.len() + size_of::<u32>();
+
+/// Size of one serialized (truncated chunk hash, (cas start index, chunk offset)) entry.
+const ENTRY_SIZE: usize = size_of::<u64>() + 2 * size_of::<u32>();
+
+/// The truncated chunk hashes of a shard with the location of each chunk in the shard, as
+/// returned by `MDBShardFile::read_all_truncated_hashes`.
+pub type ShardChunkHashes = Vec<(u64, (u32, u32))>;
+
+/// An on-disk index of the truncated chunk hashes of each shard in a shard cache directory, so that
+/// the dedup lookup table can be rebuilt on startup without reading the chunk hash section of every
+/// shard file.
+///
+/// The file is a header followed by one record per shard, appended as shards are registered:
+/// the shard hash, the number of entries, the entries, and a blake3 checksum of the record.  Any
+/// truncated or mismatching record makes the whole index invalid; records of shards that are no
+/// longer in the directory are stale.  In both cases the caller falls back to reading the shards
+/// and rewrites the index.
+pub struct ShardDedupIndex {
+    path: PathBuf,
+}
+
+impl ShardDedupIndex {
+    pub fn in_directory(directory: impl AsRef<Path>) -> Self {
+        Self {
+            path: directory.as_ref().join(SHARD_DEDUP_INDEX_FILE_NAME),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the chunk hashes of every shard in the index.  A missing index is empty; a corrupt
+    /// one is an error.
+    pub fn load(&self) -> Result<HashMap<MerkleHash, ShardChunkHashes>> {
+        let data = match std::fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let corrupt = |what: &str| MDBShardError::Other(format!("corrupt shard dedup index {:?}: {what}", self.path));
+
+        if data.len() < SHARD_DEDUP_INDEX_HEADER_SIZE
+            || data[..SHARD_DEDUP_INDEX_MAGIC.len()] != SHARD_DEDUP_INDEX_MAGIC
+        {
+            return Err(corrupt("bad header"));
+        }
+        let version = read_u32(&mut &data[SHARD_DEDUP_INDEX_MAGIC.len()..SHARD_DEDUP_INDEX_HEADER_SIZE])?;
+        if version != SHARD_DEDUP_INDEX_VERSION {
+            return Err(corrupt(&format!("unsupported version {version}")));
+        }
+
+        let mut shards = HashMap::new();
+        let mut pos = SHARD_DEDUP_INDEX_HEADER_SIZE;
+        while pos < data.len() {
+            let record_header_end = pos + size_of::<MerkleHash>() + size_of::<u64>();
+            if record_header_end > data.len() {
+                return Err(corrupt("truncated record"));
+            }
+            let mut reader = Cursor::new(&data[pos..record_header_end]);
+            let shard_hash = read_hash(&mut reader)?;
+            let num_entries = read_u64(&mut reader)? as usize;
+
+            let entries_end = num_entries
+                .checked_mul(ENTRY_SIZE)
+                .and_then(|len| record_header_end.checked_add(len))
+                .filter(|&end| end + blake3::OUT_LEN <= data.len())
+                .ok_or_else(|| corrupt("truncated record"))?;
+            if blake3::hash(&data[pos..entries_end]).as_bytes()[..] != data[entries_end..entries_end + blake3::OUT_LEN]
+            {
+                return Err(corrupt("checksum mismatch"));
+            }
+
+            let mut reader = Cursor::new(&data[record_header_end..entries_end]);
+            let mut entries = Vec::with_capacity(num_entries);
+            for _ in 0..num_entries {
+                entries.push((read_u64(&mut reader)?, (read_u32(&mut reader)?, read_u32(&mut reader)?)));
+            }
+            shards.insert(shard_hash, entries);
+
+            pos = entries_end + blake3::OUT_LEN;
+        }
+
+        Ok(shards)
+    }
+
+    /// Appends records for the given shards, creating the index if it doesn't exist.
+    pub fn append<'a>(&self, shards: impl IntoIterator<Item = (&'a MerkleHash, &'a ShardChunkHashes)>) -> Result<()> {
+        let records = serialize_records(shards)?;
+
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        // The index may have been created concurrently; only the first writer adds the header.
+        let mut buffer = Vec::new();
+        if file.metadata()?.len() == 0 {
+            write_header(&mut buffer)?;
+        }
+        buffer.extend(records);
+        file.write_all(&buffer)?;
+        Ok(())
+    }
+
+    /// Replaces the index with one holding exactly the given shards.
+    pub fn rewrite<'a>(&self, shards: impl IntoIterator<Item = (&'a MerkleHash, &'a ShardChunkHashes)>) -> Result<()> {
+        let mut buffer = Vec::new();
+        write_header(&mut buffer)?;
+        buffer.extend(serialize_records(shards)?);
+
+        // Write to a temporary file and move it into place, so readers never see a partial index.
+        let temp_path = self
+            .path
+            .with_file_name(format!(".{SHARD_DEDUP_INDEX_FILE_NAME}.{}.tmp", Uuid::new_v4()));
+        std::fs::write(&temp_path, &buffer)?;
+        std::fs::rename(&temp_path, &self.path).inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp_path);
+        })?;
+        Ok(())
+    }
+}
+
+fn write_header(writer: &mut impl Write) -> Result<()> {
+    writer.write_all(&SHARD_DEDUP_INDEX_MAGIC)?;
+    write_u32(writer, SHARD_DEDUP_INDEX_VERSION)?;
+    Ok(())
+}
+
+fn serialize_records<'a>(shards: impl IntoIterator<Item = (&'a MerkleHash, &'a ShardChunkHashes)>) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    for (shard_hash, entries) in shards {
+        let record_start = buffer.len();
+        write_hash(&mut buffer, shard_hash)?;
+        write_u64(&mut buffer, entries.len() as u64)?;
+        for &(h, (cas_start_index, cas_chunk_offset)) in entries {
+            write_u64(&mut buffer, h)?;
+            write_u32(&mut buffer, cas_start_index)?;
+            write_u32(&mut buffer, cas_chunk_offset)?;
+        }
+        let checksum = blake3::hash(&buffer[record_start..]);
+        buffer.extend_from_slice(checksum.as_bytes());
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    use crate::shard_format::test_routines::rng_hash;
+
+    #[test]
+    fn test_append_load_and_corruption() {
+        let tmp_dir = TempDir::new("shard_dedup_index").unwrap();
+        let index = ShardDedupIndex::in_directory(tmp_dir.path());
+        assert!(index.load().unwrap().is_empty());
+
+        let shards: Vec<(MerkleHash, ShardChunkHashes)> = (0..3)
+            .map(|i| (rng_hash(i), (0..10 * i).map(|j| (j * 7 + i, (j as u32, i as u32))).collect()))
+            .collect();
+        index.append(shards[..2].iter().map(|(h, e)| (h, e))).unwrap();
+        index.append(shards[2..].iter().map(|(h, e)| (h, e))).unwrap();
+        assert_eq!(index.load().unwrap(), shards.iter().cloned().collect::<HashMap<_, _>>());
+
+        index.rewrite(shards[1..].iter().map(|(h, e)| (h, e))).unwrap();
+        assert_eq!(index.load().unwrap(), shards[1..].iter().cloned().collect::<HashMap<_, _>>());
+
+        // A flipped byte in a record, or a truncated record, is detected.
+        let data = std::fs::read(index.path()).unwrap();
+        let mut flipped = data.clone();
+        flipped[SHARD_DEDUP_INDEX_HEADER_SIZE + 50] ^= 1;
+        std::fs::write(index.path(), flipped).unwrap();
+        assert!(index.load().is_err());
+        std::fs::write(index.path(), &data[..data.len() - 1]).unwrap();
+        assert!(index.load().is_err());
+    }
+}
@@ -265,6 +265,15 @@ impl MDBShardFile {
         }
     }
 
+    #[inline]
+    pub fn get_cas_chunk_hashes(&self, cas_hash: &MerkleHash) -> Result<Option<Vec<MerkleHash>>> {
+        let Some(mut reader) = self.get_reader_if_present()? else {
+            return Ok(None);
+        };
+
+        self.shard.read_cas_chunk_hashes(&mut reader, cas_hash)
+    }
+
     #[inline]
     pub fn get_file_reconstruction_info(&self, file_hash: &MerkleHash) -> Result<Option<MDBFileInfo>> {
         let Some(mut reader) = self.get_reader_if_present()? else {
@@ -398,8 +407,9 @@ impl MDBShardFile {
             cas_index += 1 + ci.chunks.len();
         }
 
-        read_truncated_hashes.sort_by_key(|s| s.0);
-        truncated_hashes.sort_by_key(|s| s.0);
+        // The same chunk may appear more than once, so sort on the full entry to get a consistent order.
+        read_truncated_hashes.sort_unstable();
+        truncated_hashes.sort_unstable();
 
         assert_eq!(read_truncated_hashes, truncated_hashes);
     }
@@ -1,17 +1,18 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use merklehash::{HMACKey, MerkleHash};
 use tokio::sync::RwLock;
-use tracing::{debug, info, trace};
+use tracing::{debug, info, trace, warn};
 
 use crate::cas_structs::*;
 use crate::constants::{CHUNK_INDEX_TABLE_MAX_SIZE, MDB_SHARD_EXPIRATION_BUFFER_SECS, MDB_SHARD_MIN_TARGET_SIZE};
 use crate::error::{MDBShardError, Result};
 use crate::file_structs::*;
+use crate::shard_dedup_index::{ShardChunkHashes, ShardDedupIndex};
 use crate::shard_file_handle::MDBShardFile;
 use crate::shard_file_reconstructor::FileReconstructor;
 use crate::shard_in_memory::MDBInMemoryShard;
@@ -76,6 +77,10 @@ pub struct ShardFileManager {
     shard_directory: PathBuf,
     target_shard_min_size: u64,
     shard_directory_cleaned: AtomicBool,
+    /// Index of the chunk hashes of the shards in a cache directory; None for session directories.
+    dedup_index: Option<ShardDedupIndex>,
+    /// Number of shards whose chunk hashes were read from the shard file rather than the index.
+    num_shard_chunk_hashes_read: AtomicUsize,
 }
 
 /// Shard file manager to manage all the shards.  It is fully thread-safe and async enabled.
@@ -120,6 +125,8 @@ impl ShardFileManager {
                 shard_directory: shard_directory.clone(),
                 target_shard_min_size,
                 shard_directory_cleaned: AtomicBool::new(false),
+                dedup_index: is_cachable.then(|| ShardDedupIndex::in_directory(&shard_directory)),
+                num_shard_chunk_hashes_read: AtomicUsize::new(0),
             })
         };
 
@@ -157,15 +164,52 @@ impl ShardFileManager {
         Ok(sfm)
     }
 
+    /// Drops the manager kept for the cache directory, if any, so that the next one created for it
+    /// starts from the shards then in the directory.  Used after the directory is cleared.
+    pub async fn forget_cache_directory(cache_directory: impl AsRef<Path>) -> Result<()> {
+        let cache_directory = std::path::absolute(cache_directory)?;
+        MDB_SHARD_FILE_MANAGER_CACHE.write().await.remove(&cache_directory);
+        Ok(())
+    }
+
+    /// Registers the shards in the shard directory that aren't registered yet.
+    ///
+    /// In a cache directory, the chunk hashes of the shards are taken from the dedup index where
+    /// possible.  If the index is corrupt or holds shards that are no longer in the directory, it
+    /// is rewritten with the shards in the directory; otherwise the shards missing from it are
+    /// appended.
     pub async fn refresh_shard_dir(&self) -> Result<()> {
         let mut shard_files = MDBShardFile::load_all_valid(&self.shard_directory)?;
+        let present_shards: HashSet<MerkleHash> = shard_files.iter().map(|s| s.shard_hash).collect();
 
         {
             let shard_read_guard = self.shard_bookkeeper.read().await;
             shard_files.retain(|s| !shard_read_guard.shard_lookup_by_shard_hash.contains_key(&s.shard_hash));
         }
 
-        self.register_shards(&shard_files).await?;
+        let Some(dedup_index) = self.dedup_index.as_ref().filter(|_| !shard_files.is_empty()) else {
+            return self.register_shards(&shard_files).await;
+        };
+
+        let (mut indexed, stale) = match dedup_index.load() {
+            Ok(indexed) => {
+                let stale = indexed.keys().any(|h| !present_shards.contains(h));
+                (indexed, stale)
+            },
+            Err(e) => {
+                warn!("Rebuilding shard dedup index after failing to load it: {e}");
+                (HashMap::new(), true)
+            },
+        };
+
+        let newly_read = self.register_shards_impl(&shard_files, &indexed).await?;
+
+        if stale {
+            indexed.retain(|h, _| present_shards.contains(h));
+            dedup_index.rewrite(indexed.iter().chain(newly_read.iter().map(|(h, e)| (h, e))))?;
+        } else if !newly_read.is_empty() {
+            dedup_index.append(newly_read.iter().map(|(h, e)| (h, e)))?;
+        }
 
         Ok(())
     }
@@ -196,6 +240,25 @@ impl ShardFileManager {
     }
 
     pub async fn register_shards(&self, new_shards: &[Arc<MDBShardFile>]) -> Result<()> {
+        let newly_read = self.register_shards_impl(new_shards, &HashMap::new()).await?;
+
+        if let Some(dedup_index) = &self.dedup_index {
+            if !newly_read.is_empty() {
+                dedup_index.append(newly_read.iter().map(|(h, e)| (h, e)))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Registers the shards, taking their chunk hashes from `indexed` if present there and reading
+    /// them from the shard files otherwise.  Returns the chunk hashes read from shard files.
+    async fn register_shards_impl(
+        &self,
+        new_shards: &[Arc<MDBShardFile>],
+        indexed: &HashMap<MerkleHash, ShardChunkHashes>,
+    ) -> Result<Vec<(MerkleHash, ShardChunkHashes)>> {
+        let mut newly_read = Vec::new();
         let mut sbkp_lg = self.shard_bookkeeper.write().await;
 
         // Go through and register the shards in order of newest to oldest
@@ -241,11 +304,19 @@ impl ShardFileManager {
                 let old_chunk_lookup_size = shard_col.chunk_lookup.len();
 
                 if update_chunk_lookup {
-                    let insert_hashes = s.read_all_truncated_hashes()?;
+                    let insert_hashes = match indexed.get(&s.shard_hash) {
+                        Some(hashes) => hashes,
+                        None => {
+                            self.num_shard_chunk_hashes_read
+                                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            newly_read.push((s.shard_hash, s.read_all_truncated_hashes()?));
+                            &newly_read.last().unwrap().1
+                        },
+                    };
 
                     shard_col.chunk_lookup.reserve(insert_hashes.len());
 
-                    for (h, (cas_start_index, cas_chunk_offset)) in insert_hashes {
+                    for &(h, (cas_start_index, cas_chunk_offset)) in insert_hashes {
                         if cas_chunk_offset > u16::MAX as u32 {
                             continue;
                         }
@@ -277,7 +348,7 @@ impl ShardFileManager {
             info!("Registered {num_shards} new shards.");
         }
 
-        Ok(())
+        Ok(newly_read)
     }
 
     pub async fn shard_is_registered(&self, shard_hash: &MerkleHash) -> bool {
@@ -348,6 +419,29 @@ impl FileReconstructor<MDBShardError> for ShardFileManager {
 
         Ok(None)
     }
+
+    async fn get_xorb_chunk_hashes(&self, xorb_hash: &MerkleHash) -> Result<Option<Vec<MerkleHash>>> {
+        {
+            let lg = self.current_state.read().await;
+            if let Some(cas_info) = lg.cas_content.get(xorb_hash) {
+                return Ok(Some(cas_info.chunks.iter().map(|c| c.chunk_hash).collect()));
+            }
+        }
+
+        let current_shards = self.shard_bookkeeper.read().await;
+
+        // The chunk hashes of shards with an hmac key are keyed, so can't be compared to the hashes
+        // of downloaded chunks.
+        for sc in current_shards.shard_collections.iter() {
+            for si in sc.shard_list.iter().filter(|si| si.chunk_hmac_key().is_none()) {
+                if let Some(chunk_hashes) = si.get_cas_chunk_hashes(xorb_hash)? {
+                    return Ok(Some(chunk_hashes));
+                }
+            }
+        }
+
+        Ok(None)
+    }
 }
 
 impl ShardFileManager {
@@ -501,6 +595,7 @@ mod tests {
     use crate::error::Result;
     use crate::file_structs::FileDataSequenceHeader;
     use crate::session_directory::consolidate_shards_in_directory;
+    use crate::shard_dedup_index::SHARD_DEDUP_INDEX_FILE_NAME;
     use crate::shard_format::test_routines::{gen_random_file_info, rng_hash, simple_hash};
 
     #[allow(clippy::type_complexity)]
@@ -1124,4 +1219,57 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_dedup_index_across_restarts() -> Result<()> {
+        let tmp_dir = TempDir::new("shard_test_dedup_index")?;
+        let tmp_dir_path = std::path::absolute(tmp_dir.path())?;
+
+        let mem_shard =
+            create_random_shard_collection(0, &tmp_dir_path, 4, &[1, 5, 10, 8], &[4, 3, 5, 9, 4, 6]).await?;
+
+        // Drops the cached manager so the next open starts from scratch, as a new process would.
+        let reopen = || async {
+            MDB_SHARD_FILE_MANAGER_CACHE.write().await.remove(&tmp_dir_path);
+            ShardFileManager::new_in_cache_directory(&tmp_dir_path).await
+        };
+        let num_read =
+            |sfm: &ShardFileManager| sfm.num_shard_chunk_hashes_read.load(std::sync::atomic::Ordering::Relaxed);
+
+        // The first open reads every shard and writes the index.
+        let sfm = reopen().await?;
+        assert_eq!(num_read(&sfm), 4);
+        assert!(tmp_dir_path.join(SHARD_DEDUP_INDEX_FILE_NAME).exists());
+
+        // Later opens take everything from the index.
+        let sfm = reopen().await?;
+        assert_eq!(num_read(&sfm), 0);
+        verify_mdb_shards_match(&sfm, &mem_shard, true).await?;
+
+        // A corrupt index falls back to reading the shards, and is rebuilt.
+        let index_path = tmp_dir_path.join(SHARD_DEDUP_INDEX_FILE_NAME);
+        let mut data = std::fs::read(&index_path)?;
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        std::fs::write(&index_path, data)?;
+
+        let sfm = reopen().await?;
+        assert_eq!(num_read(&sfm), 4);
+        verify_mdb_shards_match(&sfm, &mem_shard, true).await?;
+
+        let sfm = reopen().await?;
+        assert_eq!(num_read(&sfm), 0);
+
+        // A shard removed from the directory makes the index stale; it's rewritten without it.
+        let removed = sfm.registered_shard_list().await?.pop().unwrap();
+        std::fs::remove_file(&removed.path)?;
+
+        let sfm = reopen().await?;
+        assert_eq!(num_read(&sfm), 0);
+        let indexed = ShardDedupIndex::in_directory(&tmp_dir_path).load()?;
+        assert_eq!(indexed.len(), 3);
+        assert!(!indexed.contains_key(&removed.shard_hash));
+
+        Ok(())
+    }
 }
@@ -12,4 +12,10 @@ pub trait FileReconstructor<E> {
         &self,
         file_hash: &MerkleHash,
     ) -> Result<Option<(MDBFileInfo, Option<MerkleHash>)>, E>;
+
+    /// Returns the hashes of all the chunks of the xorb `xorb_hash`, in order, as recorded in the
+    /// known shards, or None if they aren't known.  Used to verify downloaded chunks.
+    async fn get_xorb_chunk_hashes(&self, _xorb_hash: &MerkleHash) -> Result<Option<Vec<MerkleHash>>, E> {
+        Ok(None)
+    }
 }
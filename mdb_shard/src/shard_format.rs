@@ -280,6 +280,23 @@ pub struct MDBShardInfo {
     pub metadata: MDBShardFileFooter,
 }
 
+/// An overview of what a shard holds, e.g. to debug deduplication; see `MDBShardInfo::summary`.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct ShardSummary {
+    /// The files the shard has the reconstruction of, with the size of each, in shard order.
+    pub files: Vec<(MerkleHash, u64)>,
+    /// The xorbs the shard describes, with the number of chunks of each, in shard order.
+    pub xorbs: Vec<(MerkleHash, usize)>,
+    /// The total number of chunks of the xorbs.
+    pub num_chunks: usize,
+    /// The total size of the files.
+    pub file_bytes: u64,
+    /// The total size of the xorbs' chunks, uncompressed.
+    pub xorb_bytes: u64,
+    /// The size of the shard itself.
+    pub shard_bytes: u64,
+}
+
 impl MDBShardInfo {
     pub fn load_from_reader<R: Read + Seek>(reader: &mut R) -> Result<Self> {
         let mut obj = Self::default();
@@ -508,6 +525,34 @@ impl MDBShardInfo {
         }
     }
 
+    /// Returns the hashes of the chunks of the xorb `cas_hash`, in order, or None if the shard doesn't
+    /// have the xorb.  In a shard with protected chunk hashes, these are the keyed hashes.
+    pub fn read_cas_chunk_hashes<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        cas_hash: &MerkleHash,
+    ) -> Result<Option<Vec<MerkleHash>>> {
+        let mut dest_indices = [0u32; 8];
+        let num_indices = self.get_cas_info_index_by_hash(reader, cas_hash, &mut dest_indices)?;
+
+        for &cas_entry_index in &dest_indices[..num_indices] {
+            reader.seek(SeekFrom::Start(
+                self.metadata.cas_info_offset + (MDB_CAS_INFO_ENTRY_SIZE as u64) * (cas_entry_index as u64),
+            ))?;
+            let cas_header = CASChunkSequenceHeader::deserialize(reader)?;
+            if cas_header.cas_hash != *cas_hash {
+                continue;
+            }
+
+            let chunk_hashes = (0..cas_header.num_entries)
+                .map(|_| Ok(CASChunkSequenceEntry::deserialize(reader)?.chunk_hash))
+                .collect::<Result<_>>()?;
+            return Ok(Some(chunk_hashes));
+        }
+
+        Ok(None)
+    }
+
     pub fn get_cas_info_index_by_chunk<R: Read + Seek>(
         &self,
         reader: &mut R,
@@ -778,6 +823,28 @@ impl MDBShardInfo {
         Ok(ret)
     }
 
+    /// Reads the files and xorbs of the shard into a `ShardSummary`.
+    pub fn summary<R: Read + Seek>(&self, reader: &mut R) -> Result<ShardSummary> {
+        let files: Vec<_> = self
+            .read_all_file_info_sections(reader)?
+            .iter()
+            .map(|file_info| (file_info.metadata.file_hash, file_info.file_size() as u64))
+            .collect();
+        let cas_blocks = self.read_all_cas_blocks(reader)?;
+
+        Ok(ShardSummary {
+            file_bytes: files.iter().map(|(_, size)| size).sum(),
+            files,
+            xorbs: cas_blocks
+                .iter()
+                .map(|(header, _)| (header.cas_hash, header.num_entries as usize))
+                .collect(),
+            num_chunks: cas_blocks.iter().map(|(header, _)| header.num_entries as usize).sum(),
+            xorb_bytes: cas_blocks.iter().map(|(header, _)| header.num_bytes_in_cas as u64).sum(),
+            shard_bytes: self.num_bytes(),
+        })
+    }
+
     pub fn num_cas_entries(&self) -> usize {
         self.metadata.cas_lookup_num_entry as usize
     }
@@ -1547,6 +1614,10 @@ pub mod test_routines {
         assert_eq!(mem_shard.stored_bytes(), shard_file.stored_bytes());
 
         for (k, cas_block) in mem_shard.cas_content.iter() {
+            // The chunk hashes of each xorb are read back in order.
+            let chunk_hashes: Vec<MerkleHash> = cas_block.chunks.iter().map(|c| c.chunk_hash).collect();
+            assert_eq!(shard_file.read_cas_chunk_hashes(&mut cursor, k)?, Some(chunk_hashes));
+
             // Go through and test queries on both the in-memory shard and the
             // serialized shard, making sure that they match completely.
 
@@ -1612,6 +1683,11 @@ pub mod test_routines {
             }
         }
 
+        // Xorbs the shard doesn't have have no chunk hashes.
+        for i in 0..3 {
+            assert_eq!(shard_file.read_cas_chunk_hashes(&mut cursor, &rng_hash(2000000 + i))?, None);
+        }
+
         // Make sure the cas blocks and chunks are correct.
         let cas_blocks_full = shard_file.read_all_cas_blocks_full(&mut cursor)?;
 
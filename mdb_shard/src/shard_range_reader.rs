@@ -0,0 +1,136 @@
+use std::io::Cursor;
+use std::mem::size_of;
+
+use async_trait::async_trait;
+use merklehash::MerkleHash;
+use utils::serialization_utils::read_u32;
+
+use crate::error::MDBShardError;
+use crate::file_structs::{FileDataSequenceHeader, MDBFileInfo};
+use crate::interpolation_search::search_on_sorted_u64s;
+use crate::shard_format::{MDBShardFileFooter, MDB_FILE_INFO_ENTRY_SIZE};
+use crate::utils::truncate_hash;
+
+/// Reads byte ranges of a single shard, e.g. through HTTP range requests, so that parts of the
+/// shard can be queried without fetching all of it.
+#[async_trait]
+pub trait ShardRangeReader<E> {
+    /// Reads the bytes in `start..end` of the shard.
+    async fn read_range(&self, start: u64, end: u64) -> Result<Vec<u8>, E>;
+
+    /// Reads the last `len` bytes of the shard.
+    async fn read_suffix(&self, len: u64) -> Result<Vec<u8>, E>;
+}
+
+/// Returns the file info of `file_hash` in the shard read by `reader`, or None if the shard doesn't
+/// have it.
+///
+/// Only the footer, the file lookup table and the file info entries of the candidate files are
+/// read; the rest of the shard, including its CAS info and chunk lookup, is never fetched.
+pub async fn read_file_info_from_ranges<E: From<MDBShardError>>(
+    reader: &(impl ShardRangeReader<E> + Sync),
+    file_hash: &MerkleHash,
+) -> Result<Option<MDBFileInfo>, E> {
+    let footer_bytes = reader.read_suffix(size_of::<MDBShardFileFooter>() as u64).await?;
+    let footer = MDBShardFileFooter::deserialize(&mut &footer_bytes[..]).map_err(E::from)?;
+    if footer.file_lookup_num_entry == 0 {
+        return Ok(None);
+    }
+
+    // The lookup table is sorted on the truncated file hashes; search it as if it were the whole
+    // shard.
+    let lookup_entry_size = (size_of::<u64>() + size_of::<u32>()) as u64;
+    let lookup_bytes = reader
+        .read_range(
+            footer.file_lookup_offset,
+            footer.file_lookup_offset + footer.file_lookup_num_entry * lookup_entry_size,
+        )
+        .await?;
+    let mut dest_indices = [0u32; 8];
+    let num_indices = search_on_sorted_u64s(
+        &mut Cursor::new(&lookup_bytes[..]),
+        0,
+        footer.file_lookup_num_entry,
+        truncate_hash(file_hash),
+        read_u32,
+        &mut dest_indices,
+    )
+    .map_err(MDBShardError::from)?;
+    if num_indices == dest_indices.len() {
+        return Err(MDBShardError::TruncatedHashCollisionError(truncate_hash(file_hash)).into());
+    }
+
+    for &index in &dest_indices[..num_indices] {
+        let start = footer.file_info_offset + MDB_FILE_INFO_ENTRY_SIZE as u64 * index as u64;
+        let header_bytes = reader.read_range(start, start + MDB_FILE_INFO_ENTRY_SIZE as u64).await?;
+        let header = FileDataSequenceHeader::deserialize(&mut &header_bytes[..]).map_err(MDBShardError::from)?;
+        if header.file_hash != *file_hash {
+            continue;
+        }
+
+        let num_entries = 1
+            + header.num_entries as u64 * if header.contains_verification() { 2 } else { 1 }
+            + u64::from(header.contains_metadata_ext());
+        let entry_bytes = reader
+            .read_range(start, start + MDB_FILE_INFO_ENTRY_SIZE as u64 * num_entries)
+            .await?;
+        return Ok(MDBFileInfo::deserialize(&mut &entry_bytes[..]).map_err(MDBShardError::from)?);
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::error::Result;
+    use crate::shard_format::test_routines::{convert_to_file, gen_random_shard, rng_hash};
+    use crate::shard_format::MDBShardInfo;
+
+    /// Serves ranges of an in-memory shard, counting the bytes read.
+    struct InMemoryShard {
+        data: Vec<u8>,
+        bytes_read: AtomicU64,
+    }
+
+    #[async_trait]
+    impl ShardRangeReader<MDBShardError> for InMemoryShard {
+        async fn read_range(&self, start: u64, end: u64) -> Result<Vec<u8>> {
+            self.bytes_read.fetch_add(end - start, Ordering::Relaxed);
+            Ok(self.data[start as usize..end as usize].to_vec())
+        }
+
+        async fn read_suffix(&self, len: u64) -> Result<Vec<u8>> {
+            self.read_range(self.data.len() as u64 - len, self.data.len() as u64).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_file_info_matches_full_shard() -> Result<()> {
+        for (contains_verification, contains_metadata_ext) in [(false, false), (true, false), (true, true)] {
+            let mem_shard =
+                gen_random_shard(0, &[4, 8, 16], &[1, 3, 8, 20], contains_verification, contains_metadata_ext)?;
+            let data = convert_to_file(&mem_shard)?;
+            let full_shard = MDBShardInfo::load_from_reader(&mut Cursor::new(&data))?;
+            let shard = InMemoryShard {
+                data: data.clone(),
+                bytes_read: AtomicU64::new(0),
+            };
+
+            for file_hash in mem_shard.file_content.keys() {
+                shard.bytes_read.store(0, Ordering::Relaxed);
+                let partial = read_file_info_from_ranges(&shard, file_hash).await?;
+                let full = full_shard.get_file_reconstruction_info(&mut Cursor::new(&data), file_hash)?;
+                assert!(partial.is_some());
+                assert_eq!(partial, full);
+                assert!(shard.bytes_read.load(Ordering::Relaxed) < data.len() as u64);
+            }
+
+            assert_eq!(read_file_info_from_ranges(&shard, &rng_hash(1 << 40)).await?, None);
+        }
+        Ok(())
+    }
+}
@@ -7,6 +7,8 @@ use merklehash::MerkleHash;
 use regex::Regex;
 use uuid::Uuid;
 
+use crate::error::Result;
+
 lazy_static! {
     static ref MERKLE_DB_FILE_PATTERN: Regex = Regex::new(r"^(?P<hash>[0-9a-fA-F]{64})\.mdb$").unwrap();
 }
@@ -35,6 +37,26 @@ pub fn shard_file_name(hash: &MerkleHash) -> String {
     format!("{}.mdb", hash.hex())
 }
 
+/// Lists the hashes of all the shard files in the given directory, i.e. those named as given by
+/// [shard_file_name].  Files with other names are skipped.  The shards are not loaded or verified.
+/// The returned hashes are sorted.
+pub fn list_shard_hashes_in_directory(dir: impl AsRef<Path>) -> Result<Vec<MerkleHash>> {
+    let mut hashes = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        if let Some(h) = parse_shard_filename(entry.file_name()) {
+            hashes.push(h);
+        }
+    }
+
+    hashes.sort_unstable();
+    Ok(hashes)
+}
+
 pub fn temp_shard_file_name() -> String {
     let uuid = Uuid::new_v4();
     format!(".{uuid}.mdb_temp")
@@ -50,6 +72,8 @@ pub fn is_temp_shard_file(p: &Path) -> bool {
 
 #[cfg(test)]
 mod tests {
+    use tempdir::TempDir;
+
     use super::*;
     use crate::shard_format::test_routines::rng_hash;
 
@@ -61,4 +85,25 @@ mod tests {
         assert!(parse_shard_filename(format!("{}.mdb", mh.hex())).is_some());
         assert!(parse_shard_filename(format!("other_{}.mdb", mh.hex())).is_none());
     }
+
+    #[test]
+    fn test_list_shard_hashes_in_directory() {
+        let tmp_dir = TempDir::new("shard_listing").unwrap();
+
+        let mut hashes: Vec<_> = (0..4).map(rng_hash).collect();
+        for h in hashes.iter() {
+            std::fs::write(tmp_dir.path().join(shard_file_name(h)), b"").unwrap();
+        }
+
+        // None of these should be picked up.
+        let other = rng_hash(100);
+        std::fs::write(tmp_dir.path().join(format!("other_{}.mdb", other.hex())), b"").unwrap();
+        std::fs::write(tmp_dir.path().join(format!("{}.mdb_temp", other.hex())), b"").unwrap();
+        std::fs::write(tmp_dir.path().join("abcd.mdb"), b"").unwrap();
+        std::fs::write(tmp_dir.path().join(temp_shard_file_name()), b"").unwrap();
+        std::fs::create_dir(tmp_dir.path().join(shard_file_name(&other))).unwrap();
+
+        hashes.sort_unstable();
+        assert_eq!(list_shard_hashes_in_directory(tmp_dir.path()).unwrap(), hashes);
+    }
 }
@@ -1,8 +1,9 @@
 use std::fmt::Debug;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
+use tracing::{debug, warn};
 
 use crate::errors::AuthError;
 
@@ -74,10 +75,22 @@ impl AuthConfig {
     }
 }
 
+/// Holds the current token, refreshing it once it expires.
+///
+/// Token expirations are set by the server's clock, so expiry checks are made against an estimate
+/// of the server's time: the local time corrected by the clock skew last observed with
+/// `record_server_time`.  A token is also refreshed within a safety margin of its expiration, so
+/// that it doesn't expire while a request is in flight; for refreshed tokens, the margin is capped
+/// at half their lifetime, so that short-lived tokens aren't refreshed on every request.
 pub struct TokenProvider {
     token: String,
     expiration: u64,
     refresher: Arc<dyn TokenRefresher>,
+    expiry_margin: Duration,
+    /// The margin applied to the current token, in seconds.
+    token_margin_secs: u64,
+    /// The server's clock minus the local clock, in seconds.
+    clock_skew_secs: i64,
 }
 
 impl TokenProvider {
@@ -86,23 +99,133 @@ impl TokenProvider {
             token: cfg.token.clone(),
             expiration: cfg.token_expiration,
             refresher: cfg.token_refresher.clone(),
+            expiry_margin: Duration::ZERO,
+            token_margin_secs: 0,
+            clock_skew_secs: 0,
         }
     }
 
+    /// Sets how long before its expiration a token is refreshed.
+    pub fn with_expiry_margin(mut self, expiry_margin: Duration) -> Self {
+        self.expiry_margin = expiry_margin;
+        self.token_margin_secs = expiry_margin.as_secs();
+        self
+    }
+
+    /// Records the time of the server's clock, e.g. from the `Date` header of a response, to
+    /// estimate the skew of the local clock.
+    pub fn record_server_time(&mut self, server_time: SystemTime) {
+        let clock_skew_secs = unix_secs(server_time) as i64 - unix_secs(SystemTime::now()) as i64;
+        if clock_skew_secs.abs() > 1 && clock_skew_secs != self.clock_skew_secs {
+            debug!("Local clock is {}s behind the server's", clock_skew_secs);
+        }
+        self.clock_skew_secs = clock_skew_secs;
+    }
+
     pub async fn get_valid_token(&mut self) -> Result<String, AuthError> {
-        if self.is_expired() {
+        let server_now = self.server_now();
+        if self.is_expired_at(server_now) {
             let (new_token, new_expiry) = self.refresher.refresh().await?;
+            if new_expiry <= server_now {
+                warn!("Refreshed token expired {}s ago by the server's clock", server_now - new_expiry);
+            }
             self.token = new_token;
             self.expiration = new_expiry;
+            self.token_margin_secs = self.margin_for(new_expiry, server_now);
         }
         Ok(self.token.clone())
     }
 
-    fn is_expired(&self) -> bool {
-        let cur_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map(|d| d.as_secs())
-            .unwrap_or(u64::MAX);
-        self.expiration <= cur_time
+    /// The current time of the server's clock as estimated from the local one, in epoch seconds.
+    fn server_now(&self) -> u64 {
+        unix_secs(SystemTime::now()).saturating_add_signed(self.clock_skew_secs)
+    }
+
+    fn margin_for(&self, expiration: u64, server_now: u64) -> u64 {
+        self.expiry_margin.as_secs().min(expiration.saturating_sub(server_now) / 2)
+    }
+
+    fn is_expired_at(&self, server_now: u64) -> bool {
+        self.expiration <= server_now.saturating_add(self.token_margin_secs)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    /// Hands out tokens valid for `lifetime_secs` by the server's clock, counting the refreshes.
+    #[derive(Debug)]
+    struct ServerRefresher {
+        clock_skew_secs: i64,
+        lifetime_secs: u64,
+        refreshes: AtomicU64,
+    }
+
+    #[async_trait]
+    impl TokenRefresher for ServerRefresher {
+        async fn refresh(&self) -> Result<TokenInfo, AuthError> {
+            let n = self.refreshes.fetch_add(1, Ordering::Relaxed) + 1;
+            Ok((format!("token-{n}"), server_now(self.clock_skew_secs) + self.lifetime_secs))
+        }
+    }
+
+    fn server_now(clock_skew_secs: i64) -> u64 {
+        unix_secs(SystemTime::now()).saturating_add_signed(clock_skew_secs)
+    }
+
+    fn skewed_provider(
+        clock_skew_secs: i64,
+        expires_in_secs: i64,
+        lifetime_secs: u64,
+    ) -> (TokenProvider, Arc<ServerRefresher>) {
+        let refresher = Arc::new(ServerRefresher {
+            clock_skew_secs,
+            lifetime_secs,
+            refreshes: AtomicU64::new(0),
+        });
+        let config = AuthConfig {
+            token: "token-0".to_owned(),
+            token_expiration: server_now(clock_skew_secs).saturating_add_signed(expires_in_secs),
+            token_refresher: refresher.clone(),
+        };
+        let mut provider = TokenProvider::new(&config);
+        provider.record_server_time(UNIX_EPOCH + Duration::from_secs(server_now(clock_skew_secs)));
+        (provider.with_expiry_margin(Duration::from_secs(60)), refresher)
+    }
+
+    #[tokio::test]
+    async fn test_expiry_with_clock_skew() {
+        for clock_skew_secs in [-3600, 0, 3600] {
+            // A token valid for 10 more minutes by the server's clock is used as is, whether the local
+            // clock is ahead of or behind the server's.
+            let (mut provider, refresher) = skewed_provider(clock_skew_secs, 600, 3600);
+            assert_eq!(provider.get_valid_token().await.unwrap(), "token-0");
+            assert_eq!(refresher.refreshes.load(Ordering::Relaxed), 0);
+
+            // Tokens expired by the server's clock, or expiring within the margin, are refreshed.
+            for expires_in_secs in [-600, 30] {
+                let (mut provider, refresher) = skewed_provider(clock_skew_secs, expires_in_secs, 3600);
+                assert_eq!(provider.get_valid_token().await.unwrap(), "token-1");
+                assert_eq!(provider.get_valid_token().await.unwrap(), "token-1");
+                assert_eq!(refresher.refreshes.load(Ordering::Relaxed), 1);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_short_lived_tokens_are_not_refreshed_on_every_request() {
+        // Refreshed tokens only live for 40s, less than the margin.
+        let (mut provider, refresher) = skewed_provider(-3600, -1, 40);
+        for _ in 0..3 {
+            assert_eq!(provider.get_valid_token().await.unwrap(), "token-1");
+        }
+        assert_eq!(refresher.refreshes.load(Ordering::Relaxed), 1);
     }
 }
@@ -7,6 +7,7 @@ pub mod singleflight;
 
 mod async_read;
 pub mod limited_joinset;
+pub mod metrics;
 mod output_bytes;
 pub mod progress;
 
@@ -0,0 +1,26 @@
+/// Receives the metrics reported by transfers.  The crates reporting metrics define their names;
+/// `data::metrics` holds the process-wide sink they go to.
+pub trait MetricsSink: Send + Sync {
+    /// Adds `value` to the counter `name`.
+    fn counter(&self, name: &str, value: u64);
+
+    /// Sets the gauge `name` to `value`.
+    fn gauge(&self, name: &str, value: f64);
+
+    /// Records an observation of `value` in the histogram `name`.
+    fn histogram(&self, name: &str, value: f64);
+
+    /// Adds `value` to the counter `name` for the given label values, e.g. `[("host", "cas.example.com")]`.
+    /// A counter is always reported with the same label names.  By default the labels are dropped.
+    fn labeled_counter(&self, name: &str, labels: &[(&str, &str)], value: u64) {
+        let _ = labels;
+        self.counter(name, value)
+    }
+
+    /// Sets the gauge `name` for the given label values to `value`.  A gauge is always reported with
+    /// the same label names.  By default the labels are dropped.
+    fn labeled_gauge(&self, name: &str, labels: &[(&str, &str)], value: f64) {
+        let _ = labels;
+        self.gauge(name, value)
+    }
+}
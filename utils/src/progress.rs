@@ -1,4 +1,8 @@
 use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
 
 /// ProgressUpdater helper to updater some component that progress
 /// has occurred.
@@ -14,3 +18,121 @@ pub struct NoOpProgressUpdater;
 impl ProgressUpdater for NoOpProgressUpdater {
     fn update(&self, _: u64) {}
 }
+
+/// A progress update of one item, as sent by a `ChannelProgressUpdater`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgressEvent {
+    /// The name of the item progressing, e.g. the path of a file.
+    pub item_name: Arc<str>,
+    /// The bytes of progress since the previous event of the item; the increments of all the events
+    /// of an item add up to its completed bytes.
+    pub increment: u64,
+    /// The total bytes of progress of the item so far.
+    pub completed_bytes: u64,
+}
+
+/// ProgressUpdater that forwards the progress of an item into a tokio channel, for async consumers to
+/// `recv()`.
+///
+/// Updates never block: while the channel is full, they are coalesced into the next event that
+/// fits, and once the receiver is dropped they are discarded.  Call `flush` after the last update to
+/// wait until any coalesced progress is sent.
+#[derive(Debug)]
+pub struct ChannelProgressUpdater {
+    item_name: Arc<str>,
+    sender: Sender<ProgressEvent>,
+    // (completed bytes, bytes not sent yet)
+    progress: Mutex<(u64, u64)>,
+}
+
+impl ChannelProgressUpdater {
+    pub fn new(item_name: impl Into<Arc<str>>, sender: Sender<ProgressEvent>) -> Self {
+        Self {
+            item_name: item_name.into(),
+            sender,
+            progress: Mutex::new((0, 0)),
+        }
+    }
+
+    /// Sends the progress coalesced while the channel was full, waiting for room in the channel.
+    pub async fn flush(&self) {
+        let event = {
+            let mut progress = self.progress.lock().unwrap();
+            let (completed_bytes, pending) = *progress;
+            if pending == 0 {
+                return;
+            }
+            progress.1 = 0;
+            self.event(pending, completed_bytes)
+        };
+        // Fails only if the receiver is dropped, in which case the progress is discarded.
+        let _ = self.sender.send(event).await;
+    }
+
+    fn event(&self, increment: u64, completed_bytes: u64) -> ProgressEvent {
+        ProgressEvent {
+            item_name: self.item_name.clone(),
+            increment,
+            completed_bytes,
+        }
+    }
+}
+
+impl ProgressUpdater for ChannelProgressUpdater {
+    fn update(&self, increment: u64) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.0 += increment;
+        progress.1 += increment;
+        match self.sender.try_send(self.event(progress.1, progress.0)) {
+            Ok(()) | Err(TrySendError::Closed(_)) => progress.1 = 0,
+            Err(TrySendError::Full(_)) => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_channel_progress_updater() {
+        let (sender, mut receiver) = mpsc::channel(2);
+        let updater = ChannelProgressUpdater::new("file.bin", sender);
+
+        // The first two updates fill the channel; the rest are coalesced into the next event.
+        for _ in 0..10 {
+            updater.update(100);
+        }
+        let mut received = vec![receiver.recv().await.unwrap(), receiver.recv().await.unwrap()];
+        assert!(receiver.try_recv().is_err());
+
+        // The coalesced progress goes with the next update; progress coalesced by the last updates
+        // is sent by flush once there is room.
+        updater.update(50);
+        updater.update(25);
+        updater.update(10);
+        received.push(receiver.recv().await.unwrap());
+        updater.flush().await;
+        drop(updater);
+        while let Some(event) = receiver.recv().await {
+            received.push(event);
+        }
+
+        assert!(received.iter().all(|event| &*event.item_name == "file.bin"));
+        let increments: Vec<_> = received.iter().map(|event| event.increment).collect();
+        assert_eq!(increments, [100, 100, 850, 25, 10]);
+        assert_eq!(received.last().unwrap().completed_bytes, 1085);
+    }
+
+    #[tokio::test]
+    async fn test_channel_progress_updater_closed() {
+        let (sender, receiver) = mpsc::channel(1);
+        let updater = ChannelProgressUpdater::new("file.bin", sender);
+        drop(receiver);
+
+        updater.update(100);
+        updater.flush().await;
+    }
+}
@@ -7,12 +7,18 @@ pub enum MultithreadedRuntimeError {
     #[error("Error Initializing Multithreaded Runtime: {0:?}")]
     RuntimeInitializationError(std::io::Error),
 
+    #[error("Invalid threadpool configuration: {0}")]
+    InvalidConfig(String),
+
     #[error("Task Panic: {0:?}.")]
     TaskPanic(tokio::task::JoinError),
 
     #[error("Task cancelled; possible runtime shutdown in progress ({0}).")]
     TaskCanceled(String),
 
+    #[error("Task rejected: {0} tasks are queued, at or above the limit of {1}.")]
+    TaskRejected(usize, usize),
+
     #[error("Unknown task runtime error: {0}")]
     Other(String),
 }
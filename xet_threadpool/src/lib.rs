@@ -1,4 +1,6 @@
 pub mod errors;
+mod priority;
 pub mod threadpool;
 
-pub use threadpool::ThreadPool;
+pub use priority::TaskPriority;
+pub use threadpool::{ThreadPool, ThreadPoolConfig, THREADPOOL_MIN_STACK_SIZE};
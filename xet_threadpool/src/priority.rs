@@ -0,0 +1,138 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+/// The priority of a task submitted through `ThreadPool::spawn_prioritized`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskPriority {
+    /// Work a user is actively waiting on, e.g. a requested file download.
+    Interactive,
+    /// Work that can be deferred, e.g. prefetching.
+    Background,
+}
+
+/// The number of consecutive interactive tasks started while background tasks are waiting
+/// before one background task is started.
+pub(crate) const INTERACTIVE_TASK_BURST: usize = 4;
+
+/// Limits the number of concurrently running prioritized tasks to a fixed number of slots,
+/// handing out freed slots to waiting interactive tasks ahead of waiting background tasks.
+///
+/// Fairness guarantees:
+/// - Within a priority, tasks start in the order they were submitted.
+/// - A waiting interactive task starts before any waiting background task, except that after `INTERACTIVE_TASK_BURST`
+///   consecutive interactive starts, one waiting background task is started.  Background tasks thus get at least 1 of
+///   every `INTERACTIVE_TASK_BURST + 1` slots freed under contention and are never starved.
+/// - Priority only affects when a task starts; once running, tasks are scheduled by tokio like any other task and are
+///   not preempted.
+#[derive(Debug)]
+pub(crate) struct PriorityScheduler {
+    state: Mutex<SchedulerState>,
+}
+
+#[derive(Debug)]
+struct SchedulerState {
+    available_slots: usize,
+    interactive: VecDeque<oneshot::Sender<PrioritySlot>>,
+    background: VecDeque<oneshot::Sender<PrioritySlot>>,
+    consecutive_interactive: usize,
+}
+
+impl SchedulerState {
+    fn next_waiter(&mut self) -> Option<oneshot::Sender<PrioritySlot>> {
+        let background_turn = !self.background.is_empty()
+            && (self.interactive.is_empty() || self.consecutive_interactive >= INTERACTIVE_TASK_BURST);
+
+        if background_turn {
+            self.consecutive_interactive = 0;
+            self.background.pop_front()
+        } else {
+            let waiter = self.interactive.pop_front()?;
+            if !self.background.is_empty() {
+                self.consecutive_interactive += 1;
+            }
+            Some(waiter)
+        }
+    }
+}
+
+impl PriorityScheduler {
+    pub(crate) fn new(num_slots: usize) -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new(SchedulerState {
+                available_slots: num_slots,
+                interactive: VecDeque::new(),
+                background: VecDeque::new(),
+                consecutive_interactive: 0,
+            }),
+        })
+    }
+
+    /// Waits for a slot to run a task of the given priority.  The slot is released when the
+    /// returned guard is dropped.
+    pub(crate) async fn acquire(self: &Arc<Self>, priority: TaskPriority) -> PrioritySlot {
+        let receiver = {
+            let mut state = self.state.lock().expect("priority scheduler lock poisoned");
+            if state.available_slots > 0 {
+                state.available_slots -= 1;
+                return PrioritySlot {
+                    scheduler: Some(self.clone()),
+                };
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            match priority {
+                TaskPriority::Interactive => state.interactive.push_back(sender),
+                TaskPriority::Background => state.background.push_back(sender),
+            }
+            receiver
+        };
+
+        // The senders are only dropped without sending if the scheduler itself is dropped,
+        // which can't happen while we hold a reference to it.
+        receiver.await.expect("priority scheduler dropped with waiting tasks")
+    }
+
+    /// The number of tasks waiting for a slot.
+    #[cfg(test)]
+    pub(crate) fn num_waiting(&self) -> usize {
+        let state = self.state.lock().expect("priority scheduler lock poisoned");
+        state.interactive.len() + state.background.len()
+    }
+
+    fn release(self: &Arc<Self>) {
+        let mut state = self.state.lock().expect("priority scheduler lock poisoned");
+        loop {
+            let Some(waiter) = state.next_waiter() else {
+                state.available_slots += 1;
+                return;
+            };
+
+            // Hand the slot directly to the waiter.  If the waiter has gone away, e.g. its
+            // task was aborted, disarm the returned slot and try the next one.
+            match waiter.send(PrioritySlot {
+                scheduler: Some(self.clone()),
+            }) {
+                Ok(()) => return,
+                Err(mut slot) => {
+                    slot.scheduler.take();
+                },
+            }
+        }
+    }
+}
+
+/// A running slot of the `PriorityScheduler`; released on drop.
+#[derive(Debug)]
+pub(crate) struct PrioritySlot {
+    scheduler: Option<Arc<PriorityScheduler>>,
+}
+
+impl Drop for PrioritySlot {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.release();
+        }
+    }
+}
@@ -4,10 +4,12 @@ use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 use tokio::runtime::{Builder as TokioRuntimeBuilder, Handle as TokioRuntimeHandle, Runtime as TokioRuntime};
+use tokio::sync::{Notify, Semaphore};
 use tokio::task::JoinHandle;
 use tracing::debug;
 
 use crate::errors::MultithreadedRuntimeError;
+use crate::priority::{PriorityScheduler, TaskPriority};
 
 /// This module provides a simple wrapper around Tokio's runtime to create a thread pool
 /// with some default settings. It is intended to be used as a singleton thread pool for
@@ -46,8 +48,14 @@ use crate::errors::MultithreadedRuntimeError;
 /// - Thread names prefixed with "hf-xet-"
 /// - 8MB stack size per thread (default is 2MB)
 /// - Maximum of 100 blocking threads
+/// - Maximum of 8 concurrently running tasks from `spawn_prioritized`
+/// - Maximum of 128 network requests in flight across the clients using the pool
+/// - `try_spawn` rejects tasks once 1024 tasks are waiting in the global queue
 /// - All Tokio features enabled (IO, Timer, Signal, Reactor)
 ///
+/// The worker threads, stack size and blocking threads can be changed with a `ThreadPoolConfig`,
+/// e.g. to reserve less address space in memory-constrained environments.
+///
 /// # Structs
 ///
 /// - `ThreadPool`: The main struct that encapsulates the Tokio runtime.
@@ -60,6 +68,62 @@ const THREADPOOL_NUM_WORKER_THREADS: usize = 4; // 4 active threads
 const THREADPOOL_THREAD_ID_PREFIX: &str = "hf-xet"; // thread names will be hf-xet-0, hf-xet-1, etc.
 const THREADPOOL_STACK_SIZE: usize = 8_000_000; // 8MB stack size
 const THREADPOOL_MAX_BLOCKING_THREADS: usize = 100; // max 100 threads can block IO
+const THREADPOOL_PRIORITIZED_TASK_SLOTS: usize = 8; // max 8 prioritized tasks run at once
+const THREADPOOL_MAX_CONCURRENT_NETWORK_REQUESTS: usize = 128; // max 128 requests in flight, uploads and downloads
+const THREADPOOL_MAX_GLOBAL_QUEUE_DEPTH: usize = 1024; // try_spawn rejects tasks past 1024 queued tasks
+
+/// The smallest stack size accepted in a `ThreadPoolConfig`.
+///
+/// Nothing in the upload and download paths recurses deeper than the log of its input size: chunking,
+/// hashing and compression are iterative and keep their buffers and tables on the heap.  The stack is
+/// mostly taken by the futures of transfers, which are polled on worker threads and are largest in
+/// debug builds; uploads and downloads are tested to run with this stack size.
+pub const THREADPOOL_MIN_STACK_SIZE: usize = 2 * 1024 * 1024;
+
+/// The settings of the runtime of a `ThreadPool`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadPoolConfig {
+    /// The number of worker threads, or None for one per CPU core.
+    pub worker_threads: Option<usize>,
+    /// The stack size of every thread of the pool, worker and blocking threads alike; at least
+    /// `THREADPOOL_MIN_STACK_SIZE`.
+    pub stack_size: usize,
+    /// The maximum number of threads running blocking tasks.
+    pub max_blocking_threads: usize,
+}
+
+impl Default for ThreadPoolConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: Some(THREADPOOL_NUM_WORKER_THREADS),
+            stack_size: THREADPOOL_STACK_SIZE,
+            max_blocking_threads: THREADPOOL_MAX_BLOCKING_THREADS,
+        }
+    }
+}
+
+impl ThreadPoolConfig {
+    /// Sets the stack size of the threads of the pool.
+    pub fn with_stack_size(mut self, stack_size: usize) -> Self {
+        self.stack_size = stack_size;
+        self
+    }
+
+    fn validate(&self) -> Result<(), MultithreadedRuntimeError> {
+        if self.stack_size < THREADPOOL_MIN_STACK_SIZE {
+            return Err(MultithreadedRuntimeError::InvalidConfig(format!(
+                "stack size of {} bytes is below the minimum of {THREADPOOL_MIN_STACK_SIZE} bytes",
+                self.stack_size
+            )));
+        }
+        if self.worker_threads == Some(0) || self.max_blocking_threads == 0 {
+            return Err(MultithreadedRuntimeError::InvalidConfig(
+                "worker and blocking thread counts must be positive".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 pub struct ThreadPool {
@@ -76,16 +140,38 @@ pub struct ThreadPool {
 
     // Are we in the middle of a sigint shutdown?
     sigint_shutdown: AtomicBool,
+
+    // Wakes up the external callers blocked in external_run_async_task on a sigint shutdown.
+    sigint_shutdown_notify: Notify,
+
+    // Orders the start of tasks submitted through spawn_prioritized.
+    priority_scheduler: Arc<PriorityScheduler>,
+
+    // Bounds the network requests in flight across all the clients using this pool.
+    network_request_permits: Arc<Semaphore>,
+
+    // The global queue depth at which try_spawn starts rejecting tasks.
+    max_global_queue_depth: usize,
 }
 
 impl ThreadPool {
     pub fn new() -> Result<Self, MultithreadedRuntimeError> {
-        let runtime = new_threadpool(false)?;
+        Self::new_with_config(ThreadPoolConfig::default())
+    }
+
+    /// Like `new`, with the runtime settings of `config`.
+    pub fn new_with_config(config: ThreadPoolConfig) -> Result<Self, MultithreadedRuntimeError> {
+        config.validate()?;
+        let runtime = new_threadpool(&config)?;
         Ok(Self {
             handle: runtime.handle().clone(),
             runtime: std::sync::RwLock::new(Some(runtime)),
             external_executor_count: AtomicUsize::new(0),
             sigint_shutdown: AtomicBool::new(false),
+            sigint_shutdown_notify: Notify::new(),
+            priority_scheduler: PriorityScheduler::new(THREADPOOL_PRIORITIZED_TASK_SLOTS),
+            network_request_permits: Arc::new(Semaphore::new(THREADPOOL_MAX_CONCURRENT_NETWORK_REQUESTS)),
+            max_global_queue_depth: THREADPOOL_MAX_GLOBAL_QUEUE_DEPTH,
         })
     }
 
@@ -97,12 +183,9 @@ impl ThreadPool {
     }
 
     pub fn new_with_hardware_parallelism_limit() -> Result<Self, MultithreadedRuntimeError> {
-        let runtime = new_threadpool(true)?;
-        Ok(Self {
-            handle: runtime.handle().clone(),
-            runtime: std::sync::RwLock::new(Some(runtime)),
-            external_executor_count: AtomicUsize::new(0),
-            sigint_shutdown: AtomicBool::new(false),
+        Self::new_with_config(ThreadPoolConfig {
+            worker_threads: None,
+            ..Default::default()
         })
     }
 
@@ -112,13 +195,41 @@ impl ThreadPool {
             handle,
             external_executor_count: 0.into(),
             sigint_shutdown: false.into(),
+            sigint_shutdown_notify: Notify::new(),
+            priority_scheduler: PriorityScheduler::new(THREADPOOL_PRIORITIZED_TASK_SLOTS),
+            network_request_permits: Arc::new(Semaphore::new(THREADPOOL_MAX_CONCURRENT_NETWORK_REQUESTS)),
+            max_global_queue_depth: THREADPOOL_MAX_GLOBAL_QUEUE_DEPTH,
         }
     }
 
+    /// Sets the maximum number of network requests in flight at once across all the clients using
+    /// this pool, whether uploading or downloading.
+    pub fn with_max_concurrent_network_requests(mut self, max_concurrent_network_requests: usize) -> Self {
+        self.network_request_permits = Arc::new(Semaphore::new(max_concurrent_network_requests));
+        self
+    }
+
+    /// Sets the number of tasks waiting in the global queue at which `try_spawn` rejects new tasks.
+    pub fn with_max_global_queue_depth(mut self, max_global_queue_depth: usize) -> Self {
+        self.max_global_queue_depth = max_global_queue_depth;
+        self
+    }
+
+    /// The permits each network request of a client using this pool holds while in flight.
+    pub fn network_request_permits(&self) -> Arc<Semaphore> {
+        self.network_request_permits.clone()
+    }
+
     pub fn num_worker_threads(&self) -> usize {
         self.handle.metrics().num_workers()
     }
 
+    /// The number of tasks waiting in the runtime's global queue, i.e. spawned from outside the worker
+    /// threads (or overflowing their local queues) and not yet picked up by a worker.
+    pub fn global_queue_depth(&self) -> usize {
+        self.handle.metrics().global_queue_depth()
+    }
+
     /// Gives the number of concurrent calls to external_run_async_task.
     #[inline]
     pub fn external_executor_count(&self) -> usize {
@@ -130,6 +241,9 @@ impl ThreadPool {
         // Shut down the tokio
         self.sigint_shutdown.store(true, Ordering::SeqCst);
 
+        // Release any external callers waiting on tasks, as those tasks are about to be canceled.
+        self.sigint_shutdown_notify.notify_waiters();
+
         if cfg!(debug_assertions) {
             eprintln!("SIGINT detected, shutting down.");
         }
@@ -156,21 +270,41 @@ impl ThreadPool {
 
     /// This function should ONLY be used by threads outside of tokio; it should not be called
     /// from within a task running on the runtime worker pool.  Doing so can lead to deadlocking.
+    ///
+    /// If a sigint shutdown is or becomes in progress, this returns
+    /// `MultithreadedRuntimeError::TaskCanceled` without waiting for the task.
     pub fn external_run_async_task<F>(&self, future: F) -> Result<F::Output, MultithreadedRuntimeError>
     where
         F: Future + Send + 'static,
         F::Output: Send + Sync,
     {
-        self.external_executor_count.fetch_add(1, Ordering::SeqCst);
+        // Decrements the count when dropped, so that it's correct on every return path.
+        let _executor_count_guard = ExternalExecutorCountGuard::new(&self.external_executor_count);
+
+        if self.in_sigint_shutdown() {
+            return Err(sigint_canceled_error());
+        }
+
+        self.handle.block_on(async move {
+            // Register for the shutdown notification before checking the flag, so a shutdown
+            // between the check and the wait can't be missed.
+            let shutdown_notified = self.sigint_shutdown_notify.notified();
+            tokio::pin!(shutdown_notified);
+            shutdown_notified.as_mut().enable();
+
+            if self.in_sigint_shutdown() {
+                return Err(sigint_canceled_error());
+            }
 
-        let ret = self.handle.block_on(async move {
             // Run the actual task on a task worker thread so we can get back information
             // on issues, including reporting panics as runtime errors.
-            self.handle.spawn(future).await.map_err(MultithreadedRuntimeError::from)
-        });
+            let task = self.handle.spawn(future);
 
-        self.external_executor_count.fetch_sub(1, Ordering::SeqCst);
-        ret
+            tokio::select! {
+                ret = task => ret.map_err(MultithreadedRuntimeError::from),
+                _ = shutdown_notified => Err(sigint_canceled_error()),
+            }
+        })
     }
 
     /// Spawn an async task to run in the background on the current pool of worker threads.
@@ -184,6 +318,57 @@ impl ThreadPool {
         self.handle.spawn(future)
     }
 
+    /// Like `spawn`, but returns `MultithreadedRuntimeError::TaskRejected` without spawning the task
+    /// if `global_queue_depth` is at or above the limit set with `with_max_global_queue_depth`, so
+    /// that a producer can back off instead of building an unbounded backlog.
+    ///
+    /// The check is advisory: the queue depth is a snapshot, so concurrent callers can all pass it
+    /// and overshoot the limit, and tasks from `spawn` and from the workers' local queues aren't
+    /// limited at all.
+    pub fn try_spawn<F>(&self, future: F) -> Result<JoinHandle<F::Output>, MultithreadedRuntimeError>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let queue_depth = self.global_queue_depth();
+        if queue_depth >= self.max_global_queue_depth {
+            debug!("threadpool: try_spawn rejected a task, {}", self);
+            return Err(MultithreadedRuntimeError::TaskRejected(queue_depth, self.max_global_queue_depth));
+        }
+        Ok(self.spawn(future))
+    }
+
+    /// Spawn an async task that starts once one of the `THREADPOOL_PRIORITIZED_TASK_SLOTS` slots
+    /// for prioritized tasks is free, with interactive tasks taking freed slots ahead of background
+    /// ones.  See `PriorityScheduler` for the fairness guarantees.
+    ///
+    /// Only tasks submitted through this function are ordered against each other; tasks from
+    /// `spawn` are not affected.
+    pub fn spawn_prioritized<F>(&self, priority: TaskPriority, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        debug!("threadpool: spawn_prioritized called with {priority:?}, {}", self);
+        let scheduler = self.priority_scheduler.clone();
+        self.handle.spawn(async move {
+            let _slot = scheduler.acquire(priority).await;
+            future.await
+        })
+    }
+
+    /// Run a blocking or CPU-bound closure on the runtime's pool of blocking threads, so it
+    /// doesn't stall the async worker threads.  At most `THREADPOOL_MAX_BLOCKING_THREADS`
+    /// closures run at once; the rest are queued until a blocking thread frees up.
+    pub fn spawn_blocking<F, R>(&self, f: F) -> JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        debug!("threadpool: spawn_blocking called, {}", self);
+        self.handle.spawn_blocking(f)
+    }
+
     pub fn handle(&self) -> tokio::runtime::Handle {
         self.handle.clone()
     }
@@ -212,21 +397,43 @@ impl Display for ThreadPool {
     }
 }
 
+/// Tracks a call to external_run_async_task in the external executor count for its lifetime.
+struct ExternalExecutorCountGuard<'a> {
+    count: &'a AtomicUsize,
+}
+
+impl<'a> ExternalExecutorCountGuard<'a> {
+    fn new(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Self { count }
+    }
+}
+
+impl Drop for ExternalExecutorCountGuard<'_> {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn sigint_canceled_error() -> MultithreadedRuntimeError {
+    MultithreadedRuntimeError::TaskCanceled("sigint shutdown".to_string())
+}
+
 /// Intended to be used as a singleton threadpool for the entire application.
 /// This is a simple wrapper around tokio's runtime, with some default settings.
 /// Intentionally unwrap this because if it fails, the application should not continue.
-fn new_threadpool(maximum_worker_threads: bool) -> Result<TokioRuntime, MultithreadedRuntimeError> {
+fn new_threadpool(config: &ThreadPoolConfig) -> Result<TokioRuntime, MultithreadedRuntimeError> {
     #[cfg(not(target_family = "wasm"))]
     let mut builder = TokioRuntimeBuilder::new_multi_thread();
     #[cfg(target_family = "wasm")]
     let mut builder = TokioRuntimeBuilder::new_current_thread();
-    if !maximum_worker_threads {
-        builder.worker_threads(THREADPOOL_NUM_WORKER_THREADS); // 4 active threads
+    if let Some(worker_threads) = config.worker_threads {
+        builder.worker_threads(worker_threads); // 4 active threads by default
     }
     builder
         .thread_name_fn(get_thread_name) // thread names will be hf-xet-0, hf-xet-1, etc.
-        .thread_stack_size(THREADPOOL_STACK_SIZE) // 8MB stack size, default is 2MB
-        .max_blocking_threads(THREADPOOL_MAX_BLOCKING_THREADS) // max 100 threads can block IO
+        .thread_stack_size(config.stack_size) // 8MB stack size by default, tokio's default is 2MB
+        .max_blocking_threads(config.max_blocking_threads) // max 100 threads can block IO by default
         .enable_all() // enable all features, including IO/Timer/Signal/Reactor
         .build()
         .map_err(MultithreadedRuntimeError::RuntimeInitializationError)
@@ -240,3 +447,198 @@ fn get_thread_name() -> String {
     let id = ATOMIC_ID.fetch_add(1, Ordering::SeqCst);
     format!("{THREADPOOL_THREAD_ID_PREFIX}-{id}")
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Barrier, Mutex};
+    use std::time::Duration;
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::priority::INTERACTIVE_TASK_BURST;
+
+    #[test]
+    fn test_config_stack_size() {
+        let pool = ThreadPool::new_with_config(ThreadPoolConfig::default().with_stack_size(THREADPOOL_MIN_STACK_SIZE))
+            .unwrap();
+        assert_eq!(pool.external_run_async_task(async { 42 }).unwrap(), 42);
+
+        assert!(matches!(
+            ThreadPool::new_with_config(ThreadPoolConfig::default().with_stack_size(THREADPOOL_MIN_STACK_SIZE - 1)),
+            Err(MultithreadedRuntimeError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_external_task_canceled_on_sigint_shutdown() {
+        let pool = ThreadPool::new().unwrap();
+
+        std::thread::scope(|s| {
+            let caller = s.spawn(|| {
+                pool.external_run_async_task(async {
+                    tokio::time::sleep(Duration::from_secs(3600)).await;
+                })
+            });
+
+            while pool.external_executor_count() == 0 {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            pool.perform_sigint_shutdown();
+
+            let ret = caller.join().unwrap();
+            assert!(matches!(ret, Err(MultithreadedRuntimeError::TaskCanceled(_))));
+        });
+        assert_eq!(pool.external_executor_count(), 0);
+
+        // Calls after the shutdown are canceled immediately.
+        let ret = pool.external_run_async_task(async { 42 });
+        assert!(matches!(ret, Err(MultithreadedRuntimeError::TaskCanceled(_))));
+        assert_eq!(pool.external_executor_count(), 0);
+    }
+
+    #[test]
+    fn test_spawn_blocking_concurrency() {
+        let pool = ThreadPool::new().unwrap();
+
+        // All of these wait on each other, so this only completes if they run concurrently.
+        let barrier = Arc::new(Barrier::new(THREADPOOL_MAX_BLOCKING_THREADS));
+        let handles: Vec<_> = (0..THREADPOOL_MAX_BLOCKING_THREADS)
+            .map(|i| {
+                let barrier = barrier.clone();
+                pool.spawn_blocking(move || {
+                    barrier.wait();
+                    i
+                })
+            })
+            .collect();
+        let results = pool
+            .external_run_async_task(async move {
+                let mut results = vec![];
+                for h in handles {
+                    results.push(h.await.unwrap());
+                }
+                results
+            })
+            .unwrap();
+        assert_eq!(results, (0..THREADPOOL_MAX_BLOCKING_THREADS).collect::<Vec<_>>());
+
+        // Tasks beyond the blocking thread limit are queued rather than run at the same time.
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..2 * THREADPOOL_MAX_BLOCKING_THREADS)
+            .map(|_| {
+                let running = running.clone();
+                let max_running = max_running.clone();
+                pool.spawn_blocking(move || {
+                    let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(n, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        pool.external_run_async_task(async move {
+            for h in handles {
+                h.await.unwrap();
+            }
+        })
+        .unwrap();
+        assert!(max_running.load(Ordering::SeqCst) <= THREADPOOL_MAX_BLOCKING_THREADS);
+    }
+
+    #[test]
+    fn test_try_spawn_rejects_when_saturated() {
+        const MAX_QUEUE_DEPTH: usize = 4;
+        let pool = ThreadPool::new_with_config(ThreadPoolConfig {
+            worker_threads: Some(1),
+            ..Default::default()
+        })
+        .unwrap()
+        .with_max_global_queue_depth(MAX_QUEUE_DEPTH);
+
+        // Block the only worker thread with a long task, so that the tasks spawned after it stay queued.
+        let (started, wait_started) = std::sync::mpsc::channel();
+        let (release, wait_release) = std::sync::mpsc::channel::<()>();
+        let blocker = pool.spawn(async move {
+            started.send(()).unwrap();
+            wait_release.recv().unwrap();
+        });
+        wait_started.recv().unwrap();
+
+        let handles: Vec<_> = (0..MAX_QUEUE_DEPTH)
+            .map(|i| pool.try_spawn(async move { i }).unwrap())
+            .collect();
+        assert_eq!(pool.global_queue_depth(), MAX_QUEUE_DEPTH);
+        assert!(matches!(
+            pool.try_spawn(async { MAX_QUEUE_DEPTH }),
+            Err(MultithreadedRuntimeError::TaskRejected(MAX_QUEUE_DEPTH, MAX_QUEUE_DEPTH))
+        ));
+
+        // Once the queue drains, tasks are accepted again.
+        release.send(()).unwrap();
+        let results = pool
+            .external_run_async_task(async move {
+                blocker.await.unwrap();
+                let mut results = vec![];
+                for h in handles {
+                    results.push(h.await.unwrap());
+                }
+                results
+            })
+            .unwrap();
+        assert_eq!(results, (0..MAX_QUEUE_DEPTH).collect::<Vec<_>>());
+        assert_eq!(pool.global_queue_depth(), 0);
+        assert!(pool.try_spawn(async {}).is_ok());
+    }
+
+    #[test]
+    fn test_spawn_prioritized_order() {
+        let pool = ThreadPool::new().unwrap();
+
+        // Occupy all the slots with background tasks that wait to be released.
+        let mut releases = vec![];
+        for _ in 0..THREADPOOL_PRIORITIZED_TASK_SLOTS {
+            let (release, wait) = oneshot::channel::<()>();
+            releases.push(release);
+            pool.spawn_prioritized(TaskPriority::Background, async move {
+                let _ = wait.await;
+            });
+        }
+
+        // Queue up interleaved tasks, waiting for each to be queued to fix the submission order.
+        let order = Arc::new(Mutex::new(vec![]));
+        let mut handles = vec![];
+        let tasks = [
+            ("b0", TaskPriority::Background),
+            ("b1", TaskPriority::Background),
+            ("i0", TaskPriority::Interactive),
+            ("i1", TaskPriority::Interactive),
+            ("i2", TaskPriority::Interactive),
+            ("i3", TaskPriority::Interactive),
+            ("i4", TaskPriority::Interactive),
+            ("i5", TaskPriority::Interactive),
+        ];
+        for (n_queued, (name, priority)) in tasks.into_iter().enumerate() {
+            let order = order.clone();
+            handles.push(pool.spawn_prioritized(priority, async move {
+                order.lock().unwrap().push(name);
+            }));
+            while pool.priority_scheduler.num_waiting() <= n_queued {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        // Free a single slot; the queued tasks then run one at a time through it.
+        releases.pop().unwrap().send(()).unwrap();
+        pool.external_run_async_task(async move {
+            for h in handles {
+                h.await.unwrap();
+            }
+        })
+        .unwrap();
+
+        assert_eq!(*order.lock().unwrap(), vec!["i0", "i1", "i2", "i3", "b0", "i4", "i5", "b1"]);
+        assert_eq!(INTERACTIVE_TASK_BURST, 4);
+    }
+}